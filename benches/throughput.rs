@@ -0,0 +1,115 @@
+//! Throughput of one cache behind a single lock against the sharded cache,
+//! on a mix of 90% reads and 10% writes over random keys.
+//!
+//! Run with `cargo bench --bench throughput`. The number of threads goes
+//! up to the number of cores, or `THREADS` if set.
+
+use std::{
+    sync::{Arc, Barrier, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use zen_cache_rs::{sharded, Cache, ShardedCache};
+
+const KEYS: u64 = 100_000;
+const RUN_TIME: Duration = Duration::from_secs(2);
+const READ_PERCENT: u64 = 90;
+
+// xorshift, good enough to spread keys and pick reads or writes.
+fn next(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+trait Store: Send + Sync + 'static {
+    fn get(&self, key: u64) -> bool;
+    fn set(&self, key: u64, value: u64);
+}
+
+/// How the server used to share the cache, every request taking the
+/// write lock.
+struct SingleLock(RwLock<Cache<u64, u64>>);
+
+impl Store for SingleLock {
+    fn get(&self, key: u64) -> bool {
+        self.0.write().unwrap().get(&key).is_some()
+    }
+
+    fn set(&self, key: u64, value: u64) {
+        self.0.write().unwrap().insert(key, value);
+    }
+}
+
+impl Store for ShardedCache<u64, u64> {
+    fn get(&self, key: u64) -> bool {
+        self.read(&key).get(&key).is_some()
+    }
+
+    fn set(&self, key: u64, value: u64) {
+        self.write(&key).insert(key, value);
+    }
+}
+
+/// Operations per second over all `threads`.
+fn run<S: Store>(store: S, threads: usize) -> f64 {
+    for key in 0..KEYS {
+        store.set(key, key);
+    }
+    let store = Arc::new(store);
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let workers: Vec<_> = (0..threads)
+        .map(|id| {
+            let store = Arc::clone(&store);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                let mut state = 0x9E37_79B9_7F4A_7C15 ^ (id as u64 + 1);
+                let mut ops = 0u64;
+                barrier.wait();
+                let start = Instant::now();
+                while start.elapsed() < RUN_TIME {
+                    // Checking the clock every op would cost more than the op.
+                    for _ in 0..256 {
+                        let key = next(&mut state) % KEYS;
+                        if next(&mut state) % 100 < READ_PERCENT {
+                            std::hint::black_box(store.get(key));
+                        } else {
+                            store.set(key, ops);
+                        }
+                        ops += 1;
+                    }
+                }
+                ops as f64 / start.elapsed().as_secs_f64()
+            })
+        })
+        .collect();
+    barrier.wait();
+    workers.into_iter().map(|worker| worker.join().unwrap()).sum()
+}
+
+fn main() {
+    let cores = thread::available_parallelism().map(|x| x.get()).unwrap_or(1);
+    let max_threads = std::env::var("THREADS")
+        .ok()
+        .and_then(|x| x.parse::<usize>().ok())
+        .unwrap_or(cores)
+        .max(1);
+
+    println!("{} cores, {}% reads over {} keys", cores, READ_PERCENT, KEYS);
+    println!("{:>8} {:>16} {:>16} {:>8}", "threads", "single lock", "sharded", "ratio");
+    let mut threads = 1;
+    while threads <= max_threads {
+        let single = run(SingleLock(RwLock::new(Cache::in_memory())), threads);
+        let sharded = run(ShardedCache::<u64, u64>::in_memory(sharded::DEFAULT_SHARDS), threads);
+        println!(
+            "{:>8} {:>12.0} op/s {:>12.0} op/s {:>7.2}x",
+            threads,
+            single,
+            sharded,
+            sharded / single
+        );
+        threads *= 2;
+    }
+}
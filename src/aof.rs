@@ -0,0 +1,513 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::cache::CacheValue;
+use crate::codec::{self, Decoder, Encoder};
+
+// Every record is framed as: payload length u32 | crc32 of payload u32 | payload.
+// The payload starts with one of the OP_* bytes followed by its fields.
+const OP_SET: u8 = 0;
+const OP_REMOVE: u8 = 1;
+const OP_EXPIRE: u8 = 2;
+const OP_PERSIST: u8 = 3;
+const OP_CLEAR: u8 = 4;
+
+pub const FILE_NAME: &str = "zen-cache.aof";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    /// Sync after every record. Slowest, loses nothing.
+    Always,
+    /// Sync from a background thread once per second.
+    EverySecond,
+    /// Leave syncing to the operating system.
+    Never,
+}
+
+impl FsyncPolicy {
+    pub fn from_name(name: &str) -> Option<FsyncPolicy> {
+        match name.to_lowercase().as_str() {
+            "always" => Some(FsyncPolicy::Always),
+            "everysec" => Some(FsyncPolicy::EverySecond),
+            "never" | "no" => Some(FsyncPolicy::Never),
+            _ => None,
+        }
+    }
+}
+
+/// A mutation as it is written to the log. Expiries are absolute unix
+/// milliseconds so replaying a record twice gives the same result.
+pub enum Record<'a, K = String, V = CacheValue> {
+    Set {
+        key: &'a K,
+        value: &'a V,
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: &'a K,
+    },
+    Expire {
+        key: &'a K,
+        at: u64,
+    },
+    Persist {
+        key: &'a K,
+    },
+    Clear,
+}
+
+/// Somewhere a cache writes its mutations to as they happen. `AppendLog`
+/// is the one the server uses.
+pub trait Journal<K = String, V = CacheValue>: Send + Sync {
+    fn append(&mut self, record: &Record<K, V>) -> Result<(), Error>;
+
+    /// Bytes written so far.
+    fn size(&self) -> u64;
+
+    fn sync(&mut self) -> Result<(), Error>;
+
+    /// Drops the first `offset` bytes, everything a snapshot already covers.
+    fn truncate_front(&mut self, offset: u64) -> Result<(), Error>;
+}
+
+/// Owned form of `Record` produced by replaying the log.
+pub enum Operation {
+    Set {
+        key: String,
+        value: CacheValue,
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: String,
+    },
+    Expire {
+        key: String,
+        at: u64,
+    },
+    Persist {
+        key: String,
+    },
+    Clear,
+}
+
+impl Operation {
+    /// The key the operation is about, `None` for clearing everything.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Operation::Set { key, .. }
+            | Operation::Remove { key }
+            | Operation::Expire { key, .. }
+            | Operation::Persist { key } => Some(key),
+            Operation::Clear => None,
+        }
+    }
+}
+
+fn encode(record: &Record) -> Vec<u8> {
+    let mut payload = Encoder::new();
+    match record {
+        Record::Set {
+            key,
+            value,
+            expires_at,
+        } => {
+            payload
+                .put_u8(OP_SET)
+                .put_str(key)
+                .put_option_u64(*expires_at)
+                .put_value(value);
+        }
+        Record::Remove { key } => {
+            payload.put_u8(OP_REMOVE).put_str(key);
+        }
+        Record::Expire { key, at } => {
+            payload.put_u8(OP_EXPIRE).put_str(key).put_u64(*at);
+        }
+        Record::Persist { key } => {
+            payload.put_u8(OP_PERSIST).put_str(key);
+        }
+        Record::Clear => {
+            payload.put_u8(OP_CLEAR);
+        }
+    }
+    let payload = payload.into_bytes();
+    let mut framed = Encoder::new();
+    framed
+        .put_u32(payload.len() as u32)
+        .put_u32(codec::crc32(&payload))
+        .put_raw(&payload);
+    framed.into_bytes()
+}
+
+fn decode(payload: &[u8]) -> Result<Operation, Error> {
+    let mut decoder = Decoder::new(payload);
+    let operation = match decoder.get_u8()? {
+        OP_SET => Operation::Set {
+            key: decoder.get_string()?,
+            expires_at: decoder.get_option_u64()?,
+            value: decoder.get_value()?,
+        },
+        OP_REMOVE => Operation::Remove {
+            key: decoder.get_string()?,
+        },
+        OP_EXPIRE => Operation::Expire {
+            key: decoder.get_string()?,
+            at: decoder.get_u64()?,
+        },
+        OP_PERSIST => Operation::Persist {
+            key: decoder.get_string()?,
+        },
+        OP_CLEAR => Operation::Clear,
+        op => return Err(codec::invalid(format!("Unknown log operation {}.", op).as_str())),
+    };
+    if !decoder.is_empty() {
+        return Err(codec::invalid("Trailing data in log record."));
+    }
+    Ok(operation)
+}
+
+/// Reads every intact record from the log at `path`. A torn or corrupt
+/// tail, as left behind by a crash mid-write, is cut off so new records
+/// are appended after the last good one.
+pub fn replay(path: &Path) -> Result<Vec<Operation>, Error> {
+    let mut data = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_to_end(&mut data)?;
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    }
+
+    let mut operations = Vec::new();
+    let mut decoder = Decoder::new(&data);
+    let mut good = 0;
+    while !decoder.is_empty() {
+        let record = decoder.get_u32().and_then(|len| {
+            let checksum = decoder.get_u32()?;
+            let payload = decoder.get_raw(len as usize)?;
+            if codec::crc32(payload) != checksum {
+                return Err(codec::invalid("Log record checksum mismatch."));
+            }
+            decode(payload)
+        });
+        match record {
+            Ok(operation) => {
+                operations.push(operation);
+                good = decoder.position();
+            }
+            Err(err) => {
+                eprintln!(
+                    "Truncating write log at byte {} of {}: {}",
+                    good,
+                    data.len(),
+                    err
+                );
+                OpenOptions::new().write(true).open(path)?.set_len(good as u64)?;
+                break;
+            }
+        }
+    }
+    Ok(operations)
+}
+
+/// Append-only log of every cache mutation.
+pub struct AppendLog {
+    path: PathBuf,
+    file: File,
+    policy: FsyncPolicy,
+    len: u64,
+    // Separate handle used by the background syncer so it never contends
+    // with appends. Replaced whenever the log file is rewritten.
+    sync_handle: Arc<Mutex<File>>,
+    stop: Arc<AtomicBool>,
+}
+
+#[allow(dead_code)]
+impl AppendLog {
+    pub fn open(path: PathBuf, policy: FsyncPolicy) -> Result<AppendLog, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        let sync_handle = Arc::new(Mutex::new(file.try_clone()?));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        if policy == FsyncPolicy::EverySecond {
+            let handle = Arc::clone(&sync_handle);
+            let stop = Arc::clone(&stop);
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    std::thread::sleep(Duration::from_secs(1));
+                    if let Err(err) = handle.lock().unwrap().sync_data() {
+                        eprintln!("Error occurred while syncing write log: {}", err);
+                    }
+                }
+            });
+        }
+
+        Ok(AppendLog {
+            path,
+            file,
+            policy,
+            len,
+            sync_handle,
+            stop,
+        })
+    }
+
+    /// Size of the log file in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn append(&mut self, record: &Record) -> Result<(), Error> {
+        let data = encode(record);
+        self.file.write_all(&data)?;
+        self.len += data.len() as u64;
+        if self.policy == FsyncPolicy::Always {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.file.sync_data()
+    }
+
+    /// Drops the first `offset` bytes of the log, i.e. everything a fresh
+    /// snapshot already covers, by rewriting the remainder to a new file
+    /// and renaming it over the old one.
+    pub fn truncate_front(&mut self, offset: u64) -> Result<(), Error> {
+        let mut rest = Vec::new();
+        let mut reader = File::open(&self.path)?;
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_to_end(&mut rest)?;
+
+        let tmp = self.path.with_extension("aof.tmp");
+        {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&tmp)?;
+            file.write_all(&rest)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.len = rest.len() as u64;
+        *self.sync_handle.lock().unwrap() = self.file.try_clone()?;
+        Ok(())
+    }
+}
+
+impl Journal for AppendLog {
+    fn append(&mut self, record: &Record) -> Result<(), Error> {
+        AppendLog::append(self, record)
+    }
+
+    fn size(&self) -> u64 {
+        self.len()
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        AppendLog::sync(self)
+    }
+
+    fn truncate_front(&mut self, offset: u64) -> Result<(), Error> {
+        AppendLog::truncate_front(self, offset)
+    }
+}
+
+/// One log written to by several caches, the shards of a `ShardedCache`.
+/// Records of different shards interleave, but those of a single key all
+/// come from the same shard and so stay in order.
+#[derive(Clone)]
+pub struct SharedLog(Arc<Mutex<AppendLog>>);
+
+impl SharedLog {
+    pub fn new(log: AppendLog) -> SharedLog {
+        SharedLog(Arc::new(Mutex::new(log)))
+    }
+}
+
+impl Journal for SharedLog {
+    fn append(&mut self, record: &Record) -> Result<(), Error> {
+        self.0.lock().unwrap().append(record)
+    }
+
+    fn size(&self) -> u64 {
+        self.0.lock().unwrap().len()
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.0.lock().unwrap().sync()
+    }
+
+    fn truncate_front(&mut self, offset: u64) -> Result<(), Error> {
+        self.0.lock().unwrap().truncate_front(offset)
+    }
+}
+
+impl Drop for AppendLog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.file.sync_data();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A log file in a directory of its own, removed when dropped.
+    struct TempLog(PathBuf);
+
+    impl TempLog {
+        fn new(name: &str) -> TempLog {
+            let dir = std::env::temp_dir().join(format!("zen-cache-aof-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempLog(dir.join(FILE_NAME))
+        }
+
+        fn open(&self) -> AppendLog {
+            AppendLog::open(self.0.clone(), FsyncPolicy::Never).unwrap()
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0.parent().unwrap());
+        }
+    }
+
+    fn set(log: &mut AppendLog, key: &str, value: i32) {
+        log.append(&Record::Set {
+            key: &key.to_string(),
+            value: &CacheValue::Int(value),
+            expires_at: None,
+        })
+        .unwrap();
+    }
+
+    fn keys(operations: &[Operation]) -> Vec<Option<&str>> {
+        operations.iter().map(|x| x.key()).collect()
+    }
+
+    #[test]
+    fn replays_every_record_in_order() {
+        let file = TempLog::new("order");
+        let key = String::from("k");
+        {
+            let mut log = file.open();
+            log.append(&Record::Set {
+                key: &key,
+                value: &CacheValue::String(String::from("v")),
+                expires_at: Some(42),
+            })
+            .unwrap();
+            log.append(&Record::Expire { key: &key, at: 7 }).unwrap();
+            log.append(&Record::Persist { key: &key }).unwrap();
+            log.append(&Record::Remove { key: &key }).unwrap();
+            log.append(&Record::Clear).unwrap();
+            assert_eq!(log.len(), fs::metadata(&file.0).unwrap().len());
+        }
+        let operations = replay(&file.0).unwrap();
+        assert_eq!(operations.len(), 5);
+        assert!(matches!(
+            &operations[0],
+            Operation::Set { key, value: CacheValue::String(value), expires_at: Some(42) }
+                if key == "k" && value == "v"
+        ));
+        assert!(matches!(&operations[1], Operation::Expire { at: 7, .. }));
+        assert!(matches!(&operations[2], Operation::Persist { .. }));
+        assert!(matches!(&operations[3], Operation::Remove { .. }));
+        assert!(matches!(&operations[4], Operation::Clear));
+    }
+
+    #[test]
+    fn missing_log_replays_nothing() {
+        let file = TempLog::new("missing");
+        assert!(replay(&file.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn cuts_off_a_torn_tail() {
+        let file = TempLog::new("torn");
+        let good = {
+            let mut log = file.open();
+            set(&mut log, "a", 1);
+            set(&mut log, "b", 2);
+            log.len()
+        };
+        // Half a record, as a crash mid-write leaves it.
+        let torn = encode(&Record::Set {
+            key: &String::from("c"),
+            value: &CacheValue::Int(3),
+            expires_at: None,
+        });
+        OpenOptions::new()
+            .append(true)
+            .open(&file.0)
+            .unwrap()
+            .write_all(&torn[..torn.len() / 2])
+            .unwrap();
+
+        assert_eq!(keys(&replay(&file.0).unwrap()), [Some("a"), Some("b")]);
+        assert_eq!(fs::metadata(&file.0).unwrap().len(), good);
+        // New records go after the last good one.
+        set(&mut file.open(), "d", 4);
+        assert_eq!(keys(&replay(&file.0).unwrap()), [Some("a"), Some("b"), Some("d")]);
+    }
+
+    #[test]
+    fn cuts_off_at_a_checksum_mismatch() {
+        let file = TempLog::new("checksum");
+        let first = {
+            let mut log = file.open();
+            set(&mut log, "a", 1);
+            let first = log.len();
+            set(&mut log, "b", 2);
+            set(&mut log, "c", 3);
+            first
+        };
+        let mut data = fs::read(&file.0).unwrap();
+        // Last byte of the second record's payload.
+        let second = encode(&Record::Set {
+            key: &String::from("b"),
+            value: &CacheValue::Int(2),
+            expires_at: None,
+        });
+        data[first as usize + second.len() - 1] ^= 0xff;
+        fs::write(&file.0, &data).unwrap();
+
+        assert_eq!(keys(&replay(&file.0).unwrap()), [Some("a")]);
+        assert_eq!(fs::metadata(&file.0).unwrap().len(), first);
+    }
+
+    #[test]
+    fn truncate_front_keeps_the_rest() {
+        let file = TempLog::new("front");
+        let mut log = file.open();
+        set(&mut log, "a", 1);
+        let covered = log.len();
+        set(&mut log, "b", 2);
+        log.truncate_front(covered).unwrap();
+        set(&mut log, "c", 3);
+        assert_eq!(log.len(), fs::metadata(&file.0).unwrap().len());
+        assert_eq!(keys(&replay(&file.0).unwrap()), [Some("b"), Some("c")]);
+    }
+}
@@ -66,4 +66,57 @@ impl ArgHelper {
     
     }
 
-}
\ No newline at end of file
+    /// Every value given for `key`, e.g. both paths of `--key a b`.
+    pub fn get_values(&self, key: &str) -> Vec<String> {
+        match self.get(key) {
+            Some(argument) => argument.value.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Parses a size such as `512`, `64kb`, `128mb` or `2gb` into bytes.
+    /// `None` if it isn't a size or doesn't fit a `usize`.
+    pub fn get_bytes(&self, key: &str) -> Option<usize> {
+        let value = self.get_value(key)?.trim().to_lowercase();
+        let (number, multiplier) = if let Some(x) = value.strip_suffix("gb") {
+            (x, 1024 * 1024 * 1024)
+        } else if let Some(x) = value.strip_suffix("mb") {
+            (x, 1024 * 1024)
+        } else if let Some(x) = value.strip_suffix("kb") {
+            (x, 1024)
+        } else {
+            (value.trim_end_matches('b'), 1)
+        };
+        number.trim().parse::<usize>().ok()?.checked_mul(multiplier)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(input: &[&str]) -> ArgHelper {
+        ArgHelper::parse(std::iter::once("zen-cache-rs").chain(input.iter().copied()).map(String::from).collect())
+    }
+
+    #[test]
+    fn parses_sizes() {
+        let args = args(&["--a", "512", "--b", "64KB", "--c", " 2gb", "--d", "1mb", "--e", "7b"]);
+        assert_eq!(args.get_bytes("a"), Some(512));
+        assert_eq!(args.get_bytes("b"), Some(64 * 1024));
+        assert_eq!(args.get_bytes("c"), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(args.get_bytes("d"), Some(1024 * 1024));
+        assert_eq!(args.get_bytes("e"), Some(7));
+        assert_eq!(args.get_bytes("missing"), None);
+    }
+
+    #[test]
+    fn rejects_sizes_that_dont_fit() {
+        let huge = format!("{}gb", usize::MAX / 1024);
+        let args = args(&["--a", "99999999999gb", "--b", &huge, "--c", "-1mb", "--d", "tenmb", "--e"]);
+        for key in ["a", "b", "c", "d", "e"] {
+            assert_eq!(args.get_bytes(key), None, "{}", key);
+        }
+    }
+}
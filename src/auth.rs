@@ -0,0 +1,392 @@
+use std::{
+    fmt::Display,
+    io::{Error, ErrorKind},
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::Value;
+
+use crate::base64;
+use crate::digest;
+use crate::server::HTMLRequest;
+
+// How far the timestamp of a signed request may be off, in seconds.
+const MAX_CLOCK_SKEW: u64 = 300;
+
+/// What a user may do with the keys they can access.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+impl Access {
+    pub fn from_name(name: &str) -> Option<Access> {
+        match name.trim().to_lowercase().as_str() {
+            "read" | "read-only" | "readonly" => Some(Access::ReadOnly),
+            "write" | "read-write" | "readwrite" => Some(Access::ReadWrite),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct User {
+    pub name: String,
+    pub access: Access,
+    /// Key prefixes the user may touch, empty for every key.
+    pub prefixes: Vec<String>,
+    api_key: Option<String>,
+    password: Option<String>,
+    hmac_secret: Option<String>,
+}
+
+impl User {
+    pub fn can_access(&self, key: &str) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|prefix| key.starts_with(prefix.as_str()))
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// No credentials any method understands.
+    Missing,
+    /// Credentials that don't belong to any user.
+    Invalid(String),
+    /// A known user trying something their ACL doesn't allow.
+    Forbidden(String),
+}
+
+impl AuthError {
+    pub fn status(&self) -> u64 {
+        match self {
+            AuthError::Missing | AuthError::Invalid(_) => 401,
+            AuthError::Forbidden(_) => 403,
+        }
+    }
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "Authentication required."),
+            AuthError::Invalid(message) => write!(f, "Authentication failed: {}", message),
+            AuthError::Forbidden(message) => write!(f, "Forbidden: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Compares secrets in time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn authorization<'a>(request: &'a HTMLRequest, scheme: &str) -> Option<&'a str> {
+    let value = request.get_header("Authorization")?.value.trim();
+    let (name, credentials) = value.split_once(' ')?;
+    if name.eq_ignore_ascii_case(scheme) {
+        Some(credentials.trim())
+    } else {
+        None
+    }
+}
+
+/// One way of proving who sent a request.
+pub trait Authenticator: Send + Sync {
+    /// Finds the user the request's credentials belong to. `Ok(None)` means
+    /// the request carries no credentials this method understands.
+    fn authenticate(&self, request: &HTMLRequest, users: &[Arc<User>]) -> Result<Option<Arc<User>>, AuthError>;
+
+    /// `WWW-Authenticate` challenge sent along with a 401, if any.
+    fn challenge(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+/// Static keys sent as `Authorization: Bearer <key>` or `X-API-Key: <key>`.
+pub struct ApiKey;
+
+impl Authenticator for ApiKey {
+    fn authenticate(&self, request: &HTMLRequest, users: &[Arc<User>]) -> Result<Option<Arc<User>>, AuthError> {
+        let key = match authorization(request, "Bearer")
+            .or_else(|| request.get_header("X-API-Key").map(|x| x.value.trim()))
+        {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        users
+            .iter()
+            .find(|user| {
+                user.api_key
+                    .as_ref()
+                    .is_some_and(|x| constant_time_eq(x.as_bytes(), key.as_bytes()))
+            })
+            .map(|user| Some(Arc::clone(user)))
+            .ok_or_else(|| AuthError::Invalid(String::from("Unknown API key.")))
+    }
+}
+
+/// HTTP Basic authentication, RFC 7617.
+pub struct Basic;
+
+impl Authenticator for Basic {
+    fn authenticate(&self, request: &HTMLRequest, users: &[Arc<User>]) -> Result<Option<Arc<User>>, AuthError> {
+        let credentials = match authorization(request, "Basic") {
+            Some(credentials) => credentials,
+            None => return Ok(None),
+        };
+        let invalid = || AuthError::Invalid(String::from("Wrong user name or password."));
+        let decoded = base64::decode(credentials)
+            .and_then(|x| String::from_utf8(x).ok())
+            .ok_or_else(invalid)?;
+        let (name, password) = decoded.split_once(':').ok_or_else(invalid)?;
+        users
+            .iter()
+            .find(|user| {
+                user.name == name
+                    && user
+                        .password
+                        .as_ref()
+                        .is_some_and(|x| constant_time_eq(x.as_bytes(), password.as_bytes()))
+            })
+            .map(|user| Some(Arc::clone(user)))
+            .ok_or_else(invalid)
+    }
+
+    fn challenge(&self) -> Option<&'static str> {
+        Some("Basic realm=\"zen-cache\", charset=\"UTF-8\"")
+    }
+}
+
+/// Requests signed with a secret shared between client and server. The
+/// client sends `Authorization: HMAC <user>:<signature>` and an
+/// `X-Timestamp` header with the unix time in seconds. The signature is the
+/// hex HMAC-SHA256 of the method, the request target, the timestamp and the
+/// hex SHA-256 of the body, joined by newlines.
+pub struct Hmac;
+
+impl Hmac {
+    pub fn string_to_sign(method: &str, target: &str, timestamp: &str, body: &[u8]) -> String {
+        format!(
+            "{}\n{}\n{}\n{}",
+            method,
+            target,
+            timestamp,
+            digest::to_hex(&digest::sha256(body))
+        )
+    }
+}
+
+impl Authenticator for Hmac {
+    fn authenticate(&self, request: &HTMLRequest, users: &[Arc<User>]) -> Result<Option<Arc<User>>, AuthError> {
+        let credentials = match authorization(request, "HMAC") {
+            Some(credentials) => credentials,
+            None => return Ok(None),
+        };
+        let (name, signature) = credentials
+            .split_once(':')
+            .ok_or_else(|| AuthError::Invalid(String::from("Expected HMAC <user>:<signature>.")))?;
+        let timestamp = request
+            .get_header("X-Timestamp")
+            .map(|x| x.value.trim())
+            .ok_or_else(|| AuthError::Invalid(String::from("Signed requests need an X-Timestamp.")))?;
+
+        // Signatures only hold for a few minutes, so a captured request
+        // can't be replayed forever.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        match timestamp.parse::<u64>() {
+            Ok(sent) if sent.abs_diff(now) <= MAX_CLOCK_SKEW => {}
+            _ => return Err(AuthError::Invalid(String::from("X-Timestamp is missing or too far off."))),
+        }
+
+        let message = Hmac::string_to_sign(&request.method, &request.endpoint, timestamp, &request.body);
+        users
+            .iter()
+            .find(|user| {
+                user.name == name
+                    && user.hmac_secret.as_ref().is_some_and(|secret| {
+                        let expected = digest::to_hex(&digest::hmac_sha256(secret.as_bytes(), message.as_bytes()));
+                        constant_time_eq(expected.as_bytes(), signature.to_lowercase().as_bytes())
+                    })
+            })
+            .map(|user| Some(Arc::clone(user)))
+            .ok_or_else(|| AuthError::Invalid(String::from("Signature does not match.")))
+    }
+}
+
+/// The users allowed in and the methods they may prove themselves with.
+pub struct Auth {
+    users: Vec<Arc<User>>,
+    methods: Vec<Box<dyn Authenticator>>,
+}
+
+#[allow(dead_code)]
+impl Auth {
+    /// Accepts API keys, HTTP Basic and signed requests.
+    pub fn new(users: Vec<User>) -> Auth {
+        Auth {
+            users: users.into_iter().map(Arc::new).collect(),
+            methods: vec![Box::new(ApiKey), Box::new(Basic), Box::new(Hmac)],
+        }
+    }
+
+    /// Reads users from a JSON file like
+    /// `{"users": [{"name": "app", "api_key": "...", "password": "...",
+    /// "hmac_secret": "...", "access": "read-write", "prefixes": ["app:"]}]}`.
+    /// Every credential is optional, `access` defaults to read-only.
+    pub fn from_file(path: &Path) -> Result<Auth, Error> {
+        Auth::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    /// Reads users from JSON laid out like the file `from_file` reads.
+    pub fn from_json(text: &str) -> Result<Auth, Error> {
+        let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+        let json: Value = serde_json::from_str(text)
+            .map_err(|err| invalid(format!("Invalid JSON: {}", err)))?;
+        let entries = json
+            .get("users")
+            .and_then(|x| x.as_array())
+            .ok_or_else(|| invalid(String::from("Expected a 'users' array.")))?;
+
+        let mut users = Vec::new();
+        for entry in entries {
+            let field = |name: &str| entry.get(name).and_then(|x| x.as_str()).map(|x| x.to_string());
+            let name = field("name").ok_or_else(|| invalid(String::from("Every user needs a 'name'.")))?;
+            let access = match field("access") {
+                Some(access) => Access::from_name(&access)
+                    .ok_or_else(|| invalid(format!("Unknown access '{}' for user '{}'.", access, name)))?,
+                None => Access::ReadOnly,
+            };
+            let prefixes = match entry.get("prefixes") {
+                None => Vec::new(),
+                Some(prefixes) => prefixes
+                    .as_array()
+                    .and_then(|x| x.iter().map(|x| x.as_str().map(|x| x.to_string())).collect())
+                    .ok_or_else(|| invalid(format!("'prefixes' of user '{}' must be strings.", name)))?,
+            };
+            users.push(User {
+                access,
+                prefixes,
+                api_key: field("api_key"),
+                password: field("password"),
+                hmac_secret: field("hmac_secret"),
+                name,
+            });
+        }
+        Ok(Auth::new(users))
+    }
+
+    pub fn add_method(&mut self, method: Box<dyn Authenticator>) -> &mut Auth {
+        self.methods.push(method);
+        self
+    }
+
+    /// Finds the user behind a request, trying every method in turn.
+    pub fn authenticate(&self, request: &HTMLRequest) -> Result<Arc<User>, AuthError> {
+        for method in &self.methods {
+            if let Some(user) = method.authenticate(request, &self.users)? {
+                return Ok(user);
+            }
+        }
+        Err(AuthError::Missing)
+    }
+
+    /// Finds the user behind credentials sent outside of HTTP, like RESP's
+    /// `AUTH`: a user name and password, or an API key on its own.
+    pub fn login(&self, name: Option<&str>, secret: &str) -> Result<Arc<User>, AuthError> {
+        let matches = |stored: &Option<String>| {
+            stored
+                .as_ref()
+                .is_some_and(|x| constant_time_eq(x.as_bytes(), secret.as_bytes()))
+        };
+        self.users
+            .iter()
+            .find(|user| match name {
+                Some(name) => user.name == name && matches(&user.password),
+                None => matches(&user.api_key),
+            })
+            .map(Arc::clone)
+            .ok_or_else(|| AuthError::Invalid(String::from("Wrong user name or password.")))
+    }
+
+    /// Checks the user's ACL against the request. Reading methods need read
+    /// access, everything else read-write, and a `key` has to fall under
+    /// one of the user's prefixes.
+    pub fn authorize(&self, user: &User, method: &str, key: Option<&str>) -> Result<(), AuthError> {
+        let reading = matches!(method, "GET" | "HEAD" | "OPTIONS");
+        self.authorize_access(user, !reading, key)
+    }
+
+    /// Checks the user's ACL for reading or `writing` a `key`, or no key in
+    /// particular.
+    pub fn authorize_access(&self, user: &User, writing: bool, key: Option<&str>) -> Result<(), AuthError> {
+        if writing && user.access == Access::ReadOnly {
+            return Err(AuthError::Forbidden(format!("{} has read-only access.", user.name)));
+        }
+        match key {
+            Some(key) if !user.can_access(key) => Err(AuthError::Forbidden(format!(
+                "{} may not access '{}'.",
+                user.name, key
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// `WWW-Authenticate` challenges to send with a 401.
+    pub fn challenges(&self) -> Vec<&'static str> {
+        self.methods.iter().filter_map(|method| method.challenge()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> Auth {
+        Auth::from_json(
+            r#"{"users": [
+                {"name": "app", "password": "secret", "api_key": "key-1", "access": "read-write", "prefixes": ["app:"]},
+                {"name": "viewer", "password": "view"}
+            ]}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn logs_in_with_password_or_api_key() {
+        let auth = auth();
+        assert_eq!(auth.login(Some("app"), "secret").unwrap().name, "app");
+        assert_eq!(auth.login(None, "key-1").unwrap().name, "app");
+        assert!(auth.login(Some("app"), "view").is_err());
+        assert!(auth.login(Some("viewer"), "secret").is_err());
+        // A password is not an API key.
+        assert!(auth.login(None, "secret").is_err());
+    }
+
+    #[test]
+    fn holds_users_to_access_and_prefixes() {
+        let auth = auth();
+        let app = auth.login(Some("app"), "secret").unwrap();
+        let viewer = auth.login(Some("viewer"), "view").unwrap();
+        assert!(auth.authorize_access(&app, true, Some("app:1")).is_ok());
+        assert!(auth.authorize_access(&app, false, Some("other")).is_err());
+        assert!(auth.authorize_access(&viewer, false, Some("other")).is_ok());
+        assert!(auth.authorize_access(&viewer, true, Some("other")).is_err());
+        assert!(auth.authorize(&viewer, "GET", None).is_ok());
+        assert!(auth.authorize(&viewer, "DELETE", None).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_user_files() {
+        assert!(Auth::from_json(r#"{"users": [{"password": "x"}]}"#).is_err());
+        assert!(Auth::from_json(r#"{"users": [{"name": "x", "access": "root"}]}"#).is_err());
+        assert!(Auth::from_json("[]").is_err());
+    }
+}
@@ -0,0 +1,87 @@
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 with padding, RFC 4648.
+pub fn encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(group >> (18 - i * 6)) as usize & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decodes standard base64. Padding is optional, whitespace and any other
+/// character outside the alphabet make it fail.
+pub fn decode(input: &str) -> Option<Vec<u8>> {
+    let input = input.trim_end_matches('=');
+    if input.len() % 4 == 1 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(input.len() * 3 / 4);
+    let mut group: u32 = 0;
+    for (i, c) in input.bytes().enumerate() {
+        let value = ALPHABET.iter().position(|x| *x == c)? as u32;
+        group = (group << 6) | value;
+        if i % 4 == 3 {
+            out.extend_from_slice(&group.to_be_bytes()[1..]);
+            group = 0;
+        }
+    }
+    match input.len() % 4 {
+        2 => out.push((group >> 4) as u8),
+        3 => out.extend_from_slice(&((group >> 2) as u16).to_be_bytes()),
+        _ => {}
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 4648 section 10.
+    const VECTORS: [(&str, &str); 7] = [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ];
+
+    #[test]
+    fn encodes_rfc_vectors() {
+        for (data, encoded) in VECTORS {
+            assert_eq!(encode(data.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn decodes_rfc_vectors_with_or_without_padding() {
+        for (data, encoded) in VECTORS {
+            assert_eq!(decode(encoded).unwrap(), data.as_bytes());
+            assert_eq!(decode(encoded.trim_end_matches('=')).unwrap(), data.as_bytes());
+        }
+    }
+
+    #[test]
+    fn round_trips_every_byte() {
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(decode(&encode(&data)).unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert_eq!(decode("Zm9vY"), None);
+        assert_eq!(decode("Zm9v YmFy"), None);
+        assert_eq!(decode("Zm9v-mFy"), None);
+    }
+}
@@ -1,7 +1,33 @@
-use std::collections::HashMap;
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt::Display,
+    hash::Hash,
+    io::Error,
+    mem::size_of,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, RwLock,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+use crate::aof::{AppendLog, FsyncPolicy, Journal, Operation, Record};
+use crate::base64;
+use crate::codec;
+use crate::collections::{self, SortedSet};
+use crate::json_path::{JsonPath, PathError};
+use crate::eviction::{EntryInfo, EvictionPolicy, Lru};
+use crate::snapshot::{self, SnapshotEntry};
 
 #[allow(dead_code)]
-enum CacheValue {
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheValue {
     Int(i32),
     Int64(i64),
     Float(f64),
@@ -10,39 +36,1144 @@ enum CacheValue {
     IntVec(Vec<i32>),
     I64Vec(Vec<i64>),
     FloatVec(Vec<f64>),
+    List(VecDeque<String>),
+    Set(BTreeSet<String>),
+    Hash(BTreeMap<String, String>),
+    SortedSet(SortedSet),
+    Bytes(Vec<u8>),
+    Bool(bool),
+    Json(Value),
+}
+
+/// Approximate heap size of a JSON document.
+fn json_size(value: &Value) -> usize {
+    match value {
+        Value::String(x) => x.len(),
+        Value::Array(items) => items.iter().map(|x| size_of::<Value>() + json_size(x)).sum(),
+        Value::Object(map) => map
+            .iter()
+            .map(|(key, x)| size_of::<String>() + key.len() + size_of::<Value>() + json_size(x))
+            .sum(),
+        _ => 0,
+    }
+}
+
+impl CacheValue {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            CacheValue::Int(_) => "int",
+            CacheValue::Int64(_) => "i64",
+            CacheValue::Float(_) => "float",
+            CacheValue::String(_) => "string",
+            CacheValue::StringVec(_) => "string_vec",
+            CacheValue::IntVec(_) => "int_vec",
+            CacheValue::I64Vec(_) => "i64_vec",
+            CacheValue::FloatVec(_) => "float_vec",
+            CacheValue::List(_) => "list",
+            CacheValue::Set(_) => "set",
+            CacheValue::Hash(_) => "hash",
+            CacheValue::SortedSet(_) => "zset",
+            CacheValue::Bytes(_) => "bytes",
+            CacheValue::Bool(_) => "bool",
+            CacheValue::Json(_) => "json",
+        }
+    }
+
+    /// Approximate number of bytes the value occupies, heap data included.
+    pub fn size(&self) -> usize {
+        let heap = match self {
+            CacheValue::Int(_) | CacheValue::Int64(_) | CacheValue::Float(_) | CacheValue::Bool(_) => 0,
+            CacheValue::Bytes(value) => value.len(),
+            CacheValue::Json(document) => json_size(document),
+            CacheValue::String(value) => value.len(),
+            CacheValue::StringVec(values) => values
+                .iter()
+                .map(|value| size_of::<String>() + value.len())
+                .sum(),
+            CacheValue::IntVec(values) => values.len() * size_of::<i32>(),
+            CacheValue::I64Vec(values) => values.len() * size_of::<i64>(),
+            CacheValue::FloatVec(values) => values.len() * size_of::<f64>(),
+            CacheValue::List(values) => values
+                .iter()
+                .map(|value| size_of::<String>() + value.len())
+                .sum(),
+            CacheValue::Set(values) => values
+                .iter()
+                .map(|value| size_of::<String>() + value.len())
+                .sum(),
+            CacheValue::Hash(fields) => fields
+                .iter()
+                .map(|(field, value)| 2 * size_of::<String>() + field.len() + value.len())
+                .sum(),
+            // Members are held twice, once by name and once in score order.
+            CacheValue::SortedSet(set) => set
+                .iter()
+                .map(|(member, _)| 2 * (size_of::<String>() + member.len() + size_of::<f64>()))
+                .sum(),
+        };
+        size_of::<CacheValue>() + heap
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            CacheValue::Int(x) => json!(x),
+            CacheValue::Int64(x) => json!(x),
+            CacheValue::Float(x) => json!(x),
+            CacheValue::String(x) => json!(x),
+            CacheValue::StringVec(x) => json!(x),
+            CacheValue::IntVec(x) => json!(x),
+            CacheValue::I64Vec(x) => json!(x),
+            CacheValue::FloatVec(x) => json!(x),
+            CacheValue::List(x) => json!(x),
+            CacheValue::Set(x) => json!(x),
+            CacheValue::Hash(x) => json!(x),
+            CacheValue::SortedSet(x) => Value::Array(
+                x.iter()
+                    .map(|(member, score)| json!({ "member": member, "score": score }))
+                    .collect(),
+            ),
+            // JSON has no binary type, bytes travel as base64.
+            CacheValue::Bytes(x) => json!(base64::encode(x)),
+            CacheValue::Bool(x) => json!(x),
+            CacheValue::Json(x) => x.clone(),
+        }
+    }
+
+    /// Binary data as received over the wire: a `String` if it is valid
+    /// UTF-8, `Bytes` otherwise.
+    pub fn from_bytes(data: Vec<u8>) -> CacheValue {
+        match String::from_utf8(data) {
+            Ok(text) => CacheValue::String(text),
+            Err(err) => CacheValue::Bytes(err.into_bytes()),
+        }
+    }
+
+    fn is_empty_collection(&self) -> bool {
+        match self {
+            CacheValue::List(x) => x.is_empty(),
+            CacheValue::Set(x) => x.is_empty(),
+            CacheValue::Hash(x) => x.is_empty(),
+            CacheValue::SortedSet(x) => x.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Maps the type names accepted from clients onto `type_name()` values.
+    fn canonical_type(type_name: &str) -> Result<&'static str, CacheError> {
+        match type_name.trim().to_lowercase().as_str() {
+            "int" | "i32" => Ok("int"),
+            "i64" | "int64" => Ok("i64"),
+            "float" | "f64" => Ok("float"),
+            "string" | "str" => Ok("string"),
+            "string_vec" => Ok("string_vec"),
+            "int_vec" | "i32_vec" => Ok("int_vec"),
+            "i64_vec" => Ok("i64_vec"),
+            "float_vec" | "f64_vec" => Ok("float_vec"),
+            "list" => Ok("list"),
+            "set" => Ok("set"),
+            "hash" | "map" => Ok("hash"),
+            "zset" | "sorted_set" => Ok("zset"),
+            "bytes" | "binary" => Ok("bytes"),
+            "bool" | "boolean" => Ok("bool"),
+            "json" => Ok("json"),
+            other => Err(CacheError::InvalidValue(format!("Unknown type '{}'.", other))),
+        }
+    }
+
+    /// Converts JSON into a value. Without a type name the variant is
+    /// inferred: integers become `Int` or `Int64` depending on their size,
+    /// arrays take the narrowest vector type that holds all elements and
+    /// objects, or arrays no vector can hold, become `Json` documents.
+    /// Bytes are read from base64 strings and never inferred, neither are
+    /// collections. Lists and sets are read from arrays
+    /// of strings, hashes from objects of strings and sorted sets from an
+    /// object of scores or an array of `{"member", "score"}` objects.
+    pub fn from_json(value: &Value, type_name: Option<&str>) -> Result<CacheValue, CacheError> {
+        let type_name = match type_name {
+            Some(type_name) => CacheValue::canonical_type(type_name)?,
+            None => CacheValue::infer_type(value)?,
+        };
+        let invalid = || {
+            CacheError::InvalidValue(format!("Value {} is not a valid {}.", value, type_name))
+        };
+        let array = || value.as_array().ok_or_else(invalid);
+        let as_i32 = |x: &Value| x.as_i64().and_then(|x| i32::try_from(x).ok());
+        match type_name {
+            "int" => as_i32(value).map(CacheValue::Int).ok_or_else(invalid),
+            "i64" => value.as_i64().map(CacheValue::Int64).ok_or_else(invalid),
+            "float" => value.as_f64().map(CacheValue::Float).ok_or_else(invalid),
+            "string" => value
+                .as_str()
+                .map(|x| CacheValue::String(x.to_string()))
+                .ok_or_else(invalid),
+            "string_vec" => array()?
+                .iter()
+                .map(|x| x.as_str().map(|x| x.to_string()))
+                .collect::<Option<Vec<String>>>()
+                .map(CacheValue::StringVec)
+                .ok_or_else(invalid),
+            "int_vec" => array()?
+                .iter()
+                .map(as_i32)
+                .collect::<Option<Vec<i32>>>()
+                .map(CacheValue::IntVec)
+                .ok_or_else(invalid),
+            "i64_vec" => array()?
+                .iter()
+                .map(|x| x.as_i64())
+                .collect::<Option<Vec<i64>>>()
+                .map(CacheValue::I64Vec)
+                .ok_or_else(invalid),
+            "bool" => value.as_bool().map(CacheValue::Bool).ok_or_else(invalid),
+            "json" => Ok(CacheValue::Json(value.clone())),
+            "bytes" => value
+                .as_str()
+                .and_then(base64::decode)
+                .map(CacheValue::Bytes)
+                .ok_or_else(invalid),
+            "list" => array()?
+                .iter()
+                .map(|x| x.as_str().map(|x| x.to_string()))
+                .collect::<Option<VecDeque<String>>>()
+                .map(CacheValue::List)
+                .ok_or_else(invalid),
+            "set" => array()?
+                .iter()
+                .map(|x| x.as_str().map(|x| x.to_string()))
+                .collect::<Option<BTreeSet<String>>>()
+                .map(CacheValue::Set)
+                .ok_or_else(invalid),
+            "hash" => value
+                .as_object()
+                .ok_or_else(invalid)?
+                .iter()
+                .map(|(field, x)| x.as_str().map(|x| (field.clone(), x.to_string())))
+                .collect::<Option<BTreeMap<String, String>>>()
+                .map(CacheValue::Hash)
+                .ok_or_else(invalid),
+            "zset" => {
+                let scores = match value {
+                    Value::Object(members) => members
+                        .iter()
+                        .map(|(member, score)| Some((member.clone(), score.as_f64()?)))
+                        .collect::<Option<Vec<(String, f64)>>>(),
+                    _ => array()?
+                        .iter()
+                        .map(|x| {
+                            Some((x.get("member")?.as_str()?.to_string(), x.get("score")?.as_f64()?))
+                        })
+                        .collect::<Option<Vec<(String, f64)>>>(),
+                };
+                let mut set = SortedSet::new();
+                for (member, score) in scores.ok_or_else(invalid)? {
+                    set.insert(member, score);
+                }
+                Ok(CacheValue::SortedSet(set))
+            }
+            _ => array()?
+                .iter()
+                .map(|x| x.as_f64())
+                .collect::<Option<Vec<f64>>>()
+                .map(CacheValue::FloatVec)
+                .ok_or_else(invalid),
+        }
+    }
+
+    fn infer_type(value: &Value) -> Result<&'static str, CacheError> {
+        let number_type = |x: &Value| {
+            if x.as_i64().is_some_and(|x| i32::try_from(x).is_ok()) {
+                Some("int")
+            } else if x.is_i64() {
+                Some("i64")
+            } else if x.is_number() {
+                Some("float")
+            } else {
+                None
+            }
+        };
+        match value {
+            Value::Number(_) => Ok(number_type(value).unwrap()),
+            Value::String(_) => Ok("string"),
+            Value::Bool(_) => Ok("bool"),
+            Value::Array(items) if items.is_empty() => Err(CacheError::InvalidValue(
+                String::from("Cannot infer the type of an empty array, pass a type."),
+            )),
+            Value::Array(items) if items.iter().all(|x| x.is_string()) => Ok("string_vec"),
+            Value::Array(items) => {
+                let types = match items.iter().map(number_type).collect::<Option<Vec<&str>>>() {
+                    Some(types) => types,
+                    None => return Ok("json"),
+                };
+                if types.contains(&"float") {
+                    Ok("float_vec")
+                } else if types.contains(&"i64") {
+                    Ok("i64_vec")
+                } else {
+                    Ok("int_vec")
+                }
+            }
+            Value::Object(_) => Ok("json"),
+            Value::Null => Err(CacheError::InvalidValue(String::from(
+                "Cannot store null, delete the key instead.",
+            ))),
+        }
+    }
+
+    /// Parses plain text into a value of the given type. Vectors, lists and
+    /// sets are read from a JSON array or from one element per line, hashes,
+    /// sorted sets and documents only from JSON.
+    pub fn parse(text: &str, type_name: &str) -> Result<CacheValue, CacheError> {
+        let type_name = CacheValue::canonical_type(type_name)?;
+        let trimmed = text.trim();
+        let invalid = || {
+            CacheError::InvalidValue(format!("'{}' is not a valid {}.", trimmed, type_name))
+        };
+        let lines = || trimmed.lines().map(|x| x.trim()).filter(|x| !x.is_empty());
+        let sequence = type_name.ends_with("_vec") || matches!(type_name, "list" | "set");
+        if matches!(type_name, "hash" | "zset" | "json") || sequence && trimmed.starts_with('[') {
+            let json: Value = serde_json::from_str(trimmed).map_err(|_| invalid())?;
+            return CacheValue::from_json(&json, Some(type_name));
+        }
+        match type_name {
+            "int" => trimmed.parse().map(CacheValue::Int).map_err(|_| invalid()),
+            "i64" => trimmed.parse().map(CacheValue::Int64).map_err(|_| invalid()),
+            "float" => trimmed.parse().map(CacheValue::Float).map_err(|_| invalid()),
+            "string" => Ok(CacheValue::String(text.to_string())),
+            "bytes" => Ok(CacheValue::Bytes(text.as_bytes().to_vec())),
+            "bool" => match trimmed.to_lowercase().as_str() {
+                "true" | "1" | "yes" => Ok(CacheValue::Bool(true)),
+                "false" | "0" | "no" => Ok(CacheValue::Bool(false)),
+                _ => Err(invalid()),
+            },
+            "string_vec" => Ok(CacheValue::StringVec(lines().map(|x| x.to_string()).collect())),
+            "list" => Ok(CacheValue::List(lines().map(|x| x.to_string()).collect())),
+            "set" => Ok(CacheValue::Set(lines().map(|x| x.to_string()).collect())),
+            "int_vec" => lines()
+                .map(|x| x.parse().map_err(|_| invalid()))
+                .collect::<Result<Vec<i32>, CacheError>>()
+                .map(CacheValue::IntVec),
+            "i64_vec" => lines()
+                .map(|x| x.parse().map_err(|_| invalid()))
+                .collect::<Result<Vec<i64>, CacheError>>()
+                .map(CacheValue::I64Vec),
+            _ => lines()
+                .map(|x| x.parse().map_err(|_| invalid()))
+                .collect::<Result<Vec<f64>, CacheError>>()
+                .map(CacheValue::FloatVec),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CacheError {
+    NotFound(String),
+    InvalidValue(String),
+    TypeMismatch {
+        key: String,
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// An increment that doesn't fit the type of the stored number.
+    Overflow(String),
+    /// A JSON path that leads nowhere in the document stored at the key.
+    PathNotFound {
+        key: String,
+        path: String,
+    },
+    /// A conditional write found the entry at a different version.
+    VersionMismatch {
+        key: String,
+        expected: u64,
+        found: u64,
+    },
+}
+
+impl Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheError::NotFound(key) => write!(f, "Key '{}' not found.", key),
+            CacheError::InvalidValue(message) => write!(f, "{}", message),
+            CacheError::TypeMismatch { key, expected, found } => write!(
+                f,
+                "Key '{}' holds a value of type {}, expected {}.",
+                key, found, expected
+            ),
+            CacheError::Overflow(key) => {
+                write!(f, "Incrementing '{}' would overflow.", key)
+            }
+            CacheError::PathNotFound { key, path } => {
+                write!(f, "Path {} not found in '{}'.", path, key)
+            }
+            CacheError::VersionMismatch { key, expected, found } => write!(
+                f,
+                "Key '{}' is at version {}, expected {}.",
+                key, found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+/// A change to the cache, as seen by subscribers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheEvent<K = String> {
+    Written(K),
+    Deleted(K),
+    Expired(K),
+    Evicted(K),
+    Cleared,
+}
+
+impl<K> CacheEvent<K> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CacheEvent::Written(_) => "written",
+            CacheEvent::Deleted(_) => "deleted",
+            CacheEvent::Expired(_) => "expired",
+            CacheEvent::Evicted(_) => "evicted",
+            CacheEvent::Cleared => "cleared",
+        }
+    }
+
+    /// The key the event is about, `None` if it is about all of them.
+    pub fn key(&self) -> Option<&K> {
+        match self {
+            CacheEvent::Written(key)
+            | CacheEvent::Deleted(key)
+            | CacheEvent::Expired(key)
+            | CacheEvent::Evicted(key) => Some(key),
+            CacheEvent::Cleared => None,
+        }
+    }
+}
+
+// Generates a typed getter returning a clone of the stored value, or a
+// TypeMismatch error if the entry holds a different variant.
+macro_rules! typed_getter {
+    ($name:ident, $variant:ident, $type:ty, $expected:literal) => {
+        pub fn $name(&self, key: &str) -> Result<$type, CacheError> {
+            match self.get(key) {
+                Some(CacheValue::$variant(value)) => Ok(value.clone()),
+                Some(other) => Err(CacheError::TypeMismatch {
+                    key: key.to_string(),
+                    expected: $expected,
+                    found: other.type_name(),
+                }),
+                None => Err(CacheError::NotFound(key.to_string())),
+            }
+        }
+    };
+}
+
+// Generates a function borrowing the collection inside a value, or failing
+// with a TypeMismatch if the value is something else.
+macro_rules! collection {
+    ($name:ident, $variant:ident, $type:ty, $expected:literal) => {
+        fn $name<'a>(key: &str, value: &'a mut CacheValue) -> Result<&'a mut $type, CacheError> {
+            match value {
+                CacheValue::$variant(collection) => Ok(collection),
+                other => Err(CacheError::TypeMismatch {
+                    key: key.to_string(),
+                    expected: $expected,
+                    found: other.type_name(),
+                }),
+            }
+        }
+    };
+}
+
+collection!(list_mut, List, VecDeque<String>, "list");
+collection!(set_mut, Set, BTreeSet<String>, "set");
+collection!(hash_mut, Hash, BTreeMap<String, String>, "hash");
+collection!(zset_mut, SortedSet, SortedSet, "zset");
+collection!(json_mut, Json, Value, "json");
+
+fn path_error(key: &str, path: &JsonPath, err: PathError) -> CacheError {
+    match err {
+        PathError::NotFound => CacheError::PathNotFound {
+            key: key.to_string(),
+            path: path.to_string(),
+        },
+        err => CacheError::InvalidValue(err.to_string()),
+    }
+}
+
+struct Entry<V = CacheValue> {
+    value: V,
+    expires_at: Option<Instant>,
+    /// Changes on every write to the key, never repeats within a process.
+    version: u64,
+}
+
+impl<V> Entry<V> {
+    fn is_expired(&self, now: Instant) -> bool {
+        match self.expires_at {
+            Some(at) => at <= now,
+            None => false,
+        }
+    }
+}
+
+
+/// Heap size of an entry beyond its struct, counted against `max_memory`.
+pub type Weigher<K, V> = fn(&K, &V) -> usize;
+
+/// Memory and entries counted across several caches, so the shards of a
+/// `ShardedCache` stay within one `max_memory` and `max_entries` between
+/// them instead of each getting a slice of it.
+#[derive(Default)]
+pub struct Budget {
+    used_memory: AtomicUsize,
+    entries: AtomicUsize,
+}
+
+impl Budget {
+    pub fn used_memory(&self) -> usize {
+        self.used_memory.load(Ordering::SeqCst)
+    }
+
+    pub fn entries(&self) -> usize {
+        self.entries.load(Ordering::SeqCst)
+    }
+
+    fn update(counter: &AtomicUsize, old: usize, new: usize) {
+        if new >= old {
+            counter.fetch_add(new - old, Ordering::SeqCst);
+        } else {
+            counter.fetch_sub(old - new, Ordering::SeqCst);
+        }
+    }
 }
 
 #[allow(dead_code)]
-pub struct Cache {
+pub struct Cache<K = String, V = CacheValue> {
     savelocation: String,
-    cache: HashMap<String, CacheValue>,
+    cache: HashMap<K, Entry<V>>,
+    // Behind a mutex so read-only lookups can still report accesses.
+    policy: Mutex<Box<dyn EvictionPolicy<K>>>,
+    weigher: Weigher<K, V>,
+    max_memory: Option<usize>,
+    max_entries: Option<usize>,
+    used_memory: usize,
+    // Shared with other caches, see `Budget`. `reported` is the memory and
+    // entry count it was last told about.
+    budget: Option<Arc<Budget>>,
+    reported: (usize, usize),
+    evictions: u64,
+    save_interval: Option<Duration>,
+    // Number of modifications since the last snapshot.
+    dirty: AtomicU64,
+    log: Option<Box<dyn Journal<K, V>>>,
+    log_rewrite_size: Option<u64>,
+    // Version handed to the next write.
+    next_version: u64,
+    subscribers: Vec<Sender<CacheEvent<K>>>,
+}
+
+// Everything that doesn't care what the keys and values are. Lookups take
+// anything the key borrows as, so a `Cache<String, _>` is queried with `&str`.
+#[allow(dead_code)]
+impl<K: Hash + Eq + Clone + Send + 'static, V> Cache<K, V> {
+    /// An empty cache that isn't backed by any files. Only the struct of an
+    /// entry counts against `max_memory` until a weigher is set.
+    pub fn in_memory() -> Cache<K, V> {
+        Cache {
+            savelocation: String::new(),
+            cache: HashMap::new(),
+            policy: Mutex::new(Box::new(Lru::new())),
+            weigher: |_, _| 0,
+            max_memory: None,
+            max_entries: None,
+            used_memory: 0,
+            budget: None,
+            reported: (0, 0),
+            evictions: 0,
+            save_interval: None,
+            dirty: AtomicU64::new(0),
+            log: None,
+            log_rewrite_size: None,
+            next_version: 1,
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Sets how the heap size of keys and values is estimated.
+    pub fn set_weigher(&mut self, weigher: Weigher<K, V>) -> &mut Cache<K, V> {
+        self.weigher = weigher;
+        self.used_memory = self
+            .cache
+            .iter()
+            .map(|(key, entry)| self.entry_size(key, &entry.value))
+            .sum();
+        self.enforce_limits();
+        self
+    }
+
+    /// Records every mutation to `journal` from now on, replacing any
+    /// journal set before.
+    pub fn set_journal(&mut self, journal: Option<Box<dyn Journal<K, V>>>) -> &mut Cache<K, V> {
+        self.log = journal;
+        self
+    }
+
+    /// Returns a receiver for every change made from now on. Dropping the
+    /// receiver unsubscribes.
+    pub fn subscribe(&mut self) -> Receiver<CacheEvent<K>> {
+        let (sender, receiver) = mpsc::channel();
+        self.add_subscriber(sender);
+        receiver
+    }
+
+    /// Like `subscribe`, for a channel that may already have other senders.
+    pub fn add_subscriber(&mut self, sender: Sender<CacheEvent<K>>) {
+        self.subscribers.push(sender);
+    }
+
+    // Takes a closure so nothing is allocated while nobody listens.
+    fn notify(&mut self, event: impl FnOnce() -> CacheEvent<K>) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let event = event();
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    fn log(&mut self, record: Record<K, V>) {
+        if let Some(log) = self.log.as_mut() {
+            if let Err(err) = log.append(&record) {
+                eprintln!("Error occurred while writing to the write log: {}", err);
+            }
+        }
+    }
+
+    /// Flushes the write log to disk regardless of the fsync policy.
+    pub fn sync_log(&mut self) -> Result<(), Error> {
+        match self.log.as_mut() {
+            Some(log) => log.sync(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn unsaved_changes(&self) -> u64 {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
+    fn mark_dirty(&mut self) {
+        *self.dirty.get_mut() += 1;
+    }
+
+    /// Limits the approximate memory used by keys and values, in bytes.
+    pub fn set_max_memory(&mut self, max_memory: Option<usize>) -> &mut Cache<K, V> {
+        self.max_memory = max_memory;
+        self.enforce_limits();
+        self
+    }
+
+    pub fn set_max_entries(&mut self, max_entries: Option<usize>) -> &mut Cache<K, V> {
+        self.max_entries = max_entries;
+        self.enforce_limits();
+        self
+    }
+
+    /// Replaces the eviction policy, registering all current keys with it.
+    pub fn set_eviction_policy(&mut self, mut policy: Box<dyn EvictionPolicy<K>>) -> &mut Cache<K, V> {
+        for (key, entry) in self.cache.iter() {
+            policy.record_insert(key, &self.entry_info(key, entry));
+        }
+        self.policy = Mutex::new(policy);
+        self.enforce_limits();
+        self
+    }
+
+    /// Sets both limits without evicting anything yet.
+    pub(crate) fn set_limits(&mut self, max_memory: Option<usize>, max_entries: Option<usize>) {
+        self.max_memory = max_memory;
+        self.max_entries = max_entries;
+    }
+
+    /// Counts this cache against `budget`, so the limits apply to it and
+    /// every other cache sharing it together.
+    pub fn set_budget(&mut self, budget: Option<Arc<Budget>>) -> &mut Cache<K, V> {
+        if let Some(old) = self.budget.take() {
+            Budget::update(&old.used_memory, self.reported.0, 0);
+            Budget::update(&old.entries, self.reported.1, 0);
+        }
+        self.reported = (0, 0);
+        self.budget = budget;
+        self.enforce_limits();
+        self
+    }
+
+    pub fn max_memory(&self) -> Option<usize> {
+        self.max_memory
+    }
+
+    pub fn max_entries(&self) -> Option<usize> {
+        self.max_entries
+    }
+
+    pub fn eviction_policy(&self) -> &'static str {
+        self.policy.lock().unwrap().name()
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.used_memory
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    fn entry_size(&self, key: &K, value: &V) -> usize {
+        size_of::<Entry<V>>() + size_of::<K>() + (self.weigher)(key, value)
+    }
+
+    fn entry_info(&self, key: &K, entry: &Entry<V>) -> EntryInfo {
+        EntryInfo {
+            size: self.entry_size(key, &entry.value),
+            expires_at: entry.expires_at,
+        }
+    }
+
+    // Passes the change in size since the last call on to the budget.
+    fn report_usage(&mut self) {
+        if let Some(budget) = self.budget.as_ref() {
+            let usage = (self.used_memory, self.cache.len());
+            Budget::update(&budget.used_memory, self.reported.0, usage.0);
+            Budget::update(&budget.entries, self.reported.1, usage.1);
+            self.reported = usage;
+        }
+    }
+
+    fn over_limit(&self) -> bool {
+        let (used_memory, entries) = match self.budget.as_ref() {
+            Some(budget) => (budget.used_memory(), budget.entries()),
+            None => (self.used_memory, self.cache.len()),
+        };
+        self.max_memory.is_some_and(|max| used_memory > max)
+            || self.max_entries.is_some_and(|max| entries > max)
+    }
+
+    fn enforce_limits(&mut self) {
+        self.report_usage();
+        while self.evict_one() {}
+    }
+
+    /// Evicts one key if the cache, or the budget it shares, is over a
+    /// limit. Returns false once there is nothing more to do.
+    pub(crate) fn evict_one(&mut self) -> bool {
+        if !self.over_limit() {
+            return false;
+        }
+        let victim = match self.policy.get_mut().unwrap().victim() {
+            Some(victim) => victim,
+            None => return false,
+        };
+        if let Some((key, _)) = self.remove_entry(&victim) {
+            self.evictions += 1;
+            self.notify(|| CacheEvent::Evicted(key));
+        }
+        true
+    }
+
+    fn store(&mut self, key: K, value: V, expires_at: Option<Instant>) {
+        let entry = Entry {
+            value,
+            expires_at,
+            version: self.next_version,
+        };
+        self.next_version += 1;
+        self.log(Record::Set {
+            key: &key,
+            value: &entry.value,
+            expires_at: entry.expires_at.map(codec::instant_to_unix_millis),
+        });
+        let info = self.entry_info(&key, &entry);
+        self.policy.get_mut().unwrap().record_insert(&key, &info);
+        if let Some(old) = self.cache.get(&key) {
+            self.used_memory -= self.entry_size(&key, &old.value);
+        }
+        self.notify(|| CacheEvent::Written(key.clone()));
+        self.cache.insert(key, entry);
+        self.used_memory += info.size;
+        self.mark_dirty();
+        self.enforce_limits();
+    }
+
+    fn remove_entry<Q>(&mut self, key: &Q) -> Option<(K, Entry<V>)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (key, entry) = self.cache.remove_entry(key)?;
+        self.log(Record::Remove { key: &key });
+        self.used_memory -= self.entry_size(&key, &entry.value);
+        self.report_usage();
+        self.policy.get_mut().unwrap().record_remove(&key);
+        self.mark_dirty();
+        Some((key, entry))
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> &mut Cache<K, V> {
+        // Like a plain SET in redis, overwriting a key drops any previous expiry.
+        self.store(key, value, None);
+        self
+    }
+
+    pub fn insert_with_ttl(&mut self, key: K, value: V, ttl: Duration) -> &mut Cache<K, V> {
+        self.store(key, value, Some(Instant::now() + ttl));
+        self
+    }
+
+    /// Overwrites a value but keeps the expiry of the entry it replaces,
+    /// like SET with KEEPTTL in redis.
+    pub fn insert_keep_ttl(&mut self, key: K, value: V) -> &mut Cache<K, V> {
+        let expires_at = self.live_entry(&key).and_then(|(_, entry)| entry.expires_at);
+        self.store(key, value, expires_at);
+        self
+    }
+
+    fn live_entry<Q>(&self, key: &Q) -> Option<(&K, &Entry<V>)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.cache
+            .get_key_value(key)
+            .filter(|(_, entry)| !entry.is_expired(Instant::now()))
+    }
+
+    fn live_entry_mut<Q>(&mut self, key: &Q) -> Option<&mut Entry<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.cache
+            .get_mut(key)
+            .filter(|entry| !entry.is_expired(Instant::now()))
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (key, entry) = self.live_entry(key)?;
+        self.policy.lock().unwrap().record_access(key);
+        Some(&entry.value)
+    }
+
+    /// Version of the current value, bumped by every write to the key.
+    pub fn version<Q>(&self, key: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.live_entry(key).map(|(_, entry)| entry.version)
+    }
+
+    /// Like `get`, along with the version to pass to `set_if_version`.
+    pub fn get_versioned<Q>(&self, key: &Q) -> Option<(&V, u64)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (key, entry) = self.live_entry(key)?;
+        self.policy.lock().unwrap().record_access(key);
+        Some((&entry.value, entry.version))
+    }
+
+    /// Like `get`, but doesn't count as an access for the eviction policy.
+    pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.live_entry(key).map(|(_, entry)| &entry.value)
+    }
+
+    pub fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.live_entry(key).is_some()
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (key, entry) = self.remove_entry(key)?;
+        if entry.is_expired(Instant::now()) {
+            self.notify(|| CacheEvent::Expired(key));
+            return None;
+        }
+        self.notify(|| CacheEvent::Deleted(key));
+        Some(entry.value)
+    }
+
+    /// Sets the time to live of an existing key. Returns false if the key does not exist.
+    pub fn expire<Q>(&mut self, key: &Q, ttl: Duration) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let expires_at = Instant::now() + ttl;
+        match self.live_entry_mut(key) {
+            Some(entry) => entry.expires_at = Some(expires_at),
+            None => return false,
+        }
+        let key = match self.cache.get_key_value(key) {
+            Some((key, _)) => key.clone(),
+            None => return false,
+        };
+        self.policy.get_mut().unwrap().record_expiry(&key, Some(expires_at));
+        self.log(Record::Expire {
+            key: &key,
+            at: codec::instant_to_unix_millis(expires_at),
+        });
+        self.mark_dirty();
+        true
+    }
+
+    /// Removes the expiry of a key. Returns false if the key does not exist
+    /// or had no expiry set.
+    pub fn persist<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let had_expiry = match self.live_entry_mut(key) {
+            Some(entry) => entry.expires_at.take().is_some(),
+            None => return false,
+        };
+        if had_expiry {
+            let key = match self.cache.get_key_value(key) {
+                Some((key, _)) => key.clone(),
+                None => return false,
+            };
+            self.policy.get_mut().unwrap().record_expiry(&key, None);
+            self.log(Record::Persist { key: &key });
+            self.mark_dirty();
+        }
+        had_expiry
+    }
+
+    /// Removes every expired entry and returns how many were dropped.
+    pub fn purge_expired(&mut self) -> usize {
+        let now = Instant::now();
+        let expired: Vec<K> = self
+            .cache
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let count = expired.len();
+        for key in expired {
+            self.remove_entry(&key);
+            self.notify(|| CacheEvent::Expired(key));
+        }
+        count
+    }
+
+    /// Spawns a thread that periodically purges expired entries. The thread
+    /// stops on its own once the cache has been dropped.
+    pub fn spawn_reaper(cache: &Arc<RwLock<Cache<K, V>>>, interval: Duration) -> JoinHandle<()>
+    where
+        V: Send + Sync + 'static,
+        K: Sync,
+    {
+        let weak = Arc::downgrade(cache);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let cache = match weak.upgrade() {
+                Some(cache) => cache,
+                None => return,
+            };
+            let purged = cache.write().unwrap().purge_expired();
+            if purged > 0 {
+                println!("Reaper removed {} expired entries", purged);
+            }
+        })
+    }
+
+    pub fn keys(&self) -> Vec<K> {
+        self.live_keys().cloned().collect()
+    }
+
+    /// Keys that haven't expired, without copying them.
+    pub fn live_keys(&self) -> impl Iterator<Item = &K> {
+        let now = Instant::now();
+        self.cache
+            .iter()
+            .filter(move |(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| key)
+    }
+
+    pub fn len(&self) -> usize {
+        let now = Instant::now();
+        self.cache
+            .values()
+            .filter(|entry| !entry.is_expired(now))
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.clear_entries();
+        self.log(Record::Clear);
+        self.mark_dirty();
+        self.notify(|| CacheEvent::Cleared);
+    }
+
+    /// Drops every entry without logging it or telling subscribers, for
+    /// callers that do that themselves.
+    pub(crate) fn clear_entries(&mut self) {
+        self.cache.clear();
+        self.policy.get_mut().unwrap().clear();
+        self.used_memory = 0;
+        self.report_usage();
+    }
 }
 
+// Errors name the key they are about, so these need a printable one.
+#[allow(dead_code)]
+impl<K: Hash + Eq + Clone + Send + Display + 'static, V> Cache<K, V> {
+    /// Overwrites a value only if the entry is still at `version`, the way
+    /// `insert` would. Returns the new version.
+    pub fn set_if_version(&mut self, key: K, value: V, version: u64) -> Result<u64, CacheError> {
+        match self.version(&key) {
+            None => Err(CacheError::NotFound(key.to_string())),
+            Some(found) if found != version => Err(CacheError::VersionMismatch {
+                key: key.to_string(),
+                expected: version,
+                found,
+            }),
+            Some(_) => {
+                self.store(key, value, None);
+                // Read back from the counter, the entry may already be evicted.
+                Ok(self.next_version - 1)
+            }
+        }
+    }
+
+    /// Remaining time to live of a key, `Ok(None)` if the key never expires.
+    pub fn ttl<Q>(&self, key: &Q) -> Result<Option<Duration>, CacheError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ?Sized,
+    {
+        match self.live_entry(key) {
+            Some((_, entry)) => Ok(entry
+                .expires_at
+                .map(|at| at.saturating_duration_since(Instant::now()))),
+            None => Err(CacheError::NotFound(key.to_string())),
+        }
+    }
+}
+
+// The cache the server runs, with typed values and persistence.
 #[allow(dead_code)]
 impl Cache {
     pub fn new(savelocation: String) -> Cache {
-        // Register panic hook to save to filesystem in case of failure.
-        // This will take quite some work to implement probably.
-        /*
-        panic::set_hook(Box::new(|panici| {
-            eprintln!("Panic detected:\n {}", panici)
-            
-        }));
-         */
-
         Cache {
-            savelocation: savelocation,
-            cache: HashMap::new(),
+            savelocation,
+            weigher: |key, value| key.len() + value.size(),
+            ..Cache::in_memory()
+        }
+    }
+
+    /// Log size in bytes after which the server folds the write log into a
+    /// fresh snapshot, `None` to only do so on the regular snapshot interval.
+    pub fn set_log_rewrite_size(&mut self, size: Option<u64>) -> &mut Cache {
+        self.log_rewrite_size = size;
+        self
+    }
+
+    pub fn log_rewrite_size(&self) -> Option<u64> {
+        self.log_rewrite_size
+    }
+
+    /// Size of the write log in bytes, `None` if it is disabled.
+    pub fn log_size(&self) -> Option<u64> {
+        self.log.as_ref().map(|log| log.size())
+    }
+
+    pub fn log_path(&self) -> PathBuf {
+        PathBuf::from(&self.savelocation).join(crate::aof::FILE_NAME)
+    }
+
+    /// Replays the write log in `savelocation` on top of the current
+    /// contents and from then on records every mutation to it. Returns the
+    /// number of replayed operations.
+    pub fn enable_log(&mut self, policy: FsyncPolicy) -> Result<usize, Error> {
+        let path = self.log_path();
+        let operations = crate::aof::replay(&path)?;
+        let replayed = operations.len();
+        // Apply before attaching the log so replayed operations are not logged again.
+        self.log = None;
+        for operation in operations {
+            self.apply(operation);
+        }
+        self.log = Some(Box::new(AppendLog::open(path, policy)?));
+        Ok(replayed)
+    }
+
+    pub(crate) fn apply(&mut self, operation: Operation) {
+        match operation {
+            Operation::Set {
+                key,
+                value,
+                expires_at,
+            } => match expires_at {
+                Some(millis) => match codec::unix_millis_to_instant(millis) {
+                    Some(at) => self.store(key, value, Some(at)),
+                    None => {
+                        self.remove_entry(&key);
+                    }
+                },
+                None => self.store(key, value, None),
+            },
+            Operation::Remove { key } => {
+                self.remove_entry(&key);
+            }
+            Operation::Expire { key, at } => match codec::unix_millis_to_instant(at) {
+                Some(at) => {
+                    self.expire(&key, at.saturating_duration_since(Instant::now()));
+                }
+                None => {
+                    self.remove_entry(&key);
+                }
+            },
+            Operation::Persist { key } => {
+                self.persist(&key);
+            }
+            Operation::Clear => self.clear(),
         }
     }
 
+    /// How often the server writes a snapshot in the background, `None` to disable.
+    pub fn set_save_interval(&mut self, interval: Option<Duration>) -> &mut Cache {
+        self.save_interval = interval;
+        self
+    }
+
+    pub fn save_interval(&self) -> Option<Duration> {
+        self.save_interval
+    }
+
     pub fn add_int32(&mut self, key: &str, val: i32) -> &mut Cache {
         self.add_i32(String::from(key), val)
     }
 
     pub fn add_i32(&mut self, key: String, value: i32) -> &mut Cache {
-        self.cache.insert(key, CacheValue::Int(value));
-        self
+        self.insert(key, CacheValue::Int(value))
     }
 
     pub fn add_int64(&mut self, key: &str, val: i64) -> &mut Cache {
@@ -50,8 +1181,7 @@ impl Cache {
     }
 
     pub fn add_i64(&mut self, key: String, value: i64) -> &mut Cache {
-        self.cache.insert(key, CacheValue::Int64(value));
-        self
+        self.insert(key, CacheValue::Int64(value))
     }
 
     pub fn add_float(&mut self, key: &str, val: f64) -> &mut Cache {
@@ -59,8 +1189,7 @@ impl Cache {
     }
 
     pub fn add_f64(&mut self, key: String, value: f64) -> &mut Cache {
-        self.cache.insert(key, CacheValue::Float(value));
-        self
+        self.insert(key, CacheValue::Float(value))
     }
 
     pub fn add_str(&mut self, key: &str, val: &str) -> &mut Cache {
@@ -68,8 +1197,7 @@ impl Cache {
     }
 
     pub fn add_string(&mut self, key: String, value: String) -> &mut Cache {
-        self.cache.insert(key, CacheValue::String(value));
-        self
+        self.insert(key, CacheValue::String(value))
     }
 
     pub fn add_vec_str(&mut self, key: &str, value: Vec<&str>) -> &mut Cache {
@@ -87,8 +1215,7 @@ impl Cache {
     }
 
     pub fn add_string_vector(&mut self, key: String, value: Vec<String>) -> &mut Cache {
-        self.cache.insert(key, CacheValue::StringVec(value));
-        self
+        self.insert(key, CacheValue::StringVec(value))
     }
 
     pub fn add_vec_int(&mut self, key: &str, value: Vec<i32>) -> &mut Cache {
@@ -96,8 +1223,7 @@ impl Cache {
     }
 
     pub fn add_int_vector(&mut self, key: String, value: Vec<i32>) -> &mut Cache {
-        self.cache.insert(key, CacheValue::IntVec(value));
-        self
+        self.insert(key, CacheValue::IntVec(value))
     }
 
     pub fn add_vec_i64(&mut self, key: &str, value: Vec<i64>) -> &mut Cache {
@@ -105,8 +1231,7 @@ impl Cache {
     }
 
     pub fn add_i64_vector(&mut self, key: String, value: Vec<i64>) -> &mut Cache {
-        self.cache.insert(key, CacheValue::I64Vec(value));
-        self
+        self.insert(key, CacheValue::I64Vec(value))
     }
 
     pub fn add_vec_f64(&mut self, key: &str, value: Vec<f64>) -> &mut Cache {
@@ -114,7 +1239,623 @@ impl Cache {
     }
 
     pub fn add_f64_vector(&mut self, key: String, value: Vec<f64>) -> &mut Cache {
-        self.cache.insert(key, CacheValue::FloatVec(value));
-        self
+        self.insert(key, CacheValue::FloatVec(value))
+    }
+
+    pub fn add_bytes(&mut self, key: String, value: Vec<u8>) -> &mut Cache {
+        self.insert(key, CacheValue::Bytes(value))
+    }
+
+    pub fn add_bool(&mut self, key: String, value: bool) -> &mut Cache {
+        self.insert(key, CacheValue::Bool(value))
+    }
+
+    typed_getter!(get_i32, Int, i32, "int");
+    typed_getter!(get_i64, Int64, i64, "i64");
+    typed_getter!(get_f64, Float, f64, "float");
+    typed_getter!(get_string, String, String, "string");
+    typed_getter!(get_string_vec, StringVec, Vec<String>, "string_vec");
+    typed_getter!(get_i32_vec, IntVec, Vec<i32>, "int_vec");
+    typed_getter!(get_i64_vec, I64Vec, Vec<i64>, "i64_vec");
+    typed_getter!(get_f64_vec, FloatVec, Vec<f64>, "float_vec");
+    typed_getter!(get_bytes, Bytes, Vec<u8>, "bytes");
+    typed_getter!(get_bool, Bool, bool, "bool");
+
+    pub fn incr(&mut self, key: &str) -> Result<i64, CacheError> {
+        self.incr_by(key, 1)
+    }
+
+    pub fn decr(&mut self, key: &str) -> Result<i64, CacheError> {
+        self.incr_by(key, -1)
+    }
+
+    /// Adds `delta` to an integer and returns the result. A missing key is
+    /// created as an `Int64` holding `delta`, strings holding a number stay
+    /// strings. The type and the expiry of the entry are kept, so an `Int`
+    /// that would leave the i32 range is an overflow.
+    pub fn incr_by(&mut self, key: &str, delta: i64) -> Result<i64, CacheError> {
+        let overflow = || CacheError::Overflow(key.to_string());
+        let (result, value) = match self.peek(key) {
+            None => (delta, CacheValue::Int64(delta)),
+            Some(CacheValue::Int(value)) => {
+                let delta = i32::try_from(delta).map_err(|_| overflow())?;
+                let result = value.checked_add(delta).ok_or_else(overflow)?;
+                (result as i64, CacheValue::Int(result))
+            }
+            Some(CacheValue::Int64(value)) => {
+                let result = value.checked_add(delta).ok_or_else(overflow)?;
+                (result, CacheValue::Int64(result))
+            }
+            Some(CacheValue::String(value)) => {
+                let value = value.trim().parse::<i64>().map_err(|_| {
+                    CacheError::InvalidValue(format!("Key '{}' does not hold an integer.", key))
+                })?;
+                let result = value.checked_add(delta).ok_or_else(overflow)?;
+                (result, CacheValue::String(result.to_string()))
+            }
+            Some(other) => {
+                return Err(CacheError::TypeMismatch {
+                    key: key.to_string(),
+                    expected: "int",
+                    found: other.type_name(),
+                })
+            }
+        };
+        self.insert_keep_ttl(key.to_string(), value);
+        Ok(result)
+    }
+
+    /// Adds `delta` to a number and returns the result. Integers become a
+    /// `Float`, strings holding a number stay strings and a missing key is
+    /// created as a `Float`. Results that aren't finite count as overflow.
+    pub fn incr_by_float(&mut self, key: &str, delta: f64) -> Result<f64, CacheError> {
+        if !delta.is_finite() {
+            return Err(CacheError::InvalidValue(String::from("Increment must be a finite number.")));
+        }
+        let (current, as_string) = match self.peek(key) {
+            None => (0.0, false),
+            Some(CacheValue::Int(value)) => (*value as f64, false),
+            Some(CacheValue::Int64(value)) => (*value as f64, false),
+            Some(CacheValue::Float(value)) => (*value, false),
+            Some(CacheValue::String(value)) => match value.trim().parse::<f64>() {
+                Ok(value) if value.is_finite() => (value, true),
+                _ => {
+                    return Err(CacheError::InvalidValue(format!(
+                        "Key '{}' does not hold a number.",
+                        key
+                    )))
+                }
+            },
+            Some(other) => {
+                return Err(CacheError::TypeMismatch {
+                    key: key.to_string(),
+                    expected: "float",
+                    found: other.type_name(),
+                })
+            }
+        };
+        let result = current + delta;
+        if !result.is_finite() {
+            return Err(CacheError::Overflow(key.to_string()));
+        }
+        let value = if as_string {
+            CacheValue::String(result.to_string())
+        } else {
+            CacheValue::Float(result)
+        };
+        self.insert_keep_ttl(key.to_string(), value);
+        Ok(result)
+    }
+
+    /// Runs `change` on the value of `key` in place, starting from `create`
+    /// if the key is missing. The expiry is kept and the whole value is
+    /// logged, collections left empty are removed like in redis. `change`
+    /// must not touch the value when it fails.
+    fn modify<T>(
+        &mut self,
+        key: &str,
+        create: Option<CacheValue>,
+        change: impl FnOnce(&mut CacheValue) -> Result<T, CacheError>,
+    ) -> Result<T, CacheError> {
+        let key = key.to_string();
+        let existing = match self.live_entry(&key) {
+            Some(_) => self.cache.remove(&key),
+            None => None,
+        };
+        let (mut value, expires_at, old) = match existing {
+            Some(entry) => {
+                let size = self.entry_size(&key, &entry.value);
+                self.used_memory -= size;
+                (entry.value, entry.expires_at, Some((size, entry.version)))
+            }
+            None => match create {
+                Some(value) => (value, None, None),
+                None => return Err(CacheError::NotFound(key)),
+            },
+        };
+
+        let result = match change(&mut value) {
+            Ok(result) => result,
+            Err(err) => {
+                // Put the untouched entry back the way it was.
+                if let Some((size, version)) = old {
+                    self.used_memory += size;
+                    self.cache.insert(
+                        key,
+                        Entry {
+                            value,
+                            expires_at,
+                            version,
+                        },
+                    );
+                }
+                return Err(err);
+            }
+        };
+
+        if value.is_empty_collection() {
+            if old.is_some() {
+                self.report_usage();
+                self.log(Record::Remove { key: &key });
+                self.policy.get_mut().unwrap().record_remove(&key);
+                self.mark_dirty();
+                self.notify(|| CacheEvent::Deleted(key));
+            }
+        } else {
+            self.store(key, value, expires_at);
+        }
+        Ok(result)
+    }
+
+    /// Borrows a value for reading, `Ok(None)` if the key is missing.
+    fn read<T>(
+        &self,
+        key: &str,
+        read: impl FnOnce(&CacheValue) -> Result<T, CacheError>,
+    ) -> Result<Option<T>, CacheError> {
+        match self.get(key) {
+            Some(value) => read(value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Pushes values onto the front or the back of a list, creating it if
+    /// needed, and returns the new length. Pushing to the front reverses
+    /// the values like LPUSH does.
+    pub fn list_push(&mut self, key: &str, values: Vec<String>, front: bool) -> Result<usize, CacheError> {
+        self.modify(key, Some(CacheValue::List(VecDeque::new())), |value| {
+            let list = list_mut(key, value)?;
+            for value in values {
+                if front {
+                    list.push_front(value);
+                } else {
+                    list.push_back(value);
+                }
+            }
+            Ok(list.len())
+        })
+    }
+
+    /// Removes up to `count` values from the front or the back of a list.
+    pub fn list_pop(&mut self, key: &str, count: usize, front: bool) -> Result<Vec<String>, CacheError> {
+        if !self.contains(key) {
+            return Ok(Vec::new());
+        }
+        self.modify(key, None, |value| {
+            let list = list_mut(key, value)?;
+            let count = count.min(list.len());
+            Ok(if front {
+                list.drain(..count).collect()
+            } else {
+                list.drain(list.len() - count..).rev().collect()
+            })
+        })
+    }
+
+    /// Values between two inclusive indices, negative ones count from the end.
+    pub fn list_range(&self, key: &str, start: i64, stop: i64) -> Result<Vec<String>, CacheError> {
+        let values = self.read(key, |value| match value {
+            CacheValue::List(list) => Ok(match collections::index_range(start, stop, list.len()) {
+                Some((first, last)) => list.range(first..=last).cloned().collect(),
+                None => Vec::new(),
+            }),
+            other => Err(Cache::mismatch(key, "list", other)),
+        })?;
+        Ok(values.unwrap_or_default())
+    }
+
+    /// Keeps only the values between two inclusive indices.
+    pub fn list_trim(&mut self, key: &str, start: i64, stop: i64) -> Result<(), CacheError> {
+        if !self.contains(key) {
+            return Ok(());
+        }
+        self.modify(key, None, |value| {
+            let list = list_mut(key, value)?;
+            match collections::index_range(start, stop, list.len()) {
+                Some((first, last)) => {
+                    list.truncate(last + 1);
+                    list.drain(..first);
+                }
+                None => list.clear(),
+            }
+            Ok(())
+        })
+    }
+
+    pub fn list_len(&self, key: &str) -> Result<usize, CacheError> {
+        let len = self.read(key, |value| match value {
+            CacheValue::List(list) => Ok(list.len()),
+            other => Err(Cache::mismatch(key, "list", other)),
+        })?;
+        Ok(len.unwrap_or(0))
+    }
+
+    /// Adds members to a set, creating it if needed. Returns how many were new.
+    pub fn set_add(&mut self, key: &str, members: Vec<String>) -> Result<usize, CacheError> {
+        self.modify(key, Some(CacheValue::Set(BTreeSet::new())), |value| {
+            let set = set_mut(key, value)?;
+            Ok(members.into_iter().filter(|member| set.insert(member.clone())).count())
+        })
+    }
+
+    /// Removes members from a set and returns how many were there.
+    pub fn set_remove(&mut self, key: &str, members: &[String]) -> Result<usize, CacheError> {
+        if !self.contains(key) {
+            return Ok(0);
+        }
+        self.modify(key, None, |value| {
+            let set = set_mut(key, value)?;
+            Ok(members.iter().filter(|member| set.remove(*member)).count())
+        })
+    }
+
+    pub fn set_members(&self, key: &str) -> Result<BTreeSet<String>, CacheError> {
+        let members = self.read(key, |value| match value {
+            CacheValue::Set(set) => Ok(set.clone()),
+            other => Err(Cache::mismatch(key, "set", other)),
+        })?;
+        Ok(members.unwrap_or_default())
+    }
+
+    pub fn set_contains(&self, key: &str, member: &str) -> Result<bool, CacheError> {
+        let found = self.read(key, |value| match value {
+            CacheValue::Set(set) => Ok(set.contains(member)),
+            other => Err(Cache::mismatch(key, "set", other)),
+        })?;
+        Ok(found.unwrap_or(false))
+    }
+
+    /// Members found in every one of the sets, missing keys count as empty.
+    pub fn set_intersect(&self, keys: &[String]) -> Result<BTreeSet<String>, CacheError> {
+        collections::intersect(keys.iter().map(|key| self.set_members(key)))
+    }
+
+    /// Members found in any of the sets.
+    pub fn set_union(&self, keys: &[String]) -> Result<BTreeSet<String>, CacheError> {
+        collections::union(keys.iter().map(|key| self.set_members(key)))
+    }
+
+    /// Sets a field of a hash, creating it if needed. Returns true if the
+    /// field is new.
+    pub fn hash_set(&mut self, key: &str, field: String, value: String) -> Result<bool, CacheError> {
+        self.modify(key, Some(CacheValue::Hash(BTreeMap::new())), |current| {
+            Ok(hash_mut(key, current)?.insert(field, value).is_none())
+        })
+    }
+
+    pub fn hash_get(&self, key: &str, field: &str) -> Result<Option<String>, CacheError> {
+        let value = self.read(key, |value| match value {
+            CacheValue::Hash(hash) => Ok(hash.get(field).cloned()),
+            other => Err(Cache::mismatch(key, "hash", other)),
+        })?;
+        Ok(value.flatten())
+    }
+
+    pub fn hash_get_all(&self, key: &str) -> Result<BTreeMap<String, String>, CacheError> {
+        let hash = self.read(key, |value| match value {
+            CacheValue::Hash(hash) => Ok(hash.clone()),
+            other => Err(Cache::mismatch(key, "hash", other)),
+        })?;
+        Ok(hash.unwrap_or_default())
+    }
+
+    /// Removes fields from a hash and returns how many were there.
+    pub fn hash_delete(&mut self, key: &str, fields: &[String]) -> Result<usize, CacheError> {
+        if !self.contains(key) {
+            return Ok(0);
+        }
+        self.modify(key, None, |value| {
+            let hash = hash_mut(key, value)?;
+            Ok(fields.iter().filter(|field| hash.remove(*field).is_some()).count())
+        })
+    }
+
+    /// Adds members to a sorted set or updates their scores, creating the
+    /// set if needed. Returns how many members were new.
+    pub fn zset_add(&mut self, key: &str, members: Vec<(String, f64)>) -> Result<usize, CacheError> {
+        if members.iter().any(|(_, score)| score.is_nan()) {
+            return Err(CacheError::InvalidValue(String::from("Scores must be numbers.")));
+        }
+        self.modify(key, Some(CacheValue::SortedSet(SortedSet::new())), |value| {
+            let set = zset_mut(key, value)?;
+            Ok(members
+                .into_iter()
+                .filter(|(member, score)| set.insert(member.clone(), *score))
+                .count())
+        })
+    }
+
+    /// Adds `delta` to the score of a member, which starts at 0 if missing,
+    /// and returns the new score.
+    pub fn zset_incr_by(&mut self, key: &str, member: &str, delta: f64) -> Result<f64, CacheError> {
+        self.modify(key, Some(CacheValue::SortedSet(SortedSet::new())), |value| {
+            let set = zset_mut(key, value)?;
+            let score = set.score(member).unwrap_or(0.0) + delta;
+            if score.is_nan() {
+                return Err(CacheError::InvalidValue(String::from("Resulting score is not a number.")));
+            }
+            set.insert(member.to_string(), score);
+            Ok(score)
+        })
+    }
+
+    /// Removes members from a sorted set and returns how many were there.
+    pub fn zset_remove(&mut self, key: &str, members: &[String]) -> Result<usize, CacheError> {
+        if !self.contains(key) {
+            return Ok(0);
+        }
+        self.modify(key, None, |value| {
+            let set = zset_mut(key, value)?;
+            Ok(members.iter().filter(|member| set.remove(member)).count())
+        })
+    }
+
+    pub fn zset_score(&self, key: &str, member: &str) -> Result<Option<f64>, CacheError> {
+        let score = self.read(key, |value| match value {
+            CacheValue::SortedSet(set) => Ok(set.score(member)),
+            other => Err(Cache::mismatch(key, "zset", other)),
+        })?;
+        Ok(score.flatten())
+    }
+
+    /// Position of a member by ascending score.
+    pub fn zset_rank(&self, key: &str, member: &str) -> Result<Option<usize>, CacheError> {
+        let rank = self.read(key, |value| match value {
+            CacheValue::SortedSet(set) => Ok(set.rank(member)),
+            other => Err(Cache::mismatch(key, "zset", other)),
+        })?;
+        Ok(rank.flatten())
+    }
+
+    /// Members with their scores between two inclusive ranks.
+    pub fn zset_range(&self, key: &str, start: i64, stop: i64) -> Result<Vec<(String, f64)>, CacheError> {
+        self.zset_collect(key, |set| set.range(start, stop))
+    }
+
+    /// Members with a score between `min` and `max`, both inclusive.
+    pub fn zset_range_by_score(&self, key: &str, min: f64, max: f64) -> Result<Vec<(String, f64)>, CacheError> {
+        self.zset_collect(key, |set| set.range_by_score(min, max))
+    }
+
+    fn zset_collect(
+        &self,
+        key: &str,
+        select: impl FnOnce(&SortedSet) -> Vec<(&str, f64)>,
+    ) -> Result<Vec<(String, f64)>, CacheError> {
+        let members = self.read(key, |value| match value {
+            CacheValue::SortedSet(set) => Ok(select(set)
+                .into_iter()
+                .map(|(member, score)| (member.to_string(), score))
+                .collect()),
+            other => Err(Cache::mismatch(key, "zset", other)),
+        })?;
+        Ok(members.unwrap_or_default())
+    }
+
+    /// The part of the document at `key` found at `path`.
+    pub fn json_get(&self, key: &str, path: &JsonPath) -> Result<Value, CacheError> {
+        let found = self.read(key, |value| match value {
+            CacheValue::Json(document) => path
+                .get(document)
+                .cloned()
+                .ok_or_else(|| path_error(key, path, PathError::NotFound)),
+            other => Err(Cache::mismatch(key, "json", other)),
+        })?;
+        found.ok_or_else(|| CacheError::NotFound(key.to_string()))
+    }
+
+    /// Writes `value` at `path` in the document at `key`. Only the root path
+    /// creates a missing document.
+    pub fn json_set(&mut self, key: &str, path: &JsonPath, value: Value) -> Result<(), CacheError> {
+        let create = path.is_root().then_some(CacheValue::Json(Value::Null));
+        self.modify(key, create, |current| {
+            path.set(json_mut(key, current)?, value)
+                .map_err(|err| path_error(key, path, err))
+        })
+    }
+
+    /// Removes the part of the document at `path` and returns it.
+    pub fn json_delete(&mut self, key: &str, path: &JsonPath) -> Result<Value, CacheError> {
+        self.modify(key, None, |value| {
+            path.delete(json_mut(key, value)?)
+                .map_err(|err| path_error(key, path, err))
+        })
+    }
+
+    /// Appends values to the array at `path` and returns its new length.
+    pub fn json_append(&mut self, key: &str, path: &JsonPath, values: Vec<Value>) -> Result<usize, CacheError> {
+        self.modify(key, None, |value| {
+            path.append(json_mut(key, value)?, values)
+                .map_err(|err| path_error(key, path, err))
+        })
+    }
+
+    fn mismatch(key: &str, expected: &'static str, found: &CacheValue) -> CacheError {
+        CacheError::TypeMismatch {
+            key: key.to_string(),
+            expected,
+            found: found.type_name(),
+        }
+    }
+
+    pub fn snapshot_path(&self) -> PathBuf {
+        PathBuf::from(&self.savelocation).join(snapshot::FILE_NAME)
+    }
+
+    /// Every live entry the way a snapshot stores it.
+    pub(crate) fn live_entries(&self) -> Vec<(&str, &CacheValue, Option<u64>)> {
+        let now = Instant::now();
+        self.cache
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| {
+                (
+                    key.as_str(),
+                    &entry.value,
+                    entry.expires_at.map(codec::instant_to_unix_millis),
+                )
+            })
+            .collect()
+    }
+
+    fn encode_snapshot(&self) -> (usize, Vec<u8>) {
+        let live = self.live_entries();
+        (live.len(), snapshot::encode(live.len(), live.into_iter()))
+    }
+
+    /// Takes `changes` off the unsaved changes once they are on disk.
+    pub(crate) fn mark_saved(&self, changes: u64) {
+        // Saves may overlap, never take off more than is left.
+        let _ = self
+            .dirty
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |dirty| Some(dirty.saturating_sub(changes)));
+    }
+
+    /// Writes every live entry to the snapshot file in `savelocation` and
+    /// returns the number of entries written.
+    pub fn save(&self) -> Result<usize, Error> {
+        let changes = self.unsaved_changes();
+        let (count, data) = self.encode_snapshot();
+        snapshot::write_atomic(&self.snapshot_path(), &data)?;
+        self.mark_saved(changes);
+        Ok(count)
+    }
+
+    /// Saves a snapshot and syncs the write log, used when shutting down.
+    pub fn flush(&mut self) -> Result<usize, Error> {
+        let count = self.save()?;
+        self.sync_log()?;
+        Ok(count)
+    }
+
+    /// Loads the snapshot from `savelocation`, skipping entries that expired
+    /// in the meantime. Returns the number of entries restored.
+    pub fn load(&mut self) -> Result<usize, Error> {
+        let entries = match snapshot::read(&self.snapshot_path())? {
+            Some(entries) => entries,
+            None => return Ok(0),
+        };
+        let mut loaded = 0;
+        for entry in entries {
+            if self.restore(entry) {
+                loaded += 1;
+            }
+        }
+        *self.dirty.get_mut() = 0;
+        Ok(loaded)
+    }
+
+    /// Stores an entry read from a snapshot. Returns false if it expired
+    /// in the meantime.
+    pub(crate) fn restore(&mut self, entry: SnapshotEntry) -> bool {
+        let expires_at = match entry.expires_at {
+            Some(millis) => match codec::unix_millis_to_instant(millis) {
+                Some(at) => Some(at),
+                None => return false,
+            },
+            None => None,
+        };
+        self.store(entry.key, entry.value, expires_at);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_write_bumps_the_version() {
+        let mut cache: Cache<String, String> = Cache::in_memory();
+        assert_eq!(cache.version("a"), None);
+        cache.insert("a".to_string(), "1".to_string());
+        let first = cache.version("a").unwrap();
+        cache.insert("b".to_string(), "1".to_string());
+        cache.insert("a".to_string(), "2".to_string());
+        let second = cache.version("a").unwrap();
+        assert!(second > first);
+        assert!(cache.version("b").unwrap() > first);
+        assert_eq!(cache.get_versioned("a"), Some((&"2".to_string(), second)));
+    }
+
+    #[test]
+    fn set_if_version_only_writes_the_expected_version() {
+        let mut cache: Cache<String, String> = Cache::in_memory();
+        cache.insert("a".to_string(), "1".to_string());
+        let version = cache.version("a").unwrap();
+
+        let next = cache
+            .set_if_version("a".to_string(), "2".to_string(), version)
+            .unwrap();
+        assert!(next > version);
+        assert_eq!(cache.get_versioned("a"), Some((&"2".to_string(), next)));
+
+        // The version read before the first write is stale now.
+        assert_eq!(
+            cache.set_if_version("a".to_string(), "3".to_string(), version),
+            Err(CacheError::VersionMismatch {
+                key: "a".to_string(),
+                expected: version,
+                found: next,
+            })
+        );
+        assert_eq!(cache.get("a"), Some(&"2".to_string()));
+        assert_eq!(cache.version("a"), Some(next));
+    }
+
+    #[test]
+    fn set_if_version_needs_a_live_key() {
+        let mut cache: Cache<String, String> = Cache::in_memory();
+        assert_eq!(
+            cache.set_if_version("a".to_string(), "1".to_string(), 0),
+            Err(CacheError::NotFound("a".to_string()))
+        );
+        assert_eq!(cache.get("a"), None);
+
+        cache.insert_with_ttl("b".to_string(), "1".to_string(), Duration::from_millis(1));
+        let version = cache.version("b").unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(cache.version("b"), None);
+        assert_eq!(
+            cache.set_if_version("b".to_string(), "2".to_string(), version),
+            Err(CacheError::NotFound("b".to_string()))
+        );
+    }
+
+    #[test]
+    fn in_place_changes_bump_the_version_unless_they_fail() {
+        let mut cache: Cache = Cache::in_memory();
+        cache.incr_by("n", 1).unwrap();
+        let first = cache.version("n").unwrap();
+        cache.incr_by("n", 1).unwrap();
+        let second = cache.version("n").unwrap();
+        assert!(second > first);
+
+        assert!(matches!(
+            cache.list_push("n", vec!["x".to_string()], false),
+            Err(CacheError::TypeMismatch { .. })
+        ));
+        assert_eq!(cache.version("n"), Some(second));
+        assert_eq!(cache.get("n"), Some(&CacheValue::Int64(2)));
     }
 }
@@ -0,0 +1,349 @@
+use std::{
+    io::{Error, ErrorKind},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::cache::CacheValue;
+use crate::collections::SortedSet;
+
+const TAG_INT: u8 = 0;
+const TAG_INT64: u8 = 1;
+const TAG_FLOAT: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_STRING_VEC: u8 = 4;
+const TAG_INT_VEC: u8 = 5;
+const TAG_I64_VEC: u8 = 6;
+const TAG_FLOAT_VEC: u8 = 7;
+const TAG_LIST: u8 = 8;
+const TAG_SET: u8 = 9;
+const TAG_HASH: u8 = 10;
+const TAG_SORTED_SET: u8 = 11;
+const TAG_BYTES: u8 = 12;
+const TAG_BOOL: u8 = 13;
+const TAG_JSON: u8 = 14;
+
+/// Little endian binary writer shared by the snapshot and write log formats.
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+#[allow(dead_code)]
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder { buf: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn put_raw(&mut self, data: &[u8]) -> &mut Encoder {
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    pub fn put_u8(&mut self, value: u8) -> &mut Encoder {
+        self.buf.push(value);
+        self
+    }
+
+    pub fn put_u16(&mut self, value: u16) -> &mut Encoder {
+        self.put_raw(&value.to_le_bytes())
+    }
+
+    pub fn put_u32(&mut self, value: u32) -> &mut Encoder {
+        self.put_raw(&value.to_le_bytes())
+    }
+
+    pub fn put_u64(&mut self, value: u64) -> &mut Encoder {
+        self.put_raw(&value.to_le_bytes())
+    }
+
+    pub fn put_i32(&mut self, value: i32) -> &mut Encoder {
+        self.put_raw(&value.to_le_bytes())
+    }
+
+    pub fn put_i64(&mut self, value: i64) -> &mut Encoder {
+        self.put_raw(&value.to_le_bytes())
+    }
+
+    pub fn put_f64(&mut self, value: f64) -> &mut Encoder {
+        self.put_raw(&value.to_le_bytes())
+    }
+
+    /// Writes a u32 length prefix followed by the data.
+    pub fn put_bytes(&mut self, data: &[u8]) -> &mut Encoder {
+        self.put_u32(data.len() as u32);
+        self.put_raw(data)
+    }
+
+    pub fn put_str(&mut self, value: &str) -> &mut Encoder {
+        self.put_bytes(value.as_bytes())
+    }
+
+    pub fn put_option_u64(&mut self, value: Option<u64>) -> &mut Encoder {
+        match value {
+            Some(value) => self.put_u8(1).put_u64(value),
+            None => self.put_u8(0),
+        }
+    }
+
+    pub fn put_value(&mut self, value: &CacheValue) -> &mut Encoder {
+        match value {
+            CacheValue::Int(x) => self.put_u8(TAG_INT).put_i32(*x),
+            CacheValue::Int64(x) => self.put_u8(TAG_INT64).put_i64(*x),
+            CacheValue::Float(x) => self.put_u8(TAG_FLOAT).put_f64(*x),
+            CacheValue::String(x) => self.put_u8(TAG_STRING).put_str(x),
+            CacheValue::Bytes(x) => self.put_u8(TAG_BYTES).put_bytes(x),
+            CacheValue::Bool(x) => self.put_u8(TAG_BOOL).put_u8(*x as u8),
+            CacheValue::Json(x) => self.put_u8(TAG_JSON).put_str(&x.to_string()),
+            CacheValue::StringVec(values) => {
+                self.put_u8(TAG_STRING_VEC).put_u32(values.len() as u32);
+                for value in values {
+                    self.put_str(value);
+                }
+                self
+            }
+            CacheValue::IntVec(values) => {
+                self.put_u8(TAG_INT_VEC).put_u32(values.len() as u32);
+                for value in values {
+                    self.put_i32(*value);
+                }
+                self
+            }
+            CacheValue::I64Vec(values) => {
+                self.put_u8(TAG_I64_VEC).put_u32(values.len() as u32);
+                for value in values {
+                    self.put_i64(*value);
+                }
+                self
+            }
+            CacheValue::FloatVec(values) => {
+                self.put_u8(TAG_FLOAT_VEC).put_u32(values.len() as u32);
+                for value in values {
+                    self.put_f64(*value);
+                }
+                self
+            }
+            CacheValue::List(values) => {
+                self.put_u8(TAG_LIST).put_u32(values.len() as u32);
+                for value in values {
+                    self.put_str(value);
+                }
+                self
+            }
+            CacheValue::Set(values) => {
+                self.put_u8(TAG_SET).put_u32(values.len() as u32);
+                for value in values {
+                    self.put_str(value);
+                }
+                self
+            }
+            CacheValue::Hash(fields) => {
+                self.put_u8(TAG_HASH).put_u32(fields.len() as u32);
+                for (field, value) in fields {
+                    self.put_str(field).put_str(value);
+                }
+                self
+            }
+            CacheValue::SortedSet(set) => {
+                self.put_u8(TAG_SORTED_SET).put_u32(set.len() as u32);
+                for (member, score) in set.iter() {
+                    self.put_str(member).put_f64(score);
+                }
+                self
+            }
+        }
+    }
+}
+
+/// Reader counterpart of `Encoder`. Every read fails with `InvalidData`
+/// instead of panicking when the input is truncated.
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+#[allow(dead_code)]
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Decoder<'a> {
+        Decoder { data, pos: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    pub fn get_raw(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.data.len() - self.pos < len {
+            return Err(invalid("Unexpected end of data."));
+        }
+        let out = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(out)
+    }
+
+    fn get_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut out = [0; N];
+        out.copy_from_slice(self.get_raw(N)?);
+        Ok(out)
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.get_raw(1)?[0])
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.get_array()?))
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.get_array()?))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.get_array()?))
+    }
+
+    pub fn get_i32(&mut self) -> Result<i32, Error> {
+        Ok(i32::from_le_bytes(self.get_array()?))
+    }
+
+    pub fn get_i64(&mut self) -> Result<i64, Error> {
+        Ok(i64::from_le_bytes(self.get_array()?))
+    }
+
+    pub fn get_f64(&mut self) -> Result<f64, Error> {
+        Ok(f64::from_le_bytes(self.get_array()?))
+    }
+
+    pub fn get_bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.get_u32()? as usize;
+        self.get_raw(len)
+    }
+
+    pub fn get_string(&mut self) -> Result<String, Error> {
+        String::from_utf8(self.get_bytes()?.to_vec()).map_err(|_| invalid("String is not valid UTF-8."))
+    }
+
+    pub fn get_option_u64(&mut self) -> Result<Option<u64>, Error> {
+        match self.get_u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.get_u64()?)),
+            _ => Err(invalid("Invalid option marker.")),
+        }
+    }
+
+    fn get_vec<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Decoder<'a>) -> Result<T, Error>,
+    ) -> Result<Vec<T>, Error> {
+        let len = self.get_u32()? as usize;
+        // Don't trust the length for preallocation, a corrupt file could ask for gigabytes.
+        let mut out = Vec::with_capacity(len.min(1024));
+        for _ in 0..len {
+            out.push(item(self)?);
+        }
+        Ok(out)
+    }
+
+    pub fn get_value(&mut self) -> Result<CacheValue, Error> {
+        match self.get_u8()? {
+            TAG_INT => Ok(CacheValue::Int(self.get_i32()?)),
+            TAG_INT64 => Ok(CacheValue::Int64(self.get_i64()?)),
+            TAG_FLOAT => Ok(CacheValue::Float(self.get_f64()?)),
+            TAG_STRING => Ok(CacheValue::String(self.get_string()?)),
+            TAG_BYTES => Ok(CacheValue::Bytes(self.get_bytes()?.to_vec())),
+            TAG_JSON => serde_json::from_str(&self.get_string()?)
+                .map(CacheValue::Json)
+                .map_err(|_| invalid("Invalid JSON document.")),
+            TAG_BOOL => match self.get_u8()? {
+                0 => Ok(CacheValue::Bool(false)),
+                1 => Ok(CacheValue::Bool(true)),
+                _ => Err(invalid("Invalid bool value.")),
+            },
+            TAG_STRING_VEC => Ok(CacheValue::StringVec(self.get_vec(|d| d.get_string())?)),
+            TAG_INT_VEC => Ok(CacheValue::IntVec(self.get_vec(|d| d.get_i32())?)),
+            TAG_I64_VEC => Ok(CacheValue::I64Vec(self.get_vec(|d| d.get_i64())?)),
+            TAG_FLOAT_VEC => Ok(CacheValue::FloatVec(self.get_vec(|d| d.get_f64())?)),
+            TAG_LIST => Ok(CacheValue::List(self.get_vec(|d| d.get_string())?.into())),
+            TAG_SET => Ok(CacheValue::Set(self.get_vec(|d| d.get_string())?.into_iter().collect())),
+            TAG_HASH => Ok(CacheValue::Hash(
+                self.get_vec(|d| Ok((d.get_string()?, d.get_string()?)))?
+                    .into_iter()
+                    .collect(),
+            )),
+            TAG_SORTED_SET => {
+                let mut set = SortedSet::new();
+                for (member, score) in self.get_vec(|d| Ok((d.get_string()?, d.get_f64()?)))? {
+                    if score.is_nan() {
+                        return Err(invalid("Sorted set score is not a number."));
+                    }
+                    set.insert(member, score);
+                }
+                Ok(CacheValue::SortedSet(set))
+            }
+            tag => Err(invalid(format!("Unknown value tag {}.", tag).as_str())),
+        }
+    }
+}
+
+pub fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// CRC-32 (IEEE 802.3) checksum.
+pub fn crc32(data: &[u8]) -> u32 {
+    const fn table() -> [u32; 256] {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    }
+    const TABLE: [u32; 256] = table();
+
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc = TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Converts a monotonic deadline into wall clock milliseconds since the
+/// unix epoch so it survives a restart.
+pub fn instant_to_unix_millis(at: Instant) -> u64 {
+    let now = Instant::now();
+    let wall = if at >= now {
+        SystemTime::now() + (at - now)
+    } else {
+        SystemTime::now() - (now - at)
+    };
+    wall.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Inverse of `instant_to_unix_millis`. Returns `None` if the deadline has
+/// already passed.
+pub fn unix_millis_to_instant(millis: u64) -> Option<Instant> {
+    let wall = UNIX_EPOCH + Duration::from_millis(millis);
+    let remaining = wall.duration_since(SystemTime::now()).ok()?;
+    Some(Instant::now() + remaining)
+}
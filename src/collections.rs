@@ -0,0 +1,188 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+};
+
+/// A score that can be ordered, NaN never gets this far.
+#[derive(Debug, Clone, Copy)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Score) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Score) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Score) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Members ordered by score, ties broken by the member itself like redis.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SortedSet {
+    scores: HashMap<String, f64>,
+    order: BTreeSet<(Score, String)>,
+}
+
+#[allow(dead_code)]
+impl SortedSet {
+    pub fn new() -> SortedSet {
+        SortedSet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Adds a member or updates its score. Returns true if it is new.
+    pub fn insert(&mut self, member: String, score: f64) -> bool {
+        let existed = match self.scores.insert(member.clone(), score) {
+            Some(old) => {
+                self.order.remove(&(Score(old), member.clone()));
+                true
+            }
+            None => false,
+        };
+        self.order.insert((Score(score), member));
+        !existed
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.order.remove(&(Score(score), member.to_string()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Position of a member counted from the lowest score.
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.score(member)?;
+        self.order
+            .iter()
+            .position(|(x, name)| *x == Score(score) && name == member)
+    }
+
+    /// Members from lowest to highest score.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&str, f64)> {
+        self.order.iter().map(|(score, member)| (member.as_str(), score.0))
+    }
+
+    /// Members between two ranks, see `index_range`.
+    pub fn range(&self, start: i64, stop: i64) -> Vec<(&str, f64)> {
+        match index_range(start, stop, self.len()) {
+            Some((first, last)) => self.iter().skip(first).take(last - first + 1).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Members with a score between `min` and `max`, both inclusive.
+    pub fn range_by_score(&self, min: f64, max: f64) -> Vec<(&str, f64)> {
+        self.iter()
+            .skip_while(|(_, score)| *score < min)
+            .take_while(|(_, score)| *score <= max)
+            .collect()
+    }
+}
+
+/// Resolves a redis style inclusive index range, where negative indices
+/// count from the end, to positions within `len` items. `None` if the
+/// range holds nothing.
+pub fn index_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let resolve = |index: i64| if index < 0 { len + index } else { index };
+    let start = resolve(start).max(0);
+    let stop = resolve(stop).min(len - 1);
+    if start > stop || start >= len {
+        return None;
+    }
+    Some((start as usize, stop as usize))
+}
+
+/// Members found in every one of the sets.
+pub fn intersect<E>(
+    mut sets: impl Iterator<Item = Result<BTreeSet<String>, E>>,
+) -> Result<BTreeSet<String>, E> {
+    let first = match sets.next() {
+        Some(first) => first?,
+        None => return Ok(BTreeSet::new()),
+    };
+    sets.try_fold(first, |acc, set| Ok(acc.intersection(&set?).cloned().collect()))
+}
+
+/// Members found in any of the sets.
+pub fn union<E>(sets: impl Iterator<Item = Result<BTreeSet<String>, E>>) -> Result<BTreeSet<String>, E> {
+    let mut union = BTreeSet::new();
+    for set in sets {
+        union.extend(set?);
+    }
+    Ok(union)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_range_resolves_like_redis() {
+        assert_eq!(index_range(0, -1, 5), Some((0, 4)));
+        assert_eq!(index_range(1, 3, 5), Some((1, 3)));
+        assert_eq!(index_range(-2, -1, 5), Some((3, 4)));
+        assert_eq!(index_range(2, 2, 5), Some((2, 2)));
+        // Out of range ends are clamped to the items there are.
+        assert_eq!(index_range(-100, 100, 5), Some((0, 4)));
+        assert_eq!(index_range(3, 100, 5), Some((3, 4)));
+        assert_eq!(index_range(-100, 0, 5), Some((0, 0)));
+    }
+
+    #[test]
+    fn index_range_is_none_when_nothing_is_selected() {
+        assert_eq!(index_range(3, 1, 5), None);
+        assert_eq!(index_range(5, 10, 5), None);
+        assert_eq!(index_range(-1, -2, 5), None);
+        assert_eq!(index_range(0, -6, 5), None);
+        assert_eq!(index_range(0, -1, 0), None);
+        assert_eq!(index_range(0, 0, 0), None);
+        assert_eq!(index_range(i64::MIN, i64::MAX, 0), None);
+    }
+
+    #[test]
+    fn sorted_set_orders_by_score_then_member() {
+        let mut set = SortedSet::new();
+        assert!(set.insert("b".to_string(), 1.0));
+        assert!(set.insert("a".to_string(), 1.0));
+        assert!(set.insert("c".to_string(), -2.5));
+        assert!(!set.insert("c".to_string(), 3.0));
+        assert_eq!(set.len(), 3);
+        assert_eq!(set.range(0, -1), vec![("a", 1.0), ("b", 1.0), ("c", 3.0)]);
+        assert_eq!(set.range(-1, -1), vec![("c", 3.0)]);
+        assert_eq!(set.range(2, 1), Vec::new());
+        assert_eq!(set.rank("b"), Some(1));
+        assert_eq!(set.range_by_score(1.0, 2.0), vec![("a", 1.0), ("b", 1.0)]);
+
+        assert!(set.remove("a"));
+        assert!(!set.remove("a"));
+        assert_eq!(set.rank("a"), None);
+        assert_eq!(set.range(0, -1), vec![("b", 1.0), ("c", 3.0)]);
+    }
+}
@@ -0,0 +1,396 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Read, Write},
+    os::unix::io::{AsRawFd, RawFd},
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::sharded::ShardedCache;
+use crate::listener::{Endpoint, Listener, Stream};
+use crate::parser::{self, ParseError};
+use crate::router::Router;
+use crate::server::{self, ConnectionOptions, HTMLRequest, HTTPServer};
+use crate::shutdown;
+use crate::stats;
+
+// Tokens of the listening sockets count down from here, connections use
+// their file descriptor.
+const LISTENER: u64 = u64::MAX;
+const MAX_EVENTS: usize = 256;
+// Upper bound on how long a shutdown request goes unnoticed.
+const WAIT_TIMEOUT_MS: i32 = 100;
+const READ_CHUNK: usize = 64 * 1024;
+// Stop reading new requests from a client that doesn't read its responses.
+const HIGH_WATER: usize = 1024 * 1024;
+const IDLE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Collects what a handler writes, so the loop can send it whenever the
+/// socket accepts more data instead of blocking on it.
+#[derive(Clone, Default)]
+struct ResponseBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for ResponseBuffer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Owned epoll instance.
+struct Epoll(RawFd);
+
+impl Epoll {
+    fn new() -> Result<Epoll, Error> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(Epoll(fd))
+    }
+
+    fn control(&self, op: i32, fd: RawFd, events: u32, token: u64) -> Result<(), Error> {
+        let mut event = libc::epoll_event { events, u64: token };
+        if unsafe { libc::epoll_ctl(self.0, op, fd, &mut event) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Waits for events, a signal arriving counts as a wake up without any.
+    fn wait(&self, events: &mut [libc::epoll_event], timeout_ms: i32) -> Result<usize, Error> {
+        let count = unsafe {
+            libc::epoll_wait(self.0, events.as_mut_ptr(), events.len() as i32, timeout_ms)
+        };
+        if count < 0 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                return Ok(0);
+            }
+            return Err(err);
+        }
+        Ok(count as usize)
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+struct Connection {
+    stream: Stream,
+    client_address: Endpoint,
+    local_address: Endpoint,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    /// Bytes `input` must hold before parsing it again is worth it.
+    needed: usize,
+    served: usize,
+    /// The client sent EOF, answer what was read and close.
+    read_closed: bool,
+    /// No more requests are read, the connection closes once `output` is sent.
+    closing: bool,
+    last_active: Instant,
+    /// Events currently registered with epoll.
+    interest: u32,
+}
+
+impl Connection {
+    fn interest(&self) -> u32 {
+        let mut events = 0;
+        if !self.closing && !self.read_closed && self.output.len() < HIGH_WATER {
+            events |= (libc::EPOLLIN | libc::EPOLLRDHUP) as u32;
+        }
+        if !self.output.is_empty() || self.stream.has_pending_output() {
+            events |= libc::EPOLLOUT as u32;
+        }
+        events
+    }
+
+    /// Reads until the socket runs dry. TLS may hold on to decrypted data
+    /// epoll knows nothing about, so a single read isn't enough.
+    fn read(&mut self) -> Result<(), Error> {
+        let mut chunk = [0; READ_CHUNK];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.read_closed = true;
+                    return Ok(());
+                }
+                Ok(read) => {
+                    self.input.extend_from_slice(&chunk[..read]);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn write(&mut self) -> Result<(), Error> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
+                Ok(written) => {
+                    self.output.drain(..written);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        match self.stream.flush() {
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Answers every complete request in `input`, in order.
+    fn process(&mut self, router: &Router, cache: &ShardedCache, options: &ConnectionOptions) {
+        while !self.closing && self.output.len() < HIGH_WATER && self.input.len() >= self.needed {
+            let start = Instant::now();
+            let (raw, used) = match parser::parse_request(&self.input, &options.limits) {
+                Ok(parsed) => parsed,
+                Err(ParseError::Incomplete(needed)) => {
+                    self.needed = needed.unwrap_or(self.input.len() + 1);
+                    break;
+                }
+                Err(ParseError::Closed) => {
+                    self.needed = self.input.len() + 1;
+                    break;
+                }
+                Err(err) => {
+                    if let Some(status) = err.status() {
+                        let _ = server::write_response(
+                            &mut self.output,
+                            status,
+                            &[("Content-Type", "text/plain"), ("Connection", "close")],
+                            err.to_string().as_bytes(),
+                        );
+                    }
+                    eprintln!("Error occurred while interpreting the stream: \n {} ", err);
+                    self.closing = true;
+                    break;
+                }
+            };
+            self.input.drain(..used);
+            self.needed = 0;
+            self.served += 1;
+            stats::SERVER.total_requests.fetch_add(1, Ordering::SeqCst);
+
+            let buffer = ResponseBuffer::default();
+            let mut request = HTMLRequest::from_raw(
+                raw,
+                Box::new(buffer.clone()),
+                self.client_address.clone(),
+                self.local_address.clone(),
+            );
+            request.keep_alive = request.wants_keep_alive()
+                && self.served < options.max_requests
+                && !shutdown::is_requested();
+            HTTPServer::serve(&mut request, router, cache, options.auth.as_deref(), start);
+            self.output.append(&mut *buffer.0.lock().unwrap());
+            if !request.keep_alive {
+                self.closing = true;
+            }
+        }
+        // Whatever is left after EOF can never become a request.
+        if self.read_closed {
+            self.closing = true;
+        }
+    }
+}
+
+/// One thread's share of the server: an epoll instance watching the shared
+/// listeners and the connections this thread accepted.
+pub struct EventLoop {
+    epoll: Epoll,
+    listeners: Arc<Vec<Listener>>,
+    router: Arc<Router>,
+    cache: Arc<ShardedCache>,
+    options: ConnectionOptions,
+    connections: HashMap<u64, Connection>,
+}
+
+impl EventLoop {
+    /// `listeners` have to be non-blocking already.
+    pub fn new(
+        listeners: Arc<Vec<Listener>>,
+        router: Arc<Router>,
+        cache: Arc<ShardedCache>,
+        options: ConnectionOptions,
+    ) -> Result<EventLoop, Error> {
+        let epoll = Epoll::new()?;
+        for (index, listener) in listeners.iter().enumerate() {
+            // Exclusive, so a new connection wakes one loop instead of all of them.
+            epoll.control(
+                libc::EPOLL_CTL_ADD,
+                listener.as_raw_fd(),
+                (libc::EPOLLIN | libc::EPOLLEXCLUSIVE) as u32,
+                LISTENER - index as u64,
+            )?;
+        }
+        Ok(EventLoop {
+            epoll,
+            listeners,
+            router,
+            cache,
+            options,
+            connections: HashMap::new(),
+        })
+    }
+
+    /// Serves connections until a shutdown is requested.
+    pub fn run(&mut self) {
+        let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        let mut last_sweep = Instant::now();
+        while !shutdown::is_requested() {
+            let count = match self.epoll.wait(&mut events, WAIT_TIMEOUT_MS) {
+                Ok(count) => count,
+                Err(err) => {
+                    eprintln!("Error occurred while waiting for events: {}", err);
+                    break;
+                }
+            };
+            for event in &events[..count] {
+                let (token, flags) = (event.u64, event.events);
+                let listener = (LISTENER - token) as usize;
+                if listener < self.listeners.len() {
+                    self.accept(listener);
+                } else {
+                    self.ready(token, flags);
+                }
+            }
+            if last_sweep.elapsed() >= IDLE_SWEEP_INTERVAL {
+                self.close_idle();
+                last_sweep = Instant::now();
+            }
+        }
+
+        // Hand out what is already answered, without waiting on anyone.
+        let tokens: Vec<u64> = self.connections.keys().copied().collect();
+        for token in tokens {
+            if let Some(connection) = self.connections.get_mut(&token) {
+                let _ = connection.write();
+            }
+            self.close(token);
+        }
+    }
+
+    fn accept(&mut self, listener: usize) {
+        loop {
+            let (stream, client_address) = match self.listeners[listener].accept() {
+                Ok(accepted) => accepted,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    eprintln!("Error occurred while accepting a connection: {}", err);
+                    return;
+                }
+            };
+            if let Err(err) = self.register(stream, client_address) {
+                eprintln!("Error occurred while registering a connection: {}", err);
+            }
+        }
+    }
+
+    fn register(&mut self, stream: Stream, client_address: Endpoint) -> Result<(), Error> {
+        stream.set_nonblocking(true)?;
+        let local_address = stream.local_address()?;
+        let fd = stream.as_raw_fd();
+        let mut connection = Connection {
+            stream,
+            client_address,
+            local_address,
+            input: Vec::new(),
+            output: Vec::new(),
+            needed: 0,
+            served: 0,
+            read_closed: false,
+            closing: false,
+            last_active: Instant::now(),
+            interest: 0,
+        };
+        connection.interest = connection.interest();
+        self.epoll
+            .control(libc::EPOLL_CTL_ADD, fd, connection.interest, fd as u64)?;
+        self.connections.insert(fd as u64, connection);
+        stats::SERVER.total_connections.fetch_add(1, Ordering::SeqCst);
+        stats::SERVER.active_connections.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn ready(&mut self, token: u64, flags: u32) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        connection.last_active = Instant::now();
+
+        let readable = (libc::EPOLLIN | libc::EPOLLRDHUP | libc::EPOLLHUP | libc::EPOLLERR) as u32;
+        let mut result = Ok(());
+        if flags & readable != 0 && !connection.closing {
+            result = connection.read();
+        }
+        // Writing may make room for more pipelined requests, so keep going
+        // until the connection is blocked on the client either way.
+        while result.is_ok() {
+            connection.process(&self.router, &self.cache, &self.options);
+            result = connection.write();
+            if connection.closing
+                || connection.output.len() >= HIGH_WATER
+                || connection.input.len() < connection.needed
+            {
+                break;
+            }
+        }
+
+        let sent = connection.output.is_empty() && !connection.stream.has_pending_output();
+        if result.is_err() || (connection.closing && sent) {
+            self.close(token);
+            return;
+        }
+        let interest = connection.interest();
+        if interest != connection.interest {
+            connection.interest = interest;
+            let fd = connection.stream.as_raw_fd();
+            if let Err(err) = self.epoll.control(libc::EPOLL_CTL_MOD, fd, interest, token) {
+                eprintln!("Error occurred while updating a connection: {}", err);
+                self.close(token);
+            }
+        }
+    }
+
+    fn close_idle(&mut self) {
+        let timeout = self.options.idle_timeout;
+        let idle: Vec<u64> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| connection.last_active.elapsed() >= timeout)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in idle {
+            self.close(token);
+        }
+    }
+
+    fn close(&mut self, token: u64) {
+        if let Some(connection) = self.connections.remove(&token) {
+            let _ = self.epoll.control(
+                libc::EPOLL_CTL_DEL,
+                connection.stream.as_raw_fd(),
+                0,
+                token,
+            );
+            stats::SERVER.active_connections.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
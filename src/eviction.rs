@@ -0,0 +1,752 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{DefaultHasher, Hash, Hasher},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Bookkeeping the cache hands to a policy whenever an entry is written.
+pub struct EntryInfo {
+    pub size: usize,
+    pub expires_at: Option<Instant>,
+}
+
+/// Decides which key is dropped once the cache goes over its memory or
+/// entry limit. The cache calls the `record_*` hooks for every change so
+/// the policy can keep its own ordering, and asks for a `victim` whenever
+/// it needs room.
+pub trait EvictionPolicy<K = String>: Send {
+    fn name(&self) -> &'static str;
+
+    /// Called for new keys and for overwrites of existing keys.
+    fn record_insert(&mut self, key: &K, info: &EntryInfo);
+
+    fn record_access(&mut self, key: &K);
+
+    fn record_remove(&mut self, key: &K);
+
+    /// Called when the expiry of an existing key changes.
+    fn record_expiry(&mut self, _key: &K, _expires_at: Option<Instant>) {}
+
+    /// Picks the next key to evict and forgets about it.
+    fn victim(&mut self) -> Option<K>;
+
+    fn clear(&mut self);
+}
+
+/// Returns a policy by its command line name.
+pub fn from_name<K: Hash + Eq + Clone + Send + 'static>(name: &str) -> Option<Box<dyn EvictionPolicy<K>>> {
+    match name.to_lowercase().as_str() {
+        "lru" => Some(Box::new(Lru::new())),
+        "lfu" => Some(Box::new(Lfu::new())),
+        "fifo" => Some(Box::new(Fifo::new())),
+        "random" => Some(Box::new(Random::new())),
+        "ttl" | "volatile-ttl" => Some(Box::new(TtlNearest::new())),
+        "tinylfu" | "w-tinylfu" => Some(Box::new(WTinyLfu::new())),
+        _ => None,
+    }
+}
+
+/// Ordered set of keys with O(log n) move-to-back, used by every policy
+/// that needs recency or insertion order.
+struct OrderedKeys<K> {
+    tick: u64,
+    positions: HashMap<K, u64>,
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone> OrderedKeys<K> {
+    fn new() -> OrderedKeys<K> {
+        OrderedKeys {
+            tick: 0,
+            positions: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.positions.contains_key(key)
+    }
+
+    /// Inserts the key at the back, moving it there if already present.
+    fn touch(&mut self, key: &K) {
+        self.tick += 1;
+        if let Some(old) = self.positions.insert(key.clone(), self.tick) {
+            self.order.remove(&old);
+        }
+        self.order.insert(self.tick, key.clone());
+    }
+
+    /// Inserts the key at the back only if it is not present yet.
+    fn push(&mut self, key: &K) {
+        if !self.contains(key) {
+            self.touch(key);
+        }
+    }
+
+    fn remove(&mut self, key: &K) -> bool {
+        match self.positions.remove(key) {
+            Some(position) => {
+                self.order.remove(&position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn front(&self) -> Option<&K> {
+        self.order.values().next()
+    }
+
+    fn pop_front(&mut self) -> Option<K> {
+        let (_, key) = self.order.pop_first()?;
+        self.positions.remove(&key);
+        Some(key)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &K> {
+        self.order.values()
+    }
+
+    fn clear(&mut self) {
+        self.positions.clear();
+        self.order.clear();
+    }
+}
+
+/// Evicts the least recently used key.
+pub struct Lru<K = String> {
+    keys: OrderedKeys<K>,
+}
+
+impl<K: Hash + Eq + Clone> Lru<K> {
+    pub fn new() -> Lru<K> {
+        Lru {
+            keys: OrderedKeys::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for Lru<K> {
+    fn default() -> Lru<K> {
+        Lru::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send> EvictionPolicy<K> for Lru<K> {
+    fn name(&self) -> &'static str {
+        "lru"
+    }
+
+    fn record_insert(&mut self, key: &K, _info: &EntryInfo) {
+        self.keys.touch(key);
+    }
+
+    fn record_access(&mut self, key: &K) {
+        if self.keys.contains(key) {
+            self.keys.touch(key);
+        }
+    }
+
+    fn record_remove(&mut self, key: &K) {
+        self.keys.remove(key);
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        self.keys.pop_front()
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+    }
+}
+
+/// Evicts keys in the order they were first inserted.
+pub struct Fifo<K = String> {
+    keys: OrderedKeys<K>,
+}
+
+impl<K: Hash + Eq + Clone> Fifo<K> {
+    pub fn new() -> Fifo<K> {
+        Fifo {
+            keys: OrderedKeys::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for Fifo<K> {
+    fn default() -> Fifo<K> {
+        Fifo::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send> EvictionPolicy<K> for Fifo<K> {
+    fn name(&self) -> &'static str {
+        "fifo"
+    }
+
+    fn record_insert(&mut self, key: &K, _info: &EntryInfo) {
+        self.keys.push(key);
+    }
+
+    fn record_access(&mut self, _key: &K) {}
+
+    fn record_remove(&mut self, key: &K) {
+        self.keys.remove(key);
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        self.keys.pop_front()
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+    }
+}
+
+/// Evicts the least frequently used key, ties broken by least recent use.
+pub struct Lfu<K = String> {
+    tick: u64,
+    counts: HashMap<K, (u64, u64)>,
+    order: BTreeMap<(u64, u64), K>,
+}
+
+impl<K: Hash + Eq + Clone> Lfu<K> {
+    pub fn new() -> Lfu<K> {
+        Lfu {
+            tick: 0,
+            counts: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn bump(&mut self, key: &K, reset: bool) {
+        self.tick += 1;
+        let count = match self.counts.get(key) {
+            Some(&(count, tick)) => {
+                self.order.remove(&(count, tick));
+                if reset { 1 } else { count + 1 }
+            }
+            None => 1,
+        };
+        self.counts.insert(key.clone(), (count, self.tick));
+        self.order.insert((count, self.tick), key.clone());
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for Lfu<K> {
+    fn default() -> Lfu<K> {
+        Lfu::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send> EvictionPolicy<K> for Lfu<K> {
+    fn name(&self) -> &'static str {
+        "lfu"
+    }
+
+    fn record_insert(&mut self, key: &K, _info: &EntryInfo) {
+        self.bump(key, false);
+    }
+
+    fn record_access(&mut self, key: &K) {
+        if self.counts.contains_key(key) {
+            self.bump(key, false);
+        }
+    }
+
+    fn record_remove(&mut self, key: &K) {
+        if let Some(position) = self.counts.remove(key) {
+            self.order.remove(&position);
+        }
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        let (_, key) = self.order.pop_first()?;
+        self.counts.remove(&key);
+        Some(key)
+    }
+
+    fn clear(&mut self) {
+        self.counts.clear();
+        self.order.clear();
+    }
+}
+
+/// Small xorshift generator, good enough to pick eviction victims.
+struct XorShift(u64);
+
+impl XorShift {
+    fn seeded() -> XorShift {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        XorShift(nanos | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Evicts a uniformly random key.
+pub struct Random<K = String> {
+    rng: XorShift,
+    keys: Vec<K>,
+    index: HashMap<K, usize>,
+}
+
+impl<K: Hash + Eq + Clone> Random<K> {
+    pub fn new() -> Random<K> {
+        Random {
+            rng: XorShift::seeded(),
+            keys: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    fn remove_at(&mut self, position: usize) -> K {
+        let key = self.keys.swap_remove(position);
+        self.index.remove(&key);
+        if let Some(moved) = self.keys.get(position) {
+            self.index.insert(moved.clone(), position);
+        }
+        key
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for Random<K> {
+    fn default() -> Random<K> {
+        Random::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send> EvictionPolicy<K> for Random<K> {
+    fn name(&self) -> &'static str {
+        "random"
+    }
+
+    fn record_insert(&mut self, key: &K, _info: &EntryInfo) {
+        if !self.index.contains_key(key) {
+            self.index.insert(key.clone(), self.keys.len());
+            self.keys.push(key.clone());
+        }
+    }
+
+    fn record_access(&mut self, _key: &K) {}
+
+    fn record_remove(&mut self, key: &K) {
+        if let Some(&position) = self.index.get(key) {
+            self.remove_at(position);
+        }
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        let position = (self.rng.next() % self.keys.len() as u64) as usize;
+        Some(self.remove_at(position))
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+        self.index.clear();
+    }
+}
+
+/// Evicts the key closest to expiring. Keys without an expiry are only
+/// evicted once no expiring key is left, oldest first.
+pub struct TtlNearest<K = String> {
+    // Deadlines are paired with an insertion counter so equal deadlines
+    // don't collide.
+    tick: u64,
+    expiring: BTreeMap<(Instant, u64), K>,
+    deadlines: HashMap<K, (Instant, u64)>,
+    persistent: OrderedKeys<K>,
+}
+
+impl<K: Hash + Eq + Clone> TtlNearest<K> {
+    pub fn new() -> TtlNearest<K> {
+        TtlNearest {
+            tick: 0,
+            expiring: BTreeMap::new(),
+            deadlines: HashMap::new(),
+            persistent: OrderedKeys::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for TtlNearest<K> {
+    fn default() -> TtlNearest<K> {
+        TtlNearest::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send> EvictionPolicy<K> for TtlNearest<K> {
+    fn name(&self) -> &'static str {
+        "ttl"
+    }
+
+    fn record_insert(&mut self, key: &K, info: &EntryInfo) {
+        self.record_expiry(key, info.expires_at);
+    }
+
+    fn record_access(&mut self, _key: &K) {}
+
+    fn record_remove(&mut self, key: &K) {
+        if let Some(position) = self.deadlines.remove(key) {
+            self.expiring.remove(&position);
+        }
+        self.persistent.remove(key);
+    }
+
+    fn record_expiry(&mut self, key: &K, expires_at: Option<Instant>) {
+        self.record_remove(key);
+        match expires_at {
+            Some(deadline) => {
+                self.tick += 1;
+                self.deadlines.insert(key.clone(), (deadline, self.tick));
+                self.expiring.insert((deadline, self.tick), key.clone());
+            }
+            None => self.persistent.push(key),
+        }
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        match self.expiring.pop_first() {
+            Some((_, key)) => {
+                self.deadlines.remove(&key);
+                Some(key)
+            }
+            None => self.persistent.pop_front(),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.expiring.clear();
+        self.deadlines.clear();
+        self.persistent.clear();
+    }
+}
+
+/// Count-min sketch with 4-bit style saturating counters that are halved
+/// periodically so old popularity fades out.
+struct FrequencySketch {
+    rows: [Vec<u8>; 4],
+    mask: usize,
+    additions: usize,
+    sample_size: usize,
+}
+
+impl FrequencySketch {
+    fn new(width: usize) -> FrequencySketch {
+        let width = width.next_power_of_two();
+        FrequencySketch {
+            rows: [vec![0; width], vec![0; width], vec![0; width], vec![0; width]],
+            mask: width - 1,
+            additions: 0,
+            sample_size: width * 10,
+        }
+    }
+
+    fn index<K: Hash>(&self, key: &K, row: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        row.hash(&mut hasher);
+        key.hash(&mut hasher);
+        hasher.finish() as usize & self.mask
+    }
+
+    fn increment<K: Hash>(&mut self, key: &K) {
+        for row in 0..self.rows.len() {
+            let index = self.index(key, row);
+            if self.rows[row][index] < 15 {
+                self.rows[row][index] += 1;
+            }
+        }
+        self.additions += 1;
+        if self.additions >= self.sample_size {
+            for row in self.rows.iter_mut() {
+                for counter in row.iter_mut() {
+                    *counter /= 2;
+                }
+            }
+            self.additions /= 2;
+        }
+    }
+
+    fn frequency<K: Hash>(&self, key: &K) -> u8 {
+        (0..self.rows.len())
+            .map(|row| self.rows[row][self.index(key, row)])
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// A sketch `width` wide that starts out with the estimates `keys` have
+    /// in this one, so growing it doesn't forget what was popular.
+    fn resized<'a, K: Hash + 'a>(&self, width: usize, keys: impl Iterator<Item = &'a K>) -> FrequencySketch {
+        let mut sketch = FrequencySketch::new(width);
+        for key in keys {
+            let frequency = self.frequency(key);
+            for row in 0..sketch.rows.len() {
+                let index = sketch.index(key, row);
+                sketch.rows[row][index] = sketch.rows[row][index].max(frequency);
+            }
+        }
+        sketch.additions = self.additions.min(sketch.sample_size / 2);
+        sketch
+    }
+
+    fn clear(&mut self) {
+        for row in self.rows.iter_mut() {
+            row.iter_mut().for_each(|counter| *counter = 0);
+        }
+        self.additions = 0;
+    }
+}
+
+/// Window TinyLFU: new keys land in a small LRU window, and only move into
+/// the segmented main area if they are estimated to be used more often than
+/// the key they would push out.
+pub struct WTinyLfu<K = String> {
+    sketch: FrequencySketch,
+    window: OrderedKeys<K>,
+    probation: OrderedKeys<K>,
+    protected: OrderedKeys<K>,
+}
+
+impl<K: Hash + Eq + Clone> WTinyLfu<K> {
+    // Share of tracked keys kept in the admission window, in percent.
+    const WINDOW_PERCENT: usize = 1;
+    // Share of the main area reserved for the protected segment, in percent.
+    const PROTECTED_PERCENT: usize = 80;
+
+    pub fn new() -> WTinyLfu<K> {
+        WTinyLfu {
+            sketch: FrequencySketch::new(4096),
+            window: OrderedKeys::new(),
+            probation: OrderedKeys::new(),
+            protected: OrderedKeys::new(),
+        }
+    }
+
+    fn total(&self) -> usize {
+        self.window.len() + self.probation.len() + self.protected.len()
+    }
+
+    fn tracked(&self) -> impl Iterator<Item = &K> {
+        self.window.iter().chain(self.probation.iter()).chain(self.protected.iter())
+    }
+
+    fn window_target(&self) -> usize {
+        (self.total() * Self::WINDOW_PERCENT / 100).max(1)
+    }
+
+    fn promote(&mut self, key: &K) {
+        self.probation.remove(key);
+        self.protected.touch(key);
+        let main = self.probation.len() + self.protected.len();
+        let protected_target = (main * Self::PROTECTED_PERCENT / 100).max(1);
+        while self.protected.len() > protected_target {
+            match self.protected.pop_front() {
+                Some(demoted) => self.probation.touch(&demoted),
+                None => break,
+            }
+        }
+    }
+
+    fn main_victim(&self) -> Option<K> {
+        self.probation
+            .front()
+            .or_else(|| self.protected.front())
+            .cloned()
+    }
+
+    fn remove_from_main(&mut self, key: &K) {
+        if !self.probation.remove(key) {
+            self.protected.remove(key);
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for WTinyLfu<K> {
+    fn default() -> WTinyLfu<K> {
+        WTinyLfu::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send> EvictionPolicy<K> for WTinyLfu<K> {
+    fn name(&self) -> &'static str {
+        "tinylfu"
+    }
+
+    fn record_insert(&mut self, key: &K, _info: &EntryInfo) {
+        if self.window.contains(key) || self.probation.contains(key) || self.protected.contains(key) {
+            self.record_access(key);
+            return;
+        }
+        self.sketch.increment(key);
+        self.window.touch(key);
+        // Until the cache asks for a victim it has room, so keys leaving the
+        // window go straight to probation. Once it is full they have to win
+        // admission in `victim` instead.
+        while self.window.len() > self.window_target() {
+            match self.window.pop_front() {
+                Some(key) => self.probation.touch(&key),
+                None => break,
+            }
+        }
+        if self.sketch.mask < self.total() {
+            // Keep the sketch wide enough for the number of tracked keys.
+            self.sketch = self.sketch.resized(self.total() * 2, self.tracked());
+        }
+    }
+
+    fn record_access(&mut self, key: &K) {
+        self.sketch.increment(key);
+        if self.window.contains(key) {
+            self.window.touch(key);
+        } else if self.probation.contains(key) {
+            self.promote(key);
+        } else if self.protected.contains(key) {
+            self.protected.touch(key);
+        }
+    }
+
+    fn record_remove(&mut self, key: &K) {
+        if !self.window.remove(key) {
+            self.remove_from_main(key);
+        }
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        let victim = match self.main_victim() {
+            Some(victim) => victim,
+            None => return self.window.pop_front(),
+        };
+        let candidate = match self.window.pop_front() {
+            Some(candidate) => candidate,
+            None => {
+                self.remove_from_main(&victim);
+                return Some(victim);
+            }
+        };
+        // Admission: the window candidate only enters the main area if it
+        // is used more often than the key it would replace.
+        if self.sketch.frequency(&candidate) > self.sketch.frequency(&victim) {
+            self.remove_from_main(&victim);
+            self.probation.touch(&candidate);
+            return Some(victim);
+        }
+        Some(candidate)
+    }
+
+    fn clear(&mut self) {
+        self.sketch.clear();
+        self.window.clear();
+        self.probation.clear();
+        self.protected.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Cache;
+
+    fn cache(policy: Box<dyn EvictionPolicy<u64>>, max_entries: usize) -> Cache<u64, u64> {
+        let mut cache = Cache::in_memory();
+        cache.set_eviction_policy(policy).set_max_entries(Some(max_entries));
+        cache
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let mut cache = cache(Box::new(Lru::new()), 2);
+        cache.insert(1, 1).insert(2, 2);
+        cache.get(&1);
+        cache.insert(3, 3);
+        assert!(cache.contains(&1));
+        assert!(!cache.contains(&2));
+        assert!(cache.contains(&3));
+    }
+
+    #[test]
+    fn fifo_ignores_reads() {
+        let mut cache = cache(Box::new(Fifo::new()), 2);
+        cache.insert(1, 1).insert(2, 2);
+        cache.get(&1);
+        cache.insert(3, 3);
+        assert!(!cache.contains(&1));
+        assert!(cache.contains(&2));
+    }
+
+    #[test]
+    fn lfu_evicts_least_frequently_used() {
+        let mut cache = cache(Box::new(Lfu::new()), 2);
+        cache.insert(1, 1).insert(2, 2);
+        for _ in 0..3 {
+            cache.get(&2);
+        }
+        cache.get(&1);
+        cache.insert(3, 3);
+        assert!(!cache.contains(&1) || !cache.contains(&3));
+        assert!(cache.contains(&2));
+    }
+
+    #[test]
+    fn random_stays_within_limit() {
+        let mut cache = cache(Box::new(Random::new()), 10);
+        for key in 0..100 {
+            cache.insert(key, key);
+        }
+        assert_eq!(cache.len(), 10);
+        assert_eq!(cache.evictions(), 90);
+    }
+
+    #[test]
+    fn ttl_evicts_nearest_expiry_first() {
+        let mut cache = cache(Box::new(TtlNearest::new()), 2);
+        cache.insert_with_ttl(1, 1, std::time::Duration::from_secs(100));
+        cache.insert_with_ttl(2, 2, std::time::Duration::from_secs(10));
+        cache.insert(3, 3);
+        assert!(cache.contains(&1));
+        assert!(!cache.contains(&2));
+        assert!(cache.contains(&3));
+    }
+
+    #[test]
+    fn tinylfu_keeps_hot_key_through_scan() {
+        let mut cache = cache(Box::new(WTinyLfu::new()), 100);
+        for key in 0..100 {
+            cache.insert(key, key);
+        }
+        for _ in 0..20 {
+            cache.get(&7);
+        }
+        // Far more one-off keys than fit, an LRU would have dropped key 7.
+        for key in 1000..11_000 {
+            cache.insert(key, key);
+        }
+        assert!(cache.contains(&7));
+        assert_eq!(cache.len(), 100);
+    }
+
+    #[test]
+    fn sketch_keeps_counts_when_resized() {
+        let mut sketch = FrequencySketch::new(16);
+        for _ in 0..5 {
+            sketch.increment(&"hot");
+        }
+        let sketch = sketch.resized(1024, ["hot"].iter());
+        assert_eq!(sketch.frequency(&"hot"), 5);
+        assert_eq!(sketch.frequency(&"cold"), 0);
+    }
+}
@@ -1,42 +1,48 @@
 use std::{
     collections::HashMap,
     sync::Arc,
+    time::Duration,
 };
 
+use serde_json::json;
+
 use crate::server;
 use crate::cache;
+use crate::json_path::JsonPath;
+use crate::sharded::ShardedCache;
+use crate::stats;
+
+pub type Handler = Arc<
+    dyn (Fn(&server::HTMLRequest, &ShardedCache) -> Result<String, std::io::Error>) + Send + Sync
+>;
 
 #[allow(dead_code)]
 pub struct Function {
     key: String,
     properties: Vec<String>,
     pub methods: Option<Vec<String>>,
-    pub function: Arc<
-        dyn (FnMut(&server::HTMLRequest, &mut cache::Cache) -> Result<String, std::io::Error>) + Send + Sync
-    >,
+    pub function: Handler,
     description: Option<String>,
 }
 
 #[allow(dead_code)]
 impl Function {
-    fn new(
+    pub(crate) fn new(
         key: String,
         properties: Vec<&str>,
         methods: Option<Vec<String>>,
         description: Option<String>,
-        function: Arc<
-            dyn (FnMut(&server::HTMLRequest, &mut cache::Cache) -> Result<String, std::io::Error>) + Send + Sync
-        >
+        function: Handler
     ) -> Function {
         Function {
-            key: key,
+            key,
             properties: properties
                 .iter()
                 .map(|x| x.to_string())
                 .collect::<Vec<String>>(),
-            methods: methods,
-            function: function,
-            description: description,
+            methods,
+            function,
+            description,
         }
     }
 
@@ -45,9 +51,7 @@ impl Function {
         properties: Vec<&str>,
         methods: Option<Vec<&str>>,
         description: Option<&str>,
-        function: Arc<
-            dyn (FnMut(&server::HTMLRequest, &mut cache::Cache) -> Result<String, std::io::Error>) + Send + Sync
-        >
+        function: Handler
     ) -> Function {
         Function {
             key: String::from(key),
@@ -55,20 +59,14 @@ impl Function {
                 .iter()
                 .map(|y| y.to_string())
                 .collect::<Vec<String>>(),
-            methods: match methods {
-                Some(xmethods) => {
-                    Some(xmethods
-                        .iter()
-                        .map(|y| y.to_string())
-                        .collect::<Vec<String>>())
-                },
-                None => None,
-            },
-            description: match description {
-                Some(xdesc) => Some(xdesc.to_string()),
-                None => None,
-            },
-            function: function
+            methods: methods.map(|xmethods| {
+                xmethods
+                    .iter()
+                    .map(|y| y.to_string())
+                    .collect::<Vec<String>>()
+            }),
+            description: description.map(|xdesc| xdesc.to_string()),
+            function
         }
     }
 }
@@ -84,12 +82,103 @@ impl FuncHelper {
         FuncHelper {
             store: vec![
                 Function::n(
-                    "/addfloat",
+                    "/keys",
                     vec![],
+                    Some(vec!["GET"]),
+                    Some("List all keys."),
+                    Arc::new(&list_keys)
+                ),
+                Function::n(
+                    "/keys/{key}",
+                    vec!["key", "type", "ttl"],
+                    Some(vec!["GET", "PUT", "DELETE"]),
+                    Some("Read, write or delete a single entry."),
+                    Arc::new(&key)
+                ),
+                Function::n(
+                    "/keys/{key}/incr",
+                    vec!["key", "by"],
                     Some(vec!["POST"]),
-                    Some("Add an entry to the cache."),
-                    Arc::new(&add)
-                )
+                    Some("Atomically add to a number, creating it if missing."),
+                    Arc::new(&incr)
+                ),
+                Function::n(
+                    "/keys/{key}/decr",
+                    vec!["key", "by"],
+                    Some(vec!["POST"]),
+                    Some("Atomically subtract from a number, creating it if missing."),
+                    Arc::new(&decr)
+                ),
+                Function::n(
+                    "/keys/{key}/list",
+                    vec!["key", "start", "stop", "end", "count"],
+                    Some(vec!["GET", "POST", "DELETE"]),
+                    Some("Read a range of a list, push to it or pop from it."),
+                    Arc::new(&list)
+                ),
+                Function::n(
+                    "/keys/{key}/list/trim",
+                    vec!["key", "start", "stop"],
+                    Some(vec!["POST"]),
+                    Some("Keep only a range of a list."),
+                    Arc::new(&list_trim)
+                ),
+                Function::n(
+                    "/keys/{key}/set",
+                    vec!["key", "member"],
+                    Some(vec!["GET", "POST", "DELETE"]),
+                    Some("Read the members of a set, add or remove them."),
+                    Arc::new(&set)
+                ),
+                Function::n(
+                    "/sets/{operation}",
+                    vec!["operation", "keys"],
+                    Some(vec!["GET"]),
+                    Some("Intersect or union several sets."),
+                    Arc::new(&set_operation)
+                ),
+                Function::n(
+                    "/keys/{key}/hash",
+                    vec!["key"],
+                    Some(vec!["GET"]),
+                    Some("Read all fields of a hash."),
+                    Arc::new(&hash)
+                ),
+                Function::n(
+                    "/keys/{key}/hash/{field}",
+                    vec!["key", "field"],
+                    Some(vec!["GET", "PUT", "DELETE"]),
+                    Some("Read, write or delete a field of a hash."),
+                    Arc::new(&hash_field)
+                ),
+                Function::n(
+                    "/keys/{key}/zset",
+                    vec!["key", "start", "stop", "min", "max"],
+                    Some(vec!["GET", "POST"]),
+                    Some("Read a sorted set by rank or score, or add members."),
+                    Arc::new(&zset)
+                ),
+                Function::n(
+                    "/keys/{key}/zset/{member}",
+                    vec!["key", "member", "incr"],
+                    Some(vec!["GET", "PUT", "DELETE"]),
+                    Some("Read, set or remove the score of a sorted set member."),
+                    Arc::new(&zset_member)
+                ),
+                Function::n(
+                    "/keys/{key}/json",
+                    vec!["key", "path"],
+                    Some(vec!["GET", "PUT", "POST", "DELETE"]),
+                    Some("Read, write, append to or delete part of a JSON document."),
+                    Arc::new(&json_document)
+                ),
+                Function::n(
+                    "/stats",
+                    vec![],
+                    Some(vec!["GET"]),
+                    Some("Cache and connection statistics."),
+                    Arc::new(&server_stats)
+                ),
             ],
         }
     }
@@ -99,16 +188,8 @@ impl FuncHelper {
         self
     }
 
-    pub fn get_func_map(
-        &self
-    ) -> HashMap<
-        String,
-        Arc<dyn (FnMut(&server::HTMLRequest, &mut cache::Cache) -> Result<String, std::io::Error>) + Send + Sync>
-    > {
-        let mut map: HashMap<
-            String,
-            Arc<dyn (FnMut(&server::HTMLRequest, &mut cache::Cache) -> Result<String, std::io::Error>) + Send + Sync>
-        > = HashMap::new();
+    pub fn get_func_map(&self) -> HashMap<String, Handler> {
+        let mut map: HashMap<String, Handler> = HashMap::new();
         for function in self.store.iter() {
             map.insert(function.key.clone(), Arc::clone(&function.function));
         }
@@ -199,13 +280,1085 @@ impl FuncHelper {
             } else {
                 out.push_str("None");
             }
-            out.push_str("\n");
+            out.push('\n');
         }
         out
     }
 }
 
-fn add(request: &server::HTMLRequest, cache: &mut cache::Cache) -> Result<String, std::io::Error> {
-    cache.add_float("test", 6.4);
-    Ok(String::from("Added float."))
-}
\ No newline at end of file
+impl Default for FuncHelper {
+    fn default() -> FuncHelper {
+        FuncHelper::new()
+    }
+}
+
+fn error_body(message: &str) -> String {
+    json!({ "error": message }).to_string()
+}
+
+fn value_body(key: &str, value: &cache::CacheValue) -> String {
+    json!({
+        "key": key,
+        "type": value.type_name(),
+        "value": value.to_json(),
+    })
+    .to_string()
+}
+
+/// Builds the value to store from the request body. A `type` query
+/// parameter or `X-Cache-Type` header picks the variant explicitly,
+/// otherwise JSON bodies are inferred from their shape,
+/// `application/octet-stream` bodies are stored as bytes and everything
+/// else as a string.
+fn parse_value(request: &server::HTMLRequest) -> Result<cache::CacheValue, cache::CacheError> {
+    let type_hint = request
+        .query_param("type")
+        .or_else(|| request.get_header("X-Cache-Type").map(|x| x.value.as_str()));
+    let content_type = request.get_header("Content-Type").map(|x| x.value.as_str());
+    let is_json = content_type.is_some_and(|x| x.starts_with("application/json"));
+    let is_binary = content_type.is_some_and(|x| x.starts_with("application/octet-stream"));
+
+    if is_json {
+        let json: serde_json::Value = serde_json::from_str(&request.body_text())
+            .map_err(|err| cache::CacheError::InvalidValue(format!("Invalid JSON: {}", err)))?;
+        return cache::CacheValue::from_json(&json, type_hint);
+    }
+    // Raw bodies go in untouched, the text path would mangle invalid UTF-8.
+    let wants_bytes = type_hint.map_or(is_binary, |x| matches!(x.trim(), "bytes" | "binary"));
+    if wants_bytes {
+        return Ok(cache::CacheValue::Bytes(request.body.clone()));
+    }
+    match type_hint {
+        Some(type_name) => cache::CacheValue::parse(&request.body_text(), type_name),
+        None => Ok(cache::CacheValue::String(request.body_text().into_owned())),
+    }
+}
+
+fn etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+fn respond_with_entry(request: &server::HTMLRequest, status: u64, body: String, version: Option<u64>) {
+    match version {
+        Some(version) => request.respond_with_headers(
+            status,
+            &[("Content-Type", "application/json"), ("ETag", &etag(version))],
+            body.as_bytes(),
+        ),
+        None => request.respond_with_json(status, body),
+    }
+}
+
+/// Whether the `If-Match` header, if any, lets a write to an entry at
+/// `version` through. Only strong tags match, `*` matches any existing entry.
+fn if_match(request: &server::HTMLRequest, version: Option<u64>) -> bool {
+    let header = match request.get_header("If-Match") {
+        Some(header) => header.value.trim(),
+        None => return true,
+    };
+    match version {
+        Some(version) => header
+            .split(',')
+            .map(|x| x.trim())
+            .any(|tag| tag == "*" || tag == etag(version)),
+        None => false,
+    }
+}
+
+/// Whether the `If-None-Match` header names the entry at `version`. Weak
+/// tags compare equal to strong ones here.
+fn if_none_match(request: &server::HTMLRequest, version: u64) -> bool {
+    request.get_header("If-None-Match").is_some_and(|header| {
+        header
+            .value
+            .split(',')
+            .map(|x| x.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag(version))
+    })
+}
+
+fn precondition_failed(request: &server::HTMLRequest, key: &str) -> String {
+    let message = format!("Key '{}' does not match If-Match.", key);
+    request.respond_with_json(412, error_body(&message));
+    message
+}
+
+fn list_keys(request: &server::HTMLRequest, cache: &ShardedCache) -> Result<String, std::io::Error> {
+    let mut keys: Vec<String> = cache
+        .keys()
+        .into_iter()
+        .filter(|key| request.may_access(key))
+        .collect();
+    keys.sort();
+    request.respond_with_json(200, json!({ "keys": keys }).to_string());
+    Ok(format!("Listed {} keys.", keys.len()))
+}
+
+fn key(request: &server::HTMLRequest, cache: &ShardedCache) -> Result<String, std::io::Error> {
+    let key = match request.param("key") {
+        Some(key) => key,
+        None => {
+            request.respond_with_json(400, error_body("Missing key."));
+            return Ok(String::from("Missing key."));
+        }
+    };
+    let ttl = match request.query_param("ttl").map(|x| x.parse::<u64>()) {
+        Some(Ok(seconds)) => Some(Duration::from_secs(seconds)),
+        Some(Err(_)) => {
+            request.respond_with_json(400, error_body("ttl must be a number of seconds."));
+            return Ok(String::from("Invalid ttl."));
+        }
+        None => None,
+    };
+
+    match request.method.as_str() {
+        "GET" => match cache.read(key).get_versioned(key) {
+            Some((_, version)) if if_none_match(request, version) => {
+                request.respond_with_headers(304, &[("ETag", &etag(version))], &[]);
+                Ok(format!("{} not modified.", key))
+            }
+            // Bytes are served as they are rather than base64 in JSON.
+            Some((cache::CacheValue::Bytes(data), version)) => {
+                request.respond_with_headers(
+                    200,
+                    &[("Content-Type", "application/octet-stream"), ("ETag", &etag(version))],
+                    data,
+                );
+                Ok(format!("Read {}.", key))
+            }
+            Some((value, version)) => {
+                respond_with_entry(request, 200, value_body(key, value), Some(version));
+                Ok(format!("Read {}.", key))
+            }
+            None => {
+                let err = cache::CacheError::NotFound(key.to_string());
+                request.respond_with_json(404, error_body(&err.to_string()));
+                Ok(err.to_string())
+            }
+        },
+        "PUT" => match parse_value(request) {
+            Ok(value) => {
+                let mut cache = cache.write(key);
+                let existed = cache.contains(key);
+                if !if_match(request, cache.version(key)) {
+                    return Ok(precondition_failed(request, key));
+                }
+                let expected = match request.query_param("version").map(|x| x.parse::<u64>()) {
+                    Some(Ok(version)) => Some(version),
+                    Some(Err(_)) => {
+                        request.respond_with_json(400, error_body("version must be a number."));
+                        return Ok(String::from("Invalid version."));
+                    }
+                    None => None,
+                };
+                let body = value_body(key, &value);
+                let version = match expected {
+                    Some(expected) => match cache.set_if_version(key.to_string(), value, expected) {
+                        Ok(version) => {
+                            if let Some(ttl) = ttl {
+                                cache.expire(key, ttl);
+                            }
+                            Some(version)
+                        }
+                        Err(err) => {
+                            let status = match err {
+                                cache::CacheError::NotFound(_) => 404,
+                                _ => 409,
+                            };
+                            request.respond_with_json(status, error_body(&err.to_string()));
+                            return Ok(err.to_string());
+                        }
+                    },
+                    None => {
+                        match ttl {
+                            Some(ttl) => cache.insert_with_ttl(key.to_string(), value, ttl),
+                            None => cache.insert(key.to_string(), value),
+                        };
+                        cache.version(key)
+                    }
+                };
+                respond_with_entry(request, if existed { 200 } else { 201 }, body, version);
+                Ok(format!("Stored {}.", key))
+            }
+            Err(err) => {
+                request.respond_with_json(400, error_body(&err.to_string()));
+                Ok(err.to_string())
+            }
+        },
+        "DELETE" => {
+            let mut cache = cache.write(key);
+            if !if_match(request, cache.version(key)) {
+                return Ok(precondition_failed(request, key));
+            }
+            match cache.remove(key) {
+                Some(_) => {
+                    request.respond(204);
+                    Ok(format!("Deleted {}.", key))
+                }
+                None => {
+                    let err = cache::CacheError::NotFound(key.to_string());
+                    request.respond_with_json(404, error_body(&err.to_string()));
+                    Ok(err.to_string())
+                }
+            }
+        }
+        _ => {
+            request.respond(405);
+            Ok(String::from("Unsupported method."))
+        }
+    }
+}
+
+fn incr(request: &server::HTMLRequest, cache: &ShardedCache) -> Result<String, std::io::Error> {
+    add(request, cache, false)
+}
+
+fn decr(request: &server::HTMLRequest, cache: &ShardedCache) -> Result<String, std::io::Error> {
+    add(request, cache, true)
+}
+
+/// Adds `by` (default 1) to a key, or subtracts it for `negate`. Integer
+/// amounts on integer values stay integers, a fractional amount or a float
+/// value makes it a float increment.
+fn add(request: &server::HTMLRequest, cache: &ShardedCache, negate: bool) -> Result<String, std::io::Error> {
+    let key = match request.param("key") {
+        Some(key) => key,
+        None => {
+            request.respond_with_json(400, error_body("Missing key."));
+            return Ok(String::from("Missing key."));
+        }
+    };
+    let by = request.query_param("by").unwrap_or("1");
+    let mut cache = cache.write(key);
+    let is_float = matches!(cache.peek(key), Some(cache::CacheValue::Float(_)));
+    let result = match (by.parse::<i64>(), by.parse::<f64>().map(|x| x.is_finite().then_some(x))) {
+        (Ok(by), _) if !is_float => {
+            let by = if negate { by.checked_neg() } else { Some(by) };
+            match by {
+                Some(by) => cache.incr_by(key, by).map(|_| ()),
+                None => Err(cache::CacheError::Overflow(key.to_string())),
+            }
+        }
+        (_, Ok(Some(by))) => cache.incr_by_float(key, if negate { -by } else { by }).map(|_| ()),
+        _ => {
+            request.respond_with_json(400, error_body("by must be a number."));
+            return Ok(String::from("Invalid amount."));
+        }
+    };
+    match result {
+        Ok(()) => {
+            let value = cache.peek(key).cloned().unwrap_or(cache::CacheValue::Int64(0));
+            request.respond_with_json(200, value_body(key, &value));
+            Ok(format!("Updated counter {}.", key))
+        }
+        Err(err) => {
+            // The amount is fine at this point, the stored value is what's in the way.
+            request.respond_with_json(409, error_body(&err.to_string()));
+            Ok(err.to_string())
+        }
+    }
+}
+
+fn cache_error(request: &server::HTMLRequest, err: cache::CacheError) -> String {
+    let status = match err {
+        cache::CacheError::NotFound(_) | cache::CacheError::PathNotFound { .. } => 404,
+        cache::CacheError::InvalidValue(_) => 400,
+        _ => 409,
+    };
+    request.respond_with_json(status, error_body(&err.to_string()));
+    err.to_string()
+}
+
+fn bad_request(request: &server::HTMLRequest, message: &str) -> String {
+    request.respond_with_json(400, error_body(message));
+    message.to_string()
+}
+
+/// Reads a numeric query parameter, `default` if it is missing.
+fn number_param<T: std::str::FromStr>(
+    request: &server::HTMLRequest,
+    name: &str,
+    default: T,
+) -> Result<T, String> {
+    match request.query_param(name) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("{} must be a number.", name)),
+        None => Ok(default),
+    }
+}
+
+/// `start` and `stop` of an inclusive index range, everything by default.
+fn index_params(request: &server::HTMLRequest) -> Result<(i64, i64), String> {
+    Ok((number_param(request, "start", 0)?, number_param(request, "stop", -1)?))
+}
+
+/// Values sent in the body, either a JSON array of strings or the whole
+/// body as a single value.
+fn body_values(request: &server::HTMLRequest) -> Result<Vec<String>, String> {
+    let text = request.body_text();
+    if text.trim_start().starts_with('[') {
+        let json: serde_json::Value = serde_json::from_str(&text).map_err(|err| format!("Invalid JSON: {}", err))?;
+        return json
+            .as_array()
+            .and_then(|x| x.iter().map(|x| x.as_str().map(|x| x.to_string())).collect())
+            .ok_or_else(|| String::from("Expected an array of strings."));
+    }
+    if text.is_empty() {
+        return Err(String::from("Missing value."));
+    }
+    Ok(vec![text.into_owned()])
+}
+
+/// Lists. GET reads `start` to `stop` (the whole list by default), POST
+/// pushes the body to the `end` given (`back` by default, or `front`) and
+/// DELETE pops `count` values from that end.
+fn list(request: &server::HTMLRequest, cache: &ShardedCache) -> Result<String, std::io::Error> {
+    let key = match request.param("key") {
+        Some(key) => key,
+        None => return Ok(bad_request(request, "Missing key.")),
+    };
+    let front = match request.query_param("end").unwrap_or("back") {
+        "front" => true,
+        "back" => false,
+        _ => return Ok(bad_request(request, "end must be front or back.")),
+    };
+
+    match request.method.as_str() {
+        "GET" => {
+            let (start, stop) = match index_params(request) {
+                Ok(range) => range,
+                Err(message) => return Ok(bad_request(request, &message)),
+            };
+            match cache.read(key).list_range(key, start, stop) {
+                Ok(values) => {
+                    request.respond_with_json(200, json!({ "key": key, "values": values }).to_string());
+                    Ok(format!("Read list {}.", key))
+                }
+                Err(err) => Ok(cache_error(request, err)),
+            }
+        }
+        "POST" => {
+            let values = match body_values(request) {
+                Ok(values) => values,
+                Err(message) => return Ok(bad_request(request, &message)),
+            };
+            match cache.write(key).list_push(key, values, front) {
+                Ok(len) => {
+                    request.respond_with_json(200, json!({ "key": key, "length": len }).to_string());
+                    Ok(format!("Pushed to list {}.", key))
+                }
+                Err(err) => Ok(cache_error(request, err)),
+            }
+        }
+        "DELETE" => {
+            let count = match number_param(request, "count", 1usize) {
+                Ok(count) => count,
+                Err(message) => return Ok(bad_request(request, &message)),
+            };
+            match cache.write(key).list_pop(key, count, front) {
+                Ok(values) => {
+                    request.respond_with_json(200, json!({ "key": key, "values": values }).to_string());
+                    Ok(format!("Popped {} values from list {}.", values.len(), key))
+                }
+                Err(err) => Ok(cache_error(request, err)),
+            }
+        }
+        _ => {
+            request.respond(405);
+            Ok(String::from("Unsupported method."))
+        }
+    }
+}
+
+fn list_trim(request: &server::HTMLRequest, cache: &ShardedCache) -> Result<String, std::io::Error> {
+    let key = match request.param("key") {
+        Some(key) => key,
+        None => return Ok(bad_request(request, "Missing key.")),
+    };
+    let (start, stop) = match index_params(request) {
+        Ok(range) => range,
+        Err(message) => return Ok(bad_request(request, &message)),
+    };
+    let mut cache = cache.write(key);
+    match cache.list_trim(key, start, stop).and_then(|_| cache.list_len(key)) {
+        Ok(len) => {
+            request.respond_with_json(200, json!({ "key": key, "length": len }).to_string());
+            Ok(format!("Trimmed list {}.", key))
+        }
+        Err(err) => Ok(cache_error(request, err)),
+    }
+}
+
+/// Sets. GET lists the members, or tells whether `member` is one, POST adds
+/// the members in the body and DELETE removes them.
+fn set(request: &server::HTMLRequest, cache: &ShardedCache) -> Result<String, std::io::Error> {
+    let key = match request.param("key") {
+        Some(key) => key,
+        None => return Ok(bad_request(request, "Missing key.")),
+    };
+
+    let result = match request.method.as_str() {
+        "GET" => match request.query_param("member") {
+            Some(member) => cache
+                .read(key)
+                .set_contains(key, member)
+                .map(|found| json!({ "key": key, "member": member, "contains": found })),
+            None => cache
+                .read(key)
+                .set_members(key)
+                .map(|members| json!({ "key": key, "members": members })),
+        },
+        "POST" | "DELETE" => {
+            let members = match body_values(request) {
+                Ok(members) => members,
+                Err(message) => return Ok(bad_request(request, &message)),
+            };
+            if request.method == "POST" {
+                cache
+                    .write(key)
+                    .set_add(key, members)
+                    .map(|added| json!({ "key": key, "added": added }))
+            } else {
+                cache
+                    .write(key)
+                    .set_remove(key, &members)
+                    .map(|removed| json!({ "key": key, "removed": removed }))
+            }
+        }
+        _ => {
+            request.respond(405);
+            return Ok(String::from("Unsupported method."));
+        }
+    };
+    match result {
+        Ok(body) => {
+            request.respond_with_json(200, body.to_string());
+            Ok(format!("Handled set {}.", key))
+        }
+        Err(err) => Ok(cache_error(request, err)),
+    }
+}
+
+/// `/sets/intersect?keys=a,b` and `/sets/union?keys=a,b`.
+fn set_operation(request: &server::HTMLRequest, cache: &ShardedCache) -> Result<String, std::io::Error> {
+    let keys: Vec<String> = request
+        .query_param("keys")
+        .unwrap_or("")
+        .split(',')
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+        .collect();
+    if keys.is_empty() {
+        return Ok(bad_request(request, "Pass the sets as keys=a,b."));
+    }
+    // The route carries no single key, so the ACL is checked per set here.
+    if let Some(key) = keys.iter().find(|key| !request.may_access(key)) {
+        let message = format!("Forbidden: may not access '{}'.", key);
+        request.respond_with_json(403, error_body(&message));
+        return Ok(message);
+    }
+    let result = match request.param("operation") {
+        Some("intersect") => cache.set_intersect(&keys),
+        Some("union") => cache.set_union(&keys),
+        _ => {
+            request.respond_with_json(404, error_body("Unknown set operation, use intersect or union."));
+            return Ok(String::from("Unknown set operation."));
+        }
+    };
+    match result {
+        Ok(members) => {
+            request.respond_with_json(200, json!({ "keys": keys, "members": members }).to_string());
+            Ok(format!("Combined {} sets.", keys.len()))
+        }
+        Err(err) => Ok(cache_error(request, err)),
+    }
+}
+
+fn hash(request: &server::HTMLRequest, cache: &ShardedCache) -> Result<String, std::io::Error> {
+    let key = match request.param("key") {
+        Some(key) => key,
+        None => return Ok(bad_request(request, "Missing key.")),
+    };
+    match cache.read(key).hash_get_all(key) {
+        Ok(fields) => {
+            request.respond_with_json(200, json!({ "key": key, "fields": fields }).to_string());
+            Ok(format!("Read hash {}.", key))
+        }
+        Err(err) => Ok(cache_error(request, err)),
+    }
+}
+
+fn hash_field(request: &server::HTMLRequest, cache: &ShardedCache) -> Result<String, std::io::Error> {
+    let (key, field) = match (request.param("key"), request.param("field")) {
+        (Some(key), Some(field)) => (key, field),
+        _ => return Ok(bad_request(request, "Missing key or field.")),
+    };
+
+    match request.method.as_str() {
+        "GET" => match cache.read(key).hash_get(key, field) {
+            Ok(Some(value)) => {
+                request.respond_with_json(
+                    200,
+                    json!({ "key": key, "field": field, "value": value }).to_string(),
+                );
+                Ok(format!("Read {} of hash {}.", field, key))
+            }
+            Ok(None) => {
+                let message = format!("Field '{}' of '{}' not found.", field, key);
+                request.respond_with_json(404, error_body(&message));
+                Ok(message)
+            }
+            Err(err) => Ok(cache_error(request, err)),
+        },
+        "PUT" => {
+            let value = request.body_text().into_owned();
+            match cache.write(key).hash_set(key, field.to_string(), value.clone()) {
+                Ok(created) => {
+                    request.respond_with_json(
+                        if created { 201 } else { 200 },
+                        json!({ "key": key, "field": field, "value": value }).to_string(),
+                    );
+                    Ok(format!("Stored {} of hash {}.", field, key))
+                }
+                Err(err) => Ok(cache_error(request, err)),
+            }
+        }
+        "DELETE" => match cache.write(key).hash_delete(key, &[field.to_string()]) {
+            Ok(1) => {
+                request.respond(204);
+                Ok(format!("Deleted {} of hash {}.", field, key))
+            }
+            Ok(_) => {
+                let message = format!("Field '{}' of '{}' not found.", field, key);
+                request.respond_with_json(404, error_body(&message));
+                Ok(message)
+            }
+            Err(err) => Ok(cache_error(request, err)),
+        },
+        _ => {
+            request.respond(405);
+            Ok(String::from("Unsupported method."))
+        }
+    }
+}
+
+fn scored_members(members: Vec<(String, f64)>) -> serde_json::Value {
+    members
+        .into_iter()
+        .map(|(member, score)| json!({ "member": member, "score": score }))
+        .collect()
+}
+
+/// Sorted sets. GET reads by rank with `start` and `stop`, or by score
+/// with `min` and `max`. POST adds members from a JSON object of scores.
+fn zset(request: &server::HTMLRequest, cache: &ShardedCache) -> Result<String, std::io::Error> {
+    let key = match request.param("key") {
+        Some(key) => key,
+        None => return Ok(bad_request(request, "Missing key.")),
+    };
+
+    match request.method.as_str() {
+        "GET" => {
+            let by_score = request.query_param("min").is_some() || request.query_param("max").is_some();
+            let members = if by_score {
+                let range = number_param(request, "min", f64::NEG_INFINITY)
+                    .and_then(|min| number_param(request, "max", f64::INFINITY).map(|max| (min, max)));
+                match range {
+                    Ok((min, max)) => cache.read(key).zset_range_by_score(key, min, max),
+                    Err(message) => return Ok(bad_request(request, &message)),
+                }
+            } else {
+                match index_params(request) {
+                    Ok((start, stop)) => cache.read(key).zset_range(key, start, stop),
+                    Err(message) => return Ok(bad_request(request, &message)),
+                }
+            };
+            match members {
+                Ok(members) => {
+                    request.respond_with_json(
+                        200,
+                        json!({ "key": key, "members": scored_members(members) }).to_string(),
+                    );
+                    Ok(format!("Read sorted set {}.", key))
+                }
+                Err(err) => Ok(cache_error(request, err)),
+            }
+        }
+        "POST" => {
+            let json: serde_json::Value = match serde_json::from_str(&request.body_text()) {
+                Ok(json) => json,
+                Err(err) => return Ok(bad_request(request, &format!("Invalid JSON: {}", err))),
+            };
+            let members = match cache::CacheValue::from_json(&json, Some("zset")) {
+                Ok(cache::CacheValue::SortedSet(set)) => set
+                    .iter()
+                    .map(|(member, score)| (member.to_string(), score))
+                    .collect(),
+                Ok(_) => Vec::new(),
+                Err(err) => return Ok(cache_error(request, err)),
+            };
+            match cache.write(key).zset_add(key, members) {
+                Ok(added) => {
+                    request.respond_with_json(200, json!({ "key": key, "added": added }).to_string());
+                    Ok(format!("Added to sorted set {}.", key))
+                }
+                Err(err) => Ok(cache_error(request, err)),
+            }
+        }
+        _ => {
+            request.respond(405);
+            Ok(String::from("Unsupported method."))
+        }
+    }
+}
+
+/// A single sorted set member. PUT sets its score to the body, or adds
+/// the body to it with `incr=true`.
+fn zset_member(request: &server::HTMLRequest, cache: &ShardedCache) -> Result<String, std::io::Error> {
+    let (key, member) = match (request.param("key"), request.param("member")) {
+        (Some(key), Some(member)) => (key, member),
+        _ => return Ok(bad_request(request, "Missing key or member.")),
+    };
+    let not_found = |request: &server::HTMLRequest| {
+        let message = format!("Member '{}' of '{}' not found.", member, key);
+        request.respond_with_json(404, error_body(&message));
+        message
+    };
+
+    match request.method.as_str() {
+        "GET" => {
+            let cache = cache.read(key);
+            let found = cache
+                .zset_score(key, member)
+                .and_then(|score| Ok(score.zip(cache.zset_rank(key, member)?)));
+            match found {
+                Ok(Some((score, rank))) => {
+                    request.respond_with_json(
+                        200,
+                        json!({ "key": key, "member": member, "score": score, "rank": rank }).to_string(),
+                    );
+                    Ok(format!("Read {} of sorted set {}.", member, key))
+                }
+                Ok(None) => Ok(not_found(request)),
+                Err(err) => Ok(cache_error(request, err)),
+            }
+        }
+        "PUT" => {
+            let score = match request.body_text().trim().parse::<f64>() {
+                Ok(score) if !score.is_nan() => score,
+                _ => return Ok(bad_request(request, "The body must be a score.")),
+            };
+            let mut cache = cache.write(key);
+            let result = if request.query_param("incr") == Some("true") {
+                cache.zset_incr_by(key, member, score)
+            } else {
+                cache.zset_add(key, vec![(member.to_string(), score)]).map(|_| score)
+            };
+            match result {
+                Ok(score) => {
+                    request.respond_with_json(
+                        200,
+                        json!({ "key": key, "member": member, "score": score }).to_string(),
+                    );
+                    Ok(format!("Stored {} of sorted set {}.", member, key))
+                }
+                Err(err) => Ok(cache_error(request, err)),
+            }
+        }
+        "DELETE" => match cache.write(key).zset_remove(key, &[member.to_string()]) {
+            Ok(1) => {
+                request.respond(204);
+                Ok(format!("Deleted {} of sorted set {}.", member, key))
+            }
+            Ok(_) => Ok(not_found(request)),
+            Err(err) => Ok(cache_error(request, err)),
+        },
+        _ => {
+            request.respond(405);
+            Ok(String::from("Unsupported method."))
+        }
+    }
+}
+
+/// Works on the part of a JSON document at `path` (`$` by default). GET
+/// reads it, PUT replaces it with the body, POST appends the body to the
+/// array there and DELETE removes it.
+fn json_document(request: &server::HTMLRequest, cache: &ShardedCache) -> Result<String, std::io::Error> {
+    let key = match request.param("key") {
+        Some(key) => key,
+        None => return Ok(bad_request(request, "Missing key.")),
+    };
+    let path = match JsonPath::parse(request.query_param("path").unwrap_or("$")) {
+        Ok(path) => path,
+        Err(err) => return Ok(bad_request(request, &err.to_string())),
+    };
+    let body = || {
+        serde_json::from_slice::<serde_json::Value>(&request.body).map_err(|err| format!("Invalid JSON: {}", err))
+    };
+
+    let result = match request.method.as_str() {
+        "GET" => cache.read(key).json_get(key, &path),
+        "DELETE" => cache.write(key).json_delete(key, &path),
+        "PUT" | "POST" => {
+            let value = match body() {
+                Ok(value) => value,
+                Err(message) => return Ok(bad_request(request, &message)),
+            };
+            let mut cache = cache.write(key);
+            if request.method == "PUT" {
+                cache.json_set(key, &path, value).and_then(|_| cache.json_get(key, &path))
+            } else {
+                cache.json_append(key, &path, vec![value]).map(|len| json!(len))
+            }
+        }
+        _ => {
+            request.respond(405);
+            return Ok(String::from("Unsupported method."));
+        }
+    };
+    match result {
+        Ok(value) => {
+            let name = if request.method == "POST" { "length" } else { "value" };
+            request.respond_with_json(
+                200,
+                json!({ "key": key, "path": path.to_string(), name: value }).to_string(),
+            );
+            Ok(format!("Handled JSON {} at {}.", key, path))
+        }
+        Err(err) => Ok(cache_error(request, err)),
+    }
+}
+
+fn server_stats(request: &server::HTMLRequest, cache: &ShardedCache) -> Result<String, std::io::Error> {
+    let body = json!({
+        "cache": {
+            "keys": cache.len(),
+            "used_memory": cache.used_memory(),
+            "max_memory": cache.max_memory(),
+            "max_entries": cache.max_entries(),
+            "evictions": cache.evictions(),
+            "eviction_policy": cache.eviction_policy(),
+            "unsaved_changes": cache.unsaved_changes(),
+            "shards": cache.shard_count(),
+        },
+        "server": stats::SERVER.to_json(),
+    });
+    request.respond_with_json(200, body.to_string());
+    Ok(String::from("Reported stats."))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::SocketAddr,
+        sync::Mutex,
+    };
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::listener::Endpoint;
+    use crate::parser::RawRequest;
+    use crate::router::Router;
+    use crate::server::{HTMLRequest, HTTPServer, Header};
+
+    /// Collects what a request writes, to read it back after the handler ran.
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Response {
+        status: u64,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Response {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+
+        fn json(&self) -> Value {
+            serde_json::from_slice(&self.body).unwrap()
+        }
+    }
+
+    struct Server {
+        router: Router,
+        cache: ShardedCache,
+    }
+
+    impl Server {
+        fn new() -> Server {
+            Server {
+                router: Router::new(FuncHelper::new().get_func_map_raw()),
+                cache: ShardedCache::in_memory(4),
+            }
+        }
+
+        fn send(&self, method: &str, target: &str, headers: &[(&str, &str)], body: &[u8]) -> Response {
+            let output = Output::default();
+            let raw = RawRequest {
+                method: method.to_string(),
+                target: target.to_string(),
+                version: String::from("HTTP/1.1"),
+                headers: headers
+                    .iter()
+                    .map(|(key, value)| Header {
+                        key: key.to_string(),
+                        value: value.to_string(),
+                    })
+                    .collect(),
+                body: body.to_vec(),
+            };
+            let address = Endpoint::Tcp(SocketAddr::from(([127, 0, 0, 1], 8080)));
+            let mut request = HTMLRequest::from_raw(raw, Box::new(output.clone()), address.clone(), address);
+            HTTPServer::dispatch(&mut request, &self.router, &self.cache, None);
+
+            let out = output.0.lock().unwrap().clone();
+            let split = out.windows(4).position(|x| x == b"\r\n\r\n").unwrap();
+            let head = String::from_utf8(out[..split].to_vec()).unwrap();
+            let mut lines = head.split("\r\n");
+            let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+            let headers = lines
+                .map(|line| {
+                    let (key, value) = line.split_once(": ").unwrap();
+                    (key.to_string(), value.to_string())
+                })
+                .collect();
+            Response {
+                status,
+                headers,
+                body: out[split + 4..].to_vec(),
+            }
+        }
+
+        fn get(&self, target: &str) -> Response {
+            self.send("GET", target, &[], &[])
+        }
+    }
+
+    #[test]
+    fn key_crud() {
+        let server = Server::new();
+        let created = server.send("PUT", "/keys/a", &[], b"one");
+        assert_eq!(created.status, 201);
+        assert_eq!(created.json(), json!({"key": "a", "type": "string", "value": "one"}));
+        assert!(created.header("ETag").is_some());
+        assert_eq!(server.send("PUT", "/keys/a", &[], b"two").status, 200);
+        assert_eq!(server.send("PUT", "/keys/b%2Fc", &[], b"x").status, 201);
+
+        let read = server.get("/keys/a");
+        assert_eq!(read.status, 200);
+        assert_eq!(read.header("Content-Type"), Some("application/json"));
+        assert_eq!(read.json()["value"], "two");
+        assert_eq!(server.get("/keys").json(), json!({"keys": ["a", "b/c"]}));
+
+        let deleted = server.send("DELETE", "/keys/a", &[], &[]);
+        assert_eq!(deleted.status, 204);
+        assert!(deleted.body.is_empty());
+        assert_eq!(deleted.header("Content-Length"), None);
+        assert_eq!(server.get("/keys/a").status, 404);
+        assert_eq!(server.send("DELETE", "/keys/a", &[], &[]).status, 404);
+        assert_eq!(server.get("/keys/a").json()["error"], "Key 'a' not found.");
+    }
+
+    #[test]
+    fn typed_values_and_bad_requests() {
+        let server = Server::new();
+        assert_eq!(server.send("PUT", "/keys/n?type=int", &[], b" 12 ").json()["value"], 12);
+        assert_eq!(server.send("PUT", "/keys/f", &[("X-Cache-Type", "float")], b"1.5").json()["type"], "float");
+        assert_eq!(
+            server.send("PUT", "/keys/j", &[("Content-Type", "application/json")], b"[1, 2]").json()["type"],
+            "int_vec"
+        );
+        assert_eq!(server.send("PUT", "/keys/n?type=int", &[], b"abc").status, 400);
+        assert_eq!(server.send("PUT", "/keys/n?type=nope", &[], b"1").status, 400);
+        assert_eq!(server.send("PUT", "/keys/j", &[("Content-Type", "application/json")], b"{").status, 400);
+        assert_eq!(server.send("PUT", "/keys/n?ttl=soon", &[], b"1").status, 400);
+        assert_eq!(server.get("/keys/n").json()["value"], 12);
+
+        let expiring = server.send("PUT", "/keys/t?ttl=100", &[], b"1");
+        assert_eq!(expiring.status, 201);
+        assert!(server.cache.read("t").ttl("t").unwrap().is_some());
+    }
+
+    #[test]
+    fn unknown_routes_and_methods() {
+        let server = Server::new();
+        assert_eq!(server.get("/nope").status, 404);
+        assert_eq!(server.get("/keys/a/b/c/d").status, 404);
+        let response = server.send("POST", "/keys/a", &[], b"x");
+        assert_eq!(response.status, 405);
+        assert_eq!(
+            String::from_utf8(response.body).unwrap(),
+            "Unsupported method. Supported methods: GET, PUT, DELETE"
+        );
+        assert_eq!(server.get("/keys/a/incr").status, 405);
+        assert_eq!(server.send("PUT", "/stats", &[], &[]).status, 405);
+        assert_eq!(server.get("/stats").json()["cache"]["shards"], 4);
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let server = Server::new();
+        let etag = server.send("PUT", "/keys/a", &[], b"1").header("ETag").unwrap().to_string();
+        let version: u64 = etag.trim_matches('"').parse().unwrap();
+
+        let cached = server.send("GET", "/keys/a", &[("If-None-Match", &format!("W/{}", etag))], &[]);
+        assert_eq!(cached.status, 304);
+        assert!(cached.body.is_empty());
+        assert_eq!(cached.header("ETag"), Some(etag.as_str()));
+        assert_eq!(server.send("GET", "/keys/a", &[("If-None-Match", "\"0\"")], &[]).status, 200);
+
+        assert_eq!(server.send("PUT", "/keys/a", &[("If-Match", "\"0\"")], b"2").status, 412);
+        assert_eq!(server.send("PUT", "/keys/b", &[("If-Match", "*")], b"2").status, 412);
+        assert_eq!(server.send("DELETE", "/keys/a", &[("If-Match", "\"0\"")], &[]).status, 412);
+        let updated = server.send("PUT", "/keys/a", &[("If-Match", &format!("\"0\", {}", etag))], b"2");
+        assert_eq!(updated.status, 200);
+        assert_ne!(updated.header("ETag"), Some(etag.as_str()));
+
+        // The version read first is stale now.
+        let stale = server.send("PUT", &format!("/keys/a?version={}", version), &[], b"3");
+        assert_eq!(stale.status, 409);
+        assert_eq!(server.get("/keys/a").json()["value"], "2");
+        let current = server.get("/keys/a").header("ETag").unwrap().trim_matches('"').to_string();
+        assert_eq!(server.send("PUT", &format!("/keys/a?version={}", current), &[], b"3").status, 200);
+        assert_eq!(server.send("PUT", "/keys/b?version=1", &[], b"3").status, 404);
+        assert_eq!(server.send("PUT", "/keys/a?version=x", &[], b"3").status, 400);
+        assert_eq!(server.get("/keys/a").json()["value"], "3");
+    }
+
+    #[test]
+    fn counters() {
+        let server = Server::new();
+        let created = server.send("POST", "/keys/n/incr", &[], &[]);
+        assert_eq!(created.status, 200);
+        assert_eq!(created.json(), json!({"key": "n", "type": "i64", "value": 1}));
+        assert_eq!(server.send("POST", "/keys/n/incr?by=5", &[], &[]).json()["value"], 6);
+        assert_eq!(server.send("POST", "/keys/n/decr?by=10", &[], &[]).json()["value"], -4);
+        assert_eq!(server.send("POST", "/keys/n/incr?by=x", &[], &[]).status, 400);
+        assert_eq!(server.send("POST", "/keys/n/decr?by=-9223372036854775808", &[], &[]).status, 409);
+
+        let float = server.send("POST", "/keys/f/incr?by=0.5", &[], &[]);
+        assert_eq!(float.json(), json!({"key": "f", "type": "float", "value": 0.5}));
+        assert_eq!(server.send("POST", "/keys/f/decr", &[], &[]).json()["value"], -0.5);
+
+        server.send("PUT", "/keys/s", &[], b"abc");
+        assert_eq!(server.send("POST", "/keys/s/incr", &[], &[]).status, 409);
+        assert_eq!(server.get("/keys/s").json()["value"], "abc");
+    }
+
+    #[test]
+    fn binary_values() {
+        let server = Server::new();
+        let data = [0u8, 159, 146, 150, 255, b'\n'];
+        let stored = server.send("PUT", "/keys/b", &[("Content-Type", "application/octet-stream")], &data);
+        assert_eq!(stored.status, 201);
+        assert_eq!(stored.json()["type"], "bytes");
+
+        let read = server.get("/keys/b");
+        assert_eq!(read.status, 200);
+        assert_eq!(read.header("Content-Type"), Some("application/octet-stream"));
+        assert!(read.header("ETag").is_some());
+        assert_eq!(read.body, data);
+
+        server.send("PUT", "/keys/c?type=bytes", &[], &data);
+        assert_eq!(server.get("/keys/c").body, data);
+        // The header only says how to read the body, the type can still be picked.
+        let text = server.send("PUT", "/keys/d?type=string", &[("Content-Type", "application/octet-stream")], b"hi");
+        assert_eq!(text.json()["type"], "string");
+    }
+
+    #[test]
+    fn json_documents() {
+        let server = Server::new();
+        let doc = server.send("PUT", "/keys/doc/json", &[], br#"{"tags": ["a"], "n": 1}"#);
+        assert_eq!(doc.status, 200);
+        assert_eq!(doc.json()["value"], json!({"tags": ["a"], "n": 1}));
+
+        assert_eq!(server.get("/keys/doc/json?path=$.tags[0]").json()["value"], "a");
+        let appended = server.send("POST", "/keys/doc/json?path=$.tags", &[], br#""b""#);
+        assert_eq!(appended.json(), json!({"key": "doc", "path": "$.tags", "length": 2}));
+        assert_eq!(server.send("PUT", "/keys/doc/json?path=$.n", &[], b"2").json()["value"], 2);
+        assert_eq!(server.send("DELETE", "/keys/doc/json?path=$.tags[0]", &[], &[]).json()["value"], "a");
+        assert_eq!(server.get("/keys/doc/json").json()["value"], json!({"tags": ["b"], "n": 2}));
+
+        assert_eq!(server.get("/keys/doc/json?path=$.missing").status, 404);
+        assert_eq!(server.get("/keys/other/json").status, 404);
+        assert_eq!(server.send("PUT", "/keys/other/json?path=$.a", &[], b"1").status, 404);
+        assert_eq!(server.get("/keys/doc/json?path=tags").status, 400);
+        assert_eq!(server.send("PUT", "/keys/doc/json", &[], b"{").status, 400);
+        server.send("PUT", "/keys/s", &[], b"abc");
+        assert_eq!(server.get("/keys/s/json").status, 409);
+        assert_eq!(server.send("PATCH", "/keys/doc/json", &[], &[]).status, 405);
+    }
+
+    #[test]
+    fn lists_and_sets() {
+        let server = Server::new();
+        assert_eq!(server.send("POST", "/keys/l/list", &[], br#"["a", "b"]"#).json()["length"], 2);
+        assert_eq!(server.send("POST", "/keys/l/list?end=front", &[], b"z").json()["length"], 3);
+        assert_eq!(server.get("/keys/l/list").json()["values"], json!(["z", "a", "b"]));
+        assert_eq!(server.get("/keys/l/list?start=-2").json()["values"], json!(["a", "b"]));
+        assert_eq!(server.send("DELETE", "/keys/l/list?count=2", &[], &[]).json()["values"], json!(["b", "a"]));
+        assert_eq!(server.send("POST", "/keys/l/list/trim?start=5", &[], &[]).json()["length"], 0);
+        assert_eq!(server.get("/keys/l").status, 404);
+        assert_eq!(server.get("/keys/l/list?end=middle").status, 400);
+        assert_eq!(server.get("/keys/l/list?start=x").status, 400);
+        assert_eq!(server.send("POST", "/keys/l/list", &[], &[]).status, 400);
+
+        assert_eq!(server.send("POST", "/keys/s1/set", &[], br#"["a", "b"]"#).json()["added"], 2);
+        assert_eq!(server.send("POST", "/keys/s2/set", &[], br#"["b", "c"]"#).json()["added"], 2);
+        assert_eq!(server.get("/keys/s1/set?member=a").json()["contains"], true);
+        assert_eq!(server.get("/sets/intersect?keys=s1,s2").json()["members"], json!(["b"]));
+        assert_eq!(server.get("/sets/union?keys=s1,s2,none").json()["members"], json!(["a", "b", "c"]));
+        assert_eq!(server.get("/sets/difference?keys=s1").status, 404);
+        assert_eq!(server.get("/sets/union").status, 400);
+        assert_eq!(server.send("DELETE", "/keys/s1/set", &[], b"a").json()["removed"], 1);
+        assert_eq!(server.get("/keys/s1/set").json()["members"], json!(["b"]));
+        assert_eq!(server.get("/keys/s1/list").status, 409);
+    }
+
+    #[test]
+    fn hashes_and_sorted_sets() {
+        let server = Server::new();
+        assert_eq!(server.send("PUT", "/keys/h/hash/f", &[], b"1").status, 201);
+        assert_eq!(server.send("PUT", "/keys/h/hash/f", &[], b"2").status, 200);
+        assert_eq!(server.get("/keys/h/hash/f").json()["value"], "2");
+        assert_eq!(server.get("/keys/h/hash").json()["fields"], json!({"f": "2"}));
+        assert_eq!(server.get("/keys/h/hash/g").status, 404);
+        assert_eq!(server.send("DELETE", "/keys/h/hash/g", &[], &[]).status, 404);
+        assert_eq!(server.send("DELETE", "/keys/h/hash/f", &[], &[]).status, 204);
+        // The emptied hash is gone, reading it gives no fields like HGETALL.
+        assert_eq!(server.get("/keys/h").status, 404);
+        assert_eq!(server.get("/keys/h/hash").json()["fields"], json!({}));
+
+        assert_eq!(server.send("POST", "/keys/z/zset", &[], br#"{"a": 2, "b": 1}"#).json()["added"], 2);
+        assert_eq!(
+            server.get("/keys/z/zset").json()["members"],
+            json!([{"member": "b", "score": 1.0}, {"member": "a", "score": 2.0}])
+        );
+        assert_eq!(server.get("/keys/z/zset?min=1.5").json()["members"], json!([{"member": "a", "score": 2.0}]));
+        assert_eq!(server.send("PUT", "/keys/z/zset/b?incr=true", &[], b"5").json()["score"], 6.0);
+        assert_eq!(server.get("/keys/z/zset/b").json()["rank"], 1);
+        assert_eq!(server.send("PUT", "/keys/z/zset/b", &[], b"nan").status, 400);
+        assert_eq!(server.send("POST", "/keys/z/zset", &[], b"[").status, 400);
+        assert_eq!(server.send("DELETE", "/keys/z/zset/b", &[], &[]).status, 204);
+        assert_eq!(server.get("/keys/z/zset/b").status, 404);
+    }
+}
@@ -0,0 +1,298 @@
+use std::fmt::Display;
+
+use serde_json::Value;
+
+/// One step of a path, `.name` / `['name']` or `[index]`. Negative indices
+/// count from the end of an array.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Key(String),
+    Index(i64),
+}
+
+/// A parsed path like `$.user.tags[0]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PathError {
+    /// The path itself doesn't parse.
+    Syntax(String),
+    /// Some step of the path doesn't exist in the document.
+    NotFound,
+    /// The path leads somewhere the operation can't work with.
+    Invalid(String),
+}
+
+impl Display for PathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathError::Syntax(message) => write!(f, "Invalid JSON path: {}", message),
+            PathError::NotFound => write!(f, "Path not found."),
+            PathError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Display for JsonPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "$")?;
+        for segment in &self.segments {
+            match segment {
+                Segment::Key(key) if !key.is_empty() && key.chars().all(|x| x.is_alphanumeric() || x == '_') => {
+                    write!(f, ".{}", key)?
+                }
+                Segment::Key(key) => write!(f, "[{}]", Value::String(key.clone()))?,
+                Segment::Index(index) => write!(f, "[{}]", index)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+#[allow(dead_code)]
+impl JsonPath {
+    pub fn root() -> JsonPath {
+        JsonPath { segments: Vec::new() }
+    }
+
+    pub fn parse(path: &str) -> Result<JsonPath, PathError> {
+        let syntax = |message: &str| PathError::Syntax(format!("{} in '{}'.", message, path));
+        let rest = path
+            .trim()
+            .strip_prefix('$')
+            .ok_or_else(|| syntax("Paths start with '$'"))?;
+        let chars: Vec<char> = rest.chars().collect();
+        let mut segments = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '.' => {
+                    let start = i + 1;
+                    i = start;
+                    while i < chars.len() && chars[i] != '.' && chars[i] != '[' {
+                        i += 1;
+                    }
+                    if i == start {
+                        return Err(syntax("Expected a name after '.'"));
+                    }
+                    segments.push(Segment::Key(chars[start..i].iter().collect()));
+                }
+                '[' => {
+                    let end = match chars[i + 1..].iter().position(|x| *x == ']') {
+                        Some(offset) => i + 1 + offset,
+                        None => return Err(syntax("Unclosed '['")),
+                    };
+                    let inner: String = chars[i + 1..end].iter().collect();
+                    let inner = inner.trim();
+                    let quoted = inner.len() >= 2
+                        && (inner.starts_with('\'') && inner.ends_with('\'')
+                            || inner.starts_with('"') && inner.ends_with('"'));
+                    if quoted {
+                        segments.push(Segment::Key(inner[1..inner.len() - 1].to_string()));
+                    } else {
+                        let index = inner.parse().map_err(|_| syntax("Expected an index or a quoted name"))?;
+                        segments.push(Segment::Index(index));
+                    }
+                    i = end + 1;
+                }
+                _ => return Err(syntax("Expected '.' or '['")),
+            }
+        }
+        Ok(JsonPath { segments })
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn get<'a>(&self, document: &'a Value) -> Option<&'a Value> {
+        self.segments
+            .iter()
+            .try_fold(document, |value, segment| match (segment, value) {
+                (Segment::Key(key), Value::Object(map)) => map.get(key),
+                (Segment::Index(index), Value::Array(items)) => {
+                    items.get(resolve_index(*index, items.len())?)
+                }
+                _ => None,
+            })
+    }
+
+    fn get_mut<'a>(segments: &[Segment], document: &'a mut Value) -> Option<&'a mut Value> {
+        segments
+            .iter()
+            .try_fold(document, |value, segment| match (segment, value) {
+                (Segment::Key(key), Value::Object(map)) => map.get_mut(key),
+                (Segment::Index(index), Value::Array(items)) => {
+                    let index = resolve_index(*index, items.len())?;
+                    items.get_mut(index)
+                }
+                _ => None,
+            })
+    }
+
+    /// Splits off the last step, the one set and delete act on.
+    fn split_last(&self) -> Option<(&[Segment], &Segment)> {
+        let (last, parent) = self.segments.split_last()?;
+        Some((parent, last))
+    }
+
+    /// Replaces the value at the path. The parent has to exist, a missing
+    /// last name is added to its object and the index one past the end of
+    /// an array appends.
+    pub fn set(&self, document: &mut Value, value: Value) -> Result<(), PathError> {
+        let (parent, last) = match self.split_last() {
+            Some(split) => split,
+            None => {
+                *document = value;
+                return Ok(());
+            }
+        };
+        match (last, JsonPath::get_mut(parent, document).ok_or(PathError::NotFound)?) {
+            (Segment::Key(key), Value::Object(map)) => {
+                map.insert(key.clone(), value);
+            }
+            (Segment::Index(index), Value::Array(items)) => {
+                if *index == items.len() as i64 {
+                    items.push(value);
+                } else {
+                    let index = resolve_index(*index, items.len()).ok_or(PathError::NotFound)?;
+                    items[index] = value;
+                }
+            }
+            (Segment::Key(_), _) => return Err(PathError::Invalid(String::from("Names only apply to objects."))),
+            (Segment::Index(_), _) => return Err(PathError::Invalid(String::from("Indices only apply to arrays."))),
+        }
+        Ok(())
+    }
+
+    /// Removes the value at the path and returns it.
+    pub fn delete(&self, document: &mut Value) -> Result<Value, PathError> {
+        let (parent, last) = self
+            .split_last()
+            .ok_or_else(|| PathError::Invalid(String::from("The root can't be deleted from a document.")))?;
+        match (last, JsonPath::get_mut(parent, document).ok_or(PathError::NotFound)?) {
+            (Segment::Key(key), Value::Object(map)) => map.remove(key).ok_or(PathError::NotFound),
+            (Segment::Index(index), Value::Array(items)) => {
+                let index = resolve_index(*index, items.len()).ok_or(PathError::NotFound)?;
+                Ok(items.remove(index))
+            }
+            _ => Err(PathError::NotFound),
+        }
+    }
+
+    /// Appends values to the array at the path and returns its new length.
+    pub fn append(&self, document: &mut Value, values: Vec<Value>) -> Result<usize, PathError> {
+        match JsonPath::get_mut(&self.segments, document).ok_or(PathError::NotFound)? {
+            Value::Array(items) => {
+                items.extend(values);
+                Ok(items.len())
+            }
+            _ => Err(PathError::Invalid(String::from("Values can only be appended to arrays."))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn path(text: &str) -> JsonPath {
+        JsonPath::parse(text).unwrap()
+    }
+
+    #[test]
+    fn parses_names_quoted_names_and_indices() {
+        assert!(path("$").is_root());
+        assert_eq!(
+            path(" $.user['first name'][\"x.y\"][0][-1] ").segments,
+            vec![
+                Segment::Key("user".to_string()),
+                Segment::Key("first name".to_string()),
+                Segment::Key("x.y".to_string()),
+                Segment::Index(0),
+                Segment::Index(-1),
+            ]
+        );
+        for text in ["user", "$.", "$..a", "$[0", "$[a]", "$a", "$.a[]"] {
+            assert!(
+                matches!(JsonPath::parse(text), Err(PathError::Syntax(_))),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn display_parses_back_to_the_same_path() {
+        for text in ["$", "$.user.tags[0]", "$[\"first name\"][-2]", "$[\"\"]"] {
+            let parsed = path(text);
+            assert_eq!(parsed.to_string(), text);
+            assert_eq!(path(&parsed.to_string()), parsed);
+        }
+        assert_eq!(path("$['a.b'].c").to_string(), "$[\"a.b\"].c");
+    }
+
+    #[test]
+    fn get_follows_names_and_indices() {
+        let document = json!({"user": {"tags": ["a", "b", "c"]}});
+        assert_eq!(path("$").get(&document), Some(&document));
+        assert_eq!(path("$.user.tags[0]").get(&document), Some(&json!("a")));
+        assert_eq!(path("$.user.tags[-1]").get(&document), Some(&json!("c")));
+        assert_eq!(path("$.user.tags[3]").get(&document), None);
+        assert_eq!(path("$.user.tags[-4]").get(&document), None);
+        assert_eq!(path("$.user[0]").get(&document), None);
+        assert_eq!(path("$.user.tags.name").get(&document), None);
+    }
+
+    #[test]
+    fn set_replaces_adds_and_appends() {
+        let mut document = json!({"tags": ["a"]});
+        path("$.name").set(&mut document, json!("x")).unwrap();
+        path("$.tags[0]").set(&mut document, json!("b")).unwrap();
+        path("$.tags[1]").set(&mut document, json!("c")).unwrap();
+        path("$.tags[-1]").set(&mut document, json!("d")).unwrap();
+        assert_eq!(document, json!({"name": "x", "tags": ["b", "d"]}));
+
+        assert_eq!(path("$.tags[5]").set(&mut document, json!(1)), Err(PathError::NotFound));
+        assert_eq!(path("$.missing.name").set(&mut document, json!(1)), Err(PathError::NotFound));
+        assert!(matches!(path("$.tags.name").set(&mut document, json!(1)), Err(PathError::Invalid(_))));
+        assert!(matches!(path("$.name[0]").set(&mut document, json!(1)), Err(PathError::Invalid(_))));
+        assert_eq!(document, json!({"name": "x", "tags": ["b", "d"]}));
+
+        path("$").set(&mut document, json!(1)).unwrap();
+        assert_eq!(document, json!(1));
+    }
+
+    #[test]
+    fn delete_returns_the_removed_value() {
+        let mut document = json!({"name": "x", "tags": ["a", "b", "c"]});
+        assert_eq!(path("$.tags[-1]").delete(&mut document), Ok(json!("c")));
+        assert_eq!(path("$.name").delete(&mut document), Ok(json!("x")));
+        assert_eq!(document, json!({"tags": ["a", "b"]}));
+
+        assert_eq!(path("$.name").delete(&mut document), Err(PathError::NotFound));
+        assert_eq!(path("$.tags[2]").delete(&mut document), Err(PathError::NotFound));
+        assert_eq!(path("$.tags.name").delete(&mut document), Err(PathError::NotFound));
+        assert!(matches!(path("$").delete(&mut document), Err(PathError::Invalid(_))));
+        assert_eq!(document, json!({"tags": ["a", "b"]}));
+    }
+
+    #[test]
+    fn append_only_extends_arrays() {
+        let mut document = json!({"tags": ["a"], "name": "x"});
+        assert_eq!(path("$.tags").append(&mut document, vec![json!("b"), json!(1)]), Ok(3));
+        assert_eq!(path("$.tags").append(&mut document, Vec::new()), Ok(3));
+        assert_eq!(document["tags"], json!(["a", "b", 1]));
+        assert!(matches!(path("$.name").append(&mut document, vec![json!(1)]), Err(PathError::Invalid(_))));
+        assert_eq!(path("$.missing").append(&mut document, vec![json!(1)]), Err(PathError::NotFound));
+    }
+}
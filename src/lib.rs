@@ -0,0 +1,49 @@
+//! zen-cache as a library. `Cache<K, V>` is the same store the server runs,
+//! usable in-process with any `Hash + Eq` key and any value type:
+//!
+//! ```
+//! use std::time::Duration;
+//! use zen_cache_rs::Cache;
+//!
+//! let mut sessions: Cache<u64, Vec<u8>> = Cache::in_memory();
+//! sessions.set_max_entries(Some(10_000));
+//! sessions.insert_with_ttl(7, vec![1, 2, 3], Duration::from_secs(60));
+//! assert_eq!(sessions.get(&7), Some(&vec![1, 2, 3]));
+//! ```
+//!
+//! The server itself uses `Cache<String, CacheValue>`, which adds typed
+//! values, collections, snapshots and the write log on top. To share a
+//! cache between threads it runs a `ShardedCache`, several caches each
+//! behind their own lock.
+
+pub mod aof;
+pub mod arghelper;
+pub mod auth;
+mod base64;
+pub mod cache;
+mod codec;
+pub mod collections;
+mod digest;
+#[cfg(target_os = "linux")]
+mod event_loop;
+pub mod eviction;
+pub mod handler;
+pub mod json_path;
+pub mod listener;
+pub mod memcached;
+pub mod parser;
+mod pattern;
+mod pool;
+pub mod resp;
+mod router;
+pub mod server;
+pub mod sharded;
+pub mod shutdown;
+mod snapshot;
+mod stats;
+pub mod tls;
+pub mod websocket;
+
+pub use cache::{Cache, CacheError, CacheEvent, CacheValue};
+pub use eviction::EvictionPolicy;
+pub use sharded::ShardedCache;
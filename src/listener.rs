@@ -0,0 +1,466 @@
+use std::{
+    fmt::Display,
+    io::{Error, ErrorKind, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        io::{AsRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+};
+
+use crate::shutdown;
+use crate::tls::{TlsAcceptor, TlsStream};
+
+/// A Unix domain socket to listen on, next to or instead of a TCP port.
+#[derive(Debug, Clone)]
+pub struct UnixSocket {
+    pub path: PathBuf,
+    /// Permissions of the socket file, e.g. `0o660`. `None` leaves them to the umask.
+    pub mode: Option<u32>,
+}
+
+/// Either end of a connection.
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    /// Clients connecting to a Unix socket usually have no path.
+    #[cfg(unix)]
+    Unix(Option<PathBuf>),
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Tcp(address) => write!(f, "{}", address),
+            #[cfg(unix)]
+            Endpoint::Unix(Some(path)) => write!(f, "unix:{}", path.to_string_lossy()),
+            #[cfg(unix)]
+            Endpoint::Unix(None) => write!(f, "unix:"),
+        }
+    }
+}
+
+/// Binds a Unix socket that never exists at `path` with other permissions
+/// than `mode`: it is bound in a directory only the owner may enter, given
+/// its mode there and then moved into place.
+#[cfg(unix)]
+fn bind_with_mode(path: &Path, mode: u32) -> Result<UnixListener, Error> {
+    let parent = path.parent().filter(|x| !x.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let staging = parent.join(format!(".{}.{}.bind", name, std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("socket");
+    let result = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&staging);
+    result
+}
+
+#[cfg(unix)]
+fn unix_endpoint(address: std::os::unix::net::SocketAddr) -> Endpoint {
+    Endpoint::Unix(address.as_pathname().map(|x| x.to_path_buf()))
+}
+
+/// An accepted connection, over TCP, TLS or a Unix socket.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Tls(TlsStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+#[allow(dead_code)]
+impl Stream {
+    pub fn try_clone(&self) -> Result<Stream, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Tls(stream) => stream.try_clone().map(Stream::Tls),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub fn peer_address(&self) -> Result<Endpoint, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().map(Endpoint::Tcp),
+            Stream::Tls(stream) => stream.socket().peer_addr().map(Endpoint::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.peer_addr().map(unix_endpoint),
+        }
+    }
+
+    pub fn local_address(&self) -> Result<Endpoint, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.local_addr().map(Endpoint::Tcp),
+            Stream::Tls(stream) => stream.socket().local_addr().map(Endpoint::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.local_addr().map(unix_endpoint),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            Stream::Tls(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Tls(stream) => stream.socket().set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            Stream::Tls(stream) => stream.socket().set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    /// Whether earlier writes still wait for the socket. Only TLS buffers
+    /// data on its own.
+    pub fn has_pending_output(&self) -> bool {
+        match self {
+            Stream::Tls(stream) => stream.has_pending_output(),
+            _ => false,
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Tls(stream) => stream.socket().set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+            Stream::Tls(stream) => stream.socket().as_raw_fd(),
+            Stream::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+/// A bound TCP port or Unix socket. The socket file of a Unix listener is
+/// removed again when it is dropped.
+pub enum Listener {
+    Tcp(TcpListener),
+    /// A TCP port speaking TLS.
+    Tls(TcpListener, Arc<TlsAcceptor>),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+#[allow(dead_code)]
+impl Listener {
+    pub fn bind_tcp(address: &str) -> Result<Listener, Error> {
+        TcpListener::bind(address).map(Listener::Tcp)
+    }
+
+    /// Binds a Unix socket, replacing a stale socket file left behind by a
+    /// previous run. A socket somebody still listens on is left alone.
+    #[cfg(unix)]
+    pub fn bind_unix(socket: &UnixSocket) -> Result<Listener, Error> {
+        let path = &socket.path;
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.to_string_lossy()),
+                ));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("{} is in use by another process", path.to_string_lossy()),
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+        let listener = match socket.mode {
+            Some(mode) => bind_with_mode(path, mode)?,
+            None => UnixListener::bind(path)?,
+        };
+        Ok(Listener::Unix(listener, path.clone()))
+    }
+
+    #[cfg(not(unix))]
+    pub fn bind_unix(_socket: &UnixSocket) -> Result<Listener, Error> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "Unix sockets are not supported on this platform",
+        ))
+    }
+
+    pub fn bind_tls(address: &str, acceptor: &Arc<TlsAcceptor>) -> Result<Listener, Error> {
+        TcpListener::bind(address).map(|listener| Listener::Tls(listener, Arc::clone(acceptor)))
+    }
+
+    /// Binds the TCP address and the Unix socket that are given, in that
+    /// order. The TCP port speaks TLS if there is an acceptor for it.
+    pub fn bind_all(
+        tcp: Option<&str>,
+        unix: Option<&UnixSocket>,
+        tls: Option<&Arc<TlsAcceptor>>,
+    ) -> Result<Vec<Listener>, Error> {
+        let mut listeners = Vec::new();
+        if let Some(address) = tcp {
+            listeners.push(match tls {
+                Some(acceptor) => Listener::bind_tls(address, acceptor)?,
+                None => Listener::bind_tcp(address)?,
+            });
+        }
+        if let Some(socket) = unix {
+            listeners.push(Listener::bind_unix(socket)?);
+        }
+        Ok(listeners)
+    }
+
+    pub fn accept(&self) -> Result<(Stream, Endpoint), Error> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .accept()
+                .map(|(stream, address)| (Stream::Tcp(stream), Endpoint::Tcp(address))),
+            Listener::Tls(listener, acceptor) => {
+                let (stream, address) = listener.accept()?;
+                Ok((Stream::Tls(acceptor.accept(stream)?), Endpoint::Tcp(address)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener
+                .accept()
+                .map(|(stream, address)| (Stream::Unix(stream), unix_endpoint(address))),
+        }
+    }
+
+    pub fn local_address(&self) -> Result<Endpoint, Error> {
+        match self {
+            Listener::Tcp(listener) | Listener::Tls(listener, _) => listener.local_addr().map(Endpoint::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(Endpoint::Unix(Some(path.clone()))),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error> {
+        match self {
+            Listener::Tcp(listener) | Listener::Tls(listener, _) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Returns a function that unblocks a pending `accept` by connecting to
+    /// the listener, since a blocking accept can't be interrupted otherwise.
+    pub fn waker(&self) -> Result<Box<dyn Fn() + Send>, Error> {
+        match self {
+            Listener::Tcp(listener) | Listener::Tls(listener, _) => {
+                let address = wake_address(listener.local_addr()?);
+                Ok(Box::new(move || {
+                    let _ = TcpStream::connect(address);
+                }))
+            }
+            #[cfg(unix)]
+            Listener::Unix(_, path) => {
+                let path = path.clone();
+                Ok(Box::new(move || {
+                    let _ = UnixStream::connect(&path);
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) | Listener::Tls(listener, _) => listener.as_raw_fd(),
+            Listener::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Address to connect to for reaching a listener bound to `local`,
+/// which may be the unspecified address.
+fn wake_address(local: SocketAddr) -> SocketAddr {
+    match local.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), local.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), local.port())
+        }
+        _ => local,
+    }
+}
+
+/// Accepts connections on every listener, a thread each, and hands them to
+/// `handle` until a shutdown is requested.
+pub fn accept_all(listeners: &[Listener], handle: impl Fn(Stream) + Sync) -> Result<(), Error> {
+    for listener in listeners {
+        shutdown::spawn_watcher(listener.waker()?);
+    }
+    std::thread::scope(|scope| {
+        for listener in listeners {
+            let handle = &handle;
+            scope.spawn(move || loop {
+                let accepted = listener.accept();
+                if shutdown::is_requested() {
+                    break;
+                }
+                match accepted {
+                    Ok((stream, _)) => handle(stream),
+                    Err(err) => eprintln!("Error occurred while accepting a connection: {}", err),
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Lists where `listeners` accept connections, for the startup message.
+pub fn describe(listeners: &[Listener]) -> String {
+    listeners
+        .iter()
+        .filter_map(|listener| {
+            let address = listener.local_address().ok()?;
+            Some(match listener {
+                Listener::Tls(..) => format!("{} (TLS)", address),
+                _ => address.to_string(),
+            })
+        })
+        .collect::<Vec<String>>()
+        .join(" and ")
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for one test's sockets.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zen-cache-listener-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn socket(path: &Path, mode: Option<u32>) -> UnixSocket {
+        UnixSocket {
+            path: path.to_path_buf(),
+            mode,
+        }
+    }
+
+    #[test]
+    fn binds_with_the_mode_from_the_start() {
+        let dir = temp_dir("mode");
+        let path = dir.join("cache.sock");
+        let listener = Listener::bind_unix(&socket(&path, Some(0o600))).unwrap();
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        // Nothing is left of the staging directory.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(UnixStream::connect(&path).is_ok());
+
+        drop(listener);
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replaces_a_stale_socket_but_not_a_live_one() {
+        let dir = temp_dir("stale");
+        let path = dir.join("cache.sock");
+        // A listener that went away without cleaning up.
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listener = Listener::bind_unix(&socket(&path, None)).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+
+        let err = Listener::bind_unix(&socket(&path, Some(0o600))).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        assert!(UnixStream::connect(&path).is_ok());
+        drop(listener);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_replace_other_files() {
+        let dir = temp_dir("file");
+        let path = dir.join("cache.sock");
+        fs::write(&path, "data").unwrap();
+        let err = Listener::bind_unix(&socket(&path, None)).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
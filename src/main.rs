@@ -1,9 +1,9 @@
-use std::panic;
+use std::sync::Arc;
 
-mod server;
-mod handler;
-mod arghelper;
-mod cache;
+use zen_cache_rs::{
+    aof, arghelper, auth, eviction, handler, listener, memcached, parser, server, sharded, shutdown,
+    tls, websocket,
+};
 
 use arghelper::ArgHelper;
 
@@ -13,25 +13,258 @@ fn main() {
 
     let arghelper = ArgHelper::parse(std::env::args().map(|x| x.to_string()).collect());
 
-    let cache = cache::Cache::new(
+    let shards = arghelper
+        .get_value("shards")
+        .and_then(|x| x.parse::<usize>().ok())
+        .unwrap_or(sharded::DEFAULT_SHARDS);
+
+    let mut cache = sharded::ShardedCache::new(
         String::from(
             std::env::current_dir().unwrap().to_string_lossy()
-        )
+        ),
+        shards,
+    );
+
+    if let Some(policy) = arghelper.get_value("eviction") {
+        match eviction::from_name::<String>(&policy) {
+            Some(_) => {
+                cache.set_eviction_policy(|| eviction::from_name(&policy).unwrap());
+            }
+            None => panic!("Error. Specified eviction policy not found."),
+        }
+    }
+    cache.set_max_memory(arghelper.get_bytes("max-memory"));
+    cache.set_max_entries(
+        arghelper.get_value("max-entries").and_then(|x| x.parse::<usize>().ok())
     );
 
-    // For now unused, it's for choosing between server methods
-    // Planned: Websocket, HyperHttp, RocketHttp
-    let _method = arghelper.get_value("method").unwrap_or(String::from("asynchttp"));
+    // Snapshot every 60 seconds unless configured otherwise, 0 disables it.
+    let save_interval = arghelper
+        .get_value("save-interval")
+        .and_then(|x| x.parse::<u64>().ok())
+        .unwrap_or(60);
+    cache.set_save_interval(if save_interval > 0 {
+        Some(std::time::Duration::from_secs(save_interval))
+    } else {
+        None
+    });
+
+    match cache.load() {
+        Ok(0) => {}
+        Ok(loaded) => println!(
+            "Loaded {} entries from {}",
+            loaded,
+            cache.snapshot_path().to_string_lossy()
+        ),
+        Err(err) => panic!("Error. Could not load snapshot: {}", err),
+    }
+
+    // The write log is replayed on top of the snapshot and records every
+    // mutation from then on.
+    if arghelper.get_value("append-log").is_some() {
+        let fsync = arghelper.get_value("fsync").unwrap_or(String::from("everysec"));
+        let policy = match aof::FsyncPolicy::from_name(&fsync) {
+            Some(policy) => policy,
+            None => panic!("Error. Specified fsync policy not found."),
+        };
+        match cache.enable_log(policy) {
+            Ok(0) => {}
+            Ok(replayed) => println!("Replayed {} operations from the write log", replayed),
+            Err(err) => panic!("Error. Could not open write log: {}", err),
+        }
+        cache.set_log_rewrite_size(Some(
+            arghelper.get_bytes("log-rewrite-size").unwrap_or(64 * 1024 * 1024) as u64
+        ));
+    }
+
+    // Chooses between server methods
+    // Planned: HyperHttp, RocketHttp
+    let method = arghelper.get_value("method").unwrap_or(String::from("asynchttp"));
     let port = arghelper.get_value("port").unwrap_or(String::from("8080"));
-    match _method.to_lowercase().as_str() {
+    // A Unix socket can replace the TCP port for clients on the same host.
+    let tcp = arghelper.get_value("no-tcp").is_none();
+    if !tcp && arghelper.get_value("unix-socket").is_none() {
+        panic!("Error. --no-tcp needs a --unix-socket to listen on instead.");
+    }
+    // Memcached and WebSocket clients have no way to log in, serving them
+    // would leave the cache open to anyone.
+    let method = method.to_lowercase();
+    if arghelper.get_value("auth-file").is_some() && matches!(method.as_str(), "memcached" | "websocket") {
+        panic!("Error. --auth-file is only enforced for HTTP and RESP, not by the {} method.", method);
+    }
+    let tls = tls_acceptor(&arghelper);
+    shutdown::install_signal_handlers();
+    shutdown::install_panic_hook();
+
+    match method.as_str() {
         "asynchttp" => {
-            server::HTTPServer::new(
-                Some("0.0.0.0"), 
-                Some(port.as_str())
-            ).listen(functionmap, cache).unwrap();
+            http_server(&arghelper, &port, tls)
+            .set_pool(
+                arghelper.get_value("workers").and_then(|x| x.parse::<usize>().ok()),
+                arghelper.get_value("queue-size").and_then(|x| x.parse::<usize>().ok())
+            )
+            .listen(functionmap, cache)
+            .unwrap();
+        }
+        #[cfg(target_os = "linux")]
+        "epoll" => {
+            http_server(&arghelper, &port, tls)
+            .set_event_loops(
+                arghelper.get_value("event-loops").and_then(|x| x.parse::<usize>().ok())
+            )
+            .listen_epoll(functionmap, cache)
+            .unwrap();
+        }
+        "memcached" => {
+            memcached::MemcachedServer::new(
+                Some("0.0.0.0"),
+                Some(arghelper.get_value("port").unwrap_or(String::from("11211")).as_str())
+            )
+            .set_unix_socket(unix_socket(&arghelper, "unix-socket"))
+            .set_tcp(tcp)
+            .set_tls(tls)
+            .set_pool(
+                arghelper.get_value("workers").and_then(|x| x.parse::<usize>().ok()),
+                arghelper.get_value("queue-size").and_then(|x| x.parse::<usize>().ok())
+            )
+            .set_idle_timeout(idle_timeout(&arghelper))
+            .listen(cache)
+            .unwrap();
+        }
+        "websocket" => {
+            websocket::WebSocketServer::new(Some("0.0.0.0"), Some(port.as_str()))
+            .set_unix_socket(unix_socket(&arghelper, "unix-socket"))
+            .set_tcp(tcp)
+            .set_tls(tls)
+            .set_pool(
+                arghelper.get_value("workers").and_then(|x| x.parse::<usize>().ok()),
+                arghelper.get_value("queue-size").and_then(|x| x.parse::<usize>().ok())
+            )
+            .set_idle_timeout(idle_timeout(&arghelper))
+            .listen(cache)
+            .unwrap();
         }
         _ => {
             panic!("Error. Specified method not found.")
         }
     }
+
+    if let Some(reason) = shutdown::reason() {
+        println!("Exiting: {}", reason);
+        std::process::exit(reason.exit_code());
+    }
+}
+
+/// `--idle-timeout` in seconds, if given.
+fn idle_timeout(arghelper: &ArgHelper) -> Option<std::time::Duration> {
+    arghelper
+        .get_value("idle-timeout")
+        .and_then(|x| x.parse::<u64>().ok())
+        .map(std::time::Duration::from_secs)
+}
+
+/// HTTP settings shared by every HTTP based method.
+fn http_server(arghelper: &ArgHelper, port: &str, tls: Option<Arc<tls::TlsAcceptor>>) -> server::HTTPServer {
+    let defaults = parser::Limits::default();
+    let mut server = server::HTTPServer::new(
+        Some("0.0.0.0"), 
+        Some(port)
+    );
+    server
+    .set_limits(parser::Limits {
+        max_header_size: arghelper
+            .get_bytes("max-header-size")
+            .unwrap_or(defaults.max_header_size),
+        max_body_size: arghelper
+            .get_bytes("max-body-size")
+            .unwrap_or(defaults.max_body_size),
+    })
+    .set_keep_alive(
+        idle_timeout(arghelper).unwrap_or(std::time::Duration::from_secs(5)),
+        arghelper
+            .get_value("max-requests")
+            .and_then(|x| x.parse::<usize>().ok())
+            .unwrap_or(100)
+    )
+    .set_resp_port(arghelper.get_value("resp-port"))
+    .set_resp_socket(unix_socket(arghelper, "resp-socket"))
+    .set_unix_socket(unix_socket(arghelper, "unix-socket"))
+    .set_tcp(arghelper.get_value("no-tcp").is_none())
+    .set_tls(tls)
+    .set_auth(arghelper.get_value("auth-file").map(|path| {
+        match auth::Auth::from_file(std::path::Path::new(&path)) {
+            Ok(auth) => Arc::new(auth),
+            Err(err) => panic!("Error. Could not load auth file: {}", err),
+        }
+    }));
+    server
+}
+
+/// TLS for the TCP ports, configured by `--tls-cert` and `--tls-key`.
+/// Certificates picked by SNI are given as `--tls-sni name=cert.pem,key.pem`,
+/// client certificates are checked against `--tls-client-ca`.
+fn tls_acceptor(arghelper: &ArgHelper) -> Option<Arc<tls::TlsAcceptor>> {
+    let (cert, key) = match (arghelper.get_value("tls-cert"), arghelper.get_value("tls-key")) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => return None,
+        _ => panic!("Error. TLS needs both --tls-cert and --tls-key."),
+    };
+    let sni = arghelper
+        .get_values("tls-sni")
+        .iter()
+        .map(|x| {
+            let (name, files) = x.split_once('=').unwrap_or((x, ""));
+            match files.split_once(',') {
+                Some((cert, key)) if !name.is_empty() => tls::SniCertificate {
+                    name: name.to_string(),
+                    cert: std::path::PathBuf::from(cert),
+                    key: std::path::PathBuf::from(key),
+                },
+                _ => panic!("Error. --tls-sni expects name=cert.pem,key.pem, got '{}'.", x),
+            }
+        })
+        .collect();
+    let client_auth_required = match arghelper.get_value("tls-client-auth").as_deref() {
+        None | Some("required") => true,
+        Some("optional") => false,
+        Some(_) => panic!("Error. Specified TLS client auth mode not found."),
+    };
+
+    let acceptor = tls::TlsAcceptor::new(tls::TlsSettings {
+        cert: std::path::PathBuf::from(cert),
+        key: std::path::PathBuf::from(key),
+        sni,
+        client_ca: arghelper.get_value("tls-client-ca").map(std::path::PathBuf::from),
+        client_auth_required,
+    });
+    let acceptor = match acceptor {
+        Ok(acceptor) => Arc::new(acceptor),
+        Err(err) => panic!("Error. Could not load TLS certificates: {}", err),
+    };
+    // Certificate files are checked for changes every 10 seconds unless
+    // configured otherwise, 0 disables reloading.
+    let reload_interval = arghelper
+        .get_value("tls-reload-interval")
+        .and_then(|x| x.parse::<u64>().ok())
+        .unwrap_or(10);
+    if reload_interval > 0 {
+        tls::TlsAcceptor::spawn_reloader(&acceptor, std::time::Duration::from_secs(reload_interval));
+    }
+    Some(acceptor)
+}
+
+/// Unix socket at the path given for `key`, with the octal permissions of
+/// `--unix-socket-mode` if set.
+fn unix_socket(arghelper: &ArgHelper, key: &str) -> Option<listener::UnixSocket> {
+    let path = arghelper.get_value(key)?;
+    let mode = arghelper.get_value("unix-socket-mode").map(|x| {
+        match u32::from_str_radix(x.trim(), 8) {
+            Ok(mode) if mode <= 0o777 => mode,
+            _ => panic!("Error. Specified unix socket mode is not an octal mode like 660."),
+        }
+    });
+    Some(listener::UnixSocket {
+        path: std::path::PathBuf::from(path),
+        mode,
+    })
 }
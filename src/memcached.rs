@@ -0,0 +1,1260 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Error, ErrorKind, Read, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::cache::{Cache, CacheValue};
+use crate::listener::{self, Listener, Stream, UnixSocket};
+use crate::parser::{self, MAX_LINE_SIZE};
+use crate::server::{self, ConnectionPool, HTTPServer, SESSION_IDLE_TIMEOUT};
+use crate::sharded::ShardedCache;
+use crate::shutdown;
+use crate::stats;
+use crate::tls::TlsAcceptor;
+
+const MAX_KEY_LENGTH: usize = 250;
+// Same default item size limit as memcached.
+const MAX_ITEM_SIZE: usize = 1024 * 1024;
+// Expiry times above this many seconds are absolute unix timestamps.
+const RELATIVE_EXPIRY_LIMIT: i64 = 30 * 24 * 60 * 60;
+// Expiries further out than this are cut short, `Instant` can't hold much more.
+const MAX_TTL: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+// Flags of keys that changed elsewhere are dropped once a shard has this many.
+const FLAGS_PRUNE_SIZE: usize = 1024;
+// `flush_at` when no delayed flush is pending.
+const NO_FLUSH: u64 = u64::MAX;
+
+/// How a storage command treats an existing item.
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Set,
+    Add,
+    Replace,
+    Append,
+    Prepend,
+    /// Store only if the item still has this CAS value.
+    Cas(u64),
+}
+
+#[derive(PartialEq)]
+enum Outcome {
+    Stored,
+    NotStored,
+    Exists,
+    NotFound,
+}
+
+struct Item {
+    data: Vec<u8>,
+    flags: u32,
+    cas: u64,
+}
+
+enum Expiry {
+    Never,
+    In(Duration),
+    Past,
+}
+
+/// Memcached expiry times are relative seconds up to 30 days and unix
+/// timestamps beyond that. Negative values expire the item immediately.
+fn expiry(exptime: i64) -> Expiry {
+    if exptime == 0 {
+        return Expiry::Never;
+    }
+    if exptime < 0 {
+        return Expiry::Past;
+    }
+    let seconds = if exptime > RELATIVE_EXPIRY_LIMIT {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs() as i64)
+            .unwrap_or(0);
+        if exptime <= now {
+            return Expiry::Past;
+        }
+        exptime - now
+    } else {
+        exptime
+    };
+    Expiry::In(Duration::from_secs(seconds as u64).min(MAX_TTL))
+}
+
+/// The bytes memcached clients see for a value written through any protocol.
+fn value_bytes(value: &CacheValue) -> Vec<u8> {
+    match value {
+        CacheValue::String(text) => text.as_bytes().to_vec(),
+        CacheValue::Bytes(data) => data.clone(),
+        CacheValue::Bool(value) => value.to_string().into_bytes(),
+        CacheValue::Int(value) => value.to_string().into_bytes(),
+        CacheValue::Int64(value) => value.to_string().into_bytes(),
+        CacheValue::Float(value) => value.to_string().into_bytes(),
+        value => value.to_json().to_string().into_bytes(),
+    }
+}
+
+fn valid_key(key: &[u8]) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_KEY_LENGTH
+        && key.iter().all(|x| !x.is_ascii_control() && *x != b' ')
+}
+
+// Flags and the version they were stored with, by key.
+type FlagsTable = HashMap<String, (u32, u64)>;
+
+/// Memcached semantics on top of the cache. Flags are not part of a cache
+/// entry, so they are kept on the side together with the version of the
+/// value they were stored with. A write through another protocol bumps the
+/// version, which resets the flags to 0. Like the cache, the flags are
+/// split by shard.
+pub struct Memcached {
+    cache: Arc<ShardedCache>,
+    flags: Box<[Mutex<FlagsTable>]>,
+    started: Instant,
+    // Milliseconds after `started` a delayed flush_all is due, run by the
+    // first command that comes in after that.
+    flush_at: AtomicU64,
+    cmd_get: AtomicU64,
+    cmd_set: AtomicU64,
+    get_hits: AtomicU64,
+    get_misses: AtomicU64,
+}
+
+#[allow(dead_code)]
+impl Memcached {
+    pub fn new(cache: Arc<ShardedCache>) -> Memcached {
+        Memcached {
+            flags: (0..cache.shard_count()).map(|_| Mutex::new(HashMap::new())).collect(),
+            cache,
+            started: Instant::now(),
+            flush_at: AtomicU64::new(NO_FLUSH),
+            cmd_get: AtomicU64::new(0),
+            cmd_set: AtomicU64::new(0),
+            get_hits: AtomicU64::new(0),
+            get_misses: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<Item> {
+        self.flush_if_due();
+        self.cmd_get.fetch_add(1, Ordering::SeqCst);
+        let cache = self.cache.read(key);
+        let item = cache.get(key).zip(cache.version(key)).map(|(value, cas)| Item {
+            data: value_bytes(value),
+            flags: self.flags_of(key, cas),
+            cas,
+        });
+        let counter = if item.is_some() { &self.get_hits } else { &self.get_misses };
+        counter.fetch_add(1, Ordering::SeqCst);
+        item
+    }
+
+    /// The flags table of the shard holding `key`.
+    fn flags(&self, key: &str) -> MutexGuard<'_, FlagsTable> {
+        self.flags[self.cache.shard_index(key)].lock().unwrap()
+    }
+
+    fn flags_of(&self, key: &str, cas: u64) -> u32 {
+        match self.flags(key).get(key) {
+            Some((flags, version)) if *version == cas => *flags,
+            _ => 0,
+        }
+    }
+
+    /// Records the flags of the value just written to `key` and returns its
+    /// CAS value. `cache` is the shard holding `key`.
+    fn tag(&self, cache: &Cache, key: &str, flags: u32) -> u64 {
+        let cas = cache.version(key).unwrap_or(0);
+        let mut table = self.flags(key);
+        if flags == 0 || cas == 0 {
+            table.remove(key);
+        } else {
+            table.insert(key.to_string(), (flags, cas));
+        }
+        if table.len() > FLAGS_PRUNE_SIZE && table.len() > cache.len() * 2 {
+            table.retain(|key, (_, version)| cache.version(key) == Some(*version));
+        }
+        cas
+    }
+
+    fn put(&self, cache: &mut Cache, key: &str, data: Vec<u8>, exptime: Option<i64>) {
+        let value = CacheValue::from_bytes(data);
+        match exptime.map(expiry) {
+            None => cache.insert_keep_ttl(key.to_string(), value),
+            Some(Expiry::Never) => cache.insert(key.to_string(), value),
+            Some(Expiry::In(ttl)) => cache.insert_with_ttl(key.to_string(), value, ttl),
+            Some(Expiry::Past) => {
+                cache.remove(key);
+                cache
+            }
+        };
+    }
+
+    /// Runs a storage command. Returns the outcome and the CAS value of the
+    /// stored item, errors are server errors to report.
+    fn store(
+        &self,
+        mode: Mode,
+        key: &str,
+        flags: u32,
+        exptime: i64,
+        data: Vec<u8>,
+    ) -> Result<(Outcome, u64), &'static str> {
+        self.flush_if_due();
+        self.cmd_set.fetch_add(1, Ordering::SeqCst);
+        let mut cache = self.cache.write(key);
+        let current = cache.version(key);
+        let outcome = match (mode, current) {
+            (Mode::Add, Some(_)) => Outcome::NotStored,
+            (Mode::Replace | Mode::Append | Mode::Prepend, None) => Outcome::NotStored,
+            (Mode::Cas(_), None) => Outcome::NotFound,
+            (Mode::Cas(cas), Some(version)) if cas != version => Outcome::Exists,
+            _ => Outcome::Stored,
+        };
+        if outcome != Outcome::Stored {
+            return Ok((outcome, 0));
+        }
+
+        // Appending keeps the flags and expiry of the item it extends.
+        let (data, flags, exptime) = match mode {
+            Mode::Append | Mode::Prepend => {
+                let existing = cache.get(key).map(value_bytes).unwrap_or_default();
+                let flags = self.flags_of(key, current.unwrap_or(0));
+                let data = if mode == Mode::Append {
+                    [existing, data].concat()
+                } else {
+                    [data, existing].concat()
+                };
+                if data.len() > MAX_ITEM_SIZE {
+                    return Err("object too large for cache");
+                }
+                (data, flags, None)
+            }
+            _ => (data, flags, Some(exptime)),
+        };
+        self.put(&mut cache, key, data, exptime);
+        Ok((Outcome::Stored, self.tag(&cache, key, flags)))
+    }
+
+    /// incr and decr. Counters are unsigned 64 bit, incr wraps around and
+    /// decr stops at 0. A missing item is created from `initial` if given.
+    fn counter(
+        &self,
+        key: &str,
+        delta: u64,
+        increment: bool,
+        initial: Option<(u64, i64)>,
+    ) -> Result<Option<(u64, u64)>, &'static str> {
+        self.flush_if_due();
+        let mut cache = self.cache.write(key);
+        let version = match cache.version(key) {
+            Some(version) => version,
+            None => {
+                return match initial {
+                    Some((initial, exptime)) => {
+                        self.put(&mut cache, key, initial.to_string().into_bytes(), Some(exptime));
+                        Ok(Some((initial, self.tag(&cache, key, 0))))
+                    }
+                    None => Ok(None),
+                }
+            }
+        };
+        let current = cache
+            .get(key)
+            .map(value_bytes)
+            .and_then(|x| String::from_utf8(x).ok())
+            .and_then(|x| x.parse::<u64>().ok())
+            .ok_or("cannot increment or decrement non-numeric value")?;
+        let result = if increment {
+            current.wrapping_add(delta)
+        } else {
+            current.saturating_sub(delta)
+        };
+        let flags = self.flags_of(key, version);
+        self.put(&mut cache, key, result.to_string().into_bytes(), None);
+        Ok(Some((result, self.tag(&cache, key, flags))))
+    }
+
+    /// Deletes an item, only if it still has the CAS value `cas` unless that is 0.
+    fn delete(&self, key: &str, cas: u64) -> Outcome {
+        self.flush_if_due();
+        let mut cache = self.cache.write(key);
+        match cache.version(key) {
+            None => Outcome::NotFound,
+            Some(version) if cas != 0 && cas != version => Outcome::Exists,
+            Some(_) => {
+                cache.remove(key);
+                self.flags(key).remove(key);
+                Outcome::Stored
+            }
+        }
+    }
+
+    fn touch(&self, key: &str, exptime: i64) -> bool {
+        self.flush_if_due();
+        let mut cache = self.cache.write(key);
+        if !cache.contains(key) {
+            return false;
+        }
+        match expiry(exptime) {
+            Expiry::Never => {
+                cache.persist(key);
+            }
+            Expiry::In(ttl) => {
+                cache.expire(key, ttl);
+            }
+            Expiry::Past => {
+                cache.remove(key);
+            }
+        }
+        true
+    }
+
+    /// Drops every item, now or once `delay` has passed. The delay follows
+    /// the rules of expiry times, and replaces any flush still pending.
+    fn flush_all(&self, delay: i64) {
+        let wait = match expiry(delay) {
+            Expiry::In(wait) => wait,
+            _ => {
+                self.flush_at.store(NO_FLUSH, Ordering::SeqCst);
+                self.clear();
+                return;
+            }
+        };
+        let due = (self.started.elapsed() + wait).as_millis().min(NO_FLUSH as u128 - 1);
+        self.flush_at.store(due as u64, Ordering::SeqCst);
+    }
+
+    /// Runs the pending delayed flush once it is due.
+    fn flush_if_due(&self) {
+        let due = self.flush_at.load(Ordering::SeqCst);
+        if due == NO_FLUSH || (self.started.elapsed().as_millis() as u64) < due {
+            return;
+        }
+        // Only the command that takes the deadline away flushes.
+        if self
+            .flush_at
+            .compare_exchange(due, NO_FLUSH, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            self.clear();
+        }
+    }
+
+    fn clear(&self) {
+        self.cache.clear();
+        for table in self.flags.iter() {
+            table.lock().unwrap().clear();
+        }
+    }
+
+    fn stats(&self) -> Vec<(&'static str, String)> {
+        self.flush_if_due();
+        let cache = &self.cache;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+        vec![
+            ("pid", std::process::id().to_string()),
+            ("uptime", self.started.elapsed().as_secs().to_string()),
+            ("time", now.to_string()),
+            ("version", env!("CARGO_PKG_VERSION").to_string()),
+            ("pointer_size", (usize::BITS).to_string()),
+            (
+                "curr_connections",
+                stats::SERVER.active_connections.load(Ordering::SeqCst).to_string(),
+            ),
+            (
+                "total_connections",
+                stats::SERVER.total_connections.load(Ordering::SeqCst).to_string(),
+            ),
+            ("cmd_get", self.cmd_get.load(Ordering::SeqCst).to_string()),
+            ("cmd_set", self.cmd_set.load(Ordering::SeqCst).to_string()),
+            ("get_hits", self.get_hits.load(Ordering::SeqCst).to_string()),
+            ("get_misses", self.get_misses.load(Ordering::SeqCst).to_string()),
+            ("curr_items", cache.len().to_string()),
+            ("bytes", cache.used_memory().to_string()),
+            ("limit_maxbytes", cache.max_memory().unwrap_or(0).to_string()),
+            ("evictions", cache.evictions().to_string()),
+        ]
+    }
+}
+
+fn number<T: std::str::FromStr>(token: Option<&&[u8]>) -> Option<T> {
+    std::str::from_utf8(token?).ok()?.parse::<T>().ok()
+}
+
+/// Serves the text protocol until the client quits. Returns an error if
+/// the connection has to be dropped.
+fn serve_text<R: Read, W: Write>(memcached: &Memcached, reader: &mut BufReader<R>, writer: &mut W) -> Result<(), Error> {
+    let mut out = Vec::new();
+    loop {
+        let line = match parser::read_line(reader, MAX_LINE_SIZE)? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        stats::SERVER.total_requests.fetch_add(1, Ordering::SeqCst);
+        let tokens: Vec<&[u8]> = line
+            .split(|x| *x == b' ')
+            .filter(|x| !x.is_empty())
+            .collect();
+        let command = tokens
+            .first()
+            .map(|x| String::from_utf8_lossy(x).to_lowercase())
+            .unwrap_or_default();
+        let noreply = tokens.len() > 1 && tokens.last() == Some(&&b"noreply"[..]);
+        let args = if noreply { &tokens[1..tokens.len() - 1] } else { &tokens[1.min(tokens.len())..] };
+
+        if shutdown::is_requested() {
+            out.extend_from_slice(b"SERVER_ERROR shutting down\r\n");
+            writer.write_all(&out)?;
+            return Ok(());
+        }
+
+        let mut reply = Vec::new();
+        match command.as_str() {
+            "get" | "gets" => {
+                if args.is_empty() {
+                    reply.extend_from_slice(b"ERROR\r\n");
+                }
+                for key in args.iter().filter(|x| valid_key(x)) {
+                    let key = String::from_utf8_lossy(key);
+                    if let Some(item) = memcached.get(&key) {
+                        let head = if command == "gets" {
+                            format!("VALUE {} {} {} {}\r\n", key, item.flags, item.data.len(), item.cas)
+                        } else {
+                            format!("VALUE {} {} {}\r\n", key, item.flags, item.data.len())
+                        };
+                        reply.extend_from_slice(head.as_bytes());
+                        reply.extend_from_slice(&item.data);
+                        reply.extend_from_slice(b"\r\n");
+                    }
+                }
+                if !args.is_empty() {
+                    reply.extend_from_slice(b"END\r\n");
+                }
+            }
+            "set" | "add" | "replace" | "append" | "prepend" | "cas" => {
+                let expected = if command == "cas" { 5 } else { 4 };
+                let key = args.first().copied().unwrap_or_default();
+                let flags = number::<u32>(args.get(1));
+                let exptime = number::<i64>(args.get(2));
+                let length = number::<usize>(args.get(3));
+                let cas = number::<u64>(args.get(4));
+                let (flags, exptime, length) = match (flags, exptime, length) {
+                    (Some(flags), Some(exptime), Some(length))
+                        if args.len() == expected && valid_key(key) && (command != "cas" || cas.is_some()) =>
+                    {
+                        (flags, exptime, length)
+                    }
+                    _ => {
+                        // Without a valid length the data block can't be skipped.
+                        out.extend_from_slice(b"CLIENT_ERROR bad command line format\r\n");
+                        writer.write_all(&out)?;
+                        return Ok(());
+                    }
+                };
+                if length > MAX_ITEM_SIZE {
+                    // Skip the data block so the next command lines up again.
+                    std::io::copy(&mut reader.by_ref().take(length as u64 + 2), &mut std::io::sink())?;
+                    out.extend_from_slice(b"SERVER_ERROR object too large for cache\r\n");
+                    continue;
+                }
+                let mut data = Vec::new();
+                reader.by_ref().take(length as u64 + 2).read_to_end(&mut data)?;
+                if data.len() < length + 2 {
+                    return Err(Error::from(ErrorKind::UnexpectedEof));
+                }
+                if !data.ends_with(b"\r\n") {
+                    reply.extend_from_slice(b"CLIENT_ERROR bad data chunk\r\n");
+                } else {
+                    data.truncate(length);
+                    let mode = match command.as_str() {
+                        "set" => Mode::Set,
+                        "add" => Mode::Add,
+                        "replace" => Mode::Replace,
+                        "append" => Mode::Append,
+                        "prepend" => Mode::Prepend,
+                        _ => Mode::Cas(cas.unwrap_or(0)),
+                    };
+                    let key = String::from_utf8_lossy(key);
+                    let text: &[u8] = match memcached.store(mode, &key, flags, exptime, data) {
+                        Ok((Outcome::Stored, _)) => b"STORED\r\n",
+                        Ok((Outcome::NotStored, _)) => b"NOT_STORED\r\n",
+                        Ok((Outcome::Exists, _)) => b"EXISTS\r\n",
+                        Ok((Outcome::NotFound, _)) => b"NOT_FOUND\r\n",
+                        Err(err) => {
+                            reply.extend_from_slice(format!("SERVER_ERROR {}\r\n", err).as_bytes());
+                            b""
+                        }
+                    };
+                    reply.extend_from_slice(text);
+                }
+            }
+            "incr" | "decr" => match (args.first(), number::<u64>(args.get(1))) {
+                (Some(key), Some(delta)) if args.len() == 2 => {
+                    let key = String::from_utf8_lossy(key);
+                    match memcached.counter(&key, delta, command == "incr", None) {
+                        Ok(Some((value, _))) => reply.extend_from_slice(format!("{}\r\n", value).as_bytes()),
+                        Ok(None) => reply.extend_from_slice(b"NOT_FOUND\r\n"),
+                        Err(err) => reply.extend_from_slice(format!("CLIENT_ERROR {}\r\n", err).as_bytes()),
+                    }
+                }
+                _ => reply.extend_from_slice(b"CLIENT_ERROR invalid numeric delta argument\r\n"),
+            },
+            "delete" => match args {
+                [key] => {
+                    let key = String::from_utf8_lossy(key);
+                    match memcached.delete(&key, 0) {
+                        Outcome::NotFound => reply.extend_from_slice(b"NOT_FOUND\r\n"),
+                        _ => reply.extend_from_slice(b"DELETED\r\n"),
+                    }
+                }
+                _ => reply.extend_from_slice(b"CLIENT_ERROR bad command line format\r\n"),
+            },
+            "touch" => match (args.first(), number::<i64>(args.get(1))) {
+                (Some(key), Some(exptime)) if args.len() == 2 => {
+                    let key = String::from_utf8_lossy(key);
+                    if memcached.touch(&key, exptime) {
+                        reply.extend_from_slice(b"TOUCHED\r\n");
+                    } else {
+                        reply.extend_from_slice(b"NOT_FOUND\r\n");
+                    }
+                }
+                _ => reply.extend_from_slice(b"CLIENT_ERROR bad command line format\r\n"),
+            },
+            "flush_all" => match args {
+                [] => {
+                    memcached.flush_all(0);
+                    reply.extend_from_slice(b"OK\r\n");
+                }
+                [delay] => match number::<i64>(Some(delay)) {
+                    Some(delay) => {
+                        memcached.flush_all(delay);
+                        reply.extend_from_slice(b"OK\r\n");
+                    }
+                    None => reply.extend_from_slice(b"CLIENT_ERROR bad command line format\r\n"),
+                },
+                _ => reply.extend_from_slice(b"CLIENT_ERROR bad command line format\r\n"),
+            },
+            "stats" if args.is_empty() => {
+                for (name, value) in memcached.stats() {
+                    reply.extend_from_slice(format!("STAT {} {}\r\n", name, value).as_bytes());
+                }
+                reply.extend_from_slice(b"END\r\n");
+            }
+            "version" => {
+                reply.extend_from_slice(format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).as_bytes())
+            }
+            "verbosity" => reply.extend_from_slice(b"OK\r\n"),
+            "quit" => {
+                writer.write_all(&out)?;
+                return Ok(());
+            }
+            _ => reply.extend_from_slice(b"ERROR\r\n"),
+        }
+        // Errors are reported even with noreply, like memcached does.
+        let failed = reply.starts_with(b"CLIENT_ERROR") || reply.starts_with(b"SERVER_ERROR");
+        if !noreply || failed {
+            out.append(&mut reply);
+        }
+        if reader.buffer().is_empty() {
+            writer.write_all(&out)?;
+            out.clear();
+        }
+    }
+}
+
+// Binary protocol opcodes.
+const OP_GET: u8 = 0x00;
+const OP_SET: u8 = 0x01;
+const OP_ADD: u8 = 0x02;
+const OP_REPLACE: u8 = 0x03;
+const OP_DELETE: u8 = 0x04;
+const OP_INCREMENT: u8 = 0x05;
+const OP_DECREMENT: u8 = 0x06;
+const OP_QUIT: u8 = 0x07;
+const OP_FLUSH: u8 = 0x08;
+const OP_GETQ: u8 = 0x09;
+const OP_NOOP: u8 = 0x0a;
+const OP_VERSION: u8 = 0x0b;
+const OP_GETK: u8 = 0x0c;
+const OP_GETKQ: u8 = 0x0d;
+const OP_APPEND: u8 = 0x0e;
+const OP_PREPEND: u8 = 0x0f;
+const OP_STAT: u8 = 0x10;
+const OP_SETQ: u8 = 0x11;
+const OP_ADDQ: u8 = 0x12;
+const OP_REPLACEQ: u8 = 0x13;
+const OP_DELETEQ: u8 = 0x14;
+const OP_INCREMENTQ: u8 = 0x15;
+const OP_DECREMENTQ: u8 = 0x16;
+const OP_QUITQ: u8 = 0x17;
+const OP_FLUSHQ: u8 = 0x18;
+const OP_APPENDQ: u8 = 0x19;
+const OP_PREPENDQ: u8 = 0x1a;
+const OP_TOUCH: u8 = 0x1c;
+
+// Binary protocol status codes.
+const STATUS_OK: u16 = 0x00;
+const STATUS_NOT_FOUND: u16 = 0x01;
+const STATUS_EXISTS: u16 = 0x02;
+const STATUS_TOO_LARGE: u16 = 0x03;
+const STATUS_INVALID: u16 = 0x04;
+const STATUS_NOT_STORED: u16 = 0x05;
+const STATUS_NON_NUMERIC: u16 = 0x06;
+const STATUS_UNKNOWN: u16 = 0x81;
+
+const REQUEST_MAGIC: u8 = 0x80;
+const RESPONSE_MAGIC: u8 = 0x81;
+const HEADER_SIZE: usize = 24;
+
+/// The fixed 24 byte header in front of every binary packet.
+struct Packet {
+    opcode: u8,
+    opaque: u32,
+    cas: u64,
+    extras: Vec<u8>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+fn read_packet<R: Read>(reader: &mut R) -> Result<Option<Packet>, Error> {
+    let mut header = [0; HEADER_SIZE];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    if header[0] != REQUEST_MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid magic"));
+    }
+    let key_length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let extras_length = header[4] as usize;
+    let body_length = u32::from_be_bytes(header[8..12].try_into().unwrap()) as usize;
+    if body_length < key_length + extras_length || body_length > MAX_ITEM_SIZE + MAX_LINE_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid body length"));
+    }
+    let mut body = vec![0; body_length];
+    reader.read_exact(&mut body)?;
+    let value = body.split_off(extras_length + key_length);
+    let key = body.split_off(extras_length);
+    Ok(Some(Packet {
+        opcode: header[1],
+        opaque: u32::from_be_bytes(header[12..16].try_into().unwrap()),
+        cas: u64::from_be_bytes(header[16..24].try_into().unwrap()),
+        extras: body,
+        key,
+        value,
+    }))
+}
+
+fn write_packet(
+    out: &mut Vec<u8>,
+    request: &Packet,
+    status: u16,
+    cas: u64,
+    extras: &[u8],
+    key: &[u8],
+    value: &[u8],
+) {
+    out.push(RESPONSE_MAGIC);
+    out.push(request.opcode);
+    out.extend_from_slice(&(key.len() as u16).to_be_bytes());
+    out.push(extras.len() as u8);
+    out.push(0);
+    out.extend_from_slice(&status.to_be_bytes());
+    out.extend_from_slice(&((extras.len() + key.len() + value.len()) as u32).to_be_bytes());
+    out.extend_from_slice(&request.opaque.to_be_bytes());
+    out.extend_from_slice(&cas.to_be_bytes());
+    out.extend_from_slice(extras);
+    out.extend_from_slice(key);
+    out.extend_from_slice(value);
+}
+
+fn error_packet(out: &mut Vec<u8>, request: &Packet, status: u16, message: &str) {
+    write_packet(out, request, status, 0, &[], &[], message.as_bytes());
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes[..8].try_into().unwrap())
+}
+
+/// Serves the binary protocol. Quiet opcodes only answer on errors, and
+/// quiet gets only on hits.
+fn serve_binary<R: Read, W: Write>(memcached: &Memcached, reader: &mut BufReader<R>, writer: &mut W) -> Result<(), Error> {
+    let mut out = Vec::new();
+    loop {
+        let request = match read_packet(reader)? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        stats::SERVER.total_requests.fetch_add(1, Ordering::SeqCst);
+        if shutdown::is_requested() {
+            error_packet(&mut out, &request, STATUS_UNKNOWN, "Shutting down");
+            writer.write_all(&out)?;
+            return Ok(());
+        }
+        let key = String::from_utf8_lossy(&request.key).into_owned();
+        let quiet = matches!(
+            request.opcode,
+            OP_GETQ | OP_GETKQ | OP_SETQ | OP_ADDQ | OP_REPLACEQ | OP_DELETEQ | OP_INCREMENTQ
+                | OP_DECREMENTQ | OP_QUITQ | OP_FLUSHQ | OP_APPENDQ | OP_PREPENDQ
+        );
+        let mut reply = Vec::new();
+        let mut failed = false;
+
+        match request.opcode {
+            OP_GET | OP_GETQ | OP_GETK | OP_GETKQ => match memcached.get(&key) {
+                Some(item) => {
+                    let key = if matches!(request.opcode, OP_GETK | OP_GETKQ) { &request.key[..] } else { &[] };
+                    write_packet(&mut reply, &request, STATUS_OK, item.cas, &item.flags.to_be_bytes(), key, &item.data);
+                }
+                None => {
+                    failed = !quiet;
+                    let key = if request.opcode == OP_GETK { &request.key[..] } else { &[] };
+                    write_packet(&mut reply, &request, STATUS_NOT_FOUND, 0, &[], key, b"Not found");
+                }
+            },
+            OP_SET | OP_SETQ | OP_ADD | OP_ADDQ | OP_REPLACE | OP_REPLACEQ | OP_APPEND | OP_APPENDQ
+            | OP_PREPEND | OP_PREPENDQ => {
+                let appending = matches!(request.opcode, OP_APPEND | OP_APPENDQ | OP_PREPEND | OP_PREPENDQ);
+                let extras = if appending { 0 } else { 8 };
+                if request.extras.len() != extras || !valid_key(&request.key) {
+                    failed = true;
+                    error_packet(&mut reply, &request, STATUS_INVALID, "Invalid arguments");
+                } else if request.value.len() > MAX_ITEM_SIZE {
+                    failed = true;
+                    error_packet(&mut reply, &request, STATUS_TOO_LARGE, "Too large");
+                } else {
+                    let (flags, exptime) = if appending {
+                        (0, 0)
+                    } else {
+                        (be_u32(&request.extras), be_u32(&request.extras[4..]) as i64)
+                    };
+                    let mode = match request.opcode {
+                        _ if request.cas != 0 && !appending => Mode::Cas(request.cas),
+                        OP_SET | OP_SETQ => Mode::Set,
+                        OP_ADD | OP_ADDQ => Mode::Add,
+                        OP_REPLACE | OP_REPLACEQ => Mode::Replace,
+                        OP_APPEND | OP_APPENDQ => Mode::Append,
+                        _ => Mode::Prepend,
+                    };
+                    // A CAS on add makes no sense, memcached treats it as a plain add.
+                    let mode = match (request.opcode, mode) {
+                        (OP_ADD | OP_ADDQ, Mode::Cas(_)) => Mode::Add,
+                        (_, mode) => mode,
+                    };
+                    let status = match memcached.store(mode, &key, flags, exptime, request.value.clone()) {
+                        Ok((Outcome::Stored, cas)) => {
+                            write_packet(&mut reply, &request, STATUS_OK, cas, &[], &[], &[]);
+                            STATUS_OK
+                        }
+                        Ok((Outcome::Exists, _)) => STATUS_EXISTS,
+                        Ok((Outcome::NotFound, _)) => STATUS_NOT_FOUND,
+                        Ok((Outcome::NotStored, _)) if mode == Mode::Add => STATUS_EXISTS,
+                        Ok((Outcome::NotStored, _)) if appending => STATUS_NOT_STORED,
+                        Ok((Outcome::NotStored, _)) => STATUS_NOT_FOUND,
+                        Err(err) => {
+                            error_packet(&mut reply, &request, STATUS_NOT_STORED, err);
+                            STATUS_NOT_STORED
+                        }
+                    };
+                    if status != STATUS_OK {
+                        failed = true;
+                        if reply.is_empty() {
+                            let message = match status {
+                                STATUS_EXISTS => "Data exists for key",
+                                STATUS_NOT_FOUND => "Not found",
+                                _ => "Not stored",
+                            };
+                            error_packet(&mut reply, &request, status, message);
+                        }
+                    }
+                }
+            }
+            OP_DELETE | OP_DELETEQ => match memcached.delete(&key, request.cas) {
+                Outcome::Stored => write_packet(&mut reply, &request, STATUS_OK, 0, &[], &[], &[]),
+                Outcome::Exists => {
+                    failed = true;
+                    error_packet(&mut reply, &request, STATUS_EXISTS, "Data exists for key");
+                }
+                _ => {
+                    failed = true;
+                    error_packet(&mut reply, &request, STATUS_NOT_FOUND, "Not found");
+                }
+            },
+            OP_INCREMENT | OP_INCREMENTQ | OP_DECREMENT | OP_DECREMENTQ => {
+                if request.extras.len() != 20 || !valid_key(&request.key) {
+                    failed = true;
+                    error_packet(&mut reply, &request, STATUS_INVALID, "Invalid arguments");
+                } else {
+                    let delta = be_u64(&request.extras);
+                    let initial = be_u64(&request.extras[8..]);
+                    let exptime = be_u32(&request.extras[16..]);
+                    // An expiration of all ones means "don't create".
+                    let initial = if exptime == u32::MAX { None } else { Some((initial, exptime as i64)) };
+                    let increment = matches!(request.opcode, OP_INCREMENT | OP_INCREMENTQ);
+                    match memcached.counter(&key, delta, increment, initial) {
+                        Ok(Some((value, cas))) => {
+                            write_packet(&mut reply, &request, STATUS_OK, cas, &[], &[], &value.to_be_bytes())
+                        }
+                        Ok(None) => {
+                            failed = true;
+                            error_packet(&mut reply, &request, STATUS_NOT_FOUND, "Not found");
+                        }
+                        Err(err) => {
+                            failed = true;
+                            error_packet(&mut reply, &request, STATUS_NON_NUMERIC, err);
+                        }
+                    }
+                }
+            }
+            OP_TOUCH => {
+                if request.extras.len() != 4 {
+                    failed = true;
+                    error_packet(&mut reply, &request, STATUS_INVALID, "Invalid arguments");
+                } else if memcached.touch(&key, be_u32(&request.extras) as i64) {
+                    write_packet(&mut reply, &request, STATUS_OK, 0, &[], &[], &[]);
+                } else {
+                    failed = true;
+                    error_packet(&mut reply, &request, STATUS_NOT_FOUND, "Not found");
+                }
+            }
+            OP_FLUSH | OP_FLUSHQ => {
+                let delay = if request.extras.len() == 4 { be_u32(&request.extras) as i64 } else { 0 };
+                memcached.flush_all(delay);
+                write_packet(&mut reply, &request, STATUS_OK, 0, &[], &[], &[]);
+            }
+            OP_NOOP => write_packet(&mut reply, &request, STATUS_OK, 0, &[], &[], &[]),
+            OP_VERSION => write_packet(
+                &mut reply,
+                &request,
+                STATUS_OK,
+                0,
+                &[],
+                &[],
+                env!("CARGO_PKG_VERSION").as_bytes(),
+            ),
+            OP_STAT => {
+                for (name, value) in memcached.stats() {
+                    write_packet(&mut reply, &request, STATUS_OK, 0, &[], name.as_bytes(), value.as_bytes());
+                }
+                write_packet(&mut reply, &request, STATUS_OK, 0, &[], &[], &[]);
+            }
+            OP_QUIT | OP_QUITQ => {
+                if request.opcode == OP_QUIT {
+                    write_packet(&mut out, &request, STATUS_OK, 0, &[], &[], &[]);
+                }
+                writer.write_all(&out)?;
+                return Ok(());
+            }
+            _ => {
+                failed = true;
+                error_packet(&mut reply, &request, STATUS_UNKNOWN, "Unknown command");
+            }
+        }
+        if !quiet || failed {
+            out.append(&mut reply);
+        }
+        if reader.buffer().is_empty() {
+            writer.write_all(&out)?;
+            out.clear();
+        }
+    }
+}
+
+/// Turns a client away the way memcached does once it is out of connections.
+fn write_busy(stream: &mut Stream, _queued: usize) {
+    let _ = stream.write_all(b"ERROR Too many open connections\r\n");
+}
+
+/// Serves one client, picking the protocol from the first byte it sends.
+fn handle_connection(memcached: &Memcached, stream: Stream, idle_timeout: Duration) -> Result<(), Error> {
+    stream.set_read_timeout(Some(idle_timeout))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let binary = match reader.fill_buf()?.first() {
+        Some(byte) => *byte == REQUEST_MAGIC,
+        None => return Ok(()),
+    };
+    if binary {
+        serve_binary(memcached, &mut reader, &mut writer)
+    } else {
+        serve_text(memcached, &mut reader, &mut writer)
+    }
+}
+
+pub struct MemcachedServer {
+    port: String,
+    host: String,
+    unix_socket: Option<UnixSocket>,
+    tcp: bool,
+    tls: Option<Arc<TlsAcceptor>>,
+    workers: usize,
+    queue_size: usize,
+    idle_timeout: Duration,
+}
+
+impl MemcachedServer {
+    pub fn new(host: Option<&str>, port: Option<&str>) -> MemcachedServer {
+        MemcachedServer {
+            port: port.unwrap_or("11211").to_owned(),
+            host: host.unwrap_or("localhost").to_owned(),
+            unix_socket: None,
+            tcp: true,
+            tls: None,
+            workers: server::default_workers(),
+            queue_size: server::DEFAULT_QUEUE_SIZE,
+            idle_timeout: SESSION_IDLE_TIMEOUT,
+        }
+    }
+
+    /// Also accepts connections on this Unix socket.
+    pub fn set_unix_socket(&mut self, socket: Option<UnixSocket>) -> &mut MemcachedServer {
+        self.unix_socket = socket;
+        self
+    }
+
+    /// Whether to listen on the TCP port at all.
+    pub fn set_tcp(&mut self, enabled: bool) -> &mut MemcachedServer {
+        self.tcp = enabled;
+        self
+    }
+
+    /// Speaks TLS on the TCP port.
+    pub fn set_tls(&mut self, acceptor: Option<Arc<TlsAcceptor>>) -> &mut MemcachedServer {
+        self.tls = acceptor;
+        self
+    }
+
+    /// Number of worker threads serving connections and how many accepted
+    /// connections may wait for one, anything beyond that is turned away.
+    /// `None` keeps the current setting.
+    pub fn set_pool(&mut self, workers: Option<usize>, queue_size: Option<usize>) -> &mut MemcachedServer {
+        if let Some(workers) = workers {
+            self.workers = workers.max(1);
+        }
+        if let Some(queue_size) = queue_size {
+            self.queue_size = queue_size;
+        }
+        self
+    }
+
+    /// How long a client may sit idle before it is disconnected. `None`
+    /// keeps the current setting.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) -> &mut MemcachedServer {
+        if let Some(idle_timeout) = idle_timeout {
+            self.idle_timeout = idle_timeout;
+        }
+        self
+    }
+
+    /// Serves memcached clients on a pool of workers until a shutdown is
+    /// requested. The cache is flushed once the connections being served
+    /// are drained.
+    pub fn listen(&mut self, cache: ShardedCache) -> Result<&MemcachedServer, Error> {
+        let address = format!("{}:{}", &self.host, &self.port);
+        let listeners = Listener::bind_all(
+            self.tcp.then_some(address.as_str()),
+            self.unix_socket.as_ref(),
+            self.tls.as_ref(),
+        )
+        .expect("An Error occured while registering the Listener!");
+        let cache = HTTPServer::share_cache(cache);
+        let memcached = Memcached::new(Arc::clone(&cache));
+
+        let idle_timeout = self.idle_timeout;
+        let pool = ConnectionPool::new(self.workers, self.queue_size, move |stream| {
+            if let Err(err) = handle_connection(&memcached, stream, idle_timeout) {
+                if err.kind() == ErrorKind::InvalidData {
+                    eprintln!("Error occurred while interpreting the stream: {}", err);
+                }
+            }
+        });
+
+        println!(
+            "Now listening for memcached clients on {} ({} workers, queue of {})",
+            listener::describe(&listeners),
+            self.workers,
+            self.queue_size
+        );
+
+        pool.accept(&listeners, write_busy)?;
+
+        drop(listeners);
+        if let Some(reason) = shutdown::reason() {
+            println!("Shutting down: {}", reason);
+        }
+        pool.drain();
+        HTTPServer::flush(&cache);
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_memcached() -> Memcached {
+        Memcached::new(Arc::new(ShardedCache::in_memory(1)))
+    }
+
+    /// Feeds `input` to the text protocol and returns everything it answered.
+    fn text(memcached: &Memcached, input: &[u8]) -> Result<String, Error> {
+        let mut out = Vec::new();
+        serve_text(memcached, &mut BufReader::new(input), &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    fn request(opcode: u8, cas: u64, extras: &[u8], key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut packet = vec![REQUEST_MAGIC, opcode];
+        packet.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[extras.len() as u8, 0, 0, 0]);
+        packet.extend_from_slice(&((extras.len() + key.len() + value.len()) as u32).to_be_bytes());
+        packet.extend_from_slice(&u32::from(opcode).to_be_bytes());
+        packet.extend_from_slice(&cas.to_be_bytes());
+        packet.extend_from_slice(extras);
+        packet.extend_from_slice(key);
+        packet.extend_from_slice(value);
+        packet
+    }
+
+    /// A response split into opcode, status, opaque, cas, extras, key and value.
+    type Response = (u8, u16, u32, u64, Vec<u8>, Vec<u8>, Vec<u8>);
+
+    /// Feeds `input` to the binary protocol and splits what it answered.
+    fn binary(memcached: &Memcached, input: &[u8]) -> Result<Vec<Response>, Error> {
+        let mut out = Vec::new();
+        serve_binary(memcached, &mut BufReader::new(input), &mut out)?;
+        let mut responses = Vec::new();
+        let mut rest = &out[..];
+        while !rest.is_empty() {
+            assert_eq!(rest[0], RESPONSE_MAGIC);
+            let key_length = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            let extras_length = rest[4] as usize;
+            let body_length = be_u32(&rest[8..]) as usize;
+            let body = &rest[HEADER_SIZE..HEADER_SIZE + body_length];
+            responses.push((
+                rest[1],
+                u16::from_be_bytes([rest[6], rest[7]]),
+                be_u32(&rest[12..]),
+                be_u64(&rest[16..]),
+                body[..extras_length].to_vec(),
+                body[extras_length..extras_length + key_length].to_vec(),
+                body[extras_length + key_length..].to_vec(),
+            ));
+            rest = &rest[HEADER_SIZE + body_length..];
+        }
+        Ok(responses)
+    }
+
+    #[test]
+    fn text_storage_and_retrieval() {
+        let memcached = new_memcached();
+        assert_eq!(
+            text(
+                &memcached,
+                b"set a 5 0 4\r\nx\r\ny\r\nadd a 0 0 1\r\nz\r\nappend a 0 0 1\r\n!\r\nprepend b 0 0 1\r\n!\r\n\
+                  get a b\r\nset n 0 0 2 noreply\r\n10\r\nincr n 5\r\ndecr n 100\r\nincr a 1\r\nincr b 1\r\n\
+                  delete n\r\ndelete n\r\ntouch a 100\r\nverbosity 1\r\nbogus\r\nget\r\n",
+            )
+            .unwrap(),
+            "STORED\r\nNOT_STORED\r\nSTORED\r\nNOT_STORED\r\nVALUE a 5 5\r\nx\r\ny!\r\nEND\r\n\
+             15\r\n0\r\nCLIENT_ERROR cannot increment or decrement non-numeric value\r\nNOT_FOUND\r\n\
+             DELETED\r\nNOT_FOUND\r\nTOUCHED\r\nOK\r\nERROR\r\nERROR\r\n"
+        );
+    }
+
+    #[test]
+    fn text_cas_needs_the_current_value() {
+        let memcached = new_memcached();
+        let reply = text(&memcached, b"set a 0 0 1\r\n1\r\ngets a\r\n").unwrap();
+        let cas: u64 = reply.lines().nth(1).unwrap().rsplit(' ').next().unwrap().parse().unwrap();
+        let commands = format!(
+            "cas a 0 0 1 {}\r\n2\r\ncas a 0 0 1 {}\r\n3\r\ncas b 0 0 1 {}\r\n4\r\nget a\r\n",
+            cas, cas, cas
+        );
+        assert_eq!(
+            text(&memcached, commands.as_bytes()).unwrap(),
+            "STORED\r\nEXISTS\r\nNOT_FOUND\r\nVALUE a 0 1\r\n2\r\nEND\r\n"
+        );
+    }
+
+    #[test]
+    fn text_framing_errors() {
+        let memcached = new_memcached();
+        // A data block that doesn't end where the length says.
+        assert_eq!(
+            text(&memcached, b"set a 0 0 1\r\nxy\r\nget a\r\n").unwrap(),
+            "CLIENT_ERROR bad data chunk\r\nERROR\r\nEND\r\n"
+        );
+        // Too large items are skipped whole, the next command still lines up.
+        let mut input = format!("set a 0 0 {}\r\n", MAX_ITEM_SIZE + 1).into_bytes();
+        input.extend_from_slice(&vec![b'x'; MAX_ITEM_SIZE + 1]);
+        input.extend_from_slice(b"\r\nget a\r\n");
+        assert_eq!(
+            text(&memcached, &input).unwrap(),
+            "SERVER_ERROR object too large for cache\r\nEND\r\n"
+        );
+        // Without a length the rest of the stream can't be trusted.
+        assert_eq!(
+            text(&memcached, b"set a 0 0 x\r\nget a\r\n").unwrap(),
+            "CLIENT_ERROR bad command line format\r\n"
+        );
+        assert_eq!(
+            text(&memcached, b"set a 0 0 5\r\nab").unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+        // Errors are still reported with noreply.
+        assert_eq!(
+            text(&memcached, b"incr a x noreply\r\nquit\r\nget a\r\n").unwrap(),
+            "CLIENT_ERROR invalid numeric delta argument\r\n"
+        );
+    }
+
+    #[test]
+    fn delayed_flush_runs_once_due() {
+        let memcached = new_memcached();
+        assert_eq!(text(&memcached, b"set a 5 0 1\r\nx\r\nflush_all 100\r\n").unwrap(), "STORED\r\nOK\r\n");
+        assert_eq!(text(&memcached, b"get a\r\n").unwrap(), "VALUE a 5 1\r\nx\r\nEND\r\n");
+        let due = memcached.flush_at.load(Ordering::SeqCst);
+        assert!((99_000..=100_000).contains(&due), "{}", due);
+
+        // Pretend the delay passed.
+        memcached.flush_at.store(0, Ordering::SeqCst);
+        assert_eq!(text(&memcached, b"get a\r\n").unwrap(), "END\r\n");
+        assert_eq!(memcached.flush_at.load(Ordering::SeqCst), NO_FLUSH);
+        assert!(memcached.flags.iter().all(|table| table.lock().unwrap().is_empty()));
+    }
+
+    #[test]
+    fn flush_delays_follow_the_expiry_rules() {
+        let memcached = new_memcached();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        // Beyond 30 days the delay is a unix time, not seconds to wait.
+        memcached.flush_all(now as i64 + 60);
+        let due = memcached.flush_at.load(Ordering::SeqCst);
+        assert!((58_000..=60_000).contains(&due), "{}", due);
+
+        // An immediate flush cancels the pending one.
+        text(&memcached, b"flush_all\r\nset a 0 0 1\r\nx\r\n").unwrap();
+        assert_eq!(memcached.flush_at.load(Ordering::SeqCst), NO_FLUSH);
+        assert_eq!(text(&memcached, b"get a\r\n").unwrap(), "VALUE a 0 1\r\nx\r\nEND\r\n");
+
+        // So does a unix time that already passed.
+        memcached.flush_all(100);
+        memcached.flush_all(now as i64 - 60);
+        assert_eq!(memcached.flush_at.load(Ordering::SeqCst), NO_FLUSH);
+        assert_eq!(text(&memcached, b"get a\r\n").unwrap(), "END\r\n");
+    }
+
+    #[test]
+    fn binary_storage_and_retrieval() {
+        let memcached = new_memcached();
+        let set_extras = [5u32.to_be_bytes(), 0u32.to_be_bytes()].concat();
+        let mut counter_extras = 2u64.to_be_bytes().to_vec();
+        counter_extras.extend_from_slice(&10u64.to_be_bytes());
+        counter_extras.extend_from_slice(&0u32.to_be_bytes());
+        let input = [
+            request(OP_SET, 0, &set_extras, b"a", b"xy"),
+            request(OP_GETQ, 0, &[], b"missing", &[]),
+            request(OP_GETK, 0, &[], b"a", &[]),
+            request(OP_ADD, 0, &set_extras, b"a", b"z"),
+            request(OP_INCREMENT, 0, &counter_extras, b"n", &[]),
+            request(OP_INCREMENT, 0, &counter_extras, b"n", &[]),
+            request(OP_SETQ, 0, &[], b"a", b"z"),
+            request(0x7f, 0, &[], &[], &[]),
+            request(OP_NOOP, 0, &[], &[], &[]),
+        ]
+        .concat();
+        let responses = binary(&memcached, &input).unwrap();
+        let summary: Vec<_> = responses
+            .iter()
+            .map(|(opcode, status, opaque, _, extras, key, value)| {
+                assert_eq!(u32::from(*opcode), *opaque);
+                (*opcode, *status, extras.clone(), key.clone(), value.clone())
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (OP_SET, STATUS_OK, vec![], vec![], vec![]),
+                (OP_GETK, STATUS_OK, 5u32.to_be_bytes().to_vec(), b"a".to_vec(), b"xy".to_vec()),
+                (OP_ADD, STATUS_EXISTS, vec![], vec![], b"Data exists for key".to_vec()),
+                (OP_INCREMENT, STATUS_OK, vec![], vec![], 10u64.to_be_bytes().to_vec()),
+                (OP_INCREMENT, STATUS_OK, vec![], vec![], 12u64.to_be_bytes().to_vec()),
+                (OP_SETQ, STATUS_INVALID, vec![], vec![], b"Invalid arguments".to_vec()),
+                (0x7f, STATUS_UNKNOWN, vec![], vec![], b"Unknown command".to_vec()),
+                (OP_NOOP, STATUS_OK, vec![], vec![], vec![]),
+            ]
+        );
+        // The CAS value of the set is the one get hands out.
+        assert_eq!(responses[0].3, responses[1].3);
+    }
+
+    #[test]
+    fn binary_cas_and_delete() {
+        let memcached = new_memcached();
+        let extras = [0u32.to_be_bytes(), 0u32.to_be_bytes()].concat();
+        let stored = binary(&memcached, &request(OP_SET, 0, &extras, b"a", b"1")).unwrap();
+        let cas = stored[0].3;
+        let input = [
+            request(OP_SET, cas + 1, &extras, b"a", b"2"),
+            request(OP_DELETE, cas + 1, &[], b"a", &[]),
+            request(OP_SET, cas, &extras, b"a", b"3"),
+            request(OP_DELETEQ, 0, &[], b"a", &[]),
+            request(OP_DELETE, 0, &[], b"a", &[]),
+        ]
+        .concat();
+        let statuses: Vec<_> = binary(&memcached, &input)
+            .unwrap()
+            .iter()
+            .map(|response| (response.0, response.1))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (OP_SET, STATUS_EXISTS),
+                (OP_DELETE, STATUS_EXISTS),
+                (OP_SET, STATUS_OK),
+                (OP_DELETE, STATUS_NOT_FOUND),
+            ]
+        );
+    }
+
+    #[test]
+    fn binary_framing_errors() {
+        let memcached = new_memcached();
+        let mut packet = request(OP_NOOP, 0, &[], &[], &[]);
+        packet[0] = RESPONSE_MAGIC;
+        assert_eq!(binary(&memcached, &packet).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        // A body shorter than its key.
+        let mut packet = request(OP_GET, 0, &[], b"abc", &[]);
+        packet[8..12].copy_from_slice(&2u32.to_be_bytes());
+        assert_eq!(binary(&memcached, &packet).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let mut packet = request(OP_SET, 0, &[0; 8], b"a", &[]);
+        packet[8..12].copy_from_slice(&((MAX_ITEM_SIZE + MAX_LINE_SIZE + 1) as u32).to_be_bytes());
+        assert_eq!(binary(&memcached, &packet).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let packet = request(OP_SET, 0, &[0; 8], b"a", b"value");
+        assert_eq!(
+            binary(&memcached, &packet[..packet.len() - 1]).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+        // A torn header is taken as the client going away.
+        assert!(binary(&memcached, &packet[..HEADER_SIZE - 1]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,415 @@
+use std::{
+    fmt::Display,
+    io::{BufRead, ErrorKind, Read},
+};
+
+use crate::server::Header;
+
+/// Size limits applied while reading a request.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Request line, headers and chunked trailers together, in bytes.
+    pub max_header_size: usize,
+    /// Decoded body, in bytes.
+    pub max_body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_header_size: 8 * 1024,
+            max_body_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    /// The connection was closed before a new request started.
+    Closed,
+    Io(std::io::Error),
+    BadRequest(String),
+    PayloadTooLarge,
+    HeadersTooLarge,
+    /// The input ended in the middle of a request. Carries the total number
+    /// of bytes the request needs when the head already told us.
+    Incomplete(Option<usize>),
+}
+
+impl ParseError {
+    /// Status code to answer with, `None` if the connection is gone.
+    pub fn status(&self) -> Option<u64> {
+        match self {
+            ParseError::Closed | ParseError::Io(_) => None,
+            ParseError::BadRequest(_) | ParseError::Incomplete(_) => Some(400),
+            ParseError::PayloadTooLarge => Some(413),
+            ParseError::HeadersTooLarge => Some(431),
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Closed => write!(f, "Connection closed."),
+            ParseError::Io(err) => write!(f, "{}", err),
+            ParseError::BadRequest(message) => write!(f, "Bad request: {}", message),
+            ParseError::PayloadTooLarge => write!(f, "Request body exceeds the size limit."),
+            ParseError::HeadersTooLarge => write!(f, "Request headers exceed the size limit."),
+            ParseError::Incomplete(_) => write!(f, "Bad request: Unexpected end of request."),
+        }
+    }
+}
+
+impl From<std::io::Error> for ParseError {
+    fn from(err: std::io::Error) -> ParseError {
+        match err.kind() {
+            ErrorKind::UnexpectedEof => ParseError::Incomplete(None),
+            _ => ParseError::Io(err),
+        }
+    }
+}
+
+fn bad(message: &str) -> ParseError {
+    ParseError::BadRequest(message.to_string())
+}
+
+/// A request as read off the wire, before it is tied to a connection.
+pub struct RawRequest {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<Header>,
+    pub body: Vec<u8>,
+}
+
+impl RawRequest {
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|x| x.key.eq_ignore_ascii_case(key))
+            .map(|x| x.value.as_str())
+    }
+}
+
+/// Longest line the line based protocols accept, RESP inline commands and
+/// memcached's text commands, line ending included.
+pub const MAX_LINE_SIZE: usize = 64 * 1024;
+
+/// Reads one line terminated by `\n` of at most `limit` bytes, line ending
+/// included. Returns the line without its line ending and the number of
+/// bytes it took, or `None` on a clean EOF.
+fn take_line<R: BufRead>(reader: &mut R, limit: usize) -> std::io::Result<Option<(Vec<u8>, usize)>> {
+    let mut line = Vec::new();
+    let read = reader
+        .by_ref()
+        .take(limit as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read > limit {
+        return Err(std::io::Error::new(ErrorKind::InvalidData, "line too long"));
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(std::io::Error::from(ErrorKind::UnexpectedEof));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some((line, read)))
+}
+
+/// Reads one line of a line based protocol, see `take_line`. A line over
+/// `limit` is an `InvalidData` error, one cut short by EOF `UnexpectedEof`.
+pub fn read_line<R: BufRead>(reader: &mut R, limit: usize) -> std::io::Result<Option<Vec<u8>>> {
+    Ok(take_line(reader, limit)?.map(|(line, _)| line))
+}
+
+/// Reads one line of a request head, charging its length against `budget`.
+fn read_head_line<R: BufRead>(reader: &mut R, budget: &mut usize) -> Result<Option<Vec<u8>>, ParseError> {
+    match take_line(reader, *budget) {
+        Ok(Some((line, read))) => {
+            *budget -= read;
+            Ok(Some(line))
+        }
+        Ok(None) => Ok(None),
+        Err(err) if err.kind() == ErrorKind::InvalidData => Err(ParseError::HeadersTooLarge),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|x| x.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&x))
+}
+
+/// Reads a complete request: request line, headers and a body delimited by
+/// `Content-Length` or chunked transfer encoding.
+pub fn read_request<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<RawRequest, ParseError> {
+    read_request_inner(reader, limits).map_err(|err| match err {
+        ParseError::Incomplete(_) => bad("Unexpected end of request."),
+        err => err,
+    })
+}
+
+/// Parses one request from the front of `buffer` without blocking and
+/// returns it along with the number of bytes it took up. Fails with
+/// `Incomplete` if more input is needed, or `Closed` if the buffer holds
+/// nothing but blank lines.
+pub fn parse_request(buffer: &[u8], limits: &Limits) -> Result<(RawRequest, usize), ParseError> {
+    let mut cursor = std::io::Cursor::new(buffer);
+    let request = read_request_inner(&mut cursor, limits)?;
+    Ok((request, cursor.position() as usize))
+}
+
+fn read_request_inner<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<RawRequest, ParseError> {
+    let mut budget = limits.max_header_size;
+
+    // Ignore empty lines in front of the request line, as RFC 9112 suggests.
+    let request_line = loop {
+        match read_head_line(reader, &mut budget)? {
+            None => return Err(ParseError::Closed),
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+        }
+    };
+    let request_line = String::from_utf8(request_line).map_err(|_| bad("Request line is not valid UTF-8."))?;
+    let parts: Vec<&str> = request_line.split(' ').collect();
+    if parts.len() != 3 || !is_token(parts[0]) || parts[1].is_empty() {
+        return Err(bad("Malformed request line."));
+    }
+    if !parts[2].starts_with("HTTP/1.") {
+        return Err(bad("Unsupported HTTP version."));
+    }
+
+    let headers = read_headers(reader, &mut budget)?;
+    let mut request = RawRequest {
+        method: parts[0].to_string(),
+        target: parts[1].to_string(),
+        version: parts[2].to_string(),
+        headers,
+        body: Vec::new(),
+    };
+
+    let transfer_encoding = request.header("Transfer-Encoding").map(|x| x.to_lowercase());
+    let content_lengths: Vec<&str> = request
+        .headers
+        .iter()
+        .filter(|x| x.key.eq_ignore_ascii_case("Content-Length"))
+        .map(|x| x.value.as_str())
+        .collect();
+
+    if let Some(encoding) = transfer_encoding {
+        // Both framings at once is a classic request smuggling vector.
+        if !content_lengths.is_empty() {
+            return Err(bad("Both Transfer-Encoding and Content-Length given."));
+        }
+        if encoding.rsplit(',').next().map(|x| x.trim()) != Some("chunked") {
+            return Err(bad("Unsupported transfer encoding."));
+        }
+        let (body, trailers) = read_chunked(reader, limits, &mut budget)?;
+        request.body = body;
+        request.headers.extend(trailers);
+    } else if let Some(first) = content_lengths.first() {
+        if content_lengths.iter().any(|x| x != first) {
+            return Err(bad("Conflicting Content-Length headers."));
+        }
+        if !first.bytes().all(|x| x.is_ascii_digit()) {
+            return Err(bad("Invalid Content-Length."));
+        }
+        let length: usize = first.parse().map_err(|_| ParseError::PayloadTooLarge)?;
+        if length > limits.max_body_size {
+            return Err(ParseError::PayloadTooLarge);
+        }
+        let mut body = vec![0; length];
+        if let Err(err) = reader.read_exact(&mut body) {
+            return Err(match err.kind() {
+                ErrorKind::UnexpectedEof => {
+                    ParseError::Incomplete(Some(limits.max_header_size - budget + length))
+                }
+                _ => ParseError::Io(err),
+            });
+        }
+        request.body = body;
+    }
+
+    Ok(request)
+}
+
+fn read_headers<R: BufRead>(reader: &mut R, budget: &mut usize) -> Result<Vec<Header>, ParseError> {
+    let mut headers = Vec::new();
+    loop {
+        let line = read_head_line(reader, budget)?.ok_or(ParseError::Incomplete(None))?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        if line[0] == b' ' || line[0] == b'\t' {
+            return Err(bad("Obsolete header line folding is not supported."));
+        }
+        let line = String::from_utf8_lossy(&line);
+        let (key, value) = line.split_once(':').ok_or_else(|| bad("Header without colon."))?;
+        if !is_token(key) {
+            return Err(bad("Invalid header name."));
+        }
+        headers.push(Header::new(key, value));
+    }
+}
+
+fn read_chunked<R: BufRead>(
+    reader: &mut R,
+    limits: &Limits,
+    budget: &mut usize,
+) -> Result<(Vec<u8>, Vec<Header>), ParseError> {
+    let mut body = Vec::new();
+    loop {
+        // Chunk size lines count against the header budget so a client can't
+        // send endless chunk extensions.
+        let line = read_head_line(reader, budget)?.ok_or(ParseError::Incomplete(None))?;
+        let line = String::from_utf8_lossy(&line);
+        let size = line.split(';').next().unwrap_or("").trim();
+        if size.is_empty() || !size.bytes().all(|x| x.is_ascii_hexdigit()) {
+            return Err(bad("Invalid chunk size."));
+        }
+        let size = usize::from_str_radix(size, 16).map_err(|_| ParseError::PayloadTooLarge)?;
+        if size == 0 {
+            let trailers = read_headers(reader, budget)?;
+            return Ok((body, trailers));
+        }
+        if body.len() + size > limits.max_body_size {
+            return Err(ParseError::PayloadTooLarge);
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf)?;
+        if &crlf != b"\r\n" {
+            return Err(bad("Chunk not terminated by CRLF."));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_lines_with_either_line_ending() {
+        let mut input: &[u8] = b"one\r\ntwo\nthree";
+        assert_eq!(read_line(&mut input, MAX_LINE_SIZE).unwrap().unwrap(), b"one");
+        assert_eq!(read_line(&mut input, MAX_LINE_SIZE).unwrap().unwrap(), b"two");
+        assert_eq!(read_line(&mut input, MAX_LINE_SIZE).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert!(read_line(&mut input, MAX_LINE_SIZE).unwrap().is_none());
+    }
+
+    #[test]
+    fn limits_line_length_including_line_ending() {
+        let mut input: &[u8] = b"abc\r\n";
+        assert_eq!(read_line(&mut input, 5).unwrap().unwrap(), b"abc");
+        let mut input: &[u8] = b"abcd\r\n";
+        assert_eq!(read_line(&mut input, 5).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    fn read(input: &[u8]) -> Result<RawRequest, ParseError> {
+        read_request(&mut &input[..], &Limits::default())
+    }
+
+    fn status(input: &[u8], limits: &Limits) -> Option<u64> {
+        read_request(&mut &input[..], limits).err().and_then(|err| err.status())
+    }
+
+    #[test]
+    fn reads_request_with_content_length() {
+        let request = read(b"\r\nPOST /keys/a HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.target, "/keys/a");
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.header("host"), Some("x"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn reads_chunked_body_and_trailers() {
+        let request = read(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Checksum: 1\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(request.body, b"hello world");
+        assert_eq!(request.header("X-Checksum"), Some("1"));
+    }
+
+    #[test]
+    fn rejects_malformed_requests_with_400() {
+        let limits = Limits::default();
+        for input in [
+            &b"GET /\r\n\r\n"[..],
+            b"GET / SPDY/3\r\n\r\n",
+            b"GET / HTTP/1.1\r\nNo colon\r\n\r\n",
+            b"GET / HTTP/1.1\r\n folded\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab",
+            b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 1\r\n\r\n0\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nz\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nab\r\n0\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort",
+        ] {
+            assert_eq!(status(input, &limits), Some(400), "{}", String::from_utf8_lossy(input));
+        }
+    }
+
+    #[test]
+    fn enforces_body_limit_with_413() {
+        let limits = Limits {
+            max_header_size: 1024,
+            max_body_size: 4,
+        };
+        assert_eq!(status(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello", &limits), Some(413));
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n", &limits),
+            Some(413)
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n", &limits),
+            Some(413)
+        );
+    }
+
+    #[test]
+    fn enforces_header_limit_with_431() {
+        let limits = Limits {
+            max_header_size: 64,
+            max_body_size: 1024,
+        };
+        let mut input = b"GET / HTTP/1.1\r\nX-Long: ".to_vec();
+        input.extend_from_slice(&[b'a'; 64]);
+        input.extend_from_slice(b"\r\n\r\n");
+        assert_eq!(status(&input, &limits), Some(431));
+        // Chunk size lines count against the same budget.
+        let mut input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        for _ in 0..10 {
+            input.extend_from_slice(b"1;ext\r\na\r\n");
+        }
+        assert_eq!(status(&input, &limits), Some(431));
+    }
+
+    #[test]
+    fn parses_pipelined_requests_from_a_buffer() {
+        let buffer = b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nContent-Length: 3\r\n\r\nab";
+        let limits = Limits::default();
+        let (first, used) = parse_request(buffer, &limits).unwrap();
+        assert_eq!(first.target, "/a");
+        // The second one is missing a byte, and says how many it needs.
+        match parse_request(&buffer[used..], &limits) {
+            Err(ParseError::Incomplete(Some(needed))) => assert_eq!(needed, buffer.len() - used + 1),
+            _ => panic!("expected an incomplete request"),
+        }
+        assert!(matches!(parse_request(b"\r\n", &limits), Err(ParseError::Closed)));
+    }
+
+}
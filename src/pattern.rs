@@ -0,0 +1,90 @@
+/// Redis style glob matching: `*` matches any run of characters, `?` any
+/// single one, `[abc]`, `[a-z]` and `[^a]` character classes, and `\`
+/// escapes the next character.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches_from(&pattern, &text)
+}
+
+fn matches_from(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last `*` if the rest doesn't match.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match_class(&pattern[p..], text[t]),
+            Some('\\') if p + 1 < pattern.len() => {
+                if pattern[p + 1] == text[t] {
+                    Some(2)
+                } else {
+                    None
+                }
+            }
+            Some(c) if *c == text[t] => Some(1),
+            _ => None,
+        };
+        match step {
+            Some(width) => {
+                p += width;
+                t += 1;
+            }
+            None => match backtrack {
+                Some((star, start)) => {
+                    // Let the `*` swallow one more character and retry.
+                    p = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+/// Matches `c` against the class at the start of `pattern` and returns the
+/// width of the class if it matched. An unterminated `[` is taken literally.
+fn match_class(pattern: &[char], c: char) -> Option<usize> {
+    let end = match pattern.iter().skip(1).position(|x| *x == ']') {
+        Some(position) => position + 1,
+        None => return if c == '[' { Some(1) } else { None },
+    };
+    let mut class = &pattern[1..end];
+    let negated = class.first() == Some(&'^');
+    if negated {
+        class = &class[1..];
+    }
+
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if class[i] == '\\' && i + 1 < class.len() {
+            found |= class[i + 1] == c;
+            i += 2;
+        } else if i + 2 < class.len() && class[i + 1] == '-' {
+            let (low, high) = if class[i] <= class[i + 2] {
+                (class[i], class[i + 2])
+            } else {
+                (class[i + 2], class[i])
+            };
+            found |= low <= c && c <= high;
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+    if found != negated {
+        Some(end + 1)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+/// Fixed number of worker threads fed through a bounded queue. Items that
+/// don't fit into the queue are handed back to the caller instead of
+/// blocking, so it can turn them away.
+pub struct WorkerPool<T: Send + 'static> {
+    sender: Option<SyncSender<T>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+#[allow(dead_code)]
+impl<T: Send + 'static> WorkerPool<T> {
+    pub fn new(size: usize, queue_size: usize, handler: Arc<dyn Fn(T) + Send + Sync>) -> WorkerPool<T> {
+        let (sender, receiver) = mpsc::sync_channel::<T>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size.max(1))
+            .map(|id| {
+                let receiver = Arc::clone(&receiver);
+                let handler = Arc::clone(&handler);
+                std::thread::Builder::new()
+                    .name(format!("worker-{}", id))
+                    .spawn(move || WorkerPool::work(&receiver, handler.as_ref()))
+                    .expect("An Error occured while spawning a worker thread!")
+            })
+            .collect();
+
+        WorkerPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    fn work(receiver: &Mutex<Receiver<T>>, handler: &(dyn Fn(T) + Send + Sync)) {
+        loop {
+            // The lock guard is a temporary, so it is released before the
+            // item is handled and other workers can pick up the next one.
+            let item = match receiver.lock().unwrap().recv() {
+                Ok(item) => item,
+                Err(_) => return,
+            };
+            // The panic hook reports a panicking handler, the worker itself
+            // lives on to serve the connections still queued.
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| handler(item)));
+        }
+    }
+
+    /// Queues an item, or returns it if every worker is busy and the queue is full.
+    pub fn try_submit(&self, item: T) -> Result<(), T> {
+        match self.sender.as_ref().unwrap().try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item)) | Err(TrySendError::Disconnected(item)) => Err(item),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Stops accepting items and waits for the workers to finish what they have.
+    pub fn join(mut self) {
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl<T: Send + 'static> Drop for WorkerPool<T> {
+    fn drop(&mut self) {
+        // Closing the channel lets every worker leave its loop once it is
+        // done. Workers still busy are left running rather than waited on.
+        self.sender.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn worker_survives_a_panicking_handler() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&handled);
+        let pool: WorkerPool<usize> = WorkerPool::new(
+            1,
+            4,
+            Arc::new(move |item| {
+                if item == 0 {
+                    panic!("handler failed");
+                }
+                counter.fetch_add(item, Ordering::SeqCst);
+            }),
+        );
+        for item in 0..3 {
+            pool.try_submit(item).unwrap();
+        }
+        pool.join();
+        assert_eq!(handled.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn full_queue_hands_items_back() {
+        let (started, busy) = mpsc::channel::<()>();
+        let (release, wait) = mpsc::channel::<()>();
+        let (started, wait) = (Mutex::new(started), Mutex::new(wait));
+        let pool: WorkerPool<usize> = WorkerPool::new(
+            1,
+            1,
+            Arc::new(move |_| {
+                let _ = started.lock().unwrap().send(());
+                let _ = wait.lock().unwrap().recv();
+            }),
+        );
+        pool.try_submit(1).unwrap();
+        busy.recv().unwrap();
+        pool.try_submit(2).unwrap();
+        assert_eq!(pool.try_submit(3), Err(3));
+        drop(release);
+        pool.join();
+    }
+}
@@ -0,0 +1,974 @@
+use std::{
+    io::{BufRead, BufReader, Error, ErrorKind, Read, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::auth::{Auth, User};
+use crate::cache::{Cache, CacheError, CacheValue};
+use crate::listener::{self, Listener, Stream};
+use crate::parser;
+use crate::pattern;
+use crate::server::{ConnectionPool, SESSION_IDLE_TIMEOUT};
+use crate::sharded::ShardedCache;
+use crate::shutdown;
+use crate::stats;
+
+// Limits on what a client may send, the same as redis uses.
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+const MAX_BULK_SIZE: usize = 512 * 1024 * 1024;
+// Expiries further out than this are rejected, `Instant` can't hold much more.
+const MAX_TTL: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+static CLIENT_IDS: AtomicU64 = AtomicU64::new(1);
+
+/// A reply in the RESP wire format. Types only RESP3 knows fall back to
+/// their closest RESP2 equivalent for clients that didn't ask for it.
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    fn ok() -> Reply {
+        Reply::Simple(String::from("OK"))
+    }
+
+    fn error(message: &str) -> Reply {
+        Reply::Error(format!("ERR {}", message))
+    }
+
+    fn bulk(text: &str) -> Reply {
+        Reply::Bulk(text.as_bytes().to_vec())
+    }
+
+    fn bulk_or_null(value: Option<Vec<u8>>) -> Reply {
+        value.map(Reply::Bulk).unwrap_or(Reply::Null)
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>, protocol: u8) {
+        match self {
+            Reply::Simple(text) => out.extend_from_slice(format!("+{}\r\n", text).as_bytes()),
+            Reply::Error(text) => out.extend_from_slice(format!("-{}\r\n", text).as_bytes()),
+            Reply::Integer(value) => out.extend_from_slice(format!(":{}\r\n", value).as_bytes()),
+            Reply::Bulk(data) => {
+                out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+                out.extend_from_slice(data);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Null if protocol >= 3 => out.extend_from_slice(b"_\r\n"),
+            Reply::Null => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.write(out, protocol);
+                }
+            }
+            Reply::Map(pairs) => {
+                if protocol >= 3 {
+                    out.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes());
+                } else {
+                    out.extend_from_slice(format!("*{}\r\n", pairs.len() * 2).as_bytes());
+                }
+                for (key, value) in pairs {
+                    key.write(out, protocol);
+                    value.write(out, protocol);
+                }
+            }
+        }
+    }
+}
+
+fn protocol_error(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn parse_length(digits: &[u8], max: usize, what: &str) -> Result<usize, Error> {
+    let length = std::str::from_utf8(digits)
+        .ok()
+        .and_then(|x| x.parse::<i64>().ok())
+        .ok_or_else(|| protocol_error(&format!("invalid {} length", what)))?;
+    if length > max as i64 {
+        return Err(protocol_error(&format!("invalid {} length", what)));
+    }
+    // Negative lengths mean null, which carries no arguments either.
+    Ok(length.max(0) as usize)
+}
+
+/// Reads one command, sent either as an array of bulk strings or inline as
+/// space separated words the way telnet users type them. `None` once the
+/// client closed the connection.
+pub fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>, Error> {
+    loop {
+        let line = match parser::read_line(reader, parser::MAX_LINE_SIZE)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.first() == Some(&b'*') {
+            let count = parse_length(&line[1..], MAX_ARGUMENTS, "multibulk")?;
+            let mut args = Vec::with_capacity(count.min(1024));
+            for _ in 0..count {
+                let header = parser::read_line(reader, parser::MAX_LINE_SIZE)?
+                    .ok_or_else(|| Error::from(ErrorKind::UnexpectedEof))?;
+                if header.first() != Some(&b'$') {
+                    return Err(protocol_error("expected '$'"));
+                }
+                let length = parse_length(&header[1..], MAX_BULK_SIZE, "bulk")?;
+                // Grows with what actually arrives instead of trusting the length.
+                let mut data = Vec::new();
+                reader.by_ref().take(length as u64 + 2).read_to_end(&mut data)?;
+                if data.len() < length + 2 {
+                    return Err(Error::from(ErrorKind::UnexpectedEof));
+                }
+                if !data.ends_with(b"\r\n") {
+                    return Err(protocol_error("bulk not terminated by CRLF"));
+                }
+                data.truncate(length);
+                args.push(data);
+            }
+            if !args.is_empty() {
+                return Ok(Some(args));
+            }
+            continue;
+        }
+        let args: Vec<Vec<u8>> = line
+            .split(|x| x.is_ascii_whitespace())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_vec())
+            .collect();
+        if !args.is_empty() {
+            return Ok(Some(args));
+        }
+    }
+}
+
+/// Per connection state.
+struct Session {
+    id: u64,
+    protocol: u8,
+    name: Option<Vec<u8>>,
+    /// Who the client logged in as, if auth is enabled.
+    user: Option<Arc<User>>,
+}
+
+/// Serves RESP clients on `listeners` with `pool`, next to whatever else
+/// uses the cache. The thread returns once a shutdown was requested and
+/// the connections being served are drained.
+pub fn spawn_listener(listeners: Vec<Listener>, pool: ConnectionPool) -> Result<JoinHandle<()>, Error> {
+    println!(
+        "Now listening for RESP clients on {} ({} workers)",
+        listener::describe(&listeners),
+        pool.size()
+    );
+    std::thread::Builder::new()
+        .name(String::from("resp-listener"))
+        .spawn(move || {
+            if let Err(err) = pool.accept(&listeners, write_busy) {
+                eprintln!("Error occurred while accepting RESP clients: {}", err);
+            }
+            drop(listeners);
+            pool.drain();
+        })
+}
+
+/// Turns a client away the way redis does once it hit `maxclients`.
+fn write_busy(stream: &mut Stream, _queued: usize) {
+    let _ = stream.write_all(b"-ERR max number of clients reached\r\n");
+}
+
+/// Serves commands on one connection until the client quits, stays idle
+/// for `SESSION_IDLE_TIMEOUT` or a shutdown is requested. With `auth`
+/// the client has to log in as one of its users first and is held to
+/// that user's ACL.
+pub fn handle_connection(stream: Stream, cache: &ShardedCache, auth: Option<&Auth>) {
+    if let Err(err) = stream.set_read_timeout(Some(SESSION_IDLE_TIMEOUT)) {
+        eprintln!("Error occurred while configuring the connection: {}", err);
+        return;
+    }
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(err) => {
+            eprintln!("Error occurred while configuring the connection: {}", err);
+            return;
+        }
+    };
+    let mut reader = BufReader::new(stream);
+    let mut session = Session {
+        id: CLIENT_IDS.fetch_add(1, Ordering::SeqCst),
+        protocol: 2,
+        name: None,
+        user: None,
+    };
+    let mut out = Vec::new();
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return,
+            Err(err) => {
+                if err.kind() == ErrorKind::InvalidData {
+                    Reply::error(&format!("Protocol error: {}", err)).write(&mut out, session.protocol);
+                    let _ = writer.write_all(&out);
+                }
+                return;
+            }
+        };
+        stats::SERVER.total_requests.fetch_add(1, Ordering::SeqCst);
+
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let closing = quit || shutdown::is_requested();
+        let reply = if shutdown::is_requested() {
+            Reply::error("server is shutting down")
+        } else {
+            execute(&args, cache, auth, &mut session)
+        };
+        reply.write(&mut out, session.protocol);
+
+        // Pipelined commands are answered in one write once the batch
+        // that is already buffered has been worked through.
+        if closing || reader.buffer().is_empty() {
+            if let Err(err) = writer.write_all(&out) {
+                eprintln!("Error occurred while responding: {}", err);
+                return;
+            }
+            out.clear();
+        }
+        if closing {
+            return;
+        }
+    }
+}
+
+fn text(arg: &[u8]) -> String {
+    String::from_utf8_lossy(arg).into_owned()
+}
+
+fn wrong_arguments(name: &str) -> Reply {
+    Reply::error(&format!("wrong number of arguments for '{}' command", name.to_lowercase()))
+}
+
+fn syntax_error() -> Reply {
+    Reply::error("syntax error")
+}
+
+fn integer(arg: &[u8]) -> Result<i64, Reply> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|x| x.parse::<i64>().ok())
+        .ok_or_else(|| Reply::error("value is not an integer or out of range"))
+}
+
+fn wrong_type() -> Reply {
+    Reply::Error(String::from(
+        "WRONGTYPE Operation against a key holding the wrong kind of value",
+    ))
+}
+
+/// The bytes GET hands out for a value. Only scalars have one.
+fn value_bytes(value: &CacheValue) -> Result<Vec<u8>, Reply> {
+    match value {
+        CacheValue::String(text) => Ok(text.as_bytes().to_vec()),
+        CacheValue::Bytes(data) => Ok(data.clone()),
+        CacheValue::Bool(value) => Ok(value.to_string().into_bytes()),
+        CacheValue::Int(value) => Ok(value.to_string().into_bytes()),
+        CacheValue::Int64(value) => Ok(value.to_string().into_bytes()),
+        CacheValue::Float(value) => Ok(value.to_string().into_bytes()),
+        _ => Err(wrong_type()),
+    }
+}
+
+/// The redis type name closest to a value, as SCAN's TYPE filter sees it.
+fn redis_type(value: &CacheValue) -> &'static str {
+    match value {
+        CacheValue::Int(_)
+        | CacheValue::Int64(_)
+        | CacheValue::Float(_)
+        | CacheValue::String(_)
+        | CacheValue::Bytes(_)
+        | CacheValue::Bool(_) => "string",
+        CacheValue::Set(_) => "set",
+        CacheValue::Hash(_) => "hash",
+        CacheValue::SortedSet(_) => "zset",
+        // What redis reports for documents of its JSON module.
+        CacheValue::Json(_) => "ReJSON-RL",
+        _ => "list",
+    }
+}
+
+fn expiry(amount: i64, millis: bool, command: &str) -> Result<Duration, Reply> {
+    let ttl = if millis {
+        Duration::from_millis(amount.max(0) as u64)
+    } else {
+        Duration::from_secs(amount.max(0) as u64)
+    };
+    if amount <= 0 || ttl > MAX_TTL {
+        return Err(Reply::error(&format!("invalid expire time in '{}' command", command)));
+    }
+    Ok(ttl)
+}
+
+/// Runs one command against the cache. Commands on a single key lock the
+/// shard of that key, only taking the read lock if they don't write.
+fn execute(args: &[Vec<u8>], cache: &ShardedCache, auth: Option<&Auth>, session: &mut Session) -> Reply {
+    let command = text(&args[0]);
+    let name = command.to_uppercase();
+    let args = &args[1..];
+    if let Some(auth) = auth {
+        if let Err(reply) = authorize(&name, args, auth, session) {
+            return reply;
+        }
+    }
+    let key = args.first().map(|x| text(x)).unwrap_or_default();
+    let key = key.as_str();
+    let user = session.user.clone();
+    let result = match name.as_str() {
+        "PING" => ping(args),
+        "ECHO" => echo(args),
+        "AUTH" => authenticate(args, auth, session),
+        "HELLO" => hello(args, auth, session),
+        "CLIENT" => client(args, session),
+        "SELECT" => select(args),
+        "COMMAND" => Ok(Reply::Array(Vec::new())),
+        "QUIT" => Ok(Reply::ok()),
+        "GET" => get(args, &cache.read(key)),
+        "SET" => set(args, &mut cache.write(key)),
+        "DEL" => del(args, cache),
+        "EXISTS" => exists(args, cache),
+        "EXPIRE" => expire(args, &mut cache.write(key)),
+        "TTL" => ttl(args, &cache.read(key), false),
+        "PTTL" => ttl(args, &cache.read(key), true),
+        "INCR" => incr(&name, args, &mut cache.write(key), Some(1)),
+        "DECR" => incr(&name, args, &mut cache.write(key), Some(-1)),
+        "INCRBY" => incr(&name, args, &mut cache.write(key), None),
+        "MGET" => mget(args, cache),
+        "MSET" => mset(args, cache),
+        "KEYS" => keys(args, cache, user.as_deref()),
+        "SCAN" => scan(args, cache, user.as_deref()),
+        "DBSIZE" => Ok(Reply::Integer(cache.len() as i64)),
+        "INFO" => Ok(info(args, cache)),
+        _ => Err(Reply::error(&format!(
+            "unknown command '{}', with args beginning with: {}",
+            command,
+            args.iter()
+                .map(|x| format!("'{}' ", text(x)))
+                .collect::<String>()
+        ))),
+    };
+    result.unwrap_or_else(|reply| reply)
+}
+
+/// Keys a command touches and whether it writes to them.
+fn command_keys<'a>(name: &str, args: &'a [Vec<u8>]) -> (bool, Vec<&'a [u8]>) {
+    let first = || args.iter().take(1).map(|x| x.as_slice()).collect();
+    let all = || args.iter().map(|x| x.as_slice()).collect();
+    match name {
+        "GET" | "TTL" | "PTTL" => (false, first()),
+        "SET" | "EXPIRE" | "INCR" | "DECR" | "INCRBY" => (true, first()),
+        "EXISTS" | "MGET" => (false, all()),
+        "DEL" => (true, all()),
+        "MSET" => (true, args.iter().step_by(2).map(|x| x.as_slice()).collect()),
+        _ => (false, Vec::new()),
+    }
+}
+
+/// Holds a command to the ACL of the user the session logged in as, the
+/// same one HTTP requests are held to. Until the client logs in only the
+/// commands that do so are let through.
+fn authorize(name: &str, args: &[Vec<u8>], auth: &Auth, session: &Session) -> Result<(), Reply> {
+    let user = match &session.user {
+        Some(user) => user,
+        None if matches!(name, "AUTH" | "HELLO" | "QUIT") => return Ok(()),
+        None => return Err(Reply::Error(String::from("NOAUTH Authentication required."))),
+    };
+    let (writing, keys) = command_keys(name, args);
+    for key in keys {
+        auth.authorize_access(user, writing, Some(&text(key)))
+            .map_err(|err| Reply::Error(format!("NOPERM {}", err)))?;
+    }
+    Ok(())
+}
+
+/// AUTH [username] password. Without a user name the password is taken
+/// as an API key.
+fn authenticate(args: &[Vec<u8>], auth: Option<&Auth>, session: &mut Session) -> Result<Reply, Reply> {
+    match args {
+        [secret] => log_in(auth, None, secret, session),
+        [name, secret] => log_in(auth, Some(name), secret, session),
+        _ => Err(wrong_arguments("auth")),
+    }
+}
+
+fn log_in(auth: Option<&Auth>, name: Option<&[u8]>, secret: &[u8], session: &mut Session) -> Result<Reply, Reply> {
+    let auth = auth.ok_or_else(|| {
+        Reply::error("AUTH called without any password configured for the default user")
+    })?;
+    match auth.login(name.map(text).as_deref(), &text(secret)) {
+        Ok(user) => {
+            session.user = Some(user);
+            Ok(Reply::ok())
+        }
+        Err(_) => Err(Reply::Error(String::from(
+            "WRONGPASS invalid username-password pair or user is disabled.",
+        ))),
+    }
+}
+
+fn ping(args: &[Vec<u8>]) -> Result<Reply, Reply> {
+    match args {
+        [] => Ok(Reply::Simple(String::from("PONG"))),
+        [message] => Ok(Reply::Bulk(message.clone())),
+        _ => Err(wrong_arguments("ping")),
+    }
+}
+
+fn echo(args: &[Vec<u8>]) -> Result<Reply, Reply> {
+    match args {
+        [message] => Ok(Reply::Bulk(message.clone())),
+        _ => Err(wrong_arguments("echo")),
+    }
+}
+
+/// Switches the protocol version and describes the server, logging the
+/// client in first if it sent credentials along.
+fn hello(args: &[Vec<u8>], auth: Option<&Auth>, session: &mut Session) -> Result<Reply, Reply> {
+    let protocol = match args.first() {
+        Some(version) => match integer(version) {
+            Ok(version @ 2..=3) => version as u8,
+            _ => {
+                return Err(Reply::Error(String::from(
+                    "NOPROTO unsupported protocol version",
+                )))
+            }
+        },
+        None => session.protocol,
+    };
+    let mut name = None;
+    let mut credentials = None;
+    let mut i = 1;
+    while i < args.len() {
+        match text(&args[i]).to_uppercase().as_str() {
+            "SETNAME" if i + 1 < args.len() => {
+                name = Some(args[i + 1].clone());
+                i += 2;
+            }
+            "AUTH" if i + 2 < args.len() => {
+                credentials = Some((&args[i + 1], &args[i + 2]));
+                i += 3;
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+    match credentials {
+        Some((user, password)) => {
+            log_in(auth, Some(user), password, session)?;
+        }
+        None if auth.is_some() && session.user.is_none() => {
+            return Err(Reply::Error(String::from(
+                "NOAUTH HELLO must be called with the client already authenticated, \
+                 otherwise the HELLO <proto> AUTH <user> <pass> option can be used",
+            )));
+        }
+        None => {}
+    }
+    session.protocol = protocol;
+    if name.is_some() {
+        session.name = name;
+    }
+    Ok(Reply::Map(vec![
+        (Reply::bulk("server"), Reply::bulk("zen-cache")),
+        (Reply::bulk("version"), Reply::bulk(env!("CARGO_PKG_VERSION"))),
+        (Reply::bulk("proto"), Reply::Integer(session.protocol as i64)),
+        (Reply::bulk("id"), Reply::Integer(session.id as i64)),
+        (Reply::bulk("mode"), Reply::bulk("standalone")),
+        (Reply::bulk("role"), Reply::bulk("master")),
+        (Reply::bulk("modules"), Reply::Array(Vec::new())),
+    ]))
+}
+
+/// The handful of CLIENT subcommands client libraries send on connect.
+fn client(args: &[Vec<u8>], session: &mut Session) -> Result<Reply, Reply> {
+    let subcommand = args.first().map(|x| text(x).to_uppercase()).unwrap_or_default();
+    match (subcommand.as_str(), args.len()) {
+        ("ID", 1) => Ok(Reply::Integer(session.id as i64)),
+        ("SETNAME", 2) => {
+            session.name = Some(args[1].clone());
+            Ok(Reply::ok())
+        }
+        ("GETNAME", 1) => Ok(Reply::bulk_or_null(session.name.clone())),
+        ("SETINFO", 3) => Ok(Reply::ok()),
+        _ => Err(Reply::error(&format!(
+            "unknown subcommand or wrong number of arguments for '{}'",
+            subcommand
+        ))),
+    }
+}
+
+fn select(args: &[Vec<u8>]) -> Result<Reply, Reply> {
+    match args {
+        [index] => match integer(index)? {
+            0 => Ok(Reply::ok()),
+            _ => Err(Reply::error("DB index is out of range")),
+        },
+        _ => Err(wrong_arguments("select")),
+    }
+}
+
+fn get(args: &[Vec<u8>], cache: &Cache) -> Result<Reply, Reply> {
+    match args {
+        [key] => match cache.get(&text(key)) {
+            Some(value) => Ok(Reply::Bulk(value_bytes(value)?)),
+            None => Ok(Reply::Null),
+        },
+        _ => Err(wrong_arguments("get")),
+    }
+}
+
+/// SET key value [NX | XX] [GET] [EX seconds | PX milliseconds | KEEPTTL]
+fn set(args: &[Vec<u8>], cache: &mut Cache) -> Result<Reply, Reply> {
+    if args.len() < 2 {
+        return Err(wrong_arguments("set"));
+    }
+    let key = text(&args[0]);
+    let mut ttl = None;
+    let mut keep_ttl = false;
+    let mut condition = None;
+    let mut return_old = false;
+
+    let mut i = 2;
+    while i < args.len() {
+        let option = text(&args[i]).to_uppercase();
+        match option.as_str() {
+            "EX" | "PX" if ttl.is_none() && !keep_ttl && i + 1 < args.len() => {
+                ttl = Some(expiry(integer(&args[i + 1])?, option == "PX", "set")?);
+                i += 1;
+            }
+            "NX" | "XX" if condition.is_none() => condition = Some(option),
+            "KEEPTTL" if ttl.is_none() => keep_ttl = true,
+            "GET" => return_old = true,
+            _ => return Err(syntax_error()),
+        }
+        i += 1;
+    }
+
+    let old = match cache.get(&key) {
+        Some(value) if return_old => Some(value_bytes(value)?),
+        _ => None,
+    };
+    let exists = cache.contains(&key);
+    let skip = match condition.as_deref() {
+        Some("NX") => exists,
+        Some("XX") => !exists,
+        _ => false,
+    };
+    if !skip {
+        let value = CacheValue::from_bytes(args[1].clone());
+        match ttl {
+            Some(ttl) => cache.insert_with_ttl(key, value, ttl),
+            None if keep_ttl => cache.insert_keep_ttl(key, value),
+            None => cache.insert(key, value),
+        };
+    }
+
+    Ok(match (return_old, skip) {
+        (true, _) => Reply::bulk_or_null(old),
+        (false, true) => Reply::Null,
+        (false, false) => Reply::ok(),
+    })
+}
+
+fn del(args: &[Vec<u8>], cache: &ShardedCache) -> Result<Reply, Reply> {
+    if args.is_empty() {
+        return Err(wrong_arguments("del"));
+    }
+    let removed = args
+        .iter()
+        .map(|key| text(key))
+        .filter(|key| cache.write(key.as_str()).remove(key.as_str()).is_some())
+        .count();
+    Ok(Reply::Integer(removed as i64))
+}
+
+fn exists(args: &[Vec<u8>], cache: &ShardedCache) -> Result<Reply, Reply> {
+    if args.is_empty() {
+        return Err(wrong_arguments("exists"));
+    }
+    // Keys given twice count twice, as in redis.
+    let found = args
+        .iter()
+        .map(|key| text(key))
+        .filter(|key| cache.read(key.as_str()).contains(key.as_str()))
+        .count();
+    Ok(Reply::Integer(found as i64))
+}
+
+fn expire(args: &[Vec<u8>], cache: &mut Cache) -> Result<Reply, Reply> {
+    let (key, seconds) = match args {
+        [key, seconds] => (text(key), integer(seconds)?),
+        _ => return Err(wrong_arguments("expire")),
+    };
+    // An expiry in the past deletes the key right away.
+    if seconds <= 0 {
+        return Ok(Reply::Integer(cache.remove(&key).is_some() as i64));
+    }
+    let ttl = expiry(seconds, false, "expire")?;
+    Ok(Reply::Integer(cache.expire(&key, ttl) as i64))
+}
+
+fn ttl(args: &[Vec<u8>], cache: &Cache, millis: bool) -> Result<Reply, Reply> {
+    let key = match args {
+        [key] => text(key),
+        _ => return Err(wrong_arguments(if millis { "pttl" } else { "ttl" })),
+    };
+    Ok(Reply::Integer(match cache.ttl(&key) {
+        Err(_) => -2,
+        Ok(None) => -1,
+        Ok(Some(ttl)) if millis => ttl.as_millis() as i64,
+        Ok(Some(ttl)) => ((ttl.as_millis() + 500) / 1000) as i64,
+    }))
+}
+
+/// INCR, DECR and INCRBY. Missing keys start at 0, strings holding a number
+/// stay strings and the expiry is kept.
+fn incr(name: &str, args: &[Vec<u8>], cache: &mut Cache, delta: Option<i64>) -> Result<Reply, Reply> {
+    let (key, delta) = match (args, delta) {
+        ([key], Some(delta)) => (text(key), delta),
+        ([key, delta], None) => (text(key), integer(delta)?),
+        _ => return Err(wrong_arguments(name)),
+    };
+    match cache.incr_by(&key, delta) {
+        Ok(result) => Ok(Reply::Integer(result)),
+        Err(CacheError::Overflow(_)) => Err(Reply::error("increment or decrement would overflow")),
+        Err(CacheError::TypeMismatch { found: "float", .. }) | Err(CacheError::InvalidValue(_)) => {
+            Err(Reply::error("value is not an integer or out of range"))
+        }
+        Err(_) => Err(wrong_type()),
+    }
+}
+
+fn mget(args: &[Vec<u8>], cache: &ShardedCache) -> Result<Reply, Reply> {
+    if args.is_empty() {
+        return Err(wrong_arguments("mget"));
+    }
+    // Keys that don't hold a string read as missing rather than failing.
+    Ok(Reply::Array(
+        args.iter()
+            .map(|key| {
+                let key = text(key);
+                let value = cache.read(key.as_str()).get(key.as_str()).and_then(|x| value_bytes(x).ok());
+                Reply::bulk_or_null(value)
+            })
+            .collect(),
+    ))
+}
+
+/// MSET key value [key value ...]. The keys' shards stay locked until all
+/// are set, so no reader sees only some of them.
+fn mset(args: &[Vec<u8>], cache: &ShardedCache) -> Result<Reply, Reply> {
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Err(wrong_arguments("mset"));
+    }
+    let keys: Vec<String> = args.iter().step_by(2).map(|key| text(key)).collect();
+    let mut shards = cache.write_keys(keys.iter().map(|key| key.as_str()));
+    for (key, pair) in keys.into_iter().zip(args.chunks(2)) {
+        shards
+            .get_mut(key.as_str())
+            .insert(key, CacheValue::from_bytes(pair[1].clone()));
+    }
+    Ok(Reply::ok())
+}
+
+fn keys(args: &[Vec<u8>], cache: &ShardedCache, user: Option<&User>) -> Result<Reply, Reply> {
+    let pattern = match args {
+        [pattern] => text(pattern),
+        _ => return Err(wrong_arguments("keys")),
+    };
+    let mut keys: Vec<String> = cache
+        .keys()
+        .into_iter()
+        .filter(|key| pattern::matches(&pattern, key))
+        .filter(|key| user.is_none_or(|user| user.can_access(key)))
+        .collect();
+    keys.sort();
+    Ok(Reply::Array(keys.iter().map(|key| Reply::bulk(key)).collect()))
+}
+
+/// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]. The cursor is the
+/// one `ShardedCache::scan` hands out, so keys present for the whole scan
+/// are returned exactly once. Keys outside of `user`'s prefixes are skipped.
+fn scan(args: &[Vec<u8>], cache: &ShardedCache, user: Option<&User>) -> Result<Reply, Reply> {
+    let cursor = match args.first() {
+        Some(cursor) => std::str::from_utf8(cursor)
+            .ok()
+            .and_then(|x| x.parse::<u64>().ok())
+            .ok_or_else(|| Reply::error("invalid cursor"))?,
+        None => return Err(wrong_arguments("scan")),
+    };
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut type_name = None;
+    let mut i = 1;
+    while i < args.len() {
+        if i + 1 >= args.len() {
+            return Err(syntax_error());
+        }
+        match text(&args[i]).to_uppercase().as_str() {
+            "MATCH" => pattern = Some(text(&args[i + 1])),
+            "COUNT" => match integer(&args[i + 1])? {
+                count_arg if count_arg >= 1 => count = count_arg as usize,
+                _ => return Err(syntax_error()),
+            },
+            "TYPE" => type_name = Some(text(&args[i + 1]).to_lowercase()),
+            _ => return Err(syntax_error()),
+        }
+        i += 2;
+    }
+
+    let (keys, next) = cache.scan(cursor, count);
+    let page: Vec<Reply> = keys
+        .iter()
+        .filter(|key| pattern.as_ref().is_none_or(|pattern| pattern::matches(pattern, key)))
+        .filter(|key| user.is_none_or(|user| user.can_access(key)))
+        .filter(|key| {
+            type_name.as_ref().is_none_or(|type_name| {
+                cache.read(key.as_str()).peek(key.as_str()).map(redis_type) == Some(type_name.as_str())
+            })
+        })
+        .map(|key| Reply::bulk(key))
+        .collect();
+    Ok(Reply::Array(vec![Reply::bulk(&next.to_string()), Reply::Array(page)]))
+}
+
+/// INFO [section ...] in the `# Section` / `field:value` layout clients parse.
+fn info(args: &[Vec<u8>], cache: &ShardedCache) -> Reply {
+    let server = &stats::SERVER;
+    let sections = [
+        (
+            "server",
+            vec![
+                // Client libraries look at this to decide which features they can use.
+                (String::from("redis_version"), String::from("7.2.0")),
+                (String::from("zen_cache_version"), String::from(env!("CARGO_PKG_VERSION"))),
+                (String::from("redis_mode"), String::from("standalone")),
+                (String::from("process_id"), std::process::id().to_string()),
+            ],
+        ),
+        (
+            "clients",
+            vec![(
+                String::from("connected_clients"),
+                server.active_connections.load(Ordering::SeqCst).to_string(),
+            )],
+        ),
+        (
+            "memory",
+            vec![
+                (String::from("used_memory"), cache.used_memory().to_string()),
+                (String::from("maxmemory"), cache.max_memory().unwrap_or(0).to_string()),
+                (String::from("maxmemory_policy"), cache.eviction_policy().to_string()),
+            ],
+        ),
+        (
+            "persistence",
+            vec![
+                (String::from("rdb_changes_since_last_save"), cache.unsaved_changes().to_string()),
+                (String::from("aof_enabled"), (cache.log_size().is_some() as u8).to_string()),
+            ],
+        ),
+        (
+            "stats",
+            vec![
+                (
+                    String::from("total_connections_received"),
+                    server.total_connections.load(Ordering::SeqCst).to_string(),
+                ),
+                (
+                    String::from("total_commands_processed"),
+                    server.total_requests.load(Ordering::SeqCst).to_string(),
+                ),
+                (
+                    String::from("rejected_connections"),
+                    server.rejected_connections.load(Ordering::SeqCst).to_string(),
+                ),
+                (String::from("evicted_keys"), cache.evictions().to_string()),
+            ],
+        ),
+        (
+            "keyspace",
+            vec![(String::from("db0"), format!("keys={}", cache.len()))],
+        ),
+    ];
+
+    let wanted: Vec<String> = args.iter().map(|x| text(x).to_lowercase()).collect();
+    let everything = wanted.is_empty()
+        || wanted
+            .iter()
+            .any(|x| x == "all" || x == "everything" || x == "default");
+    let mut out = String::new();
+    for (name, fields) in sections.iter() {
+        if !everything && !wanted.iter().any(|x| x == name) {
+            continue;
+        }
+        if !out.is_empty() {
+            out.push_str("\r\n");
+        }
+        let mut title = name.to_string();
+        title[..1].make_ascii_uppercase();
+        out.push_str(&format!("# {}\r\n", title));
+        for (key, value) in fields {
+            out.push_str(&format!("{}:{}\r\n", key, value));
+        }
+    }
+    Reply::Bulk(out.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_session() -> Session {
+        Session {
+            id: 1,
+            protocol: 2,
+            name: None,
+            user: None,
+        }
+    }
+
+    /// Runs an inline command and returns the reply as sent on the wire.
+    fn run(cache: &ShardedCache, auth: Option<&Auth>, session: &mut Session, command: &str) -> String {
+        let args: Vec<Vec<u8>> = command.split(' ').map(|x| x.as_bytes().to_vec()).collect();
+        let mut out = Vec::new();
+        execute(&args, cache, auth, session).write(&mut out, session.protocol);
+        String::from_utf8(out).unwrap()
+    }
+
+    fn read_all(input: &[u8]) -> Result<Vec<Vec<Vec<u8>>>, Error> {
+        let mut reader = input;
+        let mut commands = Vec::new();
+        while let Some(args) = read_command(&mut reader)? {
+            commands.push(args);
+        }
+        Ok(commands)
+    }
+
+    fn words(args: &[&str]) -> Vec<Vec<u8>> {
+        args.iter().map(|x| x.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn reads_arrays_and_inline_commands() {
+        let commands = read_all(
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\na\r\nb\r\n\
+              *0\r\n*-1\r\n\r\n  \n\
+              GET  k\tx\r\n\
+              PING\n\
+              *2\r\n$4\r\nECHO\r\n$0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            commands,
+            vec![
+                words(&["SET", "k", "a\r\nb"]),
+                words(&["GET", "k", "x"]),
+                words(&["PING"]),
+                words(&["ECHO", ""]),
+            ]
+        );
+        assert_eq!(read_all(b"").unwrap(), Vec::<Vec<Vec<u8>>>::new());
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        for input in [
+            &b"*x\r\n"[..],
+            b"*2\r\n:1\r\n",
+            b"*1\r\n$x\r\n",
+            b"*1\r\n$3\r\nGETX\r\n",
+            b"*1048577\r\n",
+            b"*1\r\n$536870913\r\n",
+        ] {
+            let err = read_all(input).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{:?}", input);
+        }
+        for input in [&b"*2\r\n$3\r\nGET\r\n"[..], b"*1\r\n$3\r\nGE", b"PING"] {
+            let err = read_all(input).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof, "{:?}", input);
+        }
+        let long = vec![b'a'; parser::MAX_LINE_SIZE + 1];
+        assert_eq!(read_all(&long).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn writes_replies_for_both_protocols() {
+        let reply = Reply::Array(vec![
+            Reply::ok(),
+            Reply::error("oops"),
+            Reply::Integer(-3),
+            Reply::bulk("a\r\nb"),
+            Reply::Bulk(Vec::new()),
+            Reply::Null,
+            Reply::Map(vec![(Reply::bulk("k"), Reply::Integer(1))]),
+        ]);
+        let mut out = Vec::new();
+        reply.write(&mut out, 2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "*7\r\n+OK\r\n-ERR oops\r\n:-3\r\n$4\r\na\r\nb\r\n$0\r\n\r\n$-1\r\n*2\r\n$1\r\nk\r\n:1\r\n"
+        );
+        let mut out = Vec::new();
+        reply.write(&mut out, 3);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "*7\r\n+OK\r\n-ERR oops\r\n:-3\r\n$4\r\na\r\nb\r\n$0\r\n\r\n_\r\n%1\r\n$1\r\nk\r\n:1\r\n"
+        );
+    }
+
+    #[test]
+    fn auth_gates_commands_and_applies_the_acl() {
+        let cache = ShardedCache::in_memory(4);
+        let auth = Auth::from_json(
+            r#"{"users": [
+                {"name": "app", "password": "secret", "access": "read-write", "prefixes": ["app:"]},
+                {"name": "viewer", "password": "view"}
+            ]}"#,
+        )
+        .unwrap();
+        let auth = Some(&auth);
+        let mut session = new_session();
+
+        assert!(run(&cache, auth, &mut session, "GET app:1").starts_with("-NOAUTH"));
+        assert!(run(&cache, auth, &mut session, "HELLO 3").starts_with("-NOAUTH"));
+        assert!(run(&cache, auth, &mut session, "AUTH app wrong").starts_with("-WRONGPASS"));
+        assert_eq!(run(&cache, auth, &mut session, "AUTH app secret"), "+OK\r\n");
+        assert_eq!(run(&cache, auth, &mut session, "SET app:1 x"), "+OK\r\n");
+        assert!(run(&cache, auth, &mut session, "SET other x").starts_with("-NOPERM"));
+        assert!(run(&cache, auth, &mut session, "MSET app:2 x other x").starts_with("-NOPERM"));
+        assert_eq!(run(&cache, None, &mut session, "SET other x"), "+OK\r\n");
+        assert_eq!(run(&cache, auth, &mut session, "KEYS *"), "*1\r\n$5\r\napp:1\r\n");
+
+        let mut viewer = new_session();
+        assert!(run(&cache, auth, &mut viewer, "HELLO 3 AUTH viewer view").starts_with("%7\r\n"));
+        assert_eq!(viewer.protocol, 3);
+        assert_eq!(run(&cache, auth, &mut viewer, "GET other"), "$1\r\nx\r\n");
+        assert!(run(&cache, auth, &mut viewer, "DEL other").starts_with("-NOPERM"));
+    }
+
+    #[test]
+    fn auth_without_users_configured_fails() {
+        let cache = ShardedCache::in_memory(1);
+        let mut session = new_session();
+        assert!(run(&cache, None, &mut session, "AUTH secret").starts_with("-ERR AUTH called without"));
+        assert!(run(&cache, None, &mut session, "HELLO 3 AUTH app secret").starts_with("-ERR AUTH called without"));
+        assert_eq!(session.protocol, 2);
+        assert_eq!(run(&cache, None, &mut session, "PING"), "+PONG\r\n");
+    }
+}
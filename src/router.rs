@@ -0,0 +1,318 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::handler::Function;
+
+/// One `/`-separated piece of a route template.
+enum Segment {
+    /// Must match exactly, e.g. `keys`.
+    Literal(String),
+    /// `{name}` matches any single segment and captures it.
+    Param(String),
+    /// `*` or `{name*}` matches the rest of the path, including slashes.
+    Wildcard(String),
+}
+
+impl Segment {
+    // Literal segments beat parameters, which beat wildcards.
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Literal(_) => 2,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 0,
+        }
+    }
+}
+
+// Rank of a template ending where the path does, which beats a wildcard
+// matching nothing there.
+const END_RANK: u8 = 3;
+
+struct Route {
+    template: String,
+    segments: Vec<Segment>,
+    function: Arc<Function>,
+}
+
+impl Route {
+    fn parse(template: &str, function: Arc<Function>) -> Route {
+        let segments = split_path(template)
+            .map(|segment| {
+                if segment == "*" {
+                    Segment::Wildcard(String::from("*"))
+                } else if let Some(name) = segment.strip_prefix('{').and_then(|x| x.strip_suffix('}')) {
+                    match name.strip_suffix('*') {
+                        Some(name) => Segment::Wildcard(name.to_string()),
+                        None => Segment::Param(name.to_string()),
+                    }
+                } else {
+                    Segment::Literal(segment.to_string())
+                }
+            })
+            .collect();
+        Route {
+            template: template.to_string(),
+            segments,
+            function,
+        }
+    }
+
+    /// How specific the route is, compared segment by segment.
+    fn ranks(&self) -> impl Iterator<Item = u8> + '_ {
+        let end = match self.segments.last() {
+            Some(Segment::Wildcard(_)) => None,
+            _ => Some(END_RANK),
+        };
+        self.segments.iter().map(|x| x.rank()).chain(end)
+    }
+
+    /// Matches raw (still percent-encoded) path segments and returns the
+    /// decoded captures.
+    fn matches(&self, path: &[&str]) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::new();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Wildcard(name) => {
+                    let rest = path[i.min(path.len())..]
+                        .iter()
+                        .map(|x| percent_decode(x, false))
+                        .collect::<Vec<String>>()
+                        .join("/");
+                    params.insert(name.clone(), rest);
+                    return Some(params);
+                }
+                Segment::Literal(literal) => {
+                    if percent_decode(path.get(i)?, false) != *literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    params.insert(name.clone(), percent_decode(path.get(i)?, false));
+                }
+            }
+        }
+        if path.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+}
+
+/// Result of routing a request.
+pub struct RouteMatch {
+    pub function: Arc<Function>,
+    pub params: HashMap<String, String>,
+}
+
+/// Maps request paths onto functions using templates such as
+/// `/keys/{key}` or `/files/{path*}`.
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new(functions: HashMap<String, Arc<Function>>) -> Router {
+        Router {
+            routes: functions
+                .into_iter()
+                .map(|(template, function)| Route::parse(&template, function))
+                .collect(),
+        }
+    }
+
+    /// Finds the most specific route for a path without its query string.
+    /// Of equally specific routes the template sorting first wins.
+    pub fn find(&self, path: &str) -> Option<RouteMatch> {
+        let segments: Vec<&str> = split_path(path).collect();
+        self.routes
+            .iter()
+            .filter_map(|route| route.matches(&segments).map(|params| (route, params)))
+            .max_by(|(a, _), (b, _)| a.ranks().cmp(b.ranks()).then_with(|| b.template.cmp(&a.template)))
+            .map(|(route, params)| RouteMatch {
+                function: Arc::clone(&route.function),
+                params,
+            })
+    }
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|x| !x.is_empty())
+}
+
+/// Splits a request target into its path and its decoded query parameters.
+pub fn split_target(target: &str) -> (String, HashMap<String, String>) {
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (target, ""),
+    };
+    (path.to_string(), parse_query(query))
+}
+
+/// Parses `a=1&b=two+words`. Keys without a value map to an empty string,
+/// later duplicates win.
+pub fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|x| !x.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (percent_decode(key, true), percent_decode(value, true)),
+            None => (percent_decode(pair, true), String::new()),
+        })
+        .collect()
+}
+
+/// Decodes `%XX` escapes, and `+` as a space when `plus_as_space` is set
+/// (query strings only). Invalid escapes are kept as they are.
+pub fn percent_decode(input: &str, plus_as_space: bool) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len()
+                && bytes[i + 1].is_ascii_hexdigit()
+                && bytes[i + 2].is_ascii_hexdigit() =>
+            {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                out.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+                continue;
+            }
+            b'+' if plus_as_space => out.push(b' '),
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router(templates: &[&str]) -> Router {
+        Router::new(
+            templates
+                .iter()
+                .map(|template| {
+                    let function = Function::new(
+                        template.to_string(),
+                        vec![],
+                        None,
+                        None,
+                        Arc::new(|_, _| Ok(String::new())),
+                    );
+                    (template.to_string(), Arc::new(function))
+                })
+                .collect(),
+        )
+    }
+
+    /// The template of the route `path` goes to, with its captures.
+    fn find(router: &Router, path: &str) -> Option<(String, HashMap<String, String>)> {
+        let found = router.find(path)?;
+        let route = router
+            .routes
+            .iter()
+            .find(|route| Arc::ptr_eq(&route.function, &found.function))
+            .unwrap();
+        Some((route.template.clone(), found.params))
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn matches_templates() {
+        let router = router(&["/keys", "/keys/{key}", "/keys/{key}/hash/{field}"]);
+        assert_eq!(find(&router, "/keys"), Some(("/keys".to_string(), params(&[]))));
+        assert_eq!(find(&router, "//keys/"), Some(("/keys".to_string(), params(&[]))));
+        assert_eq!(
+            find(&router, "/keys/a"),
+            Some(("/keys/{key}".to_string(), params(&[("key", "a")])))
+        );
+        assert_eq!(
+            find(&router, "/keys/a/hash/b"),
+            Some(("/keys/{key}/hash/{field}".to_string(), params(&[("key", "a"), ("field", "b")])))
+        );
+        assert_eq!(find(&router, "/"), None);
+        assert_eq!(find(&router, "/key"), None);
+        assert_eq!(find(&router, "/keys/a/hash"), None);
+        assert_eq!(find(&router, "/keys/a/hash/b/c"), None);
+    }
+
+    #[test]
+    fn prefers_literals_then_params_then_wildcards() {
+        let router = router(&["/files/*", "/files/{name}", "/files/latest", "/files", "/{any*}"]);
+        assert_eq!(find(&router, "/files/latest").unwrap().0, "/files/latest");
+        assert_eq!(find(&router, "/files/a").unwrap().0, "/files/{name}");
+        assert_eq!(find(&router, "/files/a/b").unwrap().0, "/files/*");
+        assert_eq!(find(&router, "/files").unwrap().0, "/files");
+        assert_eq!(find(&router, "/other/a").unwrap().0, "/{any*}");
+        // The first segment decides before later ones are looked at.
+        let router = self::router(&["/{a}/b", "/a/{b}"]);
+        assert_eq!(find(&router, "/a/b").unwrap().0, "/a/{b}");
+    }
+
+    #[test]
+    fn breaks_ties_by_template() {
+        for _ in 0..10 {
+            let router = router(&["/keys/{name}", "/keys/{key}", "/keys/{id}"]);
+            assert_eq!(
+                find(&router, "/keys/a"),
+                Some(("/keys/{id}".to_string(), params(&[("id", "a")])))
+            );
+        }
+    }
+
+    #[test]
+    fn wildcards_capture_the_rest_of_the_path() {
+        let router = router(&["/files/{path*}", "/static/*"]);
+        assert_eq!(find(&router, "/files/a/b%20c/d").unwrap().1, params(&[("path", "a/b c/d")]));
+        assert_eq!(find(&router, "/files").unwrap().1, params(&[("path", "")]));
+        assert_eq!(find(&router, "/static/x/y").unwrap().1, params(&[("*", "x/y")]));
+    }
+
+    #[test]
+    fn captures_are_decoded_after_splitting() {
+        let router = router(&["/keys/{key}", "/keys/{key}/incr"]);
+        assert_eq!(
+            find(&router, "/keys/a%2Fincr"),
+            Some(("/keys/{key}".to_string(), params(&[("key", "a/incr")])))
+        );
+        assert_eq!(find(&router, "/keys/a+b").unwrap().1, params(&[("key", "a+b")]));
+        assert_eq!(find(&router, "/keys/%69ncr").unwrap().1, params(&[("key", "incr")]));
+        // Literals match their decoded form too.
+        assert_eq!(find(&router, "/%6Beys/a/%69ncr").unwrap().0, "/keys/{key}/incr");
+    }
+
+    #[test]
+    fn percent_decode_keeps_invalid_escapes() {
+        assert_eq!(percent_decode("a%20b%2f%2F", false), "a b//");
+        assert_eq!(percent_decode("%", false), "%");
+        assert_eq!(percent_decode("%4", false), "%4");
+        assert_eq!(percent_decode("a%4", false), "a%4");
+        assert_eq!(percent_decode("%zz%41", false), "%zzA");
+        assert_eq!(percent_decode("100%", false), "100%");
+        assert_eq!(percent_decode("%E2%82%AC", false), "\u{20ac}");
+        assert_eq!(percent_decode("%FF", false), "\u{fffd}");
+        assert_eq!(percent_decode("a+b%2B", false), "a+b+");
+        assert_eq!(percent_decode("a+b%2B", true), "a b+");
+    }
+
+    #[test]
+    fn parses_queries() {
+        assert_eq!(
+            parse_query("a=1&b=two+words&c&&d=&a=2&e=x=y&%3D=%26"),
+            params(&[("a", "2"), ("b", "two words"), ("c", ""), ("d", ""), ("e", "x=y"), ("=", "&")])
+        );
+        assert!(parse_query("").is_empty());
+        assert_eq!(
+            split_target("/keys/a%2Fb?ttl=5&type=int"),
+            ("/keys/a%2Fb".to_string(), params(&[("ttl", "5"), ("type", "int")]))
+        );
+        assert_eq!(split_target("/stats"), ("/stats".to_string(), params(&[])));
+        assert_eq!(split_target("/stats?"), ("/stats".to_string(), params(&[])));
+    }
+}
@@ -1,16 +1,155 @@
 use std::{
+    borrow::Cow,
     collections::HashMap,
     ffi::OsStr,
     fmt::Display,
     fs::File,
-    io::{prelude::*, ErrorKind, Read},
-    net::{SocketAddr, TcpListener, TcpStream},
+    io::{prelude::*, BufReader},
     path::Path,
-    sync::{Arc, Mutex, RwLock},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
     time::{Duration, Instant},
 };
 
-use crate::cache::Cache;
+use crate::auth::{Auth, AuthError, User};
+#[cfg(target_os = "linux")]
+use crate::event_loop::EventLoop;
+use crate::listener::{self, Endpoint, Listener, Stream, UnixSocket};
+use crate::parser::{self, Limits, ParseError, RawRequest};
+use crate::pool::WorkerPool;
+use crate::resp;
+use crate::router::{self, Router};
+use crate::sharded::ShardedCache;
+use crate::shutdown;
+use crate::stats;
+use crate::tls::TlsAcceptor;
+
+// How often the background reaper sweeps the cache for expired entries.
+const REAPER_INTERVAL: Duration = Duration::from_secs(1);
+// Defaults for persistent connections.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_REQUESTS: usize = 100;
+// Connections waiting for a free worker before new ones are turned away.
+pub(crate) const DEFAULT_QUEUE_SIZE: usize = 128;
+// How long a shutdown waits for in-flight requests before flushing anyway.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a RESP, memcached or WebSocket client may sit idle before it
+/// is disconnected and its worker freed. Those clients keep connections
+/// open between commands, so this is far longer than HTTP keep-alive.
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Counts a connection as in flight for as long as it is alive, even if
+/// its handler panics.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(counter: &Arc<AtomicUsize>) -> InFlight {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InFlight(Arc::clone(counter))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Workers serving accepted connections, whatever protocol they speak.
+/// Connections that find every worker busy and the queue full are turned
+/// away, and a shutdown waits for the ones being served.
+pub struct ConnectionPool {
+    pool: WorkerPool<(Stream, InFlight)>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl ConnectionPool {
+    pub fn new(
+        workers: usize,
+        queue_size: usize,
+        handle: impl Fn(Stream) + Send + Sync + 'static,
+    ) -> ConnectionPool {
+        let pool = WorkerPool::new(
+            workers,
+            queue_size,
+            Arc::new(move |(stream, guard): (Stream, InFlight)| {
+                let _guard = guard;
+                stats::SERVER.queued_connections.fetch_sub(1, Ordering::SeqCst);
+                let _active = stats::Active::new();
+                handle(stream);
+            }),
+        );
+        ConnectionPool {
+            pool,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.pool.size()
+    }
+
+    /// Hands connections accepted on `listeners` to the workers until a
+    /// shutdown is requested. `busy` tells the ones that are turned away,
+    /// in their protocol, given how many connections are queued.
+    pub fn accept(&self, listeners: &[Listener], busy: fn(&mut Stream, usize)) -> Result<(), std::io::Error> {
+        listener::accept_all(listeners, |stream| {
+            stats::SERVER.total_connections.fetch_add(1, Ordering::SeqCst);
+            // Counted before submitting so a fast worker can't decrement first.
+            stats::SERVER.queued_connections.fetch_add(1, Ordering::SeqCst);
+            if let Err((mut stream, _guard)) = self.pool.try_submit((stream, InFlight::new(&self.in_flight))) {
+                let queued = stats::SERVER.queued_connections.fetch_sub(1, Ordering::SeqCst) - 1;
+                let rejected = stats::SERVER.rejected_connections.fetch_add(1, Ordering::SeqCst) + 1;
+                eprintln!(
+                    "Rejected connection, {} connections queued ({} rejected so far)",
+                    queued, rejected
+                );
+                busy(&mut stream, queued);
+            }
+        })
+    }
+
+    /// Waits for the connections being served and stops the workers.
+    /// Workers stuck on a connection past the drain timeout are not
+    /// waited for.
+    pub fn drain(self) {
+        if HTTPServer::drain(&self.in_flight, DRAIN_TIMEOUT) {
+            self.pool.join();
+        }
+    }
+}
+
+/// Answers a connection turned away by a `ConnectionPool` with a 503.
+pub fn write_busy(stream: &mut Stream, queued: usize) {
+    let _ = write_response(
+        stream,
+        503,
+        &[
+            ("Content-Type", "text/plain"),
+            ("Retry-After", "1"),
+            ("Connection", "close"),
+        ],
+        format!("Server is busy, {} connections queued.", queued).as_bytes(),
+    );
+}
+
+// Kept-alive connections hold on to a worker while idle, so there are
+// several workers per core.
+pub(crate) fn default_workers() -> usize {
+    std::thread::available_parallelism()
+        .map(|x| x.get() * 4)
+        .unwrap_or(16)
+}
+
+// Event loops never block on a client, one per core is enough.
+fn default_event_loops() -> usize {
+    std::thread::available_parallelism()
+        .map(|x| x.get())
+        .unwrap_or(4)
+}
 
 fn format_duration(duration: Duration) -> String {
     let secs = duration.as_secs();
@@ -30,7 +169,7 @@ fn format_duration(duration: Duration) -> String {
 }
 
 fn get_mime_type(file_extension: &OsStr) -> &'static str {
-    match file_extension.to_os_string().to_str().unwrap() {
+    match file_extension.to_str().unwrap_or_default() {
         "aac" => "audio/aac",
         "abw" => "application/x-abiword",
         "apng" => "image/apng",
@@ -111,11 +250,11 @@ fn get_mime_type(file_extension: &OsStr) -> &'static str {
 struct Helper {}
 
 impl Helper {
-    pub fn display_list(list: &Vec<Header>) -> String {
+    pub fn display_list(list: &[Header]) -> String {
         let mut out = String::new();
-        for i in 0..list.len() {
-            out.push_str(format!("{}", list[i]).as_str());
-            out.push_str("\n");
+        for item in list {
+            out.push_str(format!("{}", item).as_str());
+            out.push('\n');
         }
         out
     }
@@ -144,164 +283,230 @@ impl Header {
 
 pub struct HTMLRequest {
     pub method: String,
+    /// The raw request target, query string included.
     pub endpoint: String,
+    /// The path part of the endpoint, still percent-encoded.
+    pub path: String,
+    /// Decoded query string parameters.
+    pub query: HashMap<String, String>,
+    /// Parameters captured by the route template, e.g. `key` for `/keys/{key}`.
+    pub params: HashMap<String, String>,
     pub version: String,
     pub header: Vec<Header>,
-    pub body: String,
-    pub client_address: SocketAddr,
-    pub local_address: SocketAddr,
-    pub stream: Mutex<TcpStream>,
+    pub body: Vec<u8>,
+    pub client_address: Endpoint,
+    pub local_address: Endpoint,
+    /// Where responses go: the socket itself, or a buffer the event loop
+    /// drains once the socket is writable.
+    pub stream: Mutex<Box<dyn Write + Send>>,
+    /// Whether the connection stays open after this request.
+    pub keep_alive: bool,
+    /// Who sent the request, once authentication is enabled.
+    pub user: Option<Arc<User>>,
+    responded: AtomicBool,
+}
+
+/// Reason phrase for a status code.
+fn status_text(status: u64) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        426 => "Upgrade Required",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Writes a complete response. Statuses that must not carry a body, like
+/// 204 and 304, are sent without a Content-Length.
+pub fn write_response(
+    stream: &mut impl Write,
+    status: u64,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<(), std::io::Error> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status, status_text(status));
+    for (key, value) in headers {
+        head.push_str(format!("{}: {}\r\n", key, value).as_str());
+    }
+    let bodyless = status < 200 || status == 204 || status == 304;
+    if !bodyless {
+        head.push_str(format!("Content-Length: {}\r\n", body.len()).as_str());
+    }
+    head.push_str("\r\n");
+
+    let mut out = head.into_bytes();
+    if !bodyless {
+        out.extend_from_slice(body);
+    }
+    stream.write_all(&out)?;
+    stream.flush()
 }
 
 #[allow(dead_code)]
 impl HTMLRequest {
-    pub fn from_requeststr(requestin: String, stream: TcpStream) -> HTMLRequest {
-        let socketaddr = match stream.peer_addr() {
-            Ok(x) => x,
-            Err(_) => {
-                panic!()
-            }
-        };
-        let localaddr = match stream.local_addr() {
-            Ok(x) => x,
-            Err(_) => {
-                panic!()
-            }
-        };
-        let method: String;
-        let endpoint: String;
-        let version: String;
-        let mut headers: Vec<Header> = vec![];
-        let body: String;
-
-        let strings: Vec<String> = requestin
-            .trim()
-            .split("\r\n\r\n")
-            .map(|x| x.trim_end_matches("\0").to_owned())
-            .collect();
+    pub fn from_raw(
+        raw: RawRequest,
+        stream: Box<dyn Write + Send>,
+        client_address: Endpoint,
+        local_address: Endpoint,
+    ) -> HTMLRequest {
+        let (path, query) = router::split_target(&raw.target);
+        HTMLRequest {
+            method: raw.method,
+            endpoint: raw.target,
+            path,
+            query,
+            params: HashMap::new(),
+            version: raw.version,
+            header: raw.headers,
+            body: raw.body,
+            client_address,
+            local_address,
+            stream: Mutex::new(stream),
+            keep_alive: false,
+            user: None,
+            responded: AtomicBool::new(false),
+        }
+    }
+
+    /// The body decoded as UTF-8, invalid sequences replaced.
+    pub fn body_text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
 
-        body = strings[1].to_owned();
-        let headercontent: Vec<String> = strings[0].split("\n").map(|x| x.to_string()).collect();
-        let headcon = headercontent[0]
-            .as_str()
-            .to_string()
-            .split(" ")
-            .map(|x| x.to_string())
-            .collect::<Vec<String>>();
-        method = headcon[0].as_str().to_string();
-        endpoint = headcon[1].as_str().to_string();
-        version = headcon[2].as_str().to_string();
-
-        for i in 1..headercontent.len() {
-            let header = headercontent[i]
-                .as_str()
-                .to_string()
-                .split(": ")
-                .map(|x| x.trim_end_matches("\r").to_string())
-                .collect::<Vec<String>>();
-            headers.push(Header::new(header[0].as_str(), header[1].as_str()));
+    /// HTTP/1.1 keeps connections open unless told otherwise, HTTP/1.0
+    /// only when asked to.
+    pub fn wants_keep_alive(&self) -> bool {
+        let connection = self
+            .get_header("Connection")
+            .map(|x| x.value.to_lowercase())
+            .unwrap_or_default();
+        let tokens: Vec<&str> = connection.split(',').map(|x| x.trim()).collect();
+        if self.version == "HTTP/1.0" {
+            tokens.contains(&"keep-alive")
+        } else {
+            !tokens.contains(&"close")
         }
+    }
 
-        HTMLRequest {
-            method: method,
-            endpoint: endpoint,
-            version: version,
-            header: headers,
-            body: body,
-            client_address: socketaddr,
-            local_address: localaddr,
-            stream: Mutex::new(stream),
+    pub fn has_responded(&self) -> bool {
+        self.responded.load(Ordering::SeqCst)
+    }
+
+    fn connection_header(&self) -> (&'static str, &'static str) {
+        ("Connection", if self.keep_alive { "keep-alive" } else { "close" })
+    }
+
+    fn send(&self, response: u64, headers: &[(&str, &str)], body: &[u8]) {
+        let mut headers = headers.to_vec();
+        headers.push(self.connection_header());
+        self.responded.store(true, Ordering::SeqCst);
+        let mut stream = self.stream.lock().unwrap();
+        if let Err(err) = write_response(&mut *stream, response, &headers, body) {
+            eprintln!("Error occurred while responding to {}: {}", self.client_address, err);
         }
     }
 
     pub fn respond(&self, response: u64) {
-        let mut stream = self.stream.lock().unwrap();
-        let response = format!("HTTP/1.1 {}\r\n\r\n", response);
-        stream.write(response.as_bytes()).unwrap();
-        stream.flush().unwrap();
+        self.send(response, &[], &[]);
     }
 
     pub fn respond_with_body(&self, response: u64, body: String) {
-        let mut stream = self.stream.lock().unwrap();
-        let content_type = "Content-Type: text/plain"; // Example content type, can be changed as needed
-        let response = format!(
-            "HTTP/1.1 {}\r\nContent-Length: {}\r\n{}\r\n\r\n{}",
-            response,
-            body.as_bytes().len(),
-            content_type,
-            body
-        );
+        self.respond_with_content(response, "text/plain", body);
+    }
 
-        stream.write(response.as_bytes()).unwrap();
-        stream.flush().unwrap();
+    pub fn respond_with_json(&self, response: u64, body: String) {
+        self.respond_with_content(response, "application/json", body);
     }
 
-    pub fn respond_with_file(&self, file_path: &str) {
-        let mut stream = self.stream.lock().unwrap();
+    pub fn respond_with_content(&self, response: u64, content_type: &str, body: String) {
+        self.send(response, &[("Content-Type", content_type)], body.as_bytes());
+    }
+
+    pub fn respond_with_headers(&self, response: u64, headers: &[(&str, &str)], body: &[u8]) {
+        self.send(response, headers, body);
+    }
+
+    /// Whether the authenticated user, if there is one, may see `key`.
+    pub fn may_access(&self, key: &str) -> bool {
+        self.user.as_ref().is_none_or(|user| user.can_access(key))
+    }
 
+    pub fn respond_with_file(&self, file_path: &str) {
         let path = Path::new(file_path);
-        if path.is_file() {
-            let mut file = match File::open(path) {
-                Ok(x) => x,
-                Err(e) => {
-                    self.respond_with_body(500, format!("Error opening file: {}", e));
-                    return;
-                }
-            };
+        if !path.is_file() {
+            self.respond_with_body(404, String::from("File not found"));
+            return;
+        }
+        let (mut file, content_length) = match File::open(path)
+            .and_then(|file| Ok((file.metadata()?.len(), file)))
+        {
+            Ok((len, file)) => (file, len),
+            Err(e) => {
+                self.respond_with_body(500, format!("Error opening file: {}", e));
+                return;
+            }
+        };
 
-            // Send HTTP response headers
-            let content_length = file.metadata().expect("Error getting content length").len();
-            let response = format!(
-            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: {}\r\nContent-Disposition: attachment; filename=\"{}\"\r\n\r\n",
+        self.responded.store(true, Ordering::SeqCst);
+        let mut stream = self.stream.lock().unwrap();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: {}\r\nContent-Disposition: attachment; filename=\"{}\"\r\n{}: {}\r\n\r\n",
             content_length,
-            get_mime_type(path.extension().unwrap()),
-            path.file_name().unwrap().to_string_lossy()
+            get_mime_type(path.extension().unwrap_or_default()),
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            self.connection_header().0,
+            self.connection_header().1
         );
-            stream.write(response.as_bytes()).unwrap();
-            stream.flush().unwrap();
-
-            // Send file content
-            let mut buffer = [0; 1024];
-            loop {
-                match file.read(&mut buffer) {
-                    Ok(bytes_read) => {
-                        if bytes_read == 0 {
-                            break; // End of file
-                        }
-                        stream.write_all(&buffer[..bytes_read]).unwrap();
-                    }
-                    Err(e) => {
-                        if e.kind() == ErrorKind::Interrupted {
-                            continue; // Retry on interrupt
-                        }
-                        self.respond_with_body(500, format!("Error reading file: {}", e));
-                    }
-                }
-            }
-            stream.flush().unwrap();
-        } else {
-            // File not found
-            let response = format!(
-                "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}",
-                404,
-                "File not found".as_bytes().len(),
-                "File not found"
-            );
-
-            stream.write(response.as_bytes()).unwrap();
-            stream.flush().unwrap();
+        // Once the headers are out an error can only be signalled by
+        // cutting the connection short.
+        let result = stream
+            .write_all(response.as_bytes())
+            .and_then(|_| std::io::copy(&mut file, &mut *stream))
+            .and_then(|_| stream.flush());
+        if let Err(err) = result {
+            eprintln!("Error occurred while sending {}: {}", file_path, err);
         }
     }
 
     pub fn get_header(&self, key: &str) -> Option<&Header> {
+        // Header names are case-insensitive.
         for i in 0..self.header.len() {
-            if self.header[i].key == key {
+            if self.header[i].key.eq_ignore_ascii_case(key) {
                 return Some(&self.header[i]);
             }
         }
         None
     }
 
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|x| x.as_str())
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(|x| x.as_str())
+    }
+
     pub fn display(&self) -> String {
         format!(
             "Method: {}\nEndpoint: {}\nVersion: {}\nHeaders: {}Body: {}",
@@ -309,7 +514,7 @@ impl HTMLRequest {
             self.endpoint,
             self.version,
             Helper::display_list(&self.header),
-            self.body
+            self.body_text()
         )
     }
 }
@@ -317,6 +522,27 @@ impl HTMLRequest {
 pub struct HTTPServer {
     port: std::borrow::Cow<'static, str>,
     host: std::borrow::Cow<'static, str>,
+    limits: Limits,
+    idle_timeout: Duration,
+    max_requests: usize,
+    workers: usize,
+    queue_size: usize,
+    event_loops: usize,
+    resp_port: Option<String>,
+    resp_socket: Option<UnixSocket>,
+    unix_socket: Option<UnixSocket>,
+    tcp: bool,
+    tls: Option<Arc<TlsAcceptor>>,
+    auth: Option<Arc<Auth>>,
+}
+
+/// Per-connection settings handed to every connection thread.
+#[derive(Clone)]
+pub struct ConnectionOptions {
+    pub limits: Limits,
+    pub idle_timeout: Duration,
+    pub max_requests: usize,
+    pub auth: Option<Arc<Auth>>,
 }
 
 #[allow(dead_code)]
@@ -325,6 +551,18 @@ impl HTTPServer {
         HTTPServer {
             port: std::borrow::Cow::Owned("8080".to_owned()),
             host: std::borrow::Cow::Owned("localhost".to_owned()),
+            limits: Limits::default(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_requests: DEFAULT_MAX_REQUESTS,
+            workers: default_workers(),
+            queue_size: DEFAULT_QUEUE_SIZE,
+            event_loops: default_event_loops(),
+            resp_port: None,
+            resp_socket: None,
+            unix_socket: None,
+            tcp: true,
+            tls: None,
+            auth: None,
         }
     }
 
@@ -332,106 +570,619 @@ impl HTTPServer {
         HTTPServer {
             port: std::borrow::Cow::Owned(port.unwrap_or("8080").to_owned()),
             host: std::borrow::Cow::Owned(host.unwrap_or("8080").to_owned()),
+            limits: Limits::default(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_requests: DEFAULT_MAX_REQUESTS,
+            workers: default_workers(),
+            queue_size: DEFAULT_QUEUE_SIZE,
+            event_loops: default_event_loops(),
+            resp_port: None,
+            resp_socket: None,
+            unix_socket: None,
+            tcp: true,
+            tls: None,
+            auth: None,
         }
     }
 
-    fn interpret_stream(mut stream: TcpStream) -> Result<HTMLRequest, std::io::Error> {
-        let mut buffer = [0; 512];
+    pub fn set_limits(&mut self, limits: Limits) -> &mut HTTPServer {
+        self.limits = limits;
+        self
+    }
 
-        let _ = stream.read(&mut buffer);
+    /// How long a kept-alive connection may sit idle and how many requests
+    /// it may serve before it is closed. A limit of 1 disables keep-alive.
+    pub fn set_keep_alive(&mut self, idle_timeout: Duration, max_requests: usize) -> &mut HTTPServer {
+        self.idle_timeout = idle_timeout;
+        self.max_requests = max_requests.max(1);
+        self
+    }
 
-        let content = String::from_utf8_lossy(&buffer[..]).to_string();
+    /// Number of worker threads serving connections and how many accepted
+    /// connections may wait for one, anything beyond that gets a 503.
+    /// `None` keeps the current setting.
+    pub fn set_pool(&mut self, workers: Option<usize>, queue_size: Option<usize>) -> &mut HTTPServer {
+        if let Some(workers) = workers {
+            self.workers = workers.max(1);
+        }
+        if let Some(queue_size) = queue_size {
+            self.queue_size = queue_size;
+        }
+        self
+    }
 
-        if !content.trim().is_empty() && content.len() > 0 && content.lines().count() > 1 {
-            let request = HTMLRequest::from_requeststr(content, stream);
-            Ok(request)
-        } else {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Request is empty.",
-            ))
+    /// Number of event loop threads `listen_epoll` multiplexes connections on.
+    pub fn set_event_loops(&mut self, event_loops: Option<usize>) -> &mut HTTPServer {
+        if let Some(event_loops) = event_loops {
+            self.event_loops = event_loops.max(1);
         }
+        self
+    }
+
+    /// Also serves RESP clients such as redis-cli on this port.
+    pub fn set_resp_port(&mut self, port: Option<String>) -> &mut HTTPServer {
+        self.resp_port = port;
+        self
+    }
+
+    /// Also serves RESP clients on this Unix socket.
+    pub fn set_resp_socket(&mut self, socket: Option<UnixSocket>) -> &mut HTTPServer {
+        self.resp_socket = socket;
+        self
+    }
+
+    /// Also accepts connections on this Unix socket, which saves clients on
+    /// the same host the trip through the TCP stack.
+    pub fn set_unix_socket(&mut self, socket: Option<UnixSocket>) -> &mut HTTPServer {
+        self.unix_socket = socket;
+        self
+    }
+
+    /// Whether to listen on the TCP port at all. Turning it off only makes
+    /// sense with a Unix socket to listen on instead.
+    pub fn set_tcp(&mut self, enabled: bool) -> &mut HTTPServer {
+        self.tcp = enabled;
+        self
+    }
+
+    /// Speaks TLS on the TCP ports, the RESP port included.
+    pub fn set_tls(&mut self, acceptor: Option<Arc<TlsAcceptor>>) -> &mut HTTPServer {
+        self.tls = acceptor;
+        self
+    }
+
+    /// Requires every request to authenticate as one of `auth`'s users and
+    /// to stay within that user's ACL.
+    pub fn set_auth(&mut self, auth: Option<Arc<Auth>>) -> &mut HTTPServer {
+        self.auth = auth;
+        self
+    }
+
+    fn bind(&self) -> Vec<Listener> {
+        let address = format!("{}:{}", &self.host, &self.port);
+        Listener::bind_all(
+            self.tcp.then_some(address.as_str()),
+            self.unix_socket.as_ref(),
+            self.tls.as_ref(),
+        )
+            .expect("An Error occured while registering the Listener!")
+    }
+
+    fn interpret_stream(
+        reader: &mut BufReader<Stream>,
+        limits: &Limits,
+    ) -> Result<HTMLRequest, ParseError> {
+        let raw = parser::read_request(reader, limits)?;
+        let stream = reader.get_ref();
+        Ok(HTMLRequest::from_raw(
+            raw,
+            Box::new(stream.try_clone()?),
+            stream.peer_address()?,
+            stream.local_address()?,
+        ))
     }
 
     pub fn listen(
         &mut self,
         fnmap: HashMap<String, Arc<crate::handler::Function>>,
-        cache: Cache,
+        cache: ShardedCache,
+    ) -> Result<&HTTPServer, std::io::Error> {
+        let listeners = self.bind();
+
+        let cache = HTTPServer::share_cache(cache);
+        let resp = self.spawn_listeners(&cache);
+        let map: Arc<Router> = Arc::new(Router::new(fnmap));
+
+        let options = self.connection_options();
+        let pool_cache = Arc::clone(&cache);
+        let pool = ConnectionPool::new(self.workers, self.queue_size, move |stream| {
+            HTTPServer::handle_connection(stream, &map, &pool_cache, &options);
+        });
+
+        println!(
+            "Now listening on {} ({} workers, queue of {})",
+            listener::describe(&listeners),
+            self.workers,
+            self.queue_size
+        );
+
+        pool.accept(&listeners, write_busy)?;
+
+        drop(listeners);
+        if let Some(reason) = shutdown::reason() {
+            println!("Shutting down: {}", reason);
+        }
+        // The cache is flushed regardless of connections that outlive the
+        // drain timeout.
+        pool.drain();
+        HTTPServer::join_listeners(resp);
+        HTTPServer::flush(&cache);
+        Ok(self)
+    }
+
+    /// Serves requests like `listen`, but on a few non-blocking event loops
+    /// instead of a thread per connection, so idle connections cost no thread.
+    #[cfg(target_os = "linux")]
+    pub fn listen_epoll(
+        &mut self,
+        fnmap: HashMap<String, Arc<crate::handler::Function>>,
+        cache: ShardedCache,
     ) -> Result<&HTTPServer, std::io::Error> {
-        let listener = TcpListener::bind(format!("{}:{}", &self.host, &self.port))
-            .expect("An Error occured while registering the TCP Listener!");
+        let listeners = self.bind();
+        for listener in &listeners {
+            listener.set_nonblocking(true)?;
+        }
+        let listeners = Arc::new(listeners);
+
+        let cache = HTTPServer::share_cache(cache);
+        let resp = self.spawn_listeners(&cache);
+        let map: Arc<Router> = Arc::new(Router::new(fnmap));
 
-        let cache: Arc<RwLock<Cache>> = Arc::new(
-            RwLock::new(
-                cache
-            )
+        println!(
+            "Now listening on {} ({} event loops)",
+            listener::describe(&listeners),
+            self.event_loops
         );
 
-        let map: Arc<RwLock<HashMap<String, Arc<crate::handler::Function>>>> =
-            Arc::new(RwLock::new(fnmap));
+        let loops: Vec<std::thread::JoinHandle<()>> = (0..self.event_loops)
+            .map(|id| {
+                let listeners = Arc::clone(&listeners);
+                let map = Arc::clone(&map);
+                let cache = Arc::clone(&cache);
+                let options = self.connection_options();
+                std::thread::Builder::new()
+                    .name(format!("event-loop-{}", id))
+                    .spawn(move || match EventLoop::new(listeners, map, cache, options) {
+                        Ok(mut event_loop) => event_loop.run(),
+                        Err(err) => eprintln!("Error occurred while starting an event loop: {}", err),
+                    })
+                    .expect("An Error occured while spawning an event loop!")
+            })
+            .collect();
+        // Every loop returns once a shutdown has been requested.
+        for handle in loops {
+            let _ = handle.join();
+        }
+
+        if let Some(reason) = shutdown::reason() {
+            println!("Shutting down: {}", reason);
+        }
+        HTTPServer::join_listeners(resp);
+        HTTPServer::flush(&cache);
+        Ok(self)
+    }
+
+    /// Shares the cache between threads and starts its background threads.
+    pub fn share_cache(cache: ShardedCache) -> Arc<ShardedCache> {
+        let cache = Arc::new(cache);
+
+        ShardedCache::spawn_reaper(&cache, REAPER_INTERVAL);
+        if let Some(interval) = cache.save_interval() {
+            ShardedCache::spawn_snapshotter(&cache, interval);
+        }
+        if let Some(size) = cache.log_rewrite_size() {
+            ShardedCache::spawn_log_rewriter(&cache, size);
+        }
+        cache
+    }
+
+    /// Starts the listeners for other protocols sharing the cache. They
+    /// stop on their own once a shutdown is requested.
+    fn spawn_listeners(&self, cache: &Arc<ShardedCache>) -> Option<JoinHandle<()>> {
+        let address = self
+            .resp_port
+            .as_ref()
+            .map(|port| format!("{}:{}", &self.host, port));
+        let listeners = Listener::bind_all(address.as_deref(), self.resp_socket.as_ref(), self.tls.as_ref())
+            .expect("An Error occured while registering the RESP Listener!");
+        if listeners.is_empty() {
+            return None;
+        }
+        let cache = Arc::clone(cache);
+        let auth = self.auth.clone();
+        let pool = ConnectionPool::new(self.workers, self.queue_size, move |stream| {
+            resp::handle_connection(stream, &cache, auth.as_deref());
+        });
+        Some(
+            resp::spawn_listener(listeners, pool)
+                .expect("An Error occured while registering the RESP Listener!"),
+        )
+    }
+
+    /// Waits for the listeners from `spawn_listeners` to drain their
+    /// connections, so none of them still writes once the cache is flushed.
+    fn join_listeners(handle: Option<JoinHandle<()>>) {
+        if let Some(handle) = handle {
+            let _ = handle.join();
+        }
+    }
 
-        println!("Now listening on {} with port {}", &self.host, &self.port);
+    fn connection_options(&self) -> ConnectionOptions {
+        ConnectionOptions {
+            limits: self.limits,
+            idle_timeout: self.idle_timeout,
+            max_requests: self.max_requests,
+            auth: self.auth.clone(),
+        }
+    }
 
-        for stream in listener.incoming() {
+    /// Serves requests on one connection until the client closes it, asks
+    /// for `Connection: close`, stays idle for too long or reaches the
+    /// request limit. Pipelined requests are answered in order since each
+    /// one is read from the same buffered reader after the previous
+    /// response went out.
+    fn handle_connection(
+        stream: Stream,
+        router: &Router,
+        cache: &ShardedCache,
+        options: &ConnectionOptions,
+    ) {
+        if let Err(err) = stream.set_read_timeout(Some(options.idle_timeout)) {
+            eprintln!("Error occurred while configuring the connection: {}", err);
+        }
+        let mut reader = BufReader::new(stream);
+        let mut served = 0;
+        loop {
             let start = Instant::now();
-            let stream: TcpStream = stream.unwrap();
-            let arc_clone = Arc::clone(&map);
-            let cache_clone = Arc::clone(&cache);
-            std::thread::spawn(move || {
-                let request = match HTTPServer::interpret_stream(stream) {
-                    Ok(req) => req,
-                    Err(err) => {
-                        eprintln!("Error occurred while interpreting the stream: \n {} ", err);
-                        return;
-                    }
-                };
-
-                if let Some(func) = arc_clone.write().unwrap().get(&request.endpoint.to_owned()) {
-                    let methods = match &func.methods {
-                        Some(methods) => methods.clone(),
-                        None => vec![
-                            String::from("GET"),
-                            String::from("HEAD"),
-                            String::from("POST"),
-                            String::from("PUT"),
-                            String::from("DELETE"),
-                            String::from("CONNECT"),
-                            String::from("OPTIONS"),
-                            String::from("TRACE"),
-                            String::from("PATCH"),
-                        ],
-                    };
-                    // Check if the method is viable for the function
-                    if methods.contains(&request.method) {
-                        // Execute the Fn(Request) method
-                        // function is a property containing the Arc<dyn Fn(request)> function,
-                        match func.function.as_ref()(&request, &mut cache_clone.write().unwrap()) {
-                            Ok(_msg) =>
-                                /*println!("Got message: {}", _msg)*/
-                                {}
-                            Err(err) => eprintln!("Error occurred: {}", err),
-                        }
-                    } else {
-                        request.respond_with_body(
-                            405,
-                            format!(
-                                "Unsupported method. Supported methods: {}",
-                                methods.join(", ")
-                            ),
+            let mut request = match HTTPServer::interpret_stream(&mut reader, &options.limits) {
+                Ok(req) => req,
+                Err(ParseError::Closed) => return,
+                Err(ParseError::Io(err))
+                    if err.kind() == std::io::ErrorKind::WouldBlock
+                        || err.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    // Idle timeout, just hang up.
+                    return;
+                }
+                Err(err) => {
+                    if let Some(status) = err.status() {
+                        let _ = write_response(
+                            reader.get_mut(),
+                            status,
+                            &[("Content-Type", "text/plain"), ("Connection", "close")],
+                            err.to_string().as_bytes(),
                         );
                     }
-                    println!(
-                        "Received request from {} on local {}{} - {}",
-                        request.client_address.to_string(),
-                        request.local_address.to_string(),
-                        request.endpoint.to_owned(),
-                        format_duration(start.elapsed())
-                    );
-                } else {
-                    request.respond(404);
+                    eprintln!("Error occurred while interpreting the stream: \n {} ", err);
+                    return;
                 }
-            });
+            };
+            served += 1;
+            stats::SERVER.total_requests.fetch_add(1, Ordering::SeqCst);
+            request.keep_alive = request.wants_keep_alive()
+                && served < options.max_requests
+                && !shutdown::is_requested();
+
+            HTTPServer::serve(&mut request, router, cache, options.auth.as_deref(), start);
+            if !request.keep_alive {
+                return;
+            }
         }
-        Ok(self)
+    }
+
+    /// Dispatches a parsed request and logs it. `start` is when reading it began.
+    pub fn serve(
+        request: &mut HTMLRequest,
+        router: &Router,
+        cache: &ShardedCache,
+        auth: Option<&Auth>,
+        start: Instant,
+    ) {
+        HTTPServer::dispatch(request, router, cache, auth);
+        println!(
+            "Received request from {} on local {}{} - {}",
+            request.client_address,
+            request.local_address,
+            request.endpoint.to_owned(),
+            format_duration(start.elapsed())
+        );
+    }
+
+    /// Routes a request to its function and makes sure it gets exactly one
+    /// response, even if the function doesn't send one itself.
+    pub(crate) fn dispatch(request: &mut HTMLRequest, router: &Router, cache: &ShardedCache, auth: Option<&Auth>) {
+        // Credentials come first, so clients that aren't let in can't even
+        // find out which routes exist.
+        let user = match auth.map(|auth| auth.authenticate(request)) {
+            Some(Ok(user)) => Some(user),
+            Some(Err(err)) => {
+                HTTPServer::deny(request, auth, err);
+                return;
+            }
+            None => None,
+        };
+        let route = match router.find(&request.path) {
+            Some(route) => route,
+            None => {
+                request.respond(404);
+                return;
+            }
+        };
+        request.params = route.params;
+        if let (Some(auth), Some(user)) = (auth, &user) {
+            if let Err(err) = auth.authorize(user, &request.method, request.param("key")) {
+                HTTPServer::deny(request, Some(auth), err);
+                return;
+            }
+        }
+        request.user = user;
+        let func = route.function;
+        let methods = match &func.methods {
+            Some(methods) => methods.clone(),
+            None => vec![
+                String::from("GET"),
+                String::from("HEAD"),
+                String::from("POST"),
+                String::from("PUT"),
+                String::from("DELETE"),
+                String::from("CONNECT"),
+                String::from("OPTIONS"),
+                String::from("TRACE"),
+                String::from("PATCH"),
+            ],
+        };
+        // Check if the method is viable for the function
+        if !methods.contains(&request.method) {
+            request.respond_with_body(
+                405,
+                format!(
+                    "Unsupported method. Supported methods: {}",
+                    methods.join(", ")
+                ),
+            );
+            return;
+        }
+        // Execute the Fn(Request) method
+        // function is a property containing the Arc<dyn Fn(request)> function,
+        // Handlers lock the shards they need themselves, reads only take read locks.
+        let result = func.function.as_ref()(request, cache);
+        match result {
+            Ok(msg) => {
+                if !request.has_responded() {
+                    request.respond_with_body(200, msg);
+                }
+            }
+            Err(err) => {
+                eprintln!("Error occurred: {}", err);
+                if !request.has_responded() {
+                    request.respond_with_body(500, err.to_string());
+                }
+            }
+        }
+    }
+
+    /// Answers a request that failed authentication with 401, or 403 if the
+    /// user is known but not allowed to do this.
+    fn deny(request: &HTMLRequest, auth: Option<&Auth>, err: AuthError) {
+        let mut headers = vec![("Content-Type", "text/plain")];
+        if err.status() == 401 {
+            for challenge in auth.map(|auth| auth.challenges()).unwrap_or_default() {
+                headers.push(("WWW-Authenticate", challenge));
+            }
+        }
+        request.respond_with_headers(err.status(), &headers, err.to_string().as_bytes());
+    }
+
+    /// Waits until every accepted connection has finished or `timeout`
+    /// passed. Returns whether all of them finished.
+    fn drain(in_flight: &Arc<AtomicUsize>, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while in_flight.load(Ordering::SeqCst) > 0 {
+            if start.elapsed() >= timeout {
+                eprintln!(
+                    "Giving up on {} in-flight requests after {}",
+                    in_flight.load(Ordering::SeqCst),
+                    format_duration(timeout)
+                );
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    /// Saves the cache one last time.
+    pub fn flush(cache: &ShardedCache) {
+        match cache.flush() {
+            Ok(count) => println!(
+                "Saved {} entries to {}",
+                count,
+                cache.snapshot_path().to_string_lossy()
+            ),
+            Err(err) => eprintln!("Error occurred while saving the cache: {}", err),
+        }
+    }
+}
+
+// Connections are served over Unix socket pairs.
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+    use crate::handler::FuncHelper;
+
+    struct Response {
+        status: u64,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Response {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    /// Splits what the server sent into responses, bodies delimited by
+    /// `Content-Length` or missing.
+    fn split_responses(mut out: &[u8]) -> Vec<Response> {
+        let mut responses = Vec::new();
+        while !out.is_empty() {
+            let end = out.windows(4).position(|x| x == b"\r\n\r\n").unwrap();
+            let head = std::str::from_utf8(&out[..end]).unwrap();
+            let mut lines = head.split("\r\n");
+            let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+            let headers: Vec<(String, String)> = lines
+                .map(|line| {
+                    let (key, value) = line.split_once(": ").unwrap();
+                    (key.to_string(), value.to_string())
+                })
+                .collect();
+            let length = headers
+                .iter()
+                .find(|(key, _)| key == "Content-Length")
+                .map_or(0, |(_, value)| value.parse().unwrap());
+            let body = out[end + 4..end + 4 + length].to_vec();
+            out = &out[end + 4 + length..];
+            responses.push(Response { status, headers, body });
+        }
+        responses
+    }
+
+    fn options(idle_timeout: Duration, max_requests: usize) -> ConnectionOptions {
+        ConnectionOptions {
+            limits: Limits::default(),
+            idle_timeout,
+            max_requests,
+            auth: None,
+        }
+    }
+
+    /// Serves one connection that sends `input`, half closing it afterwards
+    /// if `close` is set. Returns the responses once the server hung up.
+    fn converse(input: &[u8], close: bool, options: &ConnectionOptions) -> Vec<Response> {
+        let router = Router::new(FuncHelper::new().get_func_map_raw());
+        let cache = ShardedCache::in_memory(2);
+        let (server, mut client) = UnixStream::pair().unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        std::thread::scope(|scope| {
+            scope.spawn(|| HTTPServer::handle_connection(Stream::Unix(server), &router, &cache, options));
+            client.write_all(input).unwrap();
+            if close {
+                client.shutdown(std::net::Shutdown::Write).unwrap();
+            }
+            // Reading to the end only finishes once the server hung up.
+            let mut out = Vec::new();
+            client.read_to_end(&mut out).unwrap();
+            split_responses(&out)
+        })
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order() {
+        let input = b"PUT /keys/a HTTP/1.1\r\nContent-Length: 1\r\n\r\n1\
+                      GET /keys/a HTTP/1.1\r\n\r\n\
+                      GET /keys/b HTTP/1.1\r\n\r\n\
+                      DELETE /keys/a HTTP/1.1\r\n\r\n\
+                      GET /keys/a HTTP/1.1\r\n\r\n";
+        let responses = converse(input, true, &options(DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_REQUESTS));
+        let statuses: Vec<u64> = responses.iter().map(|x| x.status).collect();
+        assert_eq!(statuses, vec![201, 200, 404, 204, 404]);
+        assert!(responses.iter().all(|x| x.header("Connection") == Some("keep-alive")));
+        assert!(String::from_utf8_lossy(&responses[1].body).contains("\"value\":\"1\""));
+    }
+
+    #[test]
+    fn closes_when_asked_to() {
+        let input = b"GET /keys HTTP/1.1\r\n\r\n\
+                      GET /keys HTTP/1.1\r\nConnection: keep-alive, close\r\n\r\n\
+                      GET /keys HTTP/1.1\r\n\r\n";
+        // Left open by the client, so only the server can end it.
+        let responses = converse(input, false, &options(DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_REQUESTS));
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].header("Connection"), Some("keep-alive"));
+        assert_eq!(responses[1].header("Connection"), Some("close"));
+    }
+
+    #[test]
+    fn http_1_0_only_keeps_alive_on_request() {
+        let options = options(DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_REQUESTS);
+        let responses = converse(b"GET /keys HTTP/1.0\r\n\r\nGET /keys HTTP/1.0\r\n\r\n", false, &options);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].header("Connection"), Some("close"));
+
+        let input = b"GET /keys HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n\
+                      GET /keys HTTP/1.0\r\n\r\n\
+                      GET /keys HTTP/1.0\r\n\r\n";
+        let responses = converse(input, false, &options);
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].header("Connection"), Some("keep-alive"));
+        assert_eq!(responses[1].header("Connection"), Some("close"));
+    }
+
+    #[test]
+    fn closes_after_max_requests() {
+        let input = b"GET /keys HTTP/1.1\r\n\r\n".repeat(5);
+        let responses = converse(&input, false, &options(DEFAULT_IDLE_TIMEOUT, 3));
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[2].header("Connection"), Some("close"));
+    }
+
+    #[test]
+    fn closes_idle_and_malformed_connections() {
+        let start = Instant::now();
+        assert!(converse(b"", false, &options(Duration::from_millis(50), DEFAULT_MAX_REQUESTS)).is_empty());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Half a request doesn't hold the connection open either.
+        let responses = converse(b"GET /keys HTTP/1.1\r\n", false, &options(Duration::from_millis(50), 10));
+        assert!(responses.is_empty());
+
+        let input = b"GET /keys HTTP/1.1\r\n\r\nNOT A REQUEST\r\n\r\nGET /keys HTTP/1.1\r\n\r\n";
+        let responses = converse(input, false, &options(DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_REQUESTS));
+        let statuses: Vec<u64> = responses.iter().map(|x| x.status).collect();
+        assert_eq!(statuses, vec![200, 400]);
+        assert_eq!(responses[1].header("Connection"), Some("close"));
+    }
+
+    #[test]
+    fn bodyless_statuses_have_no_body() {
+        for status in [204, 304, 101] {
+            let mut out = Vec::new();
+            write_response(&mut out, status, &[("ETag", "\"1\"")], b"ignored").unwrap();
+            assert_eq!(
+                String::from_utf8(out).unwrap(),
+                format!("HTTP/1.1 {} {}\r\nETag: \"1\"\r\n\r\n", status, status_text(status))
+            );
+        }
+        let mut out = Vec::new();
+        write_response(&mut out, 200, &[], b"").unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+
+        // A 304 from the handlers ends right after its head, so the next
+        // response on the connection starts where it should.
+        let input = b"PUT /keys/a HTTP/1.1\r\nContent-Length: 1\r\n\r\n1\
+                      GET /keys/a HTTP/1.1\r\nIf-None-Match: *\r\n\r\n\
+                      DELETE /keys/a HTTP/1.1\r\n\r\n\
+                      GET /keys HTTP/1.1\r\n\r\n";
+        let responses = converse(input, true, &options(DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_REQUESTS));
+        let statuses: Vec<u64> = responses.iter().map(|x| x.status).collect();
+        assert_eq!(statuses, vec![201, 304, 204, 200]);
+        assert!(responses[1].body.is_empty() && responses[2].body.is_empty());
+        assert_eq!(responses[3].body, b"{\"keys\":[]}");
     }
 }
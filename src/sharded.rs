@@ -0,0 +1,767 @@
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, BTreeSet},
+    hash::{BuildHasher, Hash, RandomState},
+    io::Error,
+    ops::{Deref, DerefMut},
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::aof::{self, AppendLog, FsyncPolicy, Journal, SharedLog};
+use crate::cache::{Budget, Cache, CacheError, CacheEvent, CacheValue, Weigher};
+use crate::collections;
+use crate::eviction::EvictionPolicy;
+use crate::snapshot;
+
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// A cache split by key hash into shards that each sit behind their own
+/// lock, so requests for different keys don't wait on each other. Work on
+/// a single key only locks its shard, the few operations spanning keys
+/// lock the shards they need in shard order.
+///
+/// `max_memory` and `max_entries` hold for all shards together: the shards
+/// count against one shared `Budget`, and a write that takes it over the
+/// limit evicts from the shard it went to.
+pub struct ShardedCache<K = String, V = CacheValue> {
+    shards: Box<[RwLock<Cache<K, V>>]>,
+    hasher: RandomState,
+    budget: Arc<Budget>,
+    max_memory: Option<usize>,
+    max_entries: Option<usize>,
+    // Settings kept here rather than read back from a shard, whose lock a
+    // panicking writer may have poisoned by the time the cache is saved.
+    eviction_policy: &'static str,
+    savelocation: String,
+    save_interval: Option<Duration>,
+    log_rewrite_size: Option<u64>,
+    // Kept apart from the shards' copies to measure and truncate it.
+    log: Option<SharedLog>,
+    // Held from encoding a snapshot until the log is truncated, so saves
+    // and checkpoints don't overwrite each other's work.
+    saving: Mutex<()>,
+}
+
+/// Locks on the shards holding a group of keys, see `read_keys` and `write_keys`.
+pub struct Locked<'a, K, V, G> {
+    cache: &'a ShardedCache<K, V>,
+    guards: BTreeMap<usize, G>,
+}
+
+impl<K: Hash + Eq + Clone + Send + 'static, V, G: Deref<Target = Cache<K, V>>> Locked<'_, K, V, G> {
+    /// The shard holding `key`, which has to be one of the locked keys.
+    pub fn get<Q>(&self, key: &Q) -> &Cache<K, V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized,
+    {
+        &self.guards[&self.cache.shard_index(key)]
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send + 'static, V, G: DerefMut<Target = Cache<K, V>>> Locked<'_, K, V, G> {
+    pub fn get_mut<Q>(&mut self, key: &Q) -> &mut Cache<K, V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized,
+    {
+        let index = self.cache.shard_index(key);
+        self.guards.get_mut(&index).unwrap()
+    }
+}
+
+#[allow(dead_code)]
+impl<K: Hash + Eq + Clone + Send + 'static, V> ShardedCache<K, V> {
+    /// An empty cache of `shards` in-memory shards.
+    pub fn in_memory(shards: usize) -> ShardedCache<K, V> {
+        ShardedCache::from_shards((0..shards.max(1)).map(|_| Cache::in_memory()).collect())
+    }
+
+    fn from_shards(shards: Vec<Cache<K, V>>) -> ShardedCache<K, V> {
+        let budget = Arc::new(Budget::default());
+        ShardedCache {
+            shards: shards
+                .into_iter()
+                .map(|mut shard| {
+                    shard.set_budget(Some(Arc::clone(&budget)));
+                    RwLock::new(shard)
+                })
+                .collect(),
+            hasher: RandomState::new(),
+            budget,
+            max_memory: None,
+            max_entries: None,
+            eviction_policy: "lru",
+            savelocation: String::new(),
+            save_interval: None,
+            log_rewrite_size: None,
+            log: None,
+            saving: Mutex::new(()),
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Index of the shard `key` lives in.
+    pub fn shard_index<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized,
+    {
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    /// Read access to the shard holding `key`.
+    pub fn read<Q>(&self, key: &Q) -> RwLockReadGuard<'_, Cache<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized,
+    {
+        self.shards[self.shard_index(key)].read().unwrap()
+    }
+
+    /// Write access to the shard holding `key`.
+    pub fn write<Q>(&self, key: &Q) -> RwLockWriteGuard<'_, Cache<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized,
+    {
+        self.shards[self.shard_index(key)].write().unwrap()
+    }
+
+    fn indices<'a, Q>(&self, keys: impl IntoIterator<Item = &'a Q>) -> BTreeSet<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized + 'a,
+    {
+        keys.into_iter().map(|key| self.shard_index(key)).collect()
+    }
+
+    /// Read access to every shard holding one of `keys`, for reads that
+    /// have to see the keys at the same point in time.
+    pub fn read_keys<'a, Q>(
+        &self,
+        keys: impl IntoIterator<Item = &'a Q>,
+    ) -> Locked<'_, K, V, RwLockReadGuard<'_, Cache<K, V>>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized + 'a,
+    {
+        let guards = self
+            .indices(keys)
+            .into_iter()
+            .map(|index| (index, self.shards[index].read().unwrap()))
+            .collect();
+        Locked { cache: self, guards }
+    }
+
+    /// Write access to every shard holding one of `keys`, for writes that
+    /// have to happen together.
+    pub fn write_keys<'a, Q>(
+        &self,
+        keys: impl IntoIterator<Item = &'a Q>,
+    ) -> Locked<'_, K, V, RwLockWriteGuard<'_, Cache<K, V>>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized + 'a,
+    {
+        let guards = self
+            .indices(keys)
+            .into_iter()
+            .map(|index| (index, self.shards[index].write().unwrap()))
+            .collect();
+        Locked { cache: self, guards }
+    }
+
+    /// Read access to every shard at once, in shard order.
+    pub fn read_all(&self) -> Vec<RwLockReadGuard<'_, Cache<K, V>>> {
+        self.shards.iter().map(|shard| shard.read().unwrap()).collect()
+    }
+
+    /// Write access to every shard at once, in shard order.
+    pub fn write_all(&self) -> Vec<RwLockWriteGuard<'_, Cache<K, V>>> {
+        self.shards.iter().map(|shard| shard.write().unwrap()).collect()
+    }
+
+    // Sums something over the shards, locking one at a time.
+    fn sum(&self, count: impl Fn(&Cache<K, V>) -> usize) -> usize {
+        self.shards.iter().map(|shard| count(&shard.read().unwrap())).sum()
+    }
+
+    /// Limits the approximate memory used by keys and values, in bytes.
+    pub fn set_max_memory(&mut self, max_memory: Option<usize>) -> &mut ShardedCache<K, V> {
+        self.max_memory = max_memory;
+        self.apply_limits();
+        self
+    }
+
+    pub fn set_max_entries(&mut self, max_entries: Option<usize>) -> &mut ShardedCache<K, V> {
+        self.max_entries = max_entries;
+        self.apply_limits();
+        self
+    }
+
+    // Hands the limits to every shard, then evicts a key from each in turn
+    // so a lowered limit doesn't empty the first shard before the others.
+    fn apply_limits(&mut self) {
+        for shard in self.shards.iter_mut() {
+            shard.get_mut().unwrap().set_limits(self.max_memory, self.max_entries);
+        }
+        let mut evicted = true;
+        while evicted {
+            evicted = false;
+            for shard in self.shards.iter_mut() {
+                evicted |= shard.get_mut().unwrap().evict_one();
+            }
+        }
+    }
+
+    /// Gives every shard its own policy made by `policy`.
+    pub fn set_eviction_policy(
+        &mut self,
+        policy: impl Fn() -> Box<dyn EvictionPolicy<K>>,
+    ) -> &mut ShardedCache<K, V> {
+        for shard in self.shards.iter_mut() {
+            shard.get_mut().unwrap().set_eviction_policy(policy());
+        }
+        self.eviction_policy = self.shards[0].get_mut().unwrap().eviction_policy();
+        self
+    }
+
+    pub fn set_weigher(&mut self, weigher: Weigher<K, V>) -> &mut ShardedCache<K, V> {
+        for shard in self.shards.iter_mut() {
+            shard.get_mut().unwrap().set_weigher(weigher);
+        }
+        self
+    }
+
+    pub fn max_memory(&self) -> Option<usize> {
+        self.max_memory
+    }
+
+    pub fn max_entries(&self) -> Option<usize> {
+        self.max_entries
+    }
+
+    pub fn eviction_policy(&self) -> &'static str {
+        self.eviction_policy
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.budget.used_memory()
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.sum(|shard| shard.evictions() as usize) as u64
+    }
+
+    pub fn unsaved_changes(&self) -> u64 {
+        self.sum(|shard| shard.unsaved_changes() as usize) as u64
+    }
+
+    /// Live keys of every shard. Shards are read one after another, so this
+    /// isn't a consistent view while writes are going on.
+    pub fn keys(&self) -> Vec<K> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.read().unwrap().keys())
+            .collect()
+    }
+
+    /// Up to `count` live keys from `cursor` on and the cursor to continue
+    /// from, 0 once the walk is done. Start with 0. A cursor is a key hash:
+    /// the shards are walked in order and each one by ascending hash, so a
+    /// key present for the whole walk is returned exactly once, whatever is
+    /// written in between, without sorting or copying the keys of shards
+    /// the page doesn't reach.
+    pub fn scan(&self, cursor: u64, count: usize) -> (Vec<K>, u64) {
+        let count = count.max(1);
+        let shards = self.shards.len() as u64;
+        let mut cursor = cursor;
+        let mut page = Vec::new();
+        loop {
+            // Every key of a shard hashes to its index modulo the shard count.
+            let index = cursor % shards;
+            let shard = self.shards[index as usize].read().unwrap();
+            let mut candidates: Vec<(u64, &K)> = shard
+                .live_keys()
+                .map(|key| (self.hasher.hash_one(key), key))
+                .filter(|(hash, _)| *hash >= cursor)
+                .collect();
+            let wanted = count - page.len();
+            if candidates.len() > wanted {
+                candidates.select_nth_unstable_by_key(wanted, |(hash, _)| *hash);
+                let next = candidates[wanted].0;
+                // Keys sharing the next hash are left for the next page with it.
+                page.extend(
+                    candidates[..wanted]
+                        .iter()
+                        .filter(|(hash, _)| *hash < next)
+                        .map(|(_, key)| (*key).clone()),
+                );
+                return (page, next);
+            }
+            page.extend(candidates.into_iter().map(|(_, key)| key.clone()));
+            if index + 1 == shards {
+                return (page, 0);
+            }
+            // The smallest hash the next shard can hold.
+            cursor = index + 1;
+            if page.len() == count {
+                return (page, cursor);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sum(|shard| shard.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops every entry. Shards share the write log and subscribers, so
+    /// the first one records and announces the clear for all of them.
+    pub fn clear(&self) {
+        let mut shards = self.write_all();
+        for shard in shards.iter_mut().skip(1) {
+            shard.clear_entries();
+        }
+        shards[0].clear();
+    }
+
+    /// Returns a receiver for every change made to any shard from now on.
+    pub fn subscribe(&self) -> Receiver<CacheEvent<K>> {
+        let (sender, receiver) = mpsc::channel();
+        for shard in self.shards.iter() {
+            shard.write().unwrap().add_subscriber(sender.clone());
+        }
+        receiver
+    }
+
+    /// Removes every expired entry, one shard at a time, and returns how
+    /// many were dropped.
+    pub fn purge_expired(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.write().unwrap().purge_expired())
+            .sum()
+    }
+
+    /// Spawns a thread that periodically purges expired entries. The thread
+    /// stops on its own once the cache has been dropped.
+    pub fn spawn_reaper(cache: &Arc<ShardedCache<K, V>>, interval: Duration) -> JoinHandle<()>
+    where
+        V: Send + Sync + 'static,
+        K: Sync,
+    {
+        let weak = Arc::downgrade(cache);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let cache = match weak.upgrade() {
+                Some(cache) => cache,
+                None => return,
+            };
+            let purged = cache.purge_expired();
+            if purged > 0 {
+                println!("Reaper removed {} expired entries", purged);
+            }
+        })
+    }
+}
+
+// Persistence and the typed operations spanning keys, for the cache the
+// server runs. All shards share one snapshot file and one write log.
+#[allow(dead_code)]
+impl ShardedCache {
+    pub fn new(savelocation: String, shards: usize) -> ShardedCache {
+        ShardedCache {
+            savelocation: savelocation.clone(),
+            ..ShardedCache::from_shards(
+                (0..shards.max(1))
+                    .map(|_| Cache::new(savelocation.clone()))
+                    .collect(),
+            )
+        }
+    }
+
+    /// How often the server writes a snapshot in the background, `None` to disable.
+    pub fn set_save_interval(&mut self, interval: Option<Duration>) -> &mut ShardedCache {
+        self.save_interval = interval;
+        self
+    }
+
+    pub fn save_interval(&self) -> Option<Duration> {
+        self.save_interval
+    }
+
+    /// Log size in bytes after which the server folds the write log into a
+    /// fresh snapshot, `None` to only do so on the regular snapshot interval.
+    pub fn set_log_rewrite_size(&mut self, size: Option<u64>) -> &mut ShardedCache {
+        self.log_rewrite_size = size;
+        self
+    }
+
+    pub fn log_rewrite_size(&self) -> Option<u64> {
+        self.log_rewrite_size
+    }
+
+    /// Size of the write log in bytes, `None` if it is disabled.
+    pub fn log_size(&self) -> Option<u64> {
+        self.log.as_ref().map(|log| log.size())
+    }
+
+    pub fn snapshot_path(&self) -> PathBuf {
+        PathBuf::from(&self.savelocation).join(snapshot::FILE_NAME)
+    }
+
+    pub fn log_path(&self) -> PathBuf {
+        PathBuf::from(&self.savelocation).join(aof::FILE_NAME)
+    }
+
+    /// Loads the snapshot, handing every entry to its shard. Returns the
+    /// number of entries restored.
+    pub fn load(&mut self) -> Result<usize, Error> {
+        let entries = match snapshot::read(&self.snapshot_path())? {
+            Some(entries) => entries,
+            None => return Ok(0),
+        };
+        let mut loaded = 0;
+        for entry in entries {
+            let index = self.shard_index(entry.key.as_str());
+            if self.shards[index].get_mut().unwrap().restore(entry) {
+                loaded += 1;
+            }
+        }
+        for shard in self.shards.iter_mut() {
+            let shard = shard.get_mut().unwrap();
+            shard.mark_saved(shard.unsaved_changes());
+        }
+        Ok(loaded)
+    }
+
+    /// Replays the write log on top of the current contents and from then
+    /// on has every shard record its mutations to it. Returns the number of
+    /// replayed operations.
+    pub fn enable_log(&mut self, policy: FsyncPolicy) -> Result<usize, Error> {
+        let path = self.log_path();
+        let operations = aof::replay(&path)?;
+        let replayed = operations.len();
+        for operation in operations {
+            match operation.key() {
+                Some(key) => {
+                    let index = self.shard_index(key);
+                    self.shards[index].get_mut().unwrap().apply(operation);
+                }
+                None => {
+                    for shard in self.shards.iter_mut() {
+                        shard.get_mut().unwrap().clear();
+                    }
+                }
+            }
+        }
+        let log = SharedLog::new(AppendLog::open(path, policy)?);
+        for shard in self.shards.iter_mut() {
+            shard.get_mut().unwrap().set_journal(Some(Box::new(log.clone())));
+        }
+        self.log = Some(log);
+        Ok(replayed)
+    }
+
+    /// Encodes every shard into one snapshot while holding all of their
+    /// read locks, so no write lands halfway. Returns the entry count, the
+    /// snapshot, the changes per shard it covers and the log size at that
+    /// point. A panicking writer may have poisoned a shard, its data is
+    /// still worth saving.
+    fn encode_snapshot(&self) -> (usize, Vec<u8>, Vec<u64>, Option<u64>) {
+        let shards: Vec<RwLockReadGuard<'_, Cache>> = self
+            .shards
+            .iter()
+            .map(|shard| shard.read().unwrap_or_else(|poisoned| poisoned.into_inner()))
+            .collect();
+        let live: Vec<(&str, &CacheValue, Option<u64>)> =
+            shards.iter().flat_map(|shard| shard.live_entries()).collect();
+        let changes = shards.iter().map(|shard| shard.unsaved_changes()).collect();
+        (
+            live.len(),
+            snapshot::encode(live.len(), live.into_iter()),
+            changes,
+            self.log_size(),
+        )
+    }
+
+    // Writes out an encoded snapshot and takes what it covers off the
+    // unsaved changes of every shard.
+    fn write_snapshot(&self, data: &[u8], changes: Vec<u64>) -> Result<(), Error> {
+        snapshot::write_atomic(&self.snapshot_path(), data)?;
+        for (shard, changes) in self.shards.iter().zip(changes) {
+            shard
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .mark_saved(changes);
+        }
+        Ok(())
+    }
+
+    /// Keeps other saves out until the guard is dropped. A save that
+    /// panicked left nothing half done that the next one could trip over.
+    fn lock_saving(&self) -> MutexGuard<'_, ()> {
+        self.saving.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Encodes and writes a snapshot, the caller holds `saving`.
+    fn save_locked(&self) -> Result<usize, Error> {
+        let (count, data, changes, _) = self.encode_snapshot();
+        self.write_snapshot(&data, changes)?;
+        Ok(count)
+    }
+
+    /// Writes every live entry to the snapshot file and returns the number
+    /// of entries written.
+    pub fn save(&self) -> Result<usize, Error> {
+        let _saving = self.lock_saving();
+        self.save_locked()
+    }
+
+    /// Saves a snapshot and syncs the write log, used when shutting down.
+    pub fn flush(&self) -> Result<usize, Error> {
+        let _saving = self.lock_saving();
+        let count = self.save_locked()?;
+        if let Some(mut log) = self.log.clone() {
+            log.sync()?;
+        }
+        Ok(count)
+    }
+
+    /// Writes a snapshot and drops the part of the write log it covers.
+    /// Records appended while the snapshot is written stay in the log.
+    pub fn checkpoint(&self) -> Result<usize, Error> {
+        let _saving = self.lock_saving();
+        let (count, data, changes, log_offset) = self.encode_snapshot();
+        self.write_snapshot(&data, changes)?;
+        if let (Some(offset), Some(mut log)) = (log_offset, self.log.clone()) {
+            log.truncate_front(offset)?;
+        }
+        Ok(count)
+    }
+
+    /// Spawns a thread that checkpoints the cache every `interval` if
+    /// anything changed.
+    pub fn spawn_snapshotter(cache: &Arc<ShardedCache>, interval: Duration) -> JoinHandle<()> {
+        let weak = Arc::downgrade(cache);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let cache = match weak.upgrade() {
+                Some(cache) => cache,
+                None => return,
+            };
+            if cache.unsaved_changes() == 0 {
+                continue;
+            }
+            if let Err(err) = cache.checkpoint() {
+                eprintln!("Error occurred while saving snapshot: {}", err);
+            }
+        })
+    }
+
+    /// Spawns a thread that checkpoints the cache as soon as the write log
+    /// grows beyond `max_size` bytes.
+    pub fn spawn_log_rewriter(cache: &Arc<ShardedCache>, max_size: u64) -> JoinHandle<()> {
+        let weak = Arc::downgrade(cache);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(1));
+            let cache = match weak.upgrade() {
+                Some(cache) => cache,
+                None => return,
+            };
+            let size = match cache.log_size() {
+                Some(size) => size,
+                None => continue,
+            };
+            if size > max_size {
+                match cache.checkpoint() {
+                    Ok(_) => println!(
+                        "Rewrote write log of {} bytes into a snapshot",
+                        size
+                    ),
+                    Err(err) => eprintln!("Error occurred while rewriting write log: {}", err),
+                }
+            }
+        })
+    }
+
+    /// Members found in every one of the sets, missing keys count as empty.
+    pub fn set_intersect(&self, keys: &[String]) -> Result<BTreeSet<String>, CacheError> {
+        let shards = self.read_keys(keys.iter().map(|key| key.as_str()));
+        collections::intersect(keys.iter().map(|key| shards.get(key.as_str()).set_members(key)))
+    }
+
+    /// Members found in any of the sets.
+    pub fn set_union(&self, keys: &[String]) -> Result<BTreeSet<String>, CacheError> {
+        let shards = self.read_keys(keys.iter().map(|key| key.as_str()));
+        collections::union(keys.iter().map(|key| shards.get(key.as_str()).set_members(key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn max_entries_holds_across_shards() {
+        let mut cache: ShardedCache<u64, u64> = ShardedCache::in_memory(16);
+        cache.set_max_entries(Some(17));
+        for key in 0..1000 {
+            cache.write(&key).insert(key, key);
+        }
+        assert_eq!(cache.len(), 17);
+    }
+
+    #[test]
+    fn max_memory_holds_across_shards() {
+        let mut cache: ShardedCache<u64, Vec<u8>> = ShardedCache::in_memory(16);
+        cache.set_weigher(|_, value| value.len()).set_max_memory(Some(1_000_000));
+        // Far more than a sixteenth of the limit, but well within all of it.
+        cache.write(&1).insert(1, vec![0; 200_000]);
+        assert!(cache.read(&1).contains(&1));
+
+        for key in 2..100 {
+            cache.write(&key).insert(key, vec![0; 50_000]);
+        }
+        assert!(cache.used_memory() <= 1_000_000);
+        assert!(cache.used_memory() > 900_000);
+    }
+
+    #[test]
+    fn lowering_a_limit_evicts_from_every_shard() {
+        let mut cache: ShardedCache<u64, u64> = ShardedCache::in_memory(4);
+        for key in 0..400 {
+            cache.write(&key).insert(key, key);
+        }
+        cache.set_max_entries(Some(200));
+        assert_eq!(cache.len(), 200);
+        assert!(cache.read_all().iter().all(|shard| !shard.is_empty()));
+    }
+
+    #[test]
+    fn clear_is_announced_once() {
+        let cache: ShardedCache<u64, u64> = ShardedCache::in_memory(8);
+        for key in 0..100 {
+            cache.write(&key).insert(key, key);
+        }
+        let events = cache.subscribe();
+        cache.clear();
+        assert!(cache.is_empty());
+        let events: Vec<CacheEvent<u64>> = events.try_iter().collect();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0], CacheEvent::Cleared));
+    }
+
+    #[test]
+    fn saves_after_a_writer_panicked() {
+        let dir = std::env::temp_dir().join(format!("zen-cache-sharded-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut cache = ShardedCache::new(dir.to_string_lossy().into_owned(), 2);
+        cache.set_save_interval(Some(Duration::from_secs(5)));
+        for key in ["a", "b", "c", "d"] {
+            cache.write(key).insert(key.to_string(), CacheValue::Int(1));
+        }
+        std::thread::scope(|scope| {
+            let result = scope
+                .spawn(|| {
+                    let _shard = cache.shards[0].write().unwrap();
+                    panic!("handler failed");
+                })
+                .join();
+            assert!(result.is_err());
+        });
+        assert!(cache.shards[0].is_poisoned());
+        assert_eq!(cache.save_interval(), Some(Duration::from_secs(5)));
+        assert_eq!(cache.flush().unwrap(), 4);
+        assert!(cache.snapshot_path().exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_checkpoints_keep_every_write() {
+        let dir = std::env::temp_dir().join(format!("zen-cache-checkpoints-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let open = || {
+            let mut cache = ShardedCache::new(dir.to_string_lossy().into_owned(), 4);
+            cache.load().unwrap();
+            cache.enable_log(FsyncPolicy::Never).unwrap();
+            cache
+        };
+
+        let cache = open();
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    for _ in 0..20 {
+                        cache.checkpoint().unwrap();
+                    }
+                });
+            }
+            for i in 0..2000 {
+                let key = i.to_string();
+                cache.write(&key).insert(key, CacheValue::Int(i));
+            }
+        });
+        // Each change is taken off the count once, so it gets back to 0.
+        cache.checkpoint().unwrap();
+        assert_eq!(cache.unsaved_changes(), 0);
+        for i in 2000..2100 {
+            let key = i.to_string();
+            cache.write(&key).insert(key, CacheValue::Int(i));
+        }
+        drop(cache);
+
+        let cache = open();
+        assert_eq!(cache.len(), 2100);
+        assert!((0..2100).all(|i| {
+            let key = i.to_string();
+            cache.read(&key).get(&key) == Some(&CacheValue::Int(i))
+        }));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scan_returns_every_key_once() {
+        let cache: ShardedCache<String, u64> = ShardedCache::in_memory(4);
+        for i in 0..1000 {
+            cache.write(&i.to_string()).insert(i.to_string(), i);
+        }
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut pages = 0;
+        loop {
+            let (keys, next) = cache.scan(cursor, 7);
+            assert!(keys.len() <= 7);
+            // Writes during the walk don't make it skip or repeat keys.
+            let key = format!("new-{}", pages);
+            cache.write(&key).insert(key, 0);
+            for key in keys {
+                assert!(seen.insert(key), "returned twice");
+            }
+            pages += 1;
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert!((0..1000).all(|i| seen.contains(&i.to_string())));
+        assert!(pages >= 1000 / 7);
+    }
+
+}
@@ -0,0 +1,124 @@
+use std::{
+    fmt::Display,
+    panic,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+// Set from the signal handler, which may only touch atomics.
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static REASON: Mutex<Option<Reason>> = Mutex::new(None);
+
+#[derive(Debug, Clone)]
+pub enum Reason {
+    Signal(i32),
+    Panic(String),
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reason::Signal(signal) => write!(f, "received {}", signal_name(*signal)),
+            Reason::Panic(message) => write!(f, "panic in handler thread: {}", message),
+        }
+    }
+}
+
+impl Reason {
+    /// Process exit code for this reason. Signals are an orderly stop.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Reason::Signal(_) => 0,
+            Reason::Panic(_) => 1,
+        }
+    }
+}
+
+fn signal_name(signal: i32) -> String {
+    #[cfg(unix)]
+    {
+        if signal == libc::SIGINT {
+            return String::from("SIGINT");
+        }
+        if signal == libc::SIGTERM {
+            return String::from("SIGTERM");
+        }
+    }
+    format!("signal {}", signal)
+}
+
+#[cfg(unix)]
+extern "C" fn handle_signal(signal: libc::c_int) {
+    SIGNAL.store(signal, Ordering::SeqCst);
+}
+
+/// Routes SIGINT and SIGTERM into a shutdown request.
+pub fn install_signal_handlers() {
+    #[cfg(unix)]
+    unsafe {
+        let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// Turns a panic in any thread into a shutdown request, after the default
+/// hook has printed it.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match info.payload().downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => String::from("unknown cause"),
+            },
+        };
+        let location = info
+            .location()
+            .map(|location| format!(" at {}:{}", location.file(), location.line()))
+            .unwrap_or_default();
+        request(Reason::Panic(format!("{}{}", message, location)));
+    }));
+}
+
+/// Asks the server to shut down. Only the first reason is kept.
+pub fn request(reason: Reason) {
+    let mut current = REASON.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if current.is_none() {
+        *current = Some(reason);
+    }
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_requested() -> bool {
+    let signal = SIGNAL.load(Ordering::SeqCst);
+    if signal != 0 && !REQUESTED.load(Ordering::SeqCst) {
+        request(Reason::Signal(signal));
+    }
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+pub fn reason() -> Option<Reason> {
+    REASON
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+}
+
+/// Spawns a thread that waits for a shutdown request and then calls `wake`
+/// once, which should unblock whatever loop is accepting connections.
+pub fn spawn_watcher(wake: impl Fn() + Send + 'static) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        while !is_requested() {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        wake();
+    })
+}
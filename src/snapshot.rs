@@ -0,0 +1,225 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind, Write},
+    path::Path,
+};
+
+use crate::cache::CacheValue;
+use crate::codec::{self, Decoder, Encoder};
+
+// Snapshot layout (all integers little endian):
+//   magic "ZENS" | version u16 | entry count u64
+//   per entry: key (u32 len + bytes) | expiry (u8 flag + u64 unix ms) | value (u8 tag + payload)
+//   crc32 of everything above, u32
+// Version 2 added the bytes, bool and JSON value tags. Version 1 snapshots
+// only use tags that version 2 still reads the same way.
+const MAGIC: &[u8; 4] = b"ZENS";
+const VERSION: u16 = 2;
+const OLDEST_VERSION: u16 = 1;
+
+pub const FILE_NAME: &str = "zen-cache.snapshot";
+
+pub struct SnapshotEntry {
+    pub key: String,
+    pub value: CacheValue,
+    /// Absolute expiry in milliseconds since the unix epoch.
+    pub expires_at: Option<u64>,
+}
+
+pub fn encode<'a>(
+    count: usize,
+    entries: impl Iterator<Item = (&'a str, &'a CacheValue, Option<u64>)>,
+) -> Vec<u8> {
+    let mut encoder = Encoder::new();
+    encoder.put_raw(MAGIC).put_u16(VERSION).put_u64(count as u64);
+    for (key, value, expires_at) in entries {
+        encoder.put_str(key).put_option_u64(expires_at).put_value(value);
+    }
+    let checksum = codec::crc32(encoder.bytes());
+    encoder.put_u32(checksum);
+    encoder.into_bytes()
+}
+
+pub fn decode(data: &[u8]) -> Result<Vec<SnapshotEntry>, Error> {
+    if data.len() < MAGIC.len() + 2 + 8 + 4 {
+        return Err(codec::invalid("Snapshot is too short."));
+    }
+    let (body, checksum) = data.split_at(data.len() - 4);
+    let checksum = u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+    if codec::crc32(body) != checksum {
+        return Err(codec::invalid("Snapshot checksum mismatch."));
+    }
+
+    let mut decoder = Decoder::new(body);
+    if decoder.get_raw(MAGIC.len())? != MAGIC {
+        return Err(codec::invalid("Not a zen-cache snapshot."));
+    }
+    let version = decoder.get_u16()?;
+    if !(OLDEST_VERSION..=VERSION).contains(&version) {
+        return Err(codec::invalid(
+            format!("Unsupported snapshot version {}.", version).as_str(),
+        ));
+    }
+
+    let count = decoder.get_u64()? as usize;
+    let mut entries = Vec::with_capacity(count.min(1 << 16));
+    for _ in 0..count {
+        entries.push(SnapshotEntry {
+            key: decoder.get_string()?,
+            expires_at: decoder.get_option_u64()?,
+            value: decoder.get_value()?,
+        });
+    }
+    if !decoder.is_empty() {
+        return Err(codec::invalid("Trailing data after snapshot entries."));
+    }
+    Ok(entries)
+}
+
+/// Writes `data` to `path` through a temporary file that is synced and
+/// renamed over the target, so readers only ever see a complete file.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    // Persist the rename itself. Not every platform allows syncing a directory.
+    if let Some(parent) = path.parent() {
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+/// Reads a snapshot file, `Ok(None)` if there is none yet.
+pub fn read(path: &Path) -> Result<Option<Vec<SnapshotEntry>>, Error> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(decode(&data)?)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::collections::SortedSet;
+
+    fn values() -> Vec<CacheValue> {
+        let mut sorted = SortedSet::new();
+        sorted.insert(String::from("a"), 1.5);
+        sorted.insert(String::from("b"), -2.0);
+        vec![
+            CacheValue::Int(-7),
+            CacheValue::Int64(i64::MAX),
+            CacheValue::Float(0.25),
+            CacheValue::String(String::from("héllo")),
+            CacheValue::StringVec(vec![String::from("a"), String::new()]),
+            CacheValue::IntVec(vec![1, -2]),
+            CacheValue::I64Vec(vec![i64::MIN]),
+            CacheValue::FloatVec(vec![1.0, f64::INFINITY]),
+            CacheValue::List(["x", "y"].iter().map(|x| x.to_string()).collect()),
+            CacheValue::Set(["x", "y"].iter().map(|x| x.to_string()).collect()),
+            CacheValue::Hash([("f", "v")].iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()),
+            CacheValue::SortedSet(sorted),
+            CacheValue::Bytes(vec![0, 255, 10]),
+            CacheValue::Bool(true),
+            CacheValue::Json(json!({"user": {"tags": ["a", 1, null]}})),
+        ]
+    }
+
+    fn encoded(values: &[CacheValue]) -> Vec<u8> {
+        let keys: Vec<String> = (0..values.len()).map(|i| format!("key-{}", i)).collect();
+        encode(
+            values.len(),
+            keys.iter()
+                .zip(values)
+                .enumerate()
+                .map(|(i, (key, value))| (key.as_str(), value, (i % 2 == 0).then_some(i as u64))),
+        )
+    }
+
+    #[test]
+    fn round_trips_every_value_type() {
+        let values = values();
+        let entries = decode(&encoded(&values)).unwrap();
+        assert_eq!(entries.len(), values.len());
+        for (i, (entry, value)) in entries.iter().zip(&values).enumerate() {
+            assert_eq!(entry.key, format!("key-{}", i));
+            assert_eq!(&entry.value, value);
+            assert_eq!(entry.expires_at, (i % 2 == 0).then_some(i as u64));
+        }
+    }
+
+    /// `data` re-stamped with another format version and a matching checksum.
+    fn with_version(data: &[u8], version: u16) -> Vec<u8> {
+        let mut body = data[..data.len() - 4].to_vec();
+        body[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&version.to_le_bytes());
+        let checksum = codec::crc32(&body);
+        body.extend_from_slice(&checksum.to_le_bytes());
+        body
+    }
+
+    #[test]
+    fn writes_the_current_version_and_reads_version_1() {
+        let values = values();
+        let data = encoded(&values);
+        assert_eq!(data[MAGIC.len()..MAGIC.len() + 2], VERSION.to_le_bytes());
+
+        // Version 1 predates the bytes, bool and JSON tags.
+        let old: Vec<CacheValue> = values
+            .into_iter()
+            .filter(|value| {
+                !matches!(value, CacheValue::Bytes(_) | CacheValue::Bool(_) | CacheValue::Json(_))
+            })
+            .collect();
+        let entries = decode(&with_version(&encoded(&old), 1)).unwrap();
+        assert_eq!(entries.len(), old.len());
+        for (entry, value) in entries.iter().zip(&old) {
+            assert_eq!(&entry.value, value);
+        }
+
+        for version in [0, VERSION + 1] {
+            let err = decode(&with_version(&data, version)).err().unwrap();
+            assert!(err.to_string().contains("Unsupported snapshot version"));
+        }
+    }
+
+    #[test]
+    fn rejects_corrupted_snapshots() {
+        let data = encoded(&values());
+        let mut flipped = data.clone();
+        flipped[20] ^= 1;
+        assert!(decode(&flipped).err().unwrap().to_string().contains("checksum"));
+        assert!(decode(&data[..data.len() - 1]).is_err());
+        assert!(decode(&data[..10]).is_err());
+
+        // A valid checksum over the wrong magic or version still fails.
+        let mut foreign = data[..data.len() - 4].to_vec();
+        foreign[0] = b'X';
+        let checksum = codec::crc32(&foreign);
+        foreign.extend_from_slice(&checksum.to_le_bytes());
+        assert!(decode(&foreign).err().unwrap().to_string().contains("Not a zen-cache"));
+    }
+
+    #[test]
+    fn empty_snapshot_round_trips() {
+        assert!(decode(&encode(0, std::iter::empty())).unwrap().is_empty());
+    }
+
+    #[test]
+    fn crc32_known_answer() {
+        assert_eq!(codec::crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(codec::crc32(b""), 0);
+    }
+}
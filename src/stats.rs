@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use serde_json::{json, Value};
+
+/// Server wide counters, readable by any handler.
+pub struct ServerStats {
+    pub active_connections: AtomicUsize,
+    pub queued_connections: AtomicUsize,
+    pub total_connections: AtomicU64,
+    pub rejected_connections: AtomicU64,
+    pub total_requests: AtomicU64,
+}
+
+pub static SERVER: ServerStats = ServerStats {
+    active_connections: AtomicUsize::new(0),
+    queued_connections: AtomicUsize::new(0),
+    total_connections: AtomicU64::new(0),
+    rejected_connections: AtomicU64::new(0),
+    total_requests: AtomicU64::new(0),
+};
+
+/// Counts a connection as active for as long as it is alive, even if its
+/// handler panics.
+pub struct Active;
+
+impl Active {
+    pub fn new() -> Active {
+        SERVER.active_connections.fetch_add(1, Ordering::SeqCst);
+        Active
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        SERVER.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ServerStats {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "active_connections": self.active_connections.load(Ordering::SeqCst),
+            "queued_connections": self.queued_connections.load(Ordering::SeqCst),
+            "total_connections": self.total_connections.load(Ordering::SeqCst),
+            "rejected_connections": self.rejected_connections.load(Ordering::SeqCst),
+            "total_requests": self.total_requests.load(Ordering::SeqCst),
+        })
+    }
+}
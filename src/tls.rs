@@ -0,0 +1,347 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Error, ErrorKind, Read, Write},
+    net::{Shutdown, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    thread::JoinHandle,
+    time::{Duration, SystemTime},
+};
+
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::CertificateDer,
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    RootCertStore, ServerConfig, ServerConnection,
+};
+
+// Enough for one full TLS record and its overhead.
+const READ_CHUNK: usize = 17 * 1024;
+
+/// A certificate served to clients asking for `name` through SNI.
+#[derive(Debug, Clone)]
+pub struct SniCertificate {
+    pub name: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Where the certificates come from and whether clients have to present one.
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    /// PEM certificate chain and private key used when no SNI certificate matches.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub sni: Vec<SniCertificate>,
+    /// CA bundle client certificates are verified against, enables mutual TLS.
+    pub client_ca: Option<PathBuf>,
+    /// Whether clients without a certificate are turned away when `client_ca` is set.
+    pub client_auth_required: bool,
+}
+
+impl TlsSettings {
+    fn files(&self) -> Vec<&Path> {
+        let mut files = vec![self.cert.as_path(), self.key.as_path()];
+        for sni in &self.sni {
+            files.push(&sni.cert);
+            files.push(&sni.key);
+        }
+        if let Some(client_ca) = &self.client_ca {
+            files.push(client_ca);
+        }
+        files
+    }
+}
+
+fn invalid(path: &Path, message: impl std::fmt::Display) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("{}: {}", path.to_string_lossy(), message),
+    )
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let mut reader = BufReader::new(File::open(path).map_err(|err| invalid(path, err))?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<CertificateDer<'static>>, Error>>()
+        .map_err(|err| invalid(path, err))?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+fn load_certified_key(provider: &CryptoProvider, cert: &Path, key: &Path) -> Result<Arc<CertifiedKey>, Error> {
+    let certs = load_certs(cert)?;
+    let mut reader = BufReader::new(File::open(key).map_err(|err| invalid(key, err))?);
+    let key_der = rustls_pemfile::private_key(&mut reader)
+        .map_err(|err| invalid(key, err))?
+        .ok_or_else(|| invalid(key, "no private key found"))?;
+    let signing_key = provider
+        .key_provider
+        .load_private_key(key_der)
+        .map_err(|err| invalid(key, err))?;
+    let certified = CertifiedKey::new(certs, signing_key);
+    certified.keys_match().map_err(|err| invalid(key, err))?;
+    Ok(Arc::new(certified))
+}
+
+/// Picks the certificate for the server name a client asked for, falling
+/// back to the default one.
+#[derive(Debug)]
+struct Certificates {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certified = client_hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()));
+        Some(Arc::clone(certified.unwrap_or(&self.default)))
+    }
+}
+
+fn build_config(settings: &TlsSettings) -> Result<Arc<ServerConfig>, Error> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let certificates = Certificates {
+        default: load_certified_key(&provider, &settings.cert, &settings.key)?,
+        by_name: settings
+            .sni
+            .iter()
+            .map(|sni| {
+                load_certified_key(&provider, &sni.cert, &sni.key)
+                    .map(|certified| (sni.name.to_ascii_lowercase(), certified))
+            })
+            .collect::<Result<HashMap<String, Arc<CertifiedKey>>, Error>>()?,
+    };
+
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    let builder = match &settings.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert).map_err(|err| invalid(path, err))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), Arc::clone(&provider));
+            let verifier = if settings.client_auth_required {
+                verifier
+            } else {
+                verifier.allow_unauthenticated()
+            };
+            builder.with_client_cert_verifier(verifier.build().map_err(|err| invalid(path, err))?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(builder.with_cert_resolver(Arc::new(certificates))))
+}
+
+fn modified(files: &[&Path]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|x| x.modified()).ok())
+        .collect()
+}
+
+/// Hands out TLS sessions for accepted connections. The certificates can be
+/// swapped while the server runs, established connections keep the ones
+/// they started with.
+pub struct TlsAcceptor {
+    settings: TlsSettings,
+    config: RwLock<Arc<ServerConfig>>,
+    // Modification times of the files the current config was built from.
+    loaded: Mutex<Vec<Option<SystemTime>>>,
+}
+
+#[allow(dead_code)]
+impl TlsAcceptor {
+    pub fn new(settings: TlsSettings) -> Result<TlsAcceptor, Error> {
+        let loaded = modified(&settings.files());
+        let config = build_config(&settings)?;
+        Ok(TlsAcceptor {
+            settings,
+            config: RwLock::new(config),
+            loaded: Mutex::new(loaded),
+        })
+    }
+
+    /// Wraps an accepted socket. The handshake happens on the first read.
+    pub fn accept(&self, socket: TcpStream) -> Result<TlsStream, Error> {
+        let config = Arc::clone(&self.config.read().unwrap());
+        let connection = ServerConnection::new(config).map_err(Error::other)?;
+        Ok(TlsStream {
+            session: Arc::new(Mutex::new(Session {
+                connection,
+                incoming: Vec::new(),
+            })),
+            socket,
+        })
+    }
+
+    /// Loads the certificates again if any of their files changed. A set
+    /// that fails to load leaves the current one in place and is retried
+    /// on the next call.
+    pub fn reload_if_changed(&self) -> Result<bool, Error> {
+        let current = modified(&self.settings.files());
+        let mut loaded = self.loaded.lock().unwrap();
+        if *loaded == current {
+            return Ok(false);
+        }
+        let config = build_config(&self.settings)?;
+        *self.config.write().unwrap() = config;
+        *loaded = current;
+        Ok(true)
+    }
+
+    /// Spawns a thread that checks the certificate files for changes. The
+    /// thread exits once the acceptor is dropped.
+    pub fn spawn_reloader(acceptor: &Arc<TlsAcceptor>, interval: Duration) -> JoinHandle<()> {
+        let weak = Arc::downgrade(acceptor);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let acceptor = match weak.upgrade() {
+                Some(acceptor) => acceptor,
+                None => return,
+            };
+            match acceptor.reload_if_changed() {
+                Ok(true) => println!("Reloaded TLS certificates"),
+                Ok(false) => {}
+                Err(err) => eprintln!("Error occurred while reloading TLS certificates: {}", err),
+            }
+        })
+    }
+}
+
+struct Session {
+    connection: ServerConnection,
+    /// Ciphertext read from the socket that rustls didn't take yet.
+    incoming: Vec<u8>,
+}
+
+impl Session {
+    /// Feeds buffered ciphertext to rustls until it holds plaintext to read.
+    fn process(&mut self) -> Result<(), Error> {
+        while !self.incoming.is_empty() && self.connection.wants_read() {
+            let read = self.connection.read_tls(&mut self.incoming.as_slice())?;
+            self.incoming.drain(..read);
+            self.process_packets()?;
+        }
+        Ok(())
+    }
+
+    fn process_packets(&mut self) -> Result<(), Error> {
+        self.connection
+            .process_new_packets()
+            .map(|_| ())
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+
+    /// Sends whatever rustls has queued, handshake messages and alerts included.
+    fn write_pending(&mut self, socket: &TcpStream) -> Result<(), Error> {
+        while self.connection.wants_write() {
+            self.connection.write_tls(&mut &*socket)?;
+        }
+        Ok(())
+    }
+}
+
+/// A TLS connection. Clones share the session, so one thread can wait for
+/// data while another writes, the way plain sockets are used.
+pub struct TlsStream {
+    session: Arc<Mutex<Session>>,
+    socket: TcpStream,
+}
+
+impl std::fmt::Debug for TlsStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsStream").field("socket", &self.socket).finish()
+    }
+}
+
+#[allow(dead_code)]
+impl TlsStream {
+    pub fn try_clone(&self) -> Result<TlsStream, Error> {
+        Ok(TlsStream {
+            session: Arc::clone(&self.session),
+            socket: self.socket.try_clone()?,
+        })
+    }
+
+    pub fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    /// Whether encrypted data is still waiting for the socket to accept it.
+    pub fn has_pending_output(&self) -> bool {
+        self.session.lock().unwrap().connection.wants_write()
+    }
+
+    /// Sends a close notification before shutting down the socket.
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        if how != Shutdown::Read {
+            let mut session = self.session.lock().unwrap();
+            session.connection.send_close_notify();
+            let _ = session.write_pending(&self.socket);
+        }
+        self.socket.shutdown(how)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            {
+                let mut session = self.session.lock().unwrap();
+                let processed = session.process();
+                if let Err(err) = processed {
+                    // Let the client know why, if the socket still takes it.
+                    let _ = session.write_pending(&self.socket);
+                    return Err(err);
+                }
+                match session.connection.reader().read(buf) {
+                    Ok(read) => return Ok(read),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                    // The client hung up without a close notification.
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(0),
+                    Err(err) => return Err(err),
+                }
+                session.write_pending(&self.socket)?;
+            }
+
+            // Waiting for the socket happens without the lock, so writers
+            // aren't held up by a reader waiting for the client.
+            let mut chunk = [0; READ_CHUNK];
+            let read = (&self.socket).read(&mut chunk)?;
+            let mut session = self.session.lock().unwrap();
+            if read == 0 {
+                session.connection.read_tls(&mut &[][..])?;
+                session.process_packets()?;
+            } else {
+                session.incoming.extend_from_slice(&chunk[..read]);
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut session = self.session.lock().unwrap();
+        // A socket that can't keep up gets no more than rustls already holds.
+        session.write_pending(&self.socket)?;
+        let written = session.connection.writer().write(buf)?;
+        match session.write_pending(&self.socket) {
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            result => result?,
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.session.lock().unwrap().write_pending(&self.socket)
+    }
+}
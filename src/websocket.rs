@@ -0,0 +1,663 @@
+use std::{
+    collections::HashMap,
+    io::{BufReader, Error, ErrorKind, Read, Write},
+    net::Shutdown,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::Receiver,
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+
+use crate::base64;
+use crate::cache::{CacheError, CacheEvent, CacheValue};
+use crate::digest;
+use crate::listener::{self, Listener, Stream, UnixSocket};
+use crate::parser::{self, Limits, RawRequest};
+use crate::pattern;
+use crate::server::{self, ConnectionPool, HTTPServer, SESSION_IDLE_TIMEOUT};
+use crate::sharded::ShardedCache;
+use crate::shutdown;
+use crate::stats;
+use crate::tls::TlsAcceptor;
+
+// Appended to the client's key before hashing, fixed by RFC 6455.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+const MAX_CONTROL_PAYLOAD: usize = 125;
+// A client that stops reading is dropped instead of stalling notifications.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_TTL: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED: u16 = 1003;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Why reading from a client stopped.
+enum FrameError {
+    Io(Error),
+    /// The client broke the protocol, close with this code and reason.
+    Close(u16, &'static str),
+}
+
+impl From<Error> for FrameError {
+    fn from(err: Error) -> FrameError {
+        FrameError::Io(err)
+    }
+}
+
+/// Reads one frame and unmasks its payload.
+fn read_frame<R: Read>(reader: &mut R, max_size: usize) -> Result<Frame, FrameError> {
+    let mut head = [0; 2];
+    reader.read_exact(&mut head)?;
+    if head[0] & 0x70 != 0 {
+        return Err(FrameError::Close(CLOSE_PROTOCOL_ERROR, "Reserved bits are set."));
+    }
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    if head[1] & 0x80 == 0 {
+        return Err(FrameError::Close(CLOSE_PROTOCOL_ERROR, "Client frames must be masked."));
+    }
+
+    let length = match head[1] & 0x7F {
+        126 => {
+            let mut length = [0; 2];
+            reader.read_exact(&mut length)?;
+            u16::from_be_bytes(length) as u64
+        }
+        127 => {
+            let mut length = [0; 8];
+            reader.read_exact(&mut length)?;
+            u64::from_be_bytes(length)
+        }
+        length => length as u64,
+    };
+    if opcode >= OP_CLOSE && (!fin || length > MAX_CONTROL_PAYLOAD as u64) {
+        return Err(FrameError::Close(
+            CLOSE_PROTOCOL_ERROR,
+            "Control frames must not be fragmented or longer than 125 bytes.",
+        ));
+    }
+    if length > max_size as u64 {
+        return Err(FrameError::Close(CLOSE_TOO_BIG, "Message exceeds the size limit."));
+    }
+
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+/// Writes a single unfragmented frame. Server frames are never masked.
+fn write_frame(stream: &mut impl Write, opcode: u8, payload: &[u8]) -> Result<(), Error> {
+    let mut out = Vec::with_capacity(payload.len() + 10);
+    out.push(0x80 | opcode);
+    match payload.len() {
+        length if length < 126 => out.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            out.push(126);
+            out.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            out.push(127);
+            out.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+    stream.write_all(&out)?;
+    stream.flush()
+}
+
+fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    payload
+}
+
+/// Answer to the opening handshake, the `Sec-WebSocket-Accept` value.
+fn accept_key(key: &str) -> String {
+    base64::encode(&digest::sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()))
+}
+
+fn has_token(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|x| x.split(',').any(|x| x.trim().eq_ignore_ascii_case(token)))
+}
+
+/// Checks an upgrade request and returns the error response to send if it
+/// can't be accepted.
+fn check_upgrade(request: &RawRequest) -> Result<String, (u64, &'static str)> {
+    if request.method != "GET" {
+        return Err((405, "WebSocket connections must be opened with GET."));
+    }
+    if !has_token(request.header("Upgrade"), "websocket")
+        || !has_token(request.header("Connection"), "upgrade")
+    {
+        return Err((426, "This endpoint only speaks WebSocket."));
+    }
+    if request.header("Sec-WebSocket-Version").map(|x| x.trim()) != Some("13") {
+        return Err((426, "Unsupported WebSocket version."));
+    }
+    match request.header("Sec-WebSocket-Key").map(|x| x.trim()) {
+        // The key is 16 random bytes, always 24 characters once encoded.
+        Some(key) if key.len() == 24 => Ok(key.to_string()),
+        _ => Err((400, "Missing or invalid Sec-WebSocket-Key.")),
+    }
+}
+
+/// A connected client, as seen by the event dispatcher.
+struct Client {
+    writer: Arc<Mutex<Stream>>,
+    patterns: Vec<String>,
+}
+
+/// Everyone connected, by connection id.
+type Clients = Mutex<HashMap<u64, Client>>;
+
+fn send(writer: &Mutex<Stream>, opcode: u8, payload: &[u8]) -> Result<(), Error> {
+    write_frame(&mut *writer.lock().unwrap(), opcode, payload)
+}
+
+/// Forwards cache events to every client subscribed to a matching pattern,
+/// until the cache is gone.
+fn dispatch(events: Receiver<CacheEvent>, clients: &Clients) {
+    for event in events {
+        let targets: Vec<(Arc<Mutex<Stream>>, Option<String>)> = clients
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|client| {
+                let pattern = match event.key() {
+                    Some(key) => Some(client.patterns.iter().find(|x| pattern::matches(x, key))?),
+                    // Clearing the cache concerns every subscriber.
+                    None if client.patterns.is_empty() => return None,
+                    None => None,
+                };
+                Some((Arc::clone(&client.writer), pattern.cloned()))
+            })
+            .collect();
+        for (writer, pattern) in targets {
+            let message = json!({
+                "event": event.name(),
+                "key": event.key(),
+                "pattern": pattern,
+            });
+            // A client that can't keep up is cut off, its reader notices.
+            if send(&writer, OP_TEXT, message.to_string().as_bytes()).is_err() {
+                let _ = writer.lock().unwrap().shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+/// Runs a single JSON command and returns the `result` to reply with.
+fn execute(command: &Value, cache: &ShardedCache, clients: &Clients, id: u64) -> Result<Value, String> {
+    let op = command
+        .get("op")
+        .and_then(|x| x.as_str())
+        .ok_or("Missing 'op'.")?;
+    let key = || {
+        command
+            .get("key")
+            .and_then(|x| x.as_str())
+            .ok_or(format!("'{}' needs a 'key'.", op))
+    };
+    let seconds = |name: &str| -> Result<Option<Duration>, String> {
+        match command.get(name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => value
+                .as_f64()
+                .filter(|x| x.is_finite() && *x >= 0.0)
+                .map(|x| Some(Duration::from_secs_f64(x).min(MAX_TTL)))
+                .ok_or(format!("'{}' must be a number of seconds.", name)),
+        }
+    };
+
+    match op {
+        "ping" => Ok(json!("pong")),
+        "get" => {
+            let key = key()?;
+            let cache = cache.read(key);
+            match cache.get(key) {
+                Some(value) => Ok(json!({
+                    "key": key,
+                    "type": value.type_name(),
+                    "value": value.to_json(),
+                })),
+                None => Err(CacheError::NotFound(key.to_string()).to_string()),
+            }
+        }
+        "set" => {
+            let key = key()?;
+            let value = command.get("value").ok_or("'set' needs a 'value'.")?;
+            let type_name = command.get("type").and_then(|x| x.as_str());
+            let value = CacheValue::from_json(value, type_name).map_err(|err| err.to_string())?;
+            let ttl = seconds("ttl")?;
+            let mut cache = cache.write(key);
+            match ttl {
+                Some(ttl) => cache.insert_with_ttl(key.to_string(), value, ttl),
+                None => cache.insert(key.to_string(), value),
+            };
+            Ok(json!(true))
+        }
+        "delete" => {
+            let key = key()?;
+            Ok(json!(cache.write(key).remove(key).is_some()))
+        }
+        "keys" => {
+            let pattern = command.get("pattern").and_then(|x| x.as_str()).unwrap_or("*");
+            let mut keys: Vec<String> = cache
+                .keys()
+                .into_iter()
+                .filter(|key| pattern::matches(pattern, key))
+                .collect();
+            keys.sort();
+            Ok(json!(keys))
+        }
+        "expire" => {
+            let key = key()?;
+            let ttl = seconds("ttl")?.ok_or("'expire' needs a 'ttl'.")?;
+            Ok(json!(cache.write(key).expire(key, ttl)))
+        }
+        "persist" => {
+            let key = key()?;
+            Ok(json!(cache.write(key).persist(key)))
+        }
+        "ttl" => {
+            let key = key()?;
+            match cache.read(key).ttl(key) {
+                Ok(ttl) => Ok(json!(ttl.map(|x| x.as_secs_f64()))),
+                Err(err) => Err(err.to_string()),
+            }
+        }
+        "subscribe" | "unsubscribe" => {
+            let pattern = command.get("pattern").and_then(|x| x.as_str());
+            let mut clients = clients.lock().unwrap();
+            let client = clients.get_mut(&id).ok_or("Connection is closing.")?;
+            match (op, pattern) {
+                ("subscribe", Some(pattern)) => {
+                    if !client.patterns.iter().any(|x| x == pattern) {
+                        client.patterns.push(pattern.to_string());
+                    }
+                }
+                ("subscribe", None) => return Err(String::from("'subscribe' needs a 'pattern'.")),
+                (_, Some(pattern)) => client.patterns.retain(|x| x != pattern),
+                (_, None) => client.patterns.clear(),
+            }
+            Ok(json!(client.patterns))
+        }
+        other => Err(format!("Unknown op '{}'.", other)),
+    }
+}
+
+/// Answers a text message. Replies echo the command's `id` when it has one.
+fn handle_message(message: &str, cache: &ShardedCache, clients: &Clients, id: u64) -> Value {
+    let command: Value = match serde_json::from_str(message) {
+        Ok(command) => command,
+        Err(err) => return json!({ "ok": false, "error": format!("Invalid JSON: {}", err) }),
+    };
+    let mut reply = match execute(&command, cache, clients, id) {
+        Ok(result) => json!({ "ok": true, "result": result }),
+        Err(error) => json!({ "ok": false, "error": error }),
+    };
+    if let Some(request_id) = command.get("id") {
+        reply["id"] = request_id.clone();
+    }
+    reply
+}
+
+/// Reads messages until the client closes or breaks the protocol. Returns
+/// the close frame to answer with, `None` if the connection is gone.
+fn serve(
+    reader: &mut impl Read,
+    writer: &Mutex<Stream>,
+    cache: &ShardedCache,
+    clients: &Clients,
+    id: u64,
+) -> Option<(u16, String)> {
+    // Opcode and payload of a fragmented message still being received.
+    let mut partial: Option<(u8, Vec<u8>)> = None;
+    loop {
+        let frame = match read_frame(reader, MAX_MESSAGE_SIZE) {
+            Ok(frame) => frame,
+            Err(FrameError::Io(err))
+                if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                return Some((CLOSE_GOING_AWAY, String::from("Idle timeout.")));
+            }
+            Err(FrameError::Io(err)) => {
+                if !matches!(err.kind(), ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset) {
+                    eprintln!("Error occurred while reading from a WebSocket client: {}", err);
+                }
+                return None;
+            }
+            Err(FrameError::Close(code, reason)) => return Some((code, reason.to_string())),
+        };
+
+        let (opcode, payload) = match frame.opcode {
+            OP_PING => {
+                send(writer, OP_PONG, &frame.payload).ok()?;
+                continue;
+            }
+            OP_PONG => continue,
+            OP_CLOSE => {
+                return match frame.payload.len() {
+                    0 => Some((CLOSE_NORMAL, String::new())),
+                    1 => Some((CLOSE_PROTOCOL_ERROR, String::from("Invalid close payload."))),
+                    _ => Some((
+                        u16::from_be_bytes([frame.payload[0], frame.payload[1]]),
+                        String::new(),
+                    )),
+                };
+            }
+            OP_TEXT | OP_BINARY if partial.is_some() => {
+                return Some((CLOSE_PROTOCOL_ERROR, String::from("Expected a continuation frame.")));
+            }
+            OP_TEXT | OP_BINARY if !frame.fin => {
+                partial = Some((frame.opcode, frame.payload));
+                continue;
+            }
+            OP_TEXT | OP_BINARY => (frame.opcode, frame.payload),
+            OP_CONTINUATION => {
+                let (opcode, mut payload) = match partial.take() {
+                    Some(partial) => partial,
+                    None => {
+                        return Some((CLOSE_PROTOCOL_ERROR, String::from("Unexpected continuation frame.")));
+                    }
+                };
+                if payload.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                    return Some((CLOSE_TOO_BIG, String::from("Message exceeds the size limit.")));
+                }
+                payload.extend_from_slice(&frame.payload);
+                if !frame.fin {
+                    partial = Some((opcode, payload));
+                    continue;
+                }
+                (opcode, payload)
+            }
+            _ => return Some((CLOSE_PROTOCOL_ERROR, String::from("Unknown opcode."))),
+        };
+
+        if opcode == OP_BINARY {
+            return Some((CLOSE_UNSUPPORTED, String::from("Only JSON text messages are supported.")));
+        }
+        let message = match String::from_utf8(payload) {
+            Ok(message) => message,
+            Err(_) => return Some((CLOSE_INVALID_DATA, String::from("Text message is not valid UTF-8."))),
+        };
+        stats::SERVER.total_requests.fetch_add(1, Ordering::SeqCst);
+        let reply = handle_message(&message, cache, clients, id);
+        send(writer, OP_TEXT, reply.to_string().as_bytes()).ok()?;
+    }
+}
+
+fn handle_connection(
+    stream: Stream,
+    cache: &ShardedCache,
+    clients: &Clients,
+    id: u64,
+    idle_timeout: Duration,
+) -> Result<(), Error> {
+    stream.set_read_timeout(Some(idle_timeout))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    let request = match parser::read_request(&mut reader, &Limits::default()) {
+        Ok(request) => request,
+        Err(err) => {
+            if let Some(status) = err.status() {
+                server::write_response(
+                    &mut writer,
+                    status,
+                    &[("Content-Type", "text/plain"), ("Connection", "close")],
+                    err.to_string().as_bytes(),
+                )?;
+            }
+            return Ok(());
+        }
+    };
+    let key = match check_upgrade(&request) {
+        Ok(key) => key,
+        Err((status, message)) => {
+            return server::write_response(
+                &mut writer,
+                status,
+                &[
+                    ("Content-Type", "text/plain"),
+                    ("Upgrade", "websocket"),
+                    ("Sec-WebSocket-Version", "13"),
+                    ("Connection", "close"),
+                ],
+                message.as_bytes(),
+            );
+        }
+    };
+    server::write_response(
+        &mut writer,
+        101,
+        &[
+            ("Upgrade", "websocket"),
+            ("Connection", "Upgrade"),
+            ("Sec-WebSocket-Accept", accept_key(&key).as_str()),
+        ],
+        b"",
+    )?;
+
+    let writer = Arc::new(Mutex::new(writer));
+    clients.lock().unwrap().insert(
+        id,
+        Client {
+            writer: Arc::clone(&writer),
+            patterns: Vec::new(),
+        },
+    );
+    let close = serve(&mut reader, &writer, cache, clients, id);
+    clients.lock().unwrap().remove(&id);
+
+    if let Some((code, reason)) = close {
+        let _ = send(&writer, OP_CLOSE, &close_payload(code, &reason));
+    }
+    let result = writer.lock().unwrap().shutdown(Shutdown::Both);
+    result
+}
+
+pub struct WebSocketServer {
+    port: String,
+    host: String,
+    unix_socket: Option<UnixSocket>,
+    tcp: bool,
+    tls: Option<Arc<TlsAcceptor>>,
+    workers: usize,
+    queue_size: usize,
+    idle_timeout: Duration,
+}
+
+impl WebSocketServer {
+    pub fn new(host: Option<&str>, port: Option<&str>) -> WebSocketServer {
+        WebSocketServer {
+            port: port.unwrap_or("8080").to_owned(),
+            host: host.unwrap_or("localhost").to_owned(),
+            unix_socket: None,
+            tcp: true,
+            tls: None,
+            workers: server::default_workers(),
+            queue_size: server::DEFAULT_QUEUE_SIZE,
+            idle_timeout: SESSION_IDLE_TIMEOUT,
+        }
+    }
+
+    /// Also accepts connections on this Unix socket.
+    pub fn set_unix_socket(&mut self, socket: Option<UnixSocket>) -> &mut WebSocketServer {
+        self.unix_socket = socket;
+        self
+    }
+
+    /// Whether to listen on the TCP port at all.
+    pub fn set_tcp(&mut self, enabled: bool) -> &mut WebSocketServer {
+        self.tcp = enabled;
+        self
+    }
+
+    /// Speaks TLS on the TCP port.
+    pub fn set_tls(&mut self, acceptor: Option<Arc<TlsAcceptor>>) -> &mut WebSocketServer {
+        self.tls = acceptor;
+        self
+    }
+
+    /// Number of worker threads serving clients and how many accepted
+    /// connections may wait for one, anything beyond that gets a 503.
+    /// Every connected client holds on to a worker. `None` keeps the
+    /// current setting.
+    pub fn set_pool(&mut self, workers: Option<usize>, queue_size: Option<usize>) -> &mut WebSocketServer {
+        if let Some(workers) = workers {
+            self.workers = workers.max(1);
+        }
+        if let Some(queue_size) = queue_size {
+            self.queue_size = queue_size;
+        }
+        self
+    }
+
+    /// How long a client may go without sending a frame, pings included,
+    /// before it is closed. `None` keeps the current setting.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) -> &mut WebSocketServer {
+        if let Some(idle_timeout) = idle_timeout {
+            self.idle_timeout = idle_timeout;
+        }
+        self
+    }
+
+    /// Serves WebSocket clients on a pool of workers until a shutdown is
+    /// requested. Clients are sent a close frame and the cache is flushed
+    /// once their connections are drained.
+    pub fn listen(&mut self, cache: ShardedCache) -> Result<&WebSocketServer, Error> {
+        let address = format!("{}:{}", &self.host, &self.port);
+        let listeners = Listener::bind_all(
+            self.tcp.then_some(address.as_str()),
+            self.unix_socket.as_ref(),
+            self.tls.as_ref(),
+        )
+        .expect("An Error occured while registering the Listener!");
+        let cache = HTTPServer::share_cache(cache);
+        let clients: Arc<Clients> = Arc::new(Mutex::new(HashMap::new()));
+
+        let events = cache.subscribe();
+        let dispatcher_clients = Arc::clone(&clients);
+        std::thread::spawn(move || dispatch(events, &dispatcher_clients));
+
+        let next_id = AtomicU64::new(1);
+        let pool_cache = Arc::clone(&cache);
+        let pool_clients = Arc::clone(&clients);
+        let idle_timeout = self.idle_timeout;
+        let pool = ConnectionPool::new(self.workers, self.queue_size, move |stream| {
+            let id = next_id.fetch_add(1, Ordering::SeqCst);
+            if let Err(err) = handle_connection(stream, &pool_cache, &pool_clients, id, idle_timeout) {
+                if err.kind() != ErrorKind::NotConnected {
+                    eprintln!("Error occurred while serving a WebSocket client: {}", err);
+                }
+            }
+        });
+
+        println!(
+            "Now listening for WebSocket clients on {} ({} workers, queue of {})",
+            listener::describe(&listeners),
+            self.workers,
+            self.queue_size
+        );
+
+        pool.accept(&listeners, server::write_busy)?;
+
+        drop(listeners);
+        if let Some(reason) = shutdown::reason() {
+            println!("Shutting down: {}", reason);
+        }
+        let writers: Vec<Arc<Mutex<Stream>>> = clients
+            .lock()
+            .unwrap()
+            .values()
+            .map(|client| Arc::clone(&client.writer))
+            .collect();
+        for writer in writers {
+            let _ = send(&writer, OP_CLOSE, &close_payload(CLOSE_GOING_AWAY, "Server is shutting down."));
+            let _ = writer.lock().unwrap().shutdown(Shutdown::Both);
+        }
+        pool.drain();
+        HTTPServer::flush(&cache);
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The sample handshake from RFC 6455 section 1.3.
+    #[test]
+    fn accept_key_matches_rfc_sample() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    // A masked "Hello" from RFC 6455 section 5.7.
+    #[test]
+    fn reads_masked_frames() {
+        let data: &[u8] = &[0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let frame = read_frame(&mut &data[..], MAX_MESSAGE_SIZE).ok().unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, OP_TEXT);
+        assert_eq!(frame.payload, b"Hello");
+    }
+
+    #[test]
+    fn rejects_unmasked_and_oversized_frames() {
+        let unmasked: &[u8] = &[0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        assert!(matches!(
+            read_frame(&mut &unmasked[..], MAX_MESSAGE_SIZE),
+            Err(FrameError::Close(CLOSE_PROTOCOL_ERROR, _))
+        ));
+        let long: &[u8] = &[0x82, 0xfe, 0x01, 0x00, 0, 0, 0, 0];
+        assert!(matches!(read_frame(&mut &long[..], 255), Err(FrameError::Close(CLOSE_TOO_BIG, _))));
+        let long_ping: &[u8] = &[0x89, 0xfe, 0x00, 0x7e];
+        assert!(matches!(
+            read_frame(&mut &long_ping[..], MAX_MESSAGE_SIZE),
+            Err(FrameError::Close(CLOSE_PROTOCOL_ERROR, _))
+        ));
+    }
+
+    #[test]
+    fn writes_length_in_the_smallest_form() {
+        let mut out = Vec::new();
+        write_frame(&mut out, OP_TEXT, b"Hello").unwrap();
+        assert_eq!(out, [0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+        out.clear();
+        write_frame(&mut out, OP_BINARY, &[0; 256]).unwrap();
+        assert_eq!(out[..4], [0x82, 126, 0x01, 0x00]);
+        assert_eq!(out.len(), 4 + 256);
+    }
+}